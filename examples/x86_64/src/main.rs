@@ -21,7 +21,7 @@ use virtio_drivers::{
     device::{blk::VirtIOBlk, gpu::VirtIOGpu, net::VirtIONet},
     transport::{
         pci::{
-            bus::{BarInfo, Cam, Command, DeviceFunction, PciRoot},
+            bus::{BarInfo, Cam, Command, DeviceFunction, MmioCam, PciRoot},
             virtio_device_type, PciTransport,
         },
         DeviceType, Transport,
@@ -147,12 +147,13 @@ fn virtio_net<T: Transport>(transport: T) {
 fn enumerate_pci(mmconfig_base: *mut u8) {
     info!("mmconfig_base = {:#x}", mmconfig_base as usize);
 
-    let mut pci_root = unsafe { PciRoot::new(mmconfig_base, Cam::Ecam) };
-    for (device_function, info) in pci_root.enumerate_bus(0) {
+    let mut pci_root = PciRoot::new(unsafe { MmioCam::new(mmconfig_base, Cam::Ecam) });
+    // The firmware has already assigned bus numbers to any bridges, such as PCIe root ports.
+    for (device_function, info, path) in pci_root.enumerate_tree(0) {
         let (status, command) = pci_root.get_status_command(device_function);
         info!(
-            "Found {} at {}, status {:?} command {:?}",
-            info, device_function, status, command
+            "Found {} at {} (via [{}]), status {:?} command {:?}",
+            info, device_function, path, status, command
         );
         if let Some(virtio_type) = virtio_device_type(&info) {
             info!("  VirtIO {:?}", virtio_type);
@@ -176,7 +177,11 @@ fn enumerate_pci(mmconfig_base: *mut u8) {
     }
 }
 
-fn dump_bar_contents(root: &mut PciRoot, device_function: DeviceFunction, bar_index: u8) {
+fn dump_bar_contents(
+    root: &mut PciRoot<MmioCam>,
+    device_function: DeviceFunction,
+    bar_index: u8,
+) {
     let bar_info = root.bar_info(device_function, bar_index).unwrap();
     trace!("Dumping bar {}: {:#x?}", bar_index, bar_info);
     if let BarInfo::Memory { address, size, .. } = bar_info {
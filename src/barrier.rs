@@ -0,0 +1,97 @@
+//! Memory barriers for ordering accesses to memory and registers shared with devices.
+
+#[cfg(any(
+    target_arch = "aarch64",
+    target_arch = "riscv32",
+    target_arch = "riscv64"
+))]
+use core::arch::asm;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use core::sync::atomic::compiler_fence;
+#[cfg(not(any(
+    target_arch = "aarch64",
+    target_arch = "riscv32",
+    target_arch = "riscv64"
+)))]
+use core::sync::atomic::{fence, Ordering};
+
+/// A kind of memory barrier, used to order the driver's accesses to memory which the device also
+/// accesses.
+///
+/// Ref: linux virtio_ring.h virtio_rmb, virtio_wmb and virtio_mb
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Barrier {
+    /// Orders earlier reads before later reads, such as reading a used ring entry only after
+    /// reading the index which says that it is there.
+    Read,
+    /// Orders earlier writes before later writes, such as writing descriptors before the available
+    /// ring index which makes them available to the device, or writing to a queue before notifying
+    /// the device through a register.
+    Write,
+    /// Orders all earlier reads and writes before all later reads and writes, such as writing the
+    /// available ring index before reading whether the device wants to be notified.
+    Full,
+}
+
+/// Issues the given memory barrier using the appropriate instructions for the target architecture.
+///
+/// If `platform` is true then the barrier orders accesses as observed by the platform's DMA and
+/// I/O, as is needed when `VIRTIO_F_ORDER_PLATFORM` has been negotiated, such as for a hardware
+/// device. Otherwise the device is assumed to be implemented by another CPU, such as in a
+/// hypervisor, and the barrier only orders accesses as observed by other CPUs, which is cheaper on
+/// some architectures.
+///
+/// This is the default implementation of [`Hal::barrier`](crate::Hal::barrier).
+///
+/// Ref: virtio 6.1 Driver Requirements: Reserved Feature Bits
+pub fn arch_barrier(barrier: Barrier, platform: bool) {
+    #[cfg(target_arch = "aarch64")]
+    // SAFETY: Barrier instructions don't access memory or have any other side effects.
+    unsafe {
+        match (barrier, platform) {
+            (Barrier::Read, false) => asm!("dmb ishld", options(nostack, preserves_flags)),
+            (Barrier::Write, false) => asm!("dmb ishst", options(nostack, preserves_flags)),
+            (Barrier::Full, false) => asm!("dmb ish", options(nostack, preserves_flags)),
+            (Barrier::Read, true) => asm!("dmb oshld", options(nostack, preserves_flags)),
+            (Barrier::Write, true) => asm!("dmb oshst", options(nostack, preserves_flags)),
+            (Barrier::Full, true) => asm!("dsb sy", options(nostack, preserves_flags)),
+        }
+    }
+
+    #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
+    // SAFETY: Fence instructions don't access memory or have any other side effects.
+    unsafe {
+        match (barrier, platform) {
+            (Barrier::Read, false) => asm!("fence r, r", options(nostack, preserves_flags)),
+            (Barrier::Write, false) => asm!("fence w, w", options(nostack, preserves_flags)),
+            (Barrier::Full, false) => asm!("fence rw, rw", options(nostack, preserves_flags)),
+            (Barrier::Read, true) => asm!("fence ir, ir", options(nostack, preserves_flags)),
+            (Barrier::Write, true) => asm!("fence ow, ow", options(nostack, preserves_flags)),
+            (Barrier::Full, true) => asm!("fence iorw, iorw", options(nostack, preserves_flags)),
+        }
+    }
+
+    // x86 doesn't reorder reads with other reads or writes with other writes, even for DMA, so only
+    // a full barrier needs an instruction.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        let _ = platform;
+        match barrier {
+            Barrier::Read | Barrier::Write => compiler_fence(Ordering::SeqCst),
+            Barrier::Full => fence(Ordering::SeqCst),
+        }
+    }
+
+    #[cfg(not(any(
+        target_arch = "aarch64",
+        target_arch = "riscv32",
+        target_arch = "riscv64",
+        target_arch = "x86",
+        target_arch = "x86_64"
+    )))]
+    {
+        // Without knowing anything about the architecture, be conservative.
+        let _ = (barrier, platform);
+        fence(Ordering::SeqCst);
+    }
+}
@@ -1,20 +1,28 @@
 //! Driver for VirtIO block devices.
 
-use crate::hal::Hal;
-use crate::queue::VirtQueue;
+use crate::hal::{DmaBuffer, Hal};
+use crate::queue::{QueueCompleter, QueueSubmitter, SharedAddrs, VirtQueue};
 use crate::transport::Transport;
-use crate::volatile::{volread, Volatile};
-use crate::{Error, Result};
+use crate::volatile::{read_config, Volatile};
+#[cfg(feature = "trace")]
+use crate::QueueStats;
+use crate::{Error, QueueWaker, Result, Timeout};
 use bitflags::bitflags;
+use core::task::{Context, Poll};
 use log::info;
 use zerocopy::{AsBytes, FromBytes, FromZeroes};
 
 const QUEUE: u16 = 0;
-const QUEUE_SIZE: u16 = 16;
+/// The maximum queue size, if the device supports it.
+const QUEUE_SIZE: u16 = 256;
 const SUPPORTED_FEATURES: BlkFeature = BlkFeature::RO
     .union(BlkFeature::FLUSH)
     .union(BlkFeature::RING_INDIRECT_DESC)
-    .union(BlkFeature::RING_EVENT_IDX);
+    .union(BlkFeature::RING_EVENT_IDX)
+    .union(BlkFeature::RING_PACKED)
+    .union(BlkFeature::IN_ORDER)
+    .union(BlkFeature::NOTIFICATION_DATA)
+    .union(BlkFeature::ORDER_PLATFORM);
 
 /// Driver for a VirtIO block device.
 ///
@@ -44,6 +52,8 @@ const SUPPORTED_FEATURES: BlkFeature = BlkFeature::RO
 /// ```
 pub struct VirtIOBlk<H: Hal, T: Transport> {
     transport: T,
+    /// How long to wait for the device to respond to requests.
+    timeout: Timeout,
     queue: VirtQueue<H, { QUEUE_SIZE as usize }>,
     capacity: u64,
     negotiated_features: BlkFeature,
@@ -55,24 +65,22 @@ impl<H: Hal, T: Transport> VirtIOBlk<H, T> {
         let negotiated_features = transport.begin_init(SUPPORTED_FEATURES);
 
         // Read configuration space.
-        let config = transport.config_space::<BlkConfig>()?;
-        info!("config: {:?}", config);
-        // Safe because config is a valid pointer to the device configuration space.
-        let capacity = unsafe {
-            volread!(config, capacity_low) as u64 | (volread!(config, capacity_high) as u64) << 32
-        };
+        let (capacity_low, capacity_high): (u32, u32) =
+            transport.read_config_atomically(|transport| {
+                Ok((
+                    read_config!(transport, BlkConfig, capacity_low)?,
+                    read_config!(transport, BlkConfig, capacity_high)?,
+                ))
+            })?;
+        let capacity = u64::from(capacity_low) | u64::from(capacity_high) << 32;
         info!("found a block device of size {}KB", capacity / 2);
 
-        let queue = VirtQueue::new(
-            &mut transport,
-            QUEUE,
-            negotiated_features.contains(BlkFeature::RING_INDIRECT_DESC),
-            negotiated_features.contains(BlkFeature::RING_EVENT_IDX),
-        )?;
+        let queue = VirtQueue::new_with_max_size(&mut transport, QUEUE, negotiated_features, None)?;
         transport.finish_init();
 
         Ok(VirtIOBlk {
             transport,
+            timeout: Timeout::Never,
             queue,
             capacity,
             negotiated_features,
@@ -89,6 +97,15 @@ impl<H: Hal, T: Transport> VirtIOBlk<H, T> {
         self.negotiated_features.contains(BlkFeature::RO)
     }
 
+    /// Sets how long blocking requests wait for the device to respond. The default is to wait
+    /// forever.
+    ///
+    /// If a request times out then [`Error::Timeout`] is returned and the
+    /// device is reset, so the driver can't be used any more.
+    pub fn set_timeout(&mut self, timeout: Timeout) {
+        self.timeout = timeout;
+    }
+
     /// Acknowledges a pending interrupt, if any.
     ///
     /// Returns true if there was an interrupt to acknowledge.
@@ -96,13 +113,36 @@ impl<H: Hal, T: Transport> VirtIOBlk<H, T> {
         self.transport.ack_interrupt()
     }
 
+    /// Returns the counters of the virtqueue with the given index, or `None` if the device doesn't
+    /// have such a queue.
+    #[cfg(feature = "trace")]
+    pub fn queue_stats(&self, queue: u16) -> Option<QueueStats> {
+        (queue == QUEUE).then(|| self.queue.stats())
+    }
+
+    /// Asks the device not to raise interrupts when it completes requests.
+    ///
+    /// This is useful for drivers which poll for completed requests.
+    pub fn disable_interrupts(&mut self) {
+        self.queue.disable_callbacks();
+    }
+
+    /// Asks the device to raise an interrupt when it next completes a request.
+    ///
+    /// Returns false if there are already completed requests waiting, in which case they should be
+    /// handled rather than waiting for an interrupt.
+    pub fn enable_interrupts(&mut self) -> bool {
+        self.queue.enable_callbacks()
+    }
+
     /// Sends the given request to the device and waits for a response, with no extra data.
     fn request(&mut self, request: BlkReq) -> Result {
         let mut resp = BlkResp::default();
-        self.queue.add_notify_wait_pop(
+        self.queue.add_notify_wait_pop_timeout(
             &[request.as_bytes()],
             &mut [resp.as_bytes_mut()],
             &mut self.transport,
+            self.timeout,
         )?;
         resp.status.into()
     }
@@ -110,10 +150,11 @@ impl<H: Hal, T: Transport> VirtIOBlk<H, T> {
     /// Sends the given request to the device and waits for a response, including the given data.
     fn request_read(&mut self, request: BlkReq, data: &mut [u8]) -> Result {
         let mut resp = BlkResp::default();
-        self.queue.add_notify_wait_pop(
+        self.queue.add_notify_wait_pop_timeout(
             &[request.as_bytes()],
             &mut [data, resp.as_bytes_mut()],
             &mut self.transport,
+            self.timeout,
         )?;
         resp.status.into()
     }
@@ -121,10 +162,11 @@ impl<H: Hal, T: Transport> VirtIOBlk<H, T> {
     /// Sends the given request and data to the device and waits for a response.
     fn request_write(&mut self, request: BlkReq, data: &[u8]) -> Result {
         let mut resp = BlkResp::default();
-        self.queue.add_notify_wait_pop(
+        self.queue.add_notify_wait_pop_timeout(
             &[request.as_bytes(), data],
             &mut [resp.as_bytes_mut()],
             &mut self.transport,
+            self.timeout,
         )?;
         resp.status.into()
     }
@@ -178,6 +220,76 @@ impl<H: Hal, T: Transport> VirtIOBlk<H, T> {
         )
     }
 
+    /// Reads one or more blocks into the given DMA buffer, which is passed to the device without
+    /// being shared.
+    ///
+    /// The buffer length must be a non-zero multiple of [`SECTOR_SIZE`].
+    ///
+    /// Blocks until the read completes or there is an error.
+    pub fn read_blocks_dma(&mut self, block_id: usize, buf: &mut DmaBuffer<H>) -> Result {
+        assert_eq!(buf.len() % SECTOR_SIZE, 0);
+        let request = BlkReq {
+            type_: ReqType::In,
+            reserved: 0,
+            sector: block_id as u64,
+        };
+        let paddr = buf.paddr();
+        let mut resp = BlkResp::default();
+        // Safe because the device can access the DMA buffer at its physical address.
+        unsafe {
+            self.queue.add_notify_wait_pop_shared(
+                &[request.as_bytes()],
+                &mut [buf, resp.as_bytes_mut()],
+                SharedAddrs {
+                    inputs: &[],
+                    outputs: &[Some(paddr)],
+                },
+                &mut self.transport,
+                self.timeout,
+            )?;
+        }
+        resp.status.into()
+    }
+
+    /// Reads one or more blocks into the given buffer, waiting for the read to complete without
+    /// blocking.
+    ///
+    /// This is the asynchronous version of [`VirtIOBlk::read_blocks`]. While waiting the task is
+    /// registered with `waker`, which the interrupt handler for the device should wake. The
+    /// buffer length must be a non-zero multiple of [`SECTOR_SIZE`].
+    ///
+    /// # Safety
+    ///
+    /// The returned future must be polled to completion once it has been polled for the first
+    /// time, as the device may write to `buf` until then.
+    pub async unsafe fn read_blocks_async(
+        &mut self,
+        block_id: usize,
+        buf: &mut [u8],
+        waker: &QueueWaker,
+    ) -> Result {
+        assert_ne!(buf.len(), 0);
+        assert_eq!(buf.len() % SECTOR_SIZE, 0);
+        let request = BlkReq {
+            type_: ReqType::In,
+            reserved: 0,
+            sector: block_id as u64,
+        };
+        let mut resp = BlkResp::default();
+        // Safe because the caller ensures that the future is polled to completion.
+        unsafe {
+            self.queue
+                .add_notify_pop(
+                    &[request.as_bytes()],
+                    &mut [buf, resp.as_bytes_mut()],
+                    &mut self.transport,
+                    waker,
+                )
+                .await?;
+        }
+        resp.status.into()
+    }
+
     /// Submits a request to read one or more blocks, but returns immediately without waiting for
     /// the read to complete.
     ///
@@ -244,18 +356,8 @@ impl<H: Hal, T: Transport> VirtIOBlk<H, T> {
     ) -> Result<u16> {
         assert_ne!(buf.len(), 0);
         assert_eq!(buf.len() % SECTOR_SIZE, 0);
-        *req = BlkReq {
-            type_: ReqType::In,
-            reserved: 0,
-            sector: block_id as u64,
-        };
-        let token = self
-            .queue
-            .add(&[req.as_bytes()], &mut [buf, resp.as_bytes_mut()])?;
-        if self.queue.should_notify() {
-            self.transport.notify(QUEUE);
-        }
-        Ok(token)
+        // Safe because our caller has the same safety requirements.
+        unsafe { self.split().0.read_blocks_nb(block_id, req, buf, resp) }
     }
 
     /// Completes a read operation which was started by `read_blocks_nb`.
@@ -271,9 +373,8 @@ impl<H: Hal, T: Transport> VirtIOBlk<H, T> {
         buf: &mut [u8],
         resp: &mut BlkResp,
     ) -> Result<()> {
-        self.queue
-            .pop_used(token, &[req.as_bytes()], &mut [buf, resp.as_bytes_mut()])?;
-        resp.status.into()
+        // Safe because our caller has the same safety requirements.
+        unsafe { self.split().1.complete_read_blocks(token, req, buf, resp) }
     }
 
     /// Writes the contents of the given buffer to a block or blocks.
@@ -294,6 +395,75 @@ impl<H: Hal, T: Transport> VirtIOBlk<H, T> {
         )
     }
 
+    /// Writes the contents of the given DMA buffer to a block or blocks. The buffer is passed to
+    /// the device without being shared.
+    ///
+    /// The buffer length must be a non-zero multiple of [`SECTOR_SIZE`].
+    ///
+    /// Blocks until the write is complete or there is an error.
+    pub fn write_blocks_dma(&mut self, block_id: usize, buf: &DmaBuffer<H>) -> Result {
+        assert_eq!(buf.len() % SECTOR_SIZE, 0);
+        let request = BlkReq {
+            type_: ReqType::Out,
+            sector: block_id as u64,
+            ..Default::default()
+        };
+        let mut resp = BlkResp::default();
+        // Safe because the device can access the DMA buffer at its physical address.
+        unsafe {
+            self.queue.add_notify_wait_pop_shared(
+                &[request.as_bytes(), buf],
+                &mut [resp.as_bytes_mut()],
+                SharedAddrs {
+                    inputs: &[None, Some(buf.paddr())],
+                    outputs: &[],
+                },
+                &mut self.transport,
+                self.timeout,
+            )?;
+        }
+        resp.status.into()
+    }
+
+    /// Writes the contents of the given buffer to a block or blocks, waiting for the write to
+    /// complete without blocking.
+    ///
+    /// This is the asynchronous version of [`VirtIOBlk::write_blocks`]. While waiting the task is
+    /// registered with `waker`, which the interrupt handler for the device should wake. The
+    /// buffer length must be a non-zero multiple of [`SECTOR_SIZE`].
+    ///
+    /// # Safety
+    ///
+    /// The returned future must be polled to completion once it has been polled for the first
+    /// time, as the device may read from `buf` until then.
+    pub async unsafe fn write_blocks_async(
+        &mut self,
+        block_id: usize,
+        buf: &[u8],
+        waker: &QueueWaker,
+    ) -> Result {
+        assert_ne!(buf.len(), 0);
+        assert_eq!(buf.len() % SECTOR_SIZE, 0);
+        let request = BlkReq {
+            type_: ReqType::Out,
+            sector: block_id as u64,
+            ..Default::default()
+        };
+        let mut resp = BlkResp::default();
+        // Safe because the caller ensures that the future is polled to completion.
+        unsafe {
+            self.queue
+                .add_notify_pop(
+                    &[request.as_bytes(), buf],
+                    &mut [resp.as_bytes_mut()],
+                    &mut self.transport,
+                    waker,
+                )
+                .await?;
+        }
+        resp.status.into()
+    }
+
     /// Submits a request to write one or more blocks, but returns immediately without waiting for
     /// the write to complete.
     ///
@@ -326,18 +496,8 @@ impl<H: Hal, T: Transport> VirtIOBlk<H, T> {
     ) -> Result<u16> {
         assert_ne!(buf.len(), 0);
         assert_eq!(buf.len() % SECTOR_SIZE, 0);
-        *req = BlkReq {
-            type_: ReqType::Out,
-            reserved: 0,
-            sector: block_id as u64,
-        };
-        let token = self
-            .queue
-            .add(&[req.as_bytes(), buf], &mut [resp.as_bytes_mut()])?;
-        if self.queue.should_notify() {
-            self.transport.notify(QUEUE);
-        }
-        Ok(token)
+        // Safe because our caller has the same safety requirements.
+        unsafe { self.split().0.write_blocks_nb(block_id, req, buf, resp) }
     }
 
     /// Completes a write operation which was started by `write_blocks_nb`.
@@ -353,9 +513,8 @@ impl<H: Hal, T: Transport> VirtIOBlk<H, T> {
         buf: &[u8],
         resp: &mut BlkResp,
     ) -> Result<()> {
-        self.queue
-            .pop_used(token, &[req.as_bytes(), buf], &mut [resp.as_bytes_mut()])?;
-        resp.status.into()
+        // Safe because our caller has the same safety requirements.
+        unsafe { self.split().1.complete_write_blocks(token, req, buf, resp) }
     }
 
     /// Fetches the token of the next completed request from the used ring and returns it, without
@@ -364,11 +523,156 @@ impl<H: Hal, T: Transport> VirtIOBlk<H, T> {
         self.queue.peek_used()
     }
 
+    /// Returns the token of the next completed request if there is one, or otherwise registers the
+    /// current task with `waker` to be woken when a request completes.
+    ///
+    /// This can be used with `read_blocks_nb` and `write_blocks_nb` to wait for any of several
+    /// requests in flight without blocking, for example with [`core::future::poll_fn`].
+    pub fn poll_used(&self, waker: &QueueWaker, cx: &mut Context) -> Poll<u16> {
+        self.queue.poll_used(waker, cx)
+    }
+
     /// Returns the size of the device's VirtQueue.
     ///
     /// This can be used to tell the caller how many channels to monitor on.
     pub fn virt_queue_size(&self) -> u16 {
-        QUEUE_SIZE
+        self.queue.size()
+    }
+
+    /// Splits the driver into a [`BlkSubmitter`], which submits non-blocking requests, and a
+    /// [`BlkCompleter`], which completes them.
+    ///
+    /// The two may be sent to different CPUs, for example so that requests are completed in the
+    /// interrupt handler while other requests are being submitted, without any locking between
+    /// them.
+    pub fn split(&mut self) -> (BlkSubmitter<'_, H, T>, BlkCompleter<'_, H>) {
+        let (queue_submitter, queue_completer) = self.queue.split();
+        (
+            BlkSubmitter {
+                transport: &mut self.transport,
+                queue: queue_submitter,
+            },
+            BlkCompleter {
+                queue: queue_completer,
+            },
+        )
+    }
+}
+
+/// The submitting half of a [`VirtIOBlk`], returned by [`VirtIOBlk::split`].
+pub struct BlkSubmitter<'a, H: Hal, T: Transport> {
+    transport: &'a mut T,
+    queue: QueueSubmitter<'a, H, { QUEUE_SIZE as usize }>,
+}
+
+impl<H: Hal, T: Transport> BlkSubmitter<'_, H, T> {
+    /// Submits a request to read one or more blocks, but returns immediately without waiting for
+    /// the read to complete. See [`VirtIOBlk::read_blocks_nb`].
+    ///
+    /// # Safety
+    ///
+    /// See [`VirtIOBlk::read_blocks_nb`].
+    pub unsafe fn read_blocks_nb(
+        &mut self,
+        block_id: usize,
+        req: &mut BlkReq,
+        buf: &mut [u8],
+        resp: &mut BlkResp,
+    ) -> Result<u16> {
+        assert_ne!(buf.len(), 0);
+        assert_eq!(buf.len() % SECTOR_SIZE, 0);
+        *req = BlkReq {
+            type_: ReqType::In,
+            reserved: 0,
+            sector: block_id as u64,
+        };
+        let token = self
+            .queue
+            .add(&[req.as_bytes()], &mut [buf, resp.as_bytes_mut()])?;
+        self.queue.kick(self.transport);
+        Ok(token)
+    }
+
+    /// Submits a request to write one or more blocks, but returns immediately without waiting for
+    /// the write to complete. See [`VirtIOBlk::write_blocks_nb`].
+    ///
+    /// # Safety
+    ///
+    /// See [`VirtIOBlk::read_blocks_nb`].
+    pub unsafe fn write_blocks_nb(
+        &mut self,
+        block_id: usize,
+        req: &mut BlkReq,
+        buf: &[u8],
+        resp: &mut BlkResp,
+    ) -> Result<u16> {
+        assert_ne!(buf.len(), 0);
+        assert_eq!(buf.len() % SECTOR_SIZE, 0);
+        *req = BlkReq {
+            type_: ReqType::Out,
+            reserved: 0,
+            sector: block_id as u64,
+        };
+        let token = self
+            .queue
+            .add(&[req.as_bytes(), buf], &mut [resp.as_bytes_mut()])?;
+        self.queue.kick(self.transport);
+        Ok(token)
+    }
+}
+
+/// The completing half of a [`VirtIOBlk`], returned by [`VirtIOBlk::split`].
+pub struct BlkCompleter<'a, H: Hal> {
+    queue: QueueCompleter<'a, H, { QUEUE_SIZE as usize }>,
+}
+
+impl<H: Hal> BlkCompleter<'_, H> {
+    /// Fetches the token of the next completed request from the used ring and returns it, without
+    /// removing it from the used ring. If there are no pending completed requests returns `None`.
+    pub fn peek_used(&self) -> Option<u16> {
+        self.queue.peek_used()
+    }
+
+    /// Returns the token of the next completed request if there is one, or otherwise registers the
+    /// current task with `waker` to be woken when a request completes.
+    pub fn poll_used(&self, waker: &QueueWaker, cx: &mut Context) -> Poll<u16> {
+        self.queue.poll_used(waker, cx)
+    }
+
+    /// Completes a read operation which was started by [`BlkSubmitter::read_blocks_nb`].
+    ///
+    /// # Safety
+    ///
+    /// The same buffers must be passed in again as were passed to `read_blocks_nb` when it returned
+    /// the token.
+    pub unsafe fn complete_read_blocks(
+        &mut self,
+        token: u16,
+        req: &BlkReq,
+        buf: &mut [u8],
+        resp: &mut BlkResp,
+    ) -> Result<()> {
+        self.queue
+            .pop_used(token, &[req.as_bytes()], &mut [buf, resp.as_bytes_mut()])?;
+        resp.status.into()
+    }
+
+    /// Completes a write operation which was started by [`BlkSubmitter::write_blocks_nb`].
+    ///
+    /// # Safety
+    ///
+    /// The same buffers must be passed in again as were passed to `write_blocks_nb` when it
+    /// returned the token.
+    pub unsafe fn complete_write_blocks(
+        &mut self,
+        token: u16,
+        req: &BlkReq,
+        buf: &[u8],
+        resp: &mut BlkResp,
+    ) -> Result<()> {
+        self.queue
+            .pop_used(token, &[req.as_bytes(), buf], &mut [resp.as_bytes_mut()])?;
+        resp.status.into()
     }
 }
 
@@ -537,6 +841,9 @@ bitflags! {
         const ORDER_PLATFORM        = 1 << 36;
         const SR_IOV                = 1 << 37;
         const NOTIFICATION_DATA     = 1 << 38;
+
+        // since virtio v1.2
+        const RING_RESET            = 1 << 40;
     }
 }
 
@@ -544,15 +851,42 @@ bitflags! {
 mod tests {
     use super::*;
     use crate::{
-        hal::fake::FakeHal,
+        hal::{fake::FakeHal, BufferDirection},
         transport::{
             fake::{FakeTransport, QueueStatus, State},
             DeviceType,
         },
     };
     use alloc::{sync::Arc, vec};
-    use core::{mem::size_of, ptr::NonNull};
-    use std::{sync::Mutex, thread};
+    use core::{future::Future, mem::size_of, pin::pin, ptr::NonNull, task::Waker};
+    use std::{
+        sync::{mpsc, Mutex},
+        task::Wake,
+        thread::{self, Thread},
+    };
+
+    /// Wakes a thread which is parked waiting for a future.
+    struct ThreadWaker(Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    /// Runs the given future to completion on the current thread, parking it while the future is
+    /// pending.
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        let mut future = pin!(future);
+        loop {
+            match future.as_mut().poll(&mut cx) {
+                Poll::Ready(output) => return output,
+                Poll::Pending => thread::park(),
+            }
+        }
+    }
 
     #[test]
     fn config() {
@@ -622,31 +956,28 @@ mod tests {
             State::wait_until_queue_notified(&state, QUEUE);
             println!("Transmit queue was notified.");
 
-            state
-                .lock()
-                .unwrap()
-                .read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE, |request| {
-                    assert_eq!(
-                        request,
-                        BlkReq {
-                            type_: ReqType::In,
-                            reserved: 0,
-                            sector: 42
-                        }
-                        .as_bytes()
-                    );
-
-                    let mut response = vec![0; SECTOR_SIZE];
-                    response[0..9].copy_from_slice(b"Test data");
-                    response.extend_from_slice(
-                        BlkResp {
-                            status: RespStatus::OK,
-                        }
-                        .as_bytes(),
-                    );
-
-                    response
-                });
+            state.lock().unwrap().read_write_queue(QUEUE, |request| {
+                assert_eq!(
+                    request,
+                    BlkReq {
+                        type_: ReqType::In,
+                        reserved: 0,
+                        sector: 42
+                    }
+                    .as_bytes()
+                );
+
+                let mut response = vec![0; SECTOR_SIZE];
+                response[0..9].copy_from_slice(b"Test data");
+                response.extend_from_slice(
+                    BlkResp {
+                        status: RespStatus::OK,
+                    }
+                    .as_bytes(),
+                );
+
+                response
+            });
         });
 
         // Read a block from the device.
@@ -657,6 +988,180 @@ mod tests {
         handle.join().unwrap();
     }
 
+    /// Returns the configuration space of a fake device with the given capacity in sectors.
+    fn fake_config_space(capacity: u32) -> BlkConfig {
+        BlkConfig {
+            capacity_low: Volatile::new(capacity),
+            capacity_high: Volatile::new(0),
+            size_max: Volatile::new(0),
+            seg_max: Volatile::new(0),
+            cylinders: Volatile::new(0),
+            heads: Volatile::new(0),
+            sectors: Volatile::new(0),
+            blk_size: Volatile::new(0),
+            physical_block_exp: Volatile::new(0),
+            alignment_offset: Volatile::new(0),
+            min_io_size: Volatile::new(0),
+            opt_io_size: Volatile::new(0),
+        }
+    }
+
+    /// Creates a block device on a fake transport with the given configuration space and device
+    /// features, and returns it along with the state of the transport.
+    fn fake_blk(
+        config_space: &mut BlkConfig,
+        device_features: BlkFeature,
+    ) -> (
+        VirtIOBlk<FakeHal, FakeTransport<BlkConfig>>,
+        Arc<Mutex<State>>,
+    ) {
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default()],
+            ..Default::default()
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::Block,
+            max_queue_size: QUEUE_SIZE.into(),
+            device_features: device_features.bits(),
+            config_space: NonNull::from(config_space),
+            state: state.clone(),
+        };
+        let blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+        (blk, state)
+    }
+
+    /// Simulates the device waiting for a request to read sector 42, and responding to it with a
+    /// sector starting with "Test data".
+    fn fake_read_sector_42(state: &Mutex<State>) {
+        println!("Device waiting for a request.");
+        State::wait_until_queue_notified(state, QUEUE);
+        println!("Transmit queue was notified.");
+
+        state.lock().unwrap().read_write_queue(QUEUE, |request| {
+            assert_eq!(
+                request,
+                BlkReq {
+                    type_: ReqType::In,
+                    reserved: 0,
+                    sector: 42
+                }
+                .as_bytes()
+            );
+
+            let mut response = vec![0; SECTOR_SIZE];
+            response[0..9].copy_from_slice(b"Test data");
+            response.extend_from_slice(
+                BlkResp {
+                    status: RespStatus::OK,
+                }
+                .as_bytes(),
+            );
+
+            response
+        });
+    }
+
+    #[test]
+    fn read_dma() {
+        let mut config_space = fake_config_space(66);
+        let (mut blk, state) = fake_blk(&mut config_space, BlkFeature::RING_INDIRECT_DESC);
+
+        // Start a thread to simulate the device waiting for a read request.
+        let handle = thread::spawn(move || fake_read_sector_42(&state));
+
+        // Read a block from the device into a DMA buffer.
+        let mut buffer = DmaBuffer::new(SECTOR_SIZE, BufferDirection::DeviceToDriver).unwrap();
+        blk.read_blocks_dma(42, &mut buffer).unwrap();
+        assert_eq!(&buffer[0..9], b"Test data");
+
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn read_async() {
+        let mut config_space = fake_config_space(66);
+        let (mut blk, state) = fake_blk(&mut config_space, BlkFeature::empty());
+        let queue_waker = Arc::new(QueueWaker::new());
+
+        // Start a thread to simulate the device waiting for a read request, and then raising an
+        // interrupt.
+        let device_waker = queue_waker.clone();
+        let handle = thread::spawn(move || {
+            fake_read_sector_42(&state);
+            device_waker.wake();
+        });
+
+        // Read a block from the device.
+        let mut buffer = [0; 512];
+        // Safe because the future is polled to completion.
+        block_on(unsafe { blk.read_blocks_async(42, &mut buffer, &queue_waker) }).unwrap();
+        assert_eq!(&buffer[0..9], b"Test data");
+
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn read_packed() {
+        let mut config_space = fake_config_space(66);
+        let (mut blk, state) = fake_blk(&mut config_space, BlkFeature::RING_PACKED);
+
+        // Start a thread to simulate the device waiting for a read request.
+        let handle = thread::spawn(move || fake_read_sector_42(&state));
+
+        // Read a block from the device.
+        let mut buffer = [0; 512];
+        blk.read_blocks(42, &mut buffer).unwrap();
+        assert_eq!(&buffer[0..9], b"Test data");
+
+        handle.join().unwrap();
+    }
+
+    /// Tests submitting a request from one thread and completing it from another.
+    #[test]
+    fn read_split() {
+        let mut config_space = fake_config_space(66);
+        let (mut blk, state) = fake_blk(&mut config_space, BlkFeature::empty());
+
+        // Start a thread to simulate the device waiting for a read request.
+        let handle = thread::spawn(move || fake_read_sector_42(&state));
+
+        let (mut submitter, mut completer) = blk.split();
+        let (sender, receiver) = mpsc::channel();
+        thread::scope(|scope| {
+            scope.spawn(move || {
+                let mut request = Box::new(BlkReq::default());
+                let mut buffer = vec![0; SECTOR_SIZE].into_boxed_slice();
+                let mut response = Box::new(BlkResp::default());
+                // Safe because the buffers are on the heap, so they stay where they are when they
+                // are sent to the completing thread, which doesn't access them until the request
+                // has completed.
+                let token = unsafe {
+                    submitter.read_blocks_nb(42, &mut request, &mut buffer, &mut response)
+                }
+                .unwrap();
+                sender.send((token, request, buffer, response)).unwrap();
+            });
+            scope.spawn(move || {
+                let token = loop {
+                    if let Some(token) = completer.peek_used() {
+                        break token;
+                    }
+                    thread::yield_now();
+                };
+                let (submitted_token, request, mut buffer, mut response) = receiver.recv().unwrap();
+                assert_eq!(token, submitted_token);
+                // Safe because these are the same buffers as were passed to `read_blocks_nb`.
+                unsafe {
+                    completer.complete_read_blocks(token, &request, &mut buffer, &mut response)
+                }
+                .unwrap();
+                assert_eq!(&buffer[0..9], b"Test data");
+            });
+        });
+
+        handle.join().unwrap();
+    }
+
     #[test]
     fn write() {
         let mut config_space = BlkConfig {
@@ -692,33 +1197,30 @@ mod tests {
             State::wait_until_queue_notified(&state, QUEUE);
             println!("Transmit queue was notified.");
 
-            state
-                .lock()
-                .unwrap()
-                .read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE, |request| {
-                    assert_eq!(
-                        &request[0..size_of::<BlkReq>()],
-                        BlkReq {
-                            type_: ReqType::Out,
-                            reserved: 0,
-                            sector: 42
-                        }
-                        .as_bytes()
-                    );
-                    let data = &request[size_of::<BlkReq>()..];
-                    assert_eq!(data.len(), SECTOR_SIZE);
-                    assert_eq!(&data[0..9], b"Test data");
-
-                    let mut response = Vec::new();
-                    response.extend_from_slice(
-                        BlkResp {
-                            status: RespStatus::OK,
-                        }
-                        .as_bytes(),
-                    );
-
-                    response
-                });
+            state.lock().unwrap().read_write_queue(QUEUE, |request| {
+                assert_eq!(
+                    &request[0..size_of::<BlkReq>()],
+                    BlkReq {
+                        type_: ReqType::Out,
+                        reserved: 0,
+                        sector: 42
+                    }
+                    .as_bytes()
+                );
+                let data = &request[size_of::<BlkReq>()..];
+                assert_eq!(data.len(), SECTOR_SIZE);
+                assert_eq!(&data[0..9], b"Test data");
+
+                let mut response = Vec::new();
+                response.extend_from_slice(
+                    BlkResp {
+                        status: RespStatus::OK,
+                    }
+                    .as_bytes(),
+                );
+
+                response
+            });
         });
 
         // Write a block to the device.
@@ -767,30 +1269,27 @@ mod tests {
             State::wait_until_queue_notified(&state, QUEUE);
             println!("Transmit queue was notified.");
 
-            state
-                .lock()
-                .unwrap()
-                .read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE, |request| {
-                    assert_eq!(
-                        request,
-                        BlkReq {
-                            type_: ReqType::Flush,
-                            reserved: 0,
-                            sector: 0,
-                        }
-                        .as_bytes()
-                    );
-
-                    let mut response = Vec::new();
-                    response.extend_from_slice(
-                        BlkResp {
-                            status: RespStatus::OK,
-                        }
-                        .as_bytes(),
-                    );
-
-                    response
-                });
+            state.lock().unwrap().read_write_queue(QUEUE, |request| {
+                assert_eq!(
+                    request,
+                    BlkReq {
+                        type_: ReqType::Flush,
+                        reserved: 0,
+                        sector: 0,
+                    }
+                    .as_bytes()
+                );
+
+                let mut response = Vec::new();
+                response.extend_from_slice(
+                    BlkResp {
+                        status: RespStatus::OK,
+                    }
+                    .as_bytes(),
+                );
+
+                response
+            });
         });
 
         // Request to flush.
@@ -834,31 +1333,28 @@ mod tests {
             State::wait_until_queue_notified(&state, QUEUE);
             println!("Transmit queue was notified.");
 
-            state
-                .lock()
-                .unwrap()
-                .read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE, |request| {
-                    assert_eq!(
-                        request,
-                        BlkReq {
-                            type_: ReqType::GetId,
-                            reserved: 0,
-                            sector: 0,
-                        }
-                        .as_bytes()
-                    );
-
-                    let mut response = Vec::new();
-                    response.extend_from_slice(b"device_id\0\0\0\0\0\0\0\0\0\0\0");
-                    response.extend_from_slice(
-                        BlkResp {
-                            status: RespStatus::OK,
-                        }
-                        .as_bytes(),
-                    );
-
-                    response
-                });
+            state.lock().unwrap().read_write_queue(QUEUE, |request| {
+                assert_eq!(
+                    request,
+                    BlkReq {
+                        type_: ReqType::GetId,
+                        reserved: 0,
+                        sector: 0,
+                    }
+                    .as_bytes()
+                );
+
+                let mut response = Vec::new();
+                response.extend_from_slice(b"device_id\0\0\0\0\0\0\0\0\0\0\0");
+                response.extend_from_slice(
+                    BlkResp {
+                        status: RespStatus::OK,
+                    }
+                    .as_bytes(),
+                );
+
+                response
+            });
         });
 
         let mut id = [0; 20];
@@ -20,5 +20,8 @@ bitflags! {
         const ORDER_PLATFORM        = 1 << 36;
         const SR_IOV                = 1 << 37;
         const NOTIFICATION_DATA     = 1 << 38;
+
+        // since virtio v1.2
+        const RING_RESET            = 1 << 40;
     }
 }
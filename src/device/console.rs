@@ -3,16 +3,20 @@
 use crate::hal::Hal;
 use crate::queue::VirtQueue;
 use crate::transport::Transport;
-use crate::volatile::{volread, ReadOnly, WriteOnly};
-use crate::{Result, PAGE_SIZE};
+use crate::volatile::{read_config, ReadOnly, WriteOnly};
+#[cfg(feature = "trace")]
+use crate::QueueStats;
+use crate::{Result, Timeout, PAGE_SIZE};
 use alloc::boxed::Box;
 use bitflags::bitflags;
-use core::ptr::NonNull;
 
 const QUEUE_RECEIVEQ_PORT_0: u16 = 0;
 const QUEUE_TRANSMITQ_PORT_0: u16 = 1;
 const QUEUE_SIZE: usize = 2;
-const SUPPORTED_FEATURES: Features = Features::RING_EVENT_IDX;
+const SUPPORTED_FEATURES: Features = Features::RING_EVENT_IDX
+    .union(Features::RING_PACKED)
+    .union(Features::NOTIFICATION_DATA)
+    .union(Features::ORDER_PLATFORM);
 
 /// Driver for a VirtIO console device.
 ///
@@ -27,7 +31,7 @@ const SUPPORTED_FEATURES: Features = Features::RING_EVENT_IDX;
 /// # fn example<HalImpl: Hal, T: Transport>(transport: T) -> Result<(), Error> {
 /// let mut console = VirtIOConsole::<HalImpl, _>::new(transport)?;
 ///
-/// let info = console.info();
+/// let info = console.info()?;
 /// println!("VirtIO console {}x{}", info.rows, info.columns);
 ///
 /// for &c in b"Hello console!\n" {
@@ -41,7 +45,8 @@ const SUPPORTED_FEATURES: Features = Features::RING_EVENT_IDX;
 /// ```
 pub struct VirtIOConsole<H: Hal, T: Transport> {
     transport: T,
-    config_space: NonNull<Config>,
+    /// How long to wait for the device to respond to requests.
+    timeout: Timeout,
     receiveq: VirtQueue<H, QUEUE_SIZE>,
     transmitq: VirtQueue<H, QUEUE_SIZE>,
     queue_buf_rx: Box<[u8; PAGE_SIZE]>,
@@ -66,18 +71,17 @@ impl<H: Hal, T: Transport> VirtIOConsole<H, T> {
     /// Creates a new VirtIO console driver.
     pub fn new(mut transport: T) -> Result<Self> {
         let negotiated_features = transport.begin_init(SUPPORTED_FEATURES);
-        let config_space = transport.config_space::<Config>()?;
-        let receiveq = VirtQueue::new(
+        let receiveq = VirtQueue::new_with_max_size(
             &mut transport,
             QUEUE_RECEIVEQ_PORT_0,
-            false,
-            negotiated_features.contains(Features::RING_EVENT_IDX),
+            negotiated_features,
+            None,
         )?;
-        let transmitq = VirtQueue::new(
+        let transmitq = VirtQueue::new_with_max_size(
             &mut transport,
             QUEUE_TRANSMITQ_PORT_0,
-            false,
-            negotiated_features.contains(Features::RING_EVENT_IDX),
+            negotiated_features,
+            None,
         )?;
 
         // Safe because no alignment or initialisation is required for [u8], the DMA buffer is
@@ -88,7 +92,7 @@ impl<H: Hal, T: Transport> VirtIOConsole<H, T> {
         transport.finish_init();
         let mut console = VirtIOConsole {
             transport,
-            config_space,
+            timeout: Timeout::Never,
             receiveq,
             transmitq,
             queue_buf_rx,
@@ -101,18 +105,14 @@ impl<H: Hal, T: Transport> VirtIOConsole<H, T> {
     }
 
     /// Returns a struct with information about the console device, such as the number of rows and columns.
-    pub fn info(&self) -> ConsoleInfo {
-        // Safe because config_space is a valid pointer to the device configuration space.
-        unsafe {
-            let columns = volread!(self.config_space, cols);
-            let rows = volread!(self.config_space, rows);
-            let max_ports = volread!(self.config_space, max_nr_ports);
-            ConsoleInfo {
-                rows,
-                columns,
-                max_ports,
-            }
-        }
+    pub fn info(&self) -> Result<ConsoleInfo> {
+        self.transport.read_config_atomically(|transport| {
+            Ok(ConsoleInfo {
+                columns: read_config!(transport, Config, cols)?,
+                rows: read_config!(transport, Config, rows)?,
+                max_ports: read_config!(transport, Config, max_nr_ports)?,
+            })
+        })
     }
 
     /// Makes a request to the device to receive data, if there is not already an outstanding
@@ -125,13 +125,20 @@ impl<H: Hal, T: Transport> VirtIOConsole<H, T> {
                 self.receiveq
                     .add(&[], &mut [self.queue_buf_rx.as_mut_slice()])
             }?);
-            if self.receiveq.should_notify() {
-                self.transport.notify(QUEUE_RECEIVEQ_PORT_0);
-            }
+            self.receiveq.kick(&mut self.transport);
         }
         Ok(())
     }
 
+    /// Sets how long blocking requests wait for the device to respond. The default is to wait
+    /// forever.
+    ///
+    /// If a request times out then [`Error::Timeout`](crate::Error::Timeout) is returned and the
+    /// device is reset, so the driver can't be used any more.
+    pub fn set_timeout(&mut self, timeout: Timeout) {
+        self.timeout = timeout;
+    }
+
     /// Acknowledges a pending interrupt, if any, and completes the outstanding finished read
     /// request if there is one.
     ///
@@ -144,6 +151,17 @@ impl<H: Hal, T: Transport> VirtIOConsole<H, T> {
         self.finish_receive()
     }
 
+    /// Returns the counters of the virtqueue with the given index, or `None` if the device doesn't
+    /// have such a queue.
+    #[cfg(feature = "trace")]
+    pub fn queue_stats(&self, queue: u16) -> Option<QueueStats> {
+        match queue {
+            QUEUE_RECEIVEQ_PORT_0 => Some(self.receiveq.stats()),
+            QUEUE_TRANSMITQ_PORT_0 => Some(self.transmitq.stats()),
+            _ => None,
+        }
+    }
+
     /// If there is an outstanding receive request and it has finished, completes it.
     ///
     /// Returns true if new data has been received.
@@ -161,7 +179,6 @@ impl<H: Hal, T: Transport> VirtIOConsole<H, T> {
                     )?
                 };
                 flag = true;
-                assert_ne!(len, 0);
                 self.cursor = 0;
                 self.pending_len = len as usize;
                 // Clear `receive_token` so that when the buffer is used up the next call to
@@ -191,8 +208,12 @@ impl<H: Hal, T: Transport> VirtIOConsole<H, T> {
     /// Sends a character to the console.
     pub fn send(&mut self, chr: u8) -> Result<()> {
         let buf: [u8; 1] = [chr];
-        self.transmitq
-            .add_notify_wait_pop(&[&buf], &mut [], &mut self.transport)?;
+        self.transmitq.add_notify_wait_pop_timeout(
+            &[&buf],
+            &mut [],
+            &mut self.transport,
+            self.timeout,
+        )?;
         Ok(())
     }
 }
@@ -236,6 +257,9 @@ bitflags! {
         const ORDER_PLATFORM        = 1 << 36;
         const SR_IOV                = 1 << 37;
         const NOTIFICATION_DATA     = 1 << 38;
+
+        // since virtio v1.2
+        const RING_RESET            = 1 << 40;
     }
 }
 
@@ -285,12 +309,12 @@ mod tests {
         // Make a character available, and simulate an interrupt.
         {
             let mut state = state.lock().unwrap();
-            state.write_to_queue::<QUEUE_SIZE>(QUEUE_RECEIVEQ_PORT_0, &[42]);
+            state.write_to_queue(QUEUE_RECEIVEQ_PORT_0, &[42]);
 
             state.interrupt_pending = true;
         }
         assert_eq!(console.ack_interrupt(), Ok(true));
-        assert_eq!(state.lock().unwrap().interrupt_pending, false);
+        assert!(!state.lock().unwrap().interrupt_pending);
 
         // Receive the character. If we don't pop it it is still there to read again.
         assert_eq!(console.recv(false).unwrap(), Some(42));
@@ -328,7 +352,7 @@ mod tests {
             let data = state
                 .lock()
                 .unwrap()
-                .read_from_queue::<QUEUE_SIZE>(QUEUE_TRANSMITQ_PORT_0);
+                .read_from_queue(QUEUE_TRANSMITQ_PORT_0);
             assert_eq!(data, b"Q");
         });
 
@@ -3,15 +3,20 @@
 use crate::hal::{BufferDirection, Dma, Hal};
 use crate::queue::VirtQueue;
 use crate::transport::Transport;
-use crate::volatile::{volread, ReadOnly, Volatile, WriteOnly};
-use crate::{pages, Error, Result, PAGE_SIZE};
+use crate::volatile::{read_config, ReadOnly, Volatile, WriteOnly};
+#[cfg(feature = "trace")]
+use crate::QueueStats;
+use crate::{pages, Error, Result, Timeout, PAGE_SIZE};
 use alloc::boxed::Box;
 use bitflags::bitflags;
 use log::info;
 use zerocopy::{AsBytes, FromBytes, FromZeroes};
 
 const QUEUE_SIZE: u16 = 2;
-const SUPPORTED_FEATURES: Features = Features::RING_EVENT_IDX;
+const SUPPORTED_FEATURES: Features = Features::RING_EVENT_IDX
+    .union(Features::RING_PACKED)
+    .union(Features::NOTIFICATION_DATA)
+    .union(Features::ORDER_PLATFORM);
 
 /// A virtio based graphics adapter.
 ///
@@ -22,6 +27,8 @@ const SUPPORTED_FEATURES: Features = Features::RING_EVENT_IDX;
 /// and multiple scanouts (aka heads).
 pub struct VirtIOGpu<H: Hal, T: Transport> {
     transport: T,
+    /// How long to wait for the device to respond to requests.
+    timeout: Timeout,
     rect: Option<Rect>,
     /// DMA area of frame buffer.
     frame_buffer_dma: Option<Dma<H>>,
@@ -43,28 +50,26 @@ impl<H: Hal, T: Transport> VirtIOGpu<H, T> {
         let negotiated_features = transport.begin_init(SUPPORTED_FEATURES);
 
         // read configuration space
-        let config_space = transport.config_space::<Config>()?;
-        unsafe {
-            let events_read = volread!(config_space, events_read);
-            let num_scanouts = volread!(config_space, num_scanouts);
-            info!(
-                "events_read: {:#x}, num_scanouts: {:#x}",
-                events_read, num_scanouts
-            );
-        }
-
-        let control_queue = VirtQueue::new(
+        let (events_read, num_scanouts): (u32, u32) =
+            transport.read_config_atomically(|transport| {
+                Ok((
+                    read_config!(transport, Config, events_read)?,
+                    read_config!(transport, Config, num_scanouts)?,
+                ))
+            })?;
+        info!(
+            "events_read: {:#x}, num_scanouts: {:#x}",
+            events_read, num_scanouts
+        );
+
+        let control_queue = VirtQueue::new_with_max_size(
             &mut transport,
             QUEUE_TRANSMIT,
-            false,
-            negotiated_features.contains(Features::RING_EVENT_IDX),
-        )?;
-        let cursor_queue = VirtQueue::new(
-            &mut transport,
-            QUEUE_CURSOR,
-            false,
-            negotiated_features.contains(Features::RING_EVENT_IDX),
+            negotiated_features,
+            None,
         )?;
+        let cursor_queue =
+            VirtQueue::new_with_max_size(&mut transport, QUEUE_CURSOR, negotiated_features, None)?;
 
         let queue_buf_send = FromZeroes::new_box_slice_zeroed(PAGE_SIZE);
         let queue_buf_recv = FromZeroes::new_box_slice_zeroed(PAGE_SIZE);
@@ -73,6 +78,7 @@ impl<H: Hal, T: Transport> VirtIOGpu<H, T> {
 
         Ok(VirtIOGpu {
             transport,
+            timeout: Timeout::Never,
             frame_buffer_dma: None,
             cursor_buffer_dma: None,
             rect: None,
@@ -83,11 +89,31 @@ impl<H: Hal, T: Transport> VirtIOGpu<H, T> {
         })
     }
 
+    /// Sets how long blocking requests wait for the device to respond. The default is to wait
+    /// forever.
+    ///
+    /// If a request times out then [`Error::Timeout`] is returned and the
+    /// device is reset, so the driver can't be used any more.
+    pub fn set_timeout(&mut self, timeout: Timeout) {
+        self.timeout = timeout;
+    }
+
     /// Acknowledge interrupt.
     pub fn ack_interrupt(&mut self) -> bool {
         self.transport.ack_interrupt()
     }
 
+    /// Returns the counters of the virtqueue with the given index, or `None` if the device doesn't
+    /// have such a queue.
+    #[cfg(feature = "trace")]
+    pub fn queue_stats(&self, queue: u16) -> Option<QueueStats> {
+        match queue {
+            QUEUE_TRANSMIT => Some(self.control_queue.stats()),
+            QUEUE_CURSOR => Some(self.cursor_queue.stats()),
+            _ => None,
+        }
+    }
+
     /// Get the resolution (width, height).
     pub fn resolution(&mut self) -> Result<(u32, u32)> {
         let display_info = self.get_display_info()?;
@@ -174,22 +200,24 @@ impl<H: Hal, T: Transport> VirtIOGpu<H, T> {
 
     /// Send a request to the device and block for a response.
     fn request<Req: AsBytes, Rsp: FromBytes>(&mut self, req: Req) -> Result<Rsp> {
-        req.write_to_prefix(&mut *self.queue_buf_send).unwrap();
-        self.control_queue.add_notify_wait_pop(
+        req.write_to_prefix(&mut self.queue_buf_send).unwrap();
+        self.control_queue.add_notify_wait_pop_timeout(
             &[&self.queue_buf_send],
             &mut [&mut self.queue_buf_recv],
             &mut self.transport,
+            self.timeout,
         )?;
-        Ok(Rsp::read_from_prefix(&*self.queue_buf_recv).unwrap())
+        Ok(Rsp::read_from_prefix(&self.queue_buf_recv).unwrap())
     }
 
     /// Send a mouse cursor operation request to the device and block for a response.
     fn cursor_request<Req: AsBytes>(&mut self, req: Req) -> Result {
-        req.write_to_prefix(&mut *self.queue_buf_send).unwrap();
-        self.cursor_queue.add_notify_wait_pop(
+        req.write_to_prefix(&mut self.queue_buf_send).unwrap();
+        self.cursor_queue.add_notify_wait_pop_timeout(
             &[&self.queue_buf_send],
             &mut [],
             &mut self.transport,
+            self.timeout,
         )?;
         Ok(())
     }
@@ -255,6 +283,7 @@ impl<H: Hal, T: Transport> VirtIOGpu<H, T> {
         rsp.check_type(Command::OK_NODATA)
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn update_cursor(
         &mut self,
         resource_id: u32,
@@ -334,6 +363,9 @@ bitflags! {
         const ORDER_PLATFORM        = 1 << 36;
         const SR_IOV                = 1 << 37;
         const NOTIFICATION_DATA     = 1 << 38;
+
+        // since virtio v1.2
+        const RING_RESET            = 1 << 40;
     }
 }
 
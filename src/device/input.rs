@@ -4,10 +4,11 @@ use super::common::Feature;
 use crate::hal::Hal;
 use crate::queue::VirtQueue;
 use crate::transport::Transport;
-use crate::volatile::{volread, volwrite, ReadOnly, WriteOnly};
+use crate::volatile::{read_config, write_config, ReadOnly, WriteOnly};
+#[cfg(feature = "trace")]
+use crate::QueueStats;
 use crate::Result;
 use alloc::boxed::Box;
-use core::ptr::NonNull;
 use zerocopy::{AsBytes, FromBytes, FromZeroes};
 
 /// Virtual human interface devices such as keyboards, mice and tablets.
@@ -20,7 +21,6 @@ pub struct VirtIOInput<H: Hal, T: Transport> {
     event_queue: VirtQueue<H, QUEUE_SIZE>,
     status_queue: VirtQueue<H, QUEUE_SIZE>,
     event_buf: Box<[InputEvent; 32]>,
-    config: NonNull<Config>,
 }
 
 impl<H: Hal, T: Transport> VirtIOInput<H, T> {
@@ -30,28 +30,17 @@ impl<H: Hal, T: Transport> VirtIOInput<H, T> {
 
         let negotiated_features = transport.begin_init(SUPPORTED_FEATURES);
 
-        let config = transport.config_space::<Config>()?;
-
-        let mut event_queue = VirtQueue::new(
-            &mut transport,
-            QUEUE_EVENT,
-            false,
-            negotiated_features.contains(Feature::RING_EVENT_IDX),
-        )?;
-        let status_queue = VirtQueue::new(
-            &mut transport,
-            QUEUE_STATUS,
-            false,
-            negotiated_features.contains(Feature::RING_EVENT_IDX),
-        )?;
-        for (i, event) in event_buf.as_mut().iter_mut().enumerate() {
+        let mut event_queue =
+            VirtQueue::new_with_max_size(&mut transport, QUEUE_EVENT, negotiated_features, None)?;
+        let status_queue =
+            VirtQueue::new_with_max_size(&mut transport, QUEUE_STATUS, negotiated_features, None)?;
+        let queue_size = event_queue.size().into();
+        for (i, event) in event_buf.as_mut().iter_mut().take(queue_size).enumerate() {
             // Safe because the buffer lasts as long as the queue.
             let token = unsafe { event_queue.add(&[], &mut [event.as_bytes_mut()])? };
             assert_eq!(token, i as u16);
         }
-        if event_queue.should_notify() {
-            transport.notify(QUEUE_EVENT);
-        }
+        event_queue.kick(&mut transport);
 
         transport.finish_init();
 
@@ -60,7 +49,6 @@ impl<H: Hal, T: Transport> VirtIOInput<H, T> {
             event_queue,
             status_queue,
             event_buf,
-            config,
         })
     }
 
@@ -69,10 +57,22 @@ impl<H: Hal, T: Transport> VirtIOInput<H, T> {
         self.transport.ack_interrupt()
     }
 
+    /// Returns the counters of the virtqueue with the given index, or `None` if the device doesn't
+    /// have such a queue.
+    #[cfg(feature = "trace")]
+    pub fn queue_stats(&self, queue: u16) -> Option<QueueStats> {
+        match queue {
+            QUEUE_EVENT => Some(self.event_queue.stats()),
+            QUEUE_STATUS => Some(self.status_queue.stats()),
+            _ => None,
+        }
+    }
+
     /// Pop the pending event.
     pub fn pop_pending_event(&mut self) -> Option<InputEvent> {
         if let Some(token) = self.event_queue.peek_used() {
-            let event = &mut self.event_buf[token as usize];
+            // The token comes from the device, so may be out of range.
+            let event = self.event_buf.get_mut(usize::from(token))?;
             // Safe because we are passing the same buffer as we passed to `VirtQueue::add` and it
             // is still valid.
             unsafe {
@@ -89,9 +89,7 @@ impl<H: Hal, T: Transport> VirtIOInput<H, T> {
                 // the list of free descriptors in the queue, so `add` reuses the descriptor which
                 // was just freed by `pop_used`.
                 assert_eq!(new_token, token);
-                if self.event_queue.should_notify() {
-                    self.transport.notify(QUEUE_EVENT);
-                }
+                self.event_queue.kick(&mut self.transport);
                 return Some(event_saved);
             }
         }
@@ -105,18 +103,17 @@ impl<H: Hal, T: Transport> VirtIOInput<H, T> {
         select: InputConfigSelect,
         subsel: u8,
         out: &mut [u8],
-    ) -> u8 {
-        let size;
-        let data;
-        // Safe because config points to a valid MMIO region for the config space.
-        unsafe {
-            volwrite!(self.config, select, select as u8);
-            volwrite!(self.config, subsel, subsel);
-            size = volread!(self.config, size);
-            data = volread!(self.config, data);
-        }
+    ) -> Result<u8> {
+        write_config!(self.transport, Config, select, select as u8)?;
+        write_config!(self.transport, Config, subsel, subsel)?;
+        let (size, data): (u8, [u8; 128]) = self.transport.read_config_atomically(|transport| {
+            Ok((
+                read_config!(transport, Config, size)?,
+                read_config!(transport, Config, data)?,
+            ))
+        })?;
         out[..size as usize].copy_from_slice(&data[..size as usize]);
-        size
+        Ok(size)
     }
 }
 
@@ -197,7 +194,10 @@ pub struct InputEvent {
 
 const QUEUE_EVENT: u16 = 0;
 const QUEUE_STATUS: u16 = 1;
-const SUPPORTED_FEATURES: Feature = Feature::RING_EVENT_IDX;
+const SUPPORTED_FEATURES: Feature = Feature::RING_EVENT_IDX
+    .union(Feature::RING_PACKED)
+    .union(Feature::NOTIFICATION_DATA)
+    .union(Feature::ORDER_PLATFORM);
 
 // a parameter that can change
 const QUEUE_SIZE: usize = 32;
@@ -1,13 +1,17 @@
 //! Driver for VirtIO network devices.
 
-use crate::hal::Hal;
-use crate::queue::VirtQueue;
+use crate::hal::{DmaBuffer, Hal};
+use crate::queue::owning::{OwningQueue, QueueBuffer};
+use crate::queue::{SharedAddrs, VirtQueue};
+use crate::timeout::Deadline;
 use crate::transport::Transport;
-use crate::volatile::{volread, ReadOnly};
-use crate::{Error, Result};
+use crate::volatile::{read_config, ReadOnly};
+#[cfg(feature = "trace")]
+use crate::QueueStats;
+use crate::{Error, Result, Timeout};
 use alloc::{vec, vec::Vec};
 use bitflags::bitflags;
-use core::{convert::TryInto, mem::size_of};
+use core::{hint::spin_loop, mem::size_of};
 use log::{debug, warn};
 use zerocopy::{AsBytes, FromBytes, FromZeroes};
 
@@ -22,7 +26,6 @@ pub struct TxBuffer(Vec<u8>);
 pub struct RxBuffer {
     buf: Vec<usize>, // for alignment
     packet_len: usize,
-    idx: u16,
 }
 
 impl TxBuffer {
@@ -49,11 +52,10 @@ impl TxBuffer {
 
 impl RxBuffer {
     /// Allocates a new buffer with length `buf_len`.
-    fn new(idx: usize, buf_len: usize) -> Self {
+    fn new(buf_len: usize) -> Self {
         Self {
             buf: vec![0; buf_len / size_of::<usize>()],
             packet_len: 0,
-            idx: idx.try_into().unwrap(),
         }
     }
 
@@ -94,6 +96,17 @@ impl RxBuffer {
     }
 }
 
+// Safe because the data is stored in a `Vec` rather than inline, so it doesn't move when the
+// `RxBuffer` does.
+unsafe impl QueueBuffer for RxBuffer {
+    fn with_slices<R>(
+        &mut self,
+        f: impl for<'a, 'b> FnOnce(&'a [&'b [u8]], &'a mut [&'b mut [u8]]) -> R,
+    ) -> R {
+        f(&[], &mut [self.buf.as_bytes_mut()])
+    }
+}
+
 /// The virtio network device is a virtual ethernet card.
 ///
 /// It has enhanced rapidly and demonstrates clearly how support for new
@@ -103,10 +116,14 @@ impl RxBuffer {
 /// A third command queue is used to control advanced filtering features.
 pub struct VirtIONet<H: Hal, T: Transport, const QUEUE_SIZE: usize> {
     transport: T,
+    /// How long to wait for the device to respond to requests.
+    timeout: Timeout,
+    negotiated_features: Features,
     mac: EthernetAddress,
-    recv_queue: VirtQueue<H, QUEUE_SIZE>,
+    /// The length of each receive buffer.
+    rx_buf_len: usize,
+    recv_queue: OwningQueue<H, QUEUE_SIZE, RxBuffer>,
     send_queue: VirtQueue<H, QUEUE_SIZE>,
-    rx_buffers: [Option<RxBuffer>; QUEUE_SIZE],
 }
 
 impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> VirtIONet<H, T, QUEUE_SIZE> {
@@ -114,17 +131,14 @@ impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> VirtIONet<H, T, QUEUE_SIZE>
     pub fn new(mut transport: T, buf_len: usize) -> Result<Self> {
         let negotiated_features = transport.begin_init(SUPPORTED_FEATURES);
         // read configuration space
-        let config = transport.config_space::<Config>()?;
-        let mac;
-        // Safe because config points to a valid MMIO region for the config space.
-        unsafe {
-            mac = volread!(config, mac);
-            debug!(
-                "Got MAC={:02x?}, status={:?}",
-                mac,
-                volread!(config, status)
-            );
-        }
+        let (mac, status): (EthernetAddress, Status) =
+            transport.read_config_atomically(|transport| {
+                Ok((
+                    read_config!(transport, Config, mac)?,
+                    read_config!(transport, Config, status)?,
+                ))
+            })?;
+        debug!("Got MAC={:02x?}, status={:?}", mac, status);
 
         if !(MIN_BUFFER_LEN..=MAX_BUFFER_LEN).contains(&buf_len) {
             warn!(
@@ -134,49 +148,100 @@ impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> VirtIONet<H, T, QUEUE_SIZE>
             return Err(Error::InvalidParam);
         }
 
-        let send_queue = VirtQueue::new(
+        let send_queue = VirtQueue::new_with_max_size(
             &mut transport,
             QUEUE_TRANSMIT,
-            false,
-            negotiated_features.contains(Features::RING_EVENT_IDX),
+            negotiated_features,
+            None,
         )?;
-        let mut recv_queue = VirtQueue::new(
+        let mut recv_queue = OwningQueue::new(VirtQueue::new_with_max_size(
             &mut transport,
             QUEUE_RECEIVE,
-            false,
-            negotiated_features.contains(Features::RING_EVENT_IDX),
-        )?;
+            negotiated_features,
+            None,
+        )?);
 
-        const NONE_BUF: Option<RxBuffer> = None;
-        let mut rx_buffers = [NONE_BUF; QUEUE_SIZE];
-        for (i, rx_buf_place) in rx_buffers.iter_mut().enumerate() {
-            let mut rx_buf = RxBuffer::new(i, buf_len);
-            // Safe because the buffer lives as long as the queue.
-            let token = unsafe { recv_queue.add(&[], &mut [rx_buf.as_bytes_mut()])? };
-            assert_eq!(token, i as u16);
-            *rx_buf_place = Some(rx_buf);
+        for _ in 0..recv_queue.size() {
+            recv_queue.add(RxBuffer::new(buf_len))?;
         }
 
-        if recv_queue.should_notify() {
-            transport.notify(QUEUE_RECEIVE);
-        }
+        recv_queue.kick(&mut transport);
 
         transport.finish_init();
 
         Ok(VirtIONet {
             transport,
+            timeout: Timeout::Never,
+            negotiated_features,
             mac,
+            rx_buf_len: buf_len,
             recv_queue,
             send_queue,
-            rx_buffers,
         })
     }
 
+    /// Sets how long blocking requests wait for the device to respond. The default is to wait
+    /// forever.
+    ///
+    /// If a request times out then [`Error::Timeout`] is returned and the
+    /// device is reset, so the driver can't be used any more.
+    pub fn set_timeout(&mut self, timeout: Timeout) {
+        self.timeout = timeout;
+    }
+
     /// Acknowledge interrupt.
     pub fn ack_interrupt(&mut self) -> bool {
         self.transport.ack_interrupt()
     }
 
+    /// Returns the counters of the virtqueue with the given index, or `None` if the device doesn't
+    /// have such a queue.
+    #[cfg(feature = "trace")]
+    pub fn queue_stats(&self, queue: u16) -> Option<QueueStats> {
+        match queue {
+            QUEUE_RECEIVE => Some(self.recv_queue.stats()),
+            QUEUE_TRANSMIT => Some(self.send_queue.stats()),
+            _ => None,
+        }
+    }
+
+    /// Asks the device not to raise interrupts when it receives packets.
+    ///
+    /// This is useful while polling for a burst of packets with [`VirtIONet::receive`]. Call
+    /// [`VirtIONet::enable_interrupts`] once there are no more packets.
+    pub fn disable_interrupts(&mut self) {
+        self.recv_queue.disable_callbacks();
+    }
+
+    /// Asks the device to raise an interrupt when it next receives a packet.
+    ///
+    /// Returns false if there are already received packets waiting, in which case they should be
+    /// handled rather than waiting for an interrupt.
+    pub fn enable_interrupts(&mut self) -> bool {
+        self.recv_queue.enable_callbacks()
+    }
+
+    /// Resets the receive queue and sets it up again with at most `max_size` buffers, without
+    /// resetting the rest of the device.
+    ///
+    /// Any packets which have been received but not yet returned by [`VirtIONet::receive`] are
+    /// dropped, and the queue is filled with newly allocated buffers. Buffers which were returned
+    /// before the reset should be dropped rather than recycled, as there won't be room for them.
+    ///
+    /// Returns [`Error::Unsupported`] if the device doesn't support `VIRTIO_F_RING_RESET`.
+    pub fn resize_recv_queue(&mut self, max_size: u16) -> Result {
+        self.recv_queue.reset(
+            &mut self.transport,
+            self.negotiated_features,
+            Some(max_size),
+        )?;
+        for _ in 0..self.recv_queue.size() {
+            self.recv_queue.add(RxBuffer::new(self.rx_buf_len))?;
+        }
+        self.recv_queue.kick(&mut self.transport);
+        Ok(())
+    }
+
     /// Get MAC address.
     pub fn mac_address(&self) -> EthernetAddress {
         self.mac
@@ -198,44 +263,21 @@ impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> VirtIONet<H, T, QUEUE_SIZE>
     /// It will try to pop a buffer that completed data reception in the
     /// NIC queue.
     pub fn receive(&mut self) -> Result<RxBuffer> {
-        if let Some(token) = self.recv_queue.peek_used() {
-            let mut rx_buf = self.rx_buffers[token as usize]
-                .take()
-                .ok_or(Error::WrongToken)?;
-            if token != rx_buf.idx {
-                return Err(Error::WrongToken);
-            }
-
-            // Safe because `token` == `rx_buf.idx`, we are passing the same
-            // buffer as we passed to `VirtQueue::add` and it is still valid.
-            let len = unsafe {
-                self.recv_queue
-                    .pop_used(token, &[], &mut [rx_buf.as_bytes_mut()])?
-            } as usize;
-            rx_buf.set_packet_len(len.checked_sub(NET_HDR_SIZE).ok_or(Error::IoError)?);
-            Ok(rx_buf)
-        } else {
-            Err(Error::NotReady)
-        }
+        let (mut rx_buf, len) = self.recv_queue.pop_used()?;
+        rx_buf.set_packet_len(
+            (len as usize)
+                .checked_sub(NET_HDR_SIZE)
+                .ok_or(Error::IoError)?,
+        );
+        Ok(rx_buf)
     }
 
     /// Gives back the ownership of `rx_buf`, and recycles it for next use.
     ///
     /// It will add the buffer back to the NIC queue.
-    pub fn recycle_rx_buffer(&mut self, mut rx_buf: RxBuffer) -> Result {
-        // Safe because we take the ownership of `rx_buf` back to `rx_buffers`,
-        // it lives as long as the queue.
-        let new_token = unsafe { self.recv_queue.add(&[], &mut [rx_buf.as_bytes_mut()]) }?;
-        // `rx_buffers[new_token]` is expected to be `None` since it was taken
-        // away at `Self::receive()` and has not been added back.
-        if self.rx_buffers[new_token as usize].is_some() {
-            return Err(Error::WrongToken);
-        }
-        rx_buf.idx = new_token;
-        self.rx_buffers[new_token as usize] = Some(rx_buf);
-        if self.recv_queue.should_notify() {
-            self.transport.notify(QUEUE_RECEIVE);
-        }
+    pub fn recycle_rx_buffer(&mut self, rx_buf: RxBuffer) -> Result {
+        self.recv_queue.add(rx_buf)?;
+        self.recv_queue.kick(&mut self.transport);
         Ok(())
     }
 
@@ -251,20 +293,142 @@ impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> VirtIONet<H, T, QUEUE_SIZE>
         if tx_buf.packet_len() == 0 {
             // Special case sending an empty packet, to avoid adding an empty buffer to the
             // virtqueue.
-            self.send_queue.add_notify_wait_pop(
+            self.send_queue.add_notify_wait_pop_timeout(
                 &[header.as_bytes()],
                 &mut [],
                 &mut self.transport,
+                self.timeout,
             )?;
         } else {
-            self.send_queue.add_notify_wait_pop(
+            self.send_queue.add_notify_wait_pop_timeout(
                 &[header.as_bytes(), tx_buf.packet()],
                 &mut [],
                 &mut self.transport,
+                self.timeout,
             )?;
         }
         Ok(())
     }
+
+    /// Sends the packet in the given DMA buffer to the network, and blocks until the request
+    /// completed.
+    ///
+    /// The buffer is passed to the device without being shared, so this avoids the copy which
+    /// [`VirtIONet::send`] may need.
+    pub fn send_dma(&mut self, packet: &DmaBuffer<H>) -> Result {
+        let header = VirtioNetHdr::default();
+        // Safe because the device can access the DMA buffer at its physical address.
+        unsafe {
+            self.send_queue.add_notify_wait_pop_shared(
+                &[header.as_bytes(), packet],
+                &mut [],
+                SharedAddrs {
+                    inputs: &[None, Some(packet.paddr())],
+                    outputs: &[],
+                },
+                &mut self.transport,
+                self.timeout,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Sends several [`TxBuffer`]s to the network, and blocks until they have all been sent.
+    ///
+    /// As many packets as fit in the queue are added at once, and the device is only notified
+    /// once for each such batch.
+    ///
+    /// The timeout set by [`VirtIONet::set_timeout`] applies to the whole call. If it expires, or
+    /// anything else goes wrong while packets are in the queue, the device is reset so that it
+    /// can't access them after this returns, and the driver can't be used any more.
+    pub fn send_batch(&mut self, tx_bufs: &[TxBuffer]) -> Result {
+        let header = VirtioNetHdr::default();
+        let mut deadline = Deadline::new::<H>(self.timeout)?;
+        let mut remaining = tx_bufs;
+        while !remaining.is_empty() {
+            // The token and index in `remaining` of each packet in the batch.
+            let mut in_flight = Vec::new();
+            match self.send_batch_chunk(&header, remaining, &mut in_flight, &mut deadline) {
+                Ok(batch_len) => remaining = &remaining[batch_len..],
+                Err(e) => {
+                    if !in_flight.is_empty() {
+                        // The device may still access the packets which haven't been popped, so
+                        // reset it before they are dropped.
+                        self.send_queue.give_up(&mut self.transport);
+                        for (token, i) in in_flight {
+                            let inputs = [header.as_bytes(), remaining[i].packet()];
+                            let inputs =
+                                &inputs[..if remaining[i].packet_len() == 0 { 1 } else { 2 }];
+                            // Safe because these are the same buffers as were staged for the
+                            // token, and the device has been reset.
+                            unsafe {
+                                self.send_queue.reclaim(
+                                    token,
+                                    inputs,
+                                    &mut [],
+                                    SharedAddrs::default(),
+                                )
+                            };
+                        }
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds as many of the given packets as fit to the send queue, notifies the device once, and
+    /// waits for it to use them all.
+    ///
+    /// Returns the number of packets which were sent. On error, the tokens of the packets which are
+    /// still in the queue are left in `in_flight`, along with their indices in `tx_bufs`.
+    fn send_batch_chunk(
+        &mut self,
+        header: &VirtioNetHdr,
+        tx_bufs: &[TxBuffer],
+        in_flight: &mut Vec<(u16, usize)>,
+        deadline: &mut Deadline,
+    ) -> Result<usize> {
+        for (i, tx_buf) in tx_bufs.iter().enumerate() {
+            let inputs = [header.as_bytes(), tx_buf.packet()];
+            // Avoid adding an empty buffer to the virtqueue for an empty packet.
+            let inputs = &inputs[..if tx_buf.packet_len() == 0 { 1 } else { 2 }];
+            if !self.send_queue.has_room_for(inputs) {
+                break;
+            }
+            // Safe because the buffers are borrowed until `send_batch` returns, and it either
+            // waits for the device to use them all or resets it before then.
+            let token = unsafe { self.send_queue.stage(inputs, &mut []) }?;
+            in_flight.push((token, i));
+        }
+        if in_flight.is_empty() {
+            return Err(Error::QueueFull);
+        }
+        self.send_queue.kick(&mut self.transport);
+
+        let batch_len = in_flight.len();
+        while !in_flight.is_empty() {
+            let Some(token) = self.send_queue.peek_used() else {
+                if deadline.expired() {
+                    return Err(Error::Timeout);
+                }
+                spin_loop();
+                continue;
+            };
+            let position = in_flight
+                .iter()
+                .position(|&(in_flight_token, _)| in_flight_token == token)
+                .ok_or(Error::WrongToken)?;
+            let (_, i) = in_flight[position];
+            let inputs = [header.as_bytes(), tx_bufs[i].packet()];
+            let inputs = &inputs[..if tx_bufs[i].packet_len() == 0 { 1 } else { 2 }];
+            // Safe because these are the same buffers as we passed to `stage` for the token.
+            unsafe { self.send_queue.pop_used(token, inputs, &mut []) }?;
+            in_flight.swap_remove(position);
+        }
+        Ok(batch_len)
+    }
 }
 
 impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> Drop for VirtIONet<H, T, QUEUE_SIZE> {
@@ -335,12 +499,26 @@ bitflags! {
         const RING_INDIRECT_DESC = 1 << 28;
         const RING_EVENT_IDX = 1 << 29;
         const VERSION_1 = 1 << 32; // legacy
+
+        // the following since virtio v1.1
+        const ACCESS_PLATFORM = 1 << 33;
+        const RING_PACKED = 1 << 34;
+        const IN_ORDER = 1 << 35;
+        const ORDER_PLATFORM = 1 << 36;
+        const SR_IOV = 1 << 37;
+        const NOTIFICATION_DATA = 1 << 38;
+
+        // since virtio v1.2
+        const RING_RESET = 1 << 40;
     }
 }
 
+#[derive(AsBytes, Copy, Clone, Debug, Default, Eq, FromBytes, FromZeroes, PartialEq)]
+#[repr(transparent)]
+struct Status(u16);
+
 bitflags! {
-    #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
-    struct Status: u16 {
+    impl Status: u16 {
         const LINK_UP = 1;
         const ANNOUNCE = 2;
     }
@@ -410,4 +588,117 @@ const QUEUE_RECEIVE: u16 = 0;
 const QUEUE_TRANSMIT: u16 = 1;
 const SUPPORTED_FEATURES: Features = Features::MAC
     .union(Features::STATUS)
-    .union(Features::RING_EVENT_IDX);
+    .union(Features::RING_EVENT_IDX)
+    .union(Features::RING_PACKED)
+    .union(Features::IN_ORDER)
+    .union(Features::RING_RESET)
+    .union(Features::NOTIFICATION_DATA)
+    .union(Features::ORDER_PLATFORM);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        hal::{fake::FakeHal, BufferDirection, PhysAddr},
+        transport::{
+            fake::{FakeTransport, QueueStatus, State},
+            DeviceStatus, DeviceType,
+        },
+    };
+    use alloc::sync::Arc;
+    use core::{
+        ops::Range,
+        ptr::NonNull,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+    use std::sync::Mutex;
+
+    /// The calls to `FragmentingHal::contiguous_len`, counting from 0, for which it splits buffers.
+    const FRAGMENTED_CALLS: Range<usize> = 8..26;
+
+    /// The number of calls to `FragmentingHal::contiguous_len` so far.
+    static CONTIGUOUS_LEN_CALLS: AtomicUsize = AtomicUsize::new(FRAGMENTED_CALLS.end);
+
+    /// A HAL which splits buffers into single bytes, but only for the calls to `contiguous_len` in
+    /// `FRAGMENTED_CALLS`.
+    struct FragmentingHal;
+
+    unsafe impl Hal for FragmentingHal {
+        fn dma_alloc(pages: usize, direction: BufferDirection) -> (PhysAddr, NonNull<u8>) {
+            FakeHal::dma_alloc(pages, direction)
+        }
+
+        unsafe fn dma_dealloc(paddr: PhysAddr, vaddr: NonNull<u8>, pages: usize) -> i32 {
+            // SAFETY: Our caller upholds the same requirements.
+            unsafe { FakeHal::dma_dealloc(paddr, vaddr, pages) }
+        }
+
+        unsafe fn mmio_phys_to_virt(paddr: PhysAddr, size: usize) -> NonNull<u8> {
+            // SAFETY: Our caller upholds the same requirements.
+            unsafe { FakeHal::mmio_phys_to_virt(paddr, size) }
+        }
+
+        unsafe fn share(buffer: NonNull<[u8]>, direction: BufferDirection) -> PhysAddr {
+            // SAFETY: Our caller upholds the same requirements.
+            unsafe { FakeHal::share(buffer, direction) }
+        }
+
+        unsafe fn unshare(paddr: PhysAddr, buffer: NonNull<[u8]>, direction: BufferDirection) {
+            // SAFETY: Our caller upholds the same requirements.
+            unsafe { FakeHal::unshare(paddr, buffer, direction) }
+        }
+
+        fn contiguous_len(buffer: NonNull<[u8]>) -> usize {
+            if FRAGMENTED_CALLS.contains(&CONTIGUOUS_LEN_CALLS.fetch_add(1, Ordering::Relaxed)) {
+                1
+            } else {
+                buffer.len()
+            }
+        }
+    }
+
+    /// Tests that if adding a packet fails partway through a batch, the device is reset so that
+    /// it can't access the packets which were already added after `send_batch` returns.
+    #[test]
+    fn send_batch_stage_fails() {
+        let mut config_space = Config {
+            mac: ReadOnly::new([1, 2, 3, 4, 5, 6]),
+            status: ReadOnly::new(Status::empty()),
+            max_virtqueue_pairs: ReadOnly::new(1),
+            mtu: ReadOnly::new(1500),
+        };
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default(), QueueStatus::default()],
+            ..Default::default()
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::Network,
+            max_queue_size: 16,
+            device_features: 0,
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut net =
+            VirtIONet::<FragmentingHal, FakeTransport<Config>, 16>::new(transport, MIN_BUFFER_LEN)
+                .unwrap();
+        // Fail rather than hang if the batch is sent.
+        net.set_timeout(Timeout::Spins(1000));
+        assert!(state
+            .lock()
+            .unwrap()
+            .status
+            .contains(DeviceStatus::DRIVER_OK));
+
+        // Checking for room for the first packet, staging it and sharing it each look at the
+        // header and packet once, as does checking for room for the second packet, which fits.
+        // Then the 10 byte header and 8 byte packet are split into 18 single bytes as the second
+        // packet is staged, and there are only 14 descriptors left.
+        CONTIGUOUS_LEN_CALLS.store(0, Ordering::Relaxed);
+        let tx_bufs = [TxBuffer::from(&[42; 8]), TxBuffer::from(&[43; 8])];
+        assert_eq!(net.send_batch(&tx_bufs), Err(Error::QueueFull));
+
+        // The first packet was never popped, so the device must have been reset.
+        assert_eq!(state.lock().unwrap().status, DeviceStatus::empty());
+        assert_eq!(net.send_batch(&tx_bufs), Err(Error::DeviceMisbehaved));
+    }
+}
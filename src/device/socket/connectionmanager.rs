@@ -2,7 +2,9 @@ use super::{
     protocol::VsockAddr, vsock::ConnectionInfo, DisconnectReason, SocketError, VirtIOSocket,
     VsockEvent, VsockEventType,
 };
-use crate::{transport::Transport, Hal, Result};
+#[cfg(feature = "trace")]
+use crate::QueueStats;
+use crate::{timeout::Deadline, transport::Transport, Error, Hal, Result, Timeout};
 use alloc::{boxed::Box, vec::Vec};
 use core::cmp::min;
 use core::convert::TryInto;
@@ -82,6 +84,13 @@ impl<H: Hal, T: Transport> VsockConnectionManager<H, T> {
         self.driver.guest_cid()
     }
 
+    /// Returns the counters of the virtqueue with the given index, or `None` if the device doesn't
+    /// have such a queue.
+    #[cfg(feature = "trace")]
+    pub fn queue_stats(&self, queue: u16) -> Option<QueueStats> {
+        self.driver.queue_stats(queue)
+    }
+
     /// Allows incoming connections on the given port number.
     pub fn listen(&mut self, port: u32) {
         if !self.listening_ports.contains(&port) {
@@ -243,9 +252,20 @@ impl<H: Hal, T: Transport> VsockConnectionManager<H, T> {
 
     /// Blocks until we get some event from the vsock device.
     pub fn wait_for_event(&mut self) -> Result<VsockEvent> {
+        self.wait_for_event_timeout(Timeout::Never)
+    }
+
+    /// Blocks until we get some event from the vsock device, or the given timeout expires.
+    ///
+    /// Returns [`Error::Timeout`] if there is no event in time. Unlike a request timing out, this
+    /// doesn't affect the device, so the connection manager can still be used.
+    pub fn wait_for_event_timeout(&mut self, timeout: Timeout) -> Result<VsockEvent> {
+        let mut deadline = Deadline::new::<H>(timeout)?;
         loop {
             if let Some(event) = self.poll()? {
                 return Ok(event);
+            } else if deadline.expired() {
+                return Err(Error::Timeout);
             } else {
                 spin_loop();
             }
@@ -390,7 +410,7 @@ mod tests {
     use crate::{
         device::socket::{
             protocol::{SocketType, VirtioVsockConfig, VirtioVsockHdr, VirtioVsockOp},
-            vsock::{VsockBufferStatus, QUEUE_SIZE, RX_QUEUE_IDX, TX_QUEUE_IDX},
+            vsock::{VsockBufferStatus, RX_QUEUE_IDX, TX_QUEUE_IDX},
         },
         hal::fake::FakeHal,
         transport::{
@@ -449,7 +469,7 @@ mod tests {
                     state
                         .lock()
                         .unwrap()
-                        .read_from_queue::<QUEUE_SIZE>(TX_QUEUE_IDX)
+                        .read_from_queue(TX_QUEUE_IDX)
                         .as_slice()
                 )
                 .unwrap(),
@@ -468,7 +488,7 @@ mod tests {
             );
 
             // Accept connection and give the peer enough credit to send the message.
-            state.lock().unwrap().write_to_queue::<QUEUE_SIZE>(
+            state.lock().unwrap().write_to_queue(
                 RX_QUEUE_IDX,
                 VirtioVsockHdr {
                     op: VirtioVsockOp::Response.into(),
@@ -487,10 +507,7 @@ mod tests {
 
             // Expect the guest to send some data.
             State::wait_until_queue_notified(&state, TX_QUEUE_IDX);
-            let request = state
-                .lock()
-                .unwrap()
-                .read_from_queue::<QUEUE_SIZE>(TX_QUEUE_IDX);
+            let request = state.lock().unwrap().read_from_queue(TX_QUEUE_IDX);
             assert_eq!(
                 request.len(),
                 size_of::<VirtioVsockHdr>() + hello_from_guest.len()
@@ -536,7 +553,7 @@ mod tests {
             state
                 .lock()
                 .unwrap()
-                .write_to_queue::<QUEUE_SIZE>(RX_QUEUE_IDX, &response);
+                .write_to_queue(RX_QUEUE_IDX, &response);
 
             // Expect a shutdown.
             State::wait_until_queue_notified(&state, TX_QUEUE_IDX);
@@ -545,7 +562,7 @@ mod tests {
                     state
                         .lock()
                         .unwrap()
-                        .read_from_queue::<QUEUE_SIZE>(TX_QUEUE_IDX)
+                        .read_from_queue(TX_QUEUE_IDX)
                         .as_slice()
                 )
                 .unwrap(),
@@ -658,7 +675,7 @@ mod tests {
         let handle = thread::spawn(move || {
             // Send a connection request for a port the guest isn't listening on.
             println!("Host sending connection request to wrong port");
-            state.lock().unwrap().write_to_queue::<QUEUE_SIZE>(
+            state.lock().unwrap().write_to_queue(
                 RX_QUEUE_IDX,
                 VirtioVsockHdr {
                     op: VirtioVsockOp::Request.into(),
@@ -683,7 +700,7 @@ mod tests {
                     state
                         .lock()
                         .unwrap()
-                        .read_from_queue::<QUEUE_SIZE>(TX_QUEUE_IDX)
+                        .read_from_queue(TX_QUEUE_IDX)
                         .as_slice()
                 )
                 .unwrap(),
@@ -703,7 +720,7 @@ mod tests {
 
             // Send a connection request for a port the guest is listening on.
             println!("Host sending connection request to right port");
-            state.lock().unwrap().write_to_queue::<QUEUE_SIZE>(
+            state.lock().unwrap().write_to_queue(
                 RX_QUEUE_IDX,
                 VirtioVsockHdr {
                     op: VirtioVsockOp::Request.into(),
@@ -728,7 +745,7 @@ mod tests {
                     state
                         .lock()
                         .unwrap()
-                        .read_from_queue::<QUEUE_SIZE>(TX_QUEUE_IDX)
+                        .read_from_queue(TX_QUEUE_IDX)
                         .as_slice()
                 )
                 .unwrap(),
@@ -3,14 +3,16 @@
 
 use super::error::SocketError;
 use super::protocol::{Feature, VirtioVsockConfig, VirtioVsockHdr, VirtioVsockOp, VsockAddr};
-use crate::hal::Hal;
-use crate::queue::VirtQueue;
+use crate::hal::{DmaBuffer, Hal, PhysAddr};
+use crate::queue::owning::{OwningQueue, QueueBuffer};
+use crate::queue::{SharedAddrs, VirtQueue};
 use crate::transport::Transport;
-use crate::volatile::volread;
-use crate::{Error, Result};
+use crate::volatile::read_config;
+#[cfg(feature = "trace")]
+use crate::QueueStats;
+use crate::{Result, Timeout};
 use alloc::boxed::Box;
 use core::mem::size_of;
-use core::ptr::{null_mut, NonNull};
 use log::debug;
 use zerocopy::{AsBytes, FromBytes, FromZeroes};
 
@@ -19,7 +21,11 @@ pub(crate) const TX_QUEUE_IDX: u16 = 1;
 const EVENT_QUEUE_IDX: u16 = 2;
 
 pub(crate) const QUEUE_SIZE: usize = 8;
-const SUPPORTED_FEATURES: Feature = Feature::RING_EVENT_IDX;
+const SUPPORTED_FEATURES: Feature = Feature::RING_EVENT_IDX
+    .union(Feature::RING_PACKED)
+    .union(Feature::RING_RESET)
+    .union(Feature::NOTIFICATION_DATA)
+    .union(Feature::ORDER_PLATFORM);
 
 /// The size in bytes of each buffer used in the RX virtqueue. This must be bigger than size_of::<VirtioVsockHdr>().
 const RX_BUFFER_SIZE: usize = 512;
@@ -212,15 +218,17 @@ pub enum VsockEventType {
 /// using this directly.
 pub struct VirtIOSocket<H: Hal, T: Transport> {
     transport: T,
+    /// How long to wait for the device to respond to requests.
+    timeout: Timeout,
+    negotiated_features: Feature,
     /// Virtqueue to receive packets.
-    rx: VirtQueue<H, { QUEUE_SIZE }>,
+    rx: OwningQueue<H, { QUEUE_SIZE }, RxBuffer>,
     tx: VirtQueue<H, { QUEUE_SIZE }>,
     /// Virtqueue to receive events from the device.
     event: VirtQueue<H, { QUEUE_SIZE }>,
     /// The guest_cid field contains the guest’s context ID, which uniquely identifies
     /// the device for its lifetime. The upper 32 bits of the CID are reserved and zeroed.
     guest_cid: u64,
-    rx_queue_buffers: [NonNull<[u8; RX_BUFFER_SIZE]>; QUEUE_SIZE],
 }
 
 impl<H: Hal, T: Transport> Drop for VirtIOSocket<H, T> {
@@ -230,12 +238,6 @@ impl<H: Hal, T: Transport> Drop for VirtIOSocket<H, T> {
         self.transport.queue_unset(RX_QUEUE_IDX);
         self.transport.queue_unset(TX_QUEUE_IDX);
         self.transport.queue_unset(EVENT_QUEUE_IDX);
-
-        for buffer in self.rx_queue_buffers {
-            // Safe because we obtained the RX buffer pointer from Box::into_raw, and it won't be
-            // used anywhere else after the driver is destroyed.
-            unsafe { drop(Box::from_raw(buffer.as_ptr())) };
-        }
     }
 }
 
@@ -244,65 +246,87 @@ impl<H: Hal, T: Transport> VirtIOSocket<H, T> {
     pub fn new(mut transport: T) -> Result<Self> {
         let negotiated_features = transport.begin_init(SUPPORTED_FEATURES);
 
-        let config = transport.config_space::<VirtioVsockConfig>()?;
-        debug!("config: {:?}", config);
-        // Safe because config is a valid pointer to the device configuration space.
-        let guest_cid = unsafe {
-            volread!(config, guest_cid_low) as u64 | (volread!(config, guest_cid_high) as u64) << 32
-        };
+        let (guest_cid_low, guest_cid_high): (u32, u32) =
+            transport.read_config_atomically(|transport| {
+                Ok((
+                    read_config!(transport, VirtioVsockConfig, guest_cid_low)?,
+                    read_config!(transport, VirtioVsockConfig, guest_cid_high)?,
+                ))
+            })?;
+        let guest_cid = u64::from(guest_cid_low) | u64::from(guest_cid_high) << 32;
         debug!("guest cid: {guest_cid:?}");
 
-        let mut rx = VirtQueue::new(
+        let mut rx = OwningQueue::new(VirtQueue::new_with_max_size(
             &mut transport,
             RX_QUEUE_IDX,
-            false,
-            negotiated_features.contains(Feature::RING_EVENT_IDX),
-        )?;
-        let tx = VirtQueue::new(
-            &mut transport,
-            TX_QUEUE_IDX,
-            false,
-            negotiated_features.contains(Feature::RING_EVENT_IDX),
-        )?;
-        let event = VirtQueue::new(
+            negotiated_features,
+            None,
+        )?);
+        let tx =
+            VirtQueue::new_with_max_size(&mut transport, TX_QUEUE_IDX, negotiated_features, None)?;
+        let event = VirtQueue::new_with_max_size(
             &mut transport,
             EVENT_QUEUE_IDX,
-            false,
-            negotiated_features.contains(Feature::RING_EVENT_IDX),
+            negotiated_features,
+            None,
         )?;
 
         // Allocate and add buffers for the RX queue.
-        let mut rx_queue_buffers = [null_mut(); QUEUE_SIZE];
-        for (i, rx_queue_buffer) in rx_queue_buffers.iter_mut().enumerate() {
-            let mut buffer: Box<[u8; RX_BUFFER_SIZE]> = FromZeroes::new_box_zeroed();
-            // Safe because the buffer lives as long as the queue, as specified in the function
-            // safety requirement, and we don't access it until it is popped.
-            let token = unsafe { rx.add(&[], &mut [buffer.as_mut_slice()]) }?;
-            assert_eq!(i, token.into());
-            *rx_queue_buffer = Box::into_raw(buffer);
+        for _ in 0..rx.size() {
+            rx.add(RxBuffer(FromZeroes::new_box_zeroed()))?;
         }
-        let rx_queue_buffers = rx_queue_buffers.map(|ptr| NonNull::new(ptr).unwrap());
 
         transport.finish_init();
-        if rx.should_notify() {
-            transport.notify(RX_QUEUE_IDX);
-        }
+        rx.kick(&mut transport);
 
         Ok(Self {
             transport,
+            timeout: Timeout::Never,
+            negotiated_features,
             rx,
             tx,
             event,
             guest_cid,
-            rx_queue_buffers,
         })
     }
 
+    /// Sets how long blocking requests wait for the device to respond. The default is to wait
+    /// forever.
+    ///
+    /// If a request times out then [`Error::Timeout`](crate::Error::Timeout) is returned and the
+    /// device is reset, so the driver can't be used any more.
+    pub fn set_timeout(&mut self, timeout: Timeout) {
+        self.timeout = timeout;
+    }
+
     /// Returns the CID which has been assigned to this guest.
     pub fn guest_cid(&self) -> u64 {
         self.guest_cid
     }
 
+    /// Returns the counters of the virtqueue with the given index, or `None` if the device doesn't
+    /// have such a queue.
+    #[cfg(feature = "trace")]
+    pub fn queue_stats(&self, queue: u16) -> Option<QueueStats> {
+        match queue {
+            RX_QUEUE_IDX => Some(self.rx.stats()),
+            TX_QUEUE_IDX => Some(self.tx.stats()),
+            EVENT_QUEUE_IDX => Some(self.event.stats()),
+            _ => None,
+        }
+    }
+
+    /// Resets the TX queue and sets it up again, without resetting the rest of the device or
+    /// affecting any connections.
+    ///
+    /// This can be used to recover if the device has stopped using buffers from the TX queue.
+    /// Returns [`Error::Unsupported`](crate::Error::Unsupported) if the device doesn't support
+    /// `VIRTIO_F_RING_RESET`.
+    pub fn reset_tx_queue(&mut self) -> Result {
+        self.tx
+            .reset(&mut self.transport, self.negotiated_features, None)
+    }
+
     /// Sends a request to connect to the given destination.
     ///
     /// This returns as soon as the request is sent; you should wait until `poll` returns a
@@ -350,6 +374,28 @@ impl<H: Hal, T: Transport> VirtIOSocket<H, T> {
         self.send_packet_to_tx_queue(&header, buffer)
     }
 
+    /// Sends the contents of the given DMA buffer to the destination.
+    ///
+    /// The buffer is passed to the device without being shared, so this avoids the copy which
+    /// [`VirtIOSocket::send`] may need.
+    pub fn send_dma(
+        &mut self,
+        buffer: &DmaBuffer<H>,
+        connection_info: &mut ConnectionInfo,
+    ) -> Result {
+        self.check_peer_buffer_is_sufficient(connection_info, buffer.len())?;
+
+        let len = buffer.len() as u32;
+        let header = VirtioVsockHdr {
+            op: VirtioVsockOp::Rw.into(),
+            len: len.into(),
+            ..connection_info.new_header(self.guest_cid)
+        };
+        connection_info.tx_cnt += len;
+        // Safe because the device can access the DMA buffer at its physical address.
+        unsafe { self.send_packet_to_tx_queue_shared(&header, buffer, Some(buffer.paddr())) }
+    }
+
     fn check_peer_buffer_is_sufficient(
         &mut self,
         connection_info: &mut ConnectionInfo,
@@ -383,16 +429,25 @@ impl<H: Hal, T: Transport> VirtIOSocket<H, T> {
         &mut self,
         handler: impl FnOnce(VsockEvent, &[u8]) -> Result<Option<VsockEvent>>,
     ) -> Result<Option<VsockEvent>> {
-        let Some((header, body, token)) = self.pop_packet_from_rx_queue()? else {
+        let Some(buffer) = self.pop_packet_from_rx_queue()? else {
             return Ok(None);
         };
 
-        let result = VsockEvent::from_header(&header).and_then(|event| handler(event, body));
+        let result = match read_header_and_body(buffer.0.as_slice()) {
+            Ok((header, body)) => {
+                debug!("Received packet {:?}. Op {:?}", header, header.op());
+                VsockEvent::from_header(&header).and_then(|event| handler(event, body))
+            }
+            Err(e) => {
+                // Add the buffer back before returning the error. Ignore any errors, as we need to
+                // return the first error.
+                let _ = self.add_buffer_to_rx_queue(buffer);
+                return Err(e);
+            }
+        };
 
-        unsafe {
-            // TODO: What about if both handler and this give errors?
-            self.add_buffer_to_rx_queue(token)?;
-        }
+        // TODO: What about if both handler and this give errors?
+        self.add_buffer_to_rx_queue(buffer)?;
 
         result
     }
@@ -421,77 +476,79 @@ impl<H: Hal, T: Transport> VirtIOSocket<H, T> {
     }
 
     fn send_packet_to_tx_queue(&mut self, header: &VirtioVsockHdr, buffer: &[u8]) -> Result {
+        // Safe because no physical address is given.
+        unsafe { self.send_packet_to_tx_queue_shared(header, buffer, None) }
+    }
+
+    /// Sends a packet with the given header and body, where the body may already have been shared
+    /// with the device at `paddr`.
+    ///
+    /// # Safety
+    ///
+    /// If `paddr` is given, it must be where the device can access `buffer`.
+    unsafe fn send_packet_to_tx_queue_shared(
+        &mut self,
+        header: &VirtioVsockHdr,
+        buffer: &[u8],
+        paddr: Option<PhysAddr>,
+    ) -> Result {
         let _len = if buffer.is_empty() {
-            self.tx
-                .add_notify_wait_pop(&[header.as_bytes()], &mut [], &mut self.transport)?
-        } else {
-            self.tx.add_notify_wait_pop(
-                &[header.as_bytes(), buffer],
+            self.tx.add_notify_wait_pop_timeout(
+                &[header.as_bytes()],
                 &mut [],
                 &mut self.transport,
+                self.timeout,
             )?
+        } else {
+            // Safe because our caller ensures that `paddr` is valid.
+            unsafe {
+                self.tx.add_notify_wait_pop_shared(
+                    &[header.as_bytes(), buffer],
+                    &mut [],
+                    SharedAddrs {
+                        inputs: &[None, paddr],
+                        outputs: &[],
+                    },
+                    &mut self.transport,
+                    self.timeout,
+                )?
+            }
         };
         Ok(())
     }
 
-    /// Adds the buffer at the given index in `rx_queue_buffers` back to the RX queue.
-    ///
-    /// # Safety
-    ///
-    /// The buffer must not currently be in the RX queue, and no other references to it must exist
-    /// between when this method is called and when it is popped from the queue.
-    unsafe fn add_buffer_to_rx_queue(&mut self, index: u16) -> Result {
-        // Safe because the buffer lives as long as the queue, and the caller guarantees that it's
-        // not currently in the queue or referred to anywhere else until it is popped.
-        unsafe {
-            let buffer = self
-                .rx_queue_buffers
-                .get_mut(usize::from(index))
-                .ok_or(Error::WrongToken)?
-                .as_mut();
-            let new_token = self.rx.add(&[], &mut [buffer])?;
-            // If the RX buffer somehow gets assigned a different token, then our safety assumptions
-            // are broken and we can't safely continue to do anything with the device.
-            assert_eq!(new_token, index);
-        }
-
-        if self.rx.should_notify() {
-            self.transport.notify(RX_QUEUE_IDX);
-        }
+    /// Adds the given buffer back to the RX queue.
+    fn add_buffer_to_rx_queue(&mut self, buffer: RxBuffer) -> Result {
+        self.rx.add(buffer)?;
+        self.rx.kick(&mut self.transport);
 
         Ok(())
     }
 
-    /// Pops one packet from the RX queue, if there is one pending. Returns the header, and a
-    /// reference to the buffer containing the body.
+    /// Pops one packet from the RX queue, if there is one pending, and returns the buffer
+    /// containing it.
     ///
     /// Returns `None` if there is no pending packet.
-    fn pop_packet_from_rx_queue(&mut self) -> Result<Option<(VirtioVsockHdr, &[u8], u16)>> {
-        let Some(token) = self.rx.peek_used() else {
+    fn pop_packet_from_rx_queue(&mut self) -> Result<Option<RxBuffer>> {
+        if !self.rx.can_pop() {
             return Ok(None);
-        };
-
-        // Safe because we maintain a consistent mapping of tokens to buffers, so we pass the same
-        // buffer to `pop_used` as we previously passed to `add` for the token. Once we add the
-        // buffer back to the RX queue then we don't access it again until next time it is popped.
-        let (header, body) = unsafe {
-            let buffer = self.rx_queue_buffers[usize::from(token)].as_mut();
-            let _len = self.rx.pop_used(token, &[], &mut [buffer])?;
-
-            // Read the header and body from the buffer. Don't check the result yet, because we need
-            // to add the buffer back to the queue either way.
-            let header_result = read_header_and_body(buffer);
-            if header_result.is_err() {
-                // If there was an error, add the buffer back immediately. Ignore any errors, as we
-                // need to return the first error.
-                let _ = self.add_buffer_to_rx_queue(token);
-            }
+        }
+        let (buffer, _len) = self.rx.pop_used()?;
+        Ok(Some(buffer))
+    }
+}
 
-            header_result
-        }?;
+/// A buffer used in the RX virtqueue.
+struct RxBuffer(Box<[u8; RX_BUFFER_SIZE]>);
 
-        debug!("Received packet {:?}. Op {:?}", header, header.op());
-        Ok(Some((header, body, token)))
+// Safe because the data is stored in a `Box` rather than inline, so it doesn't move when the
+// `RxBuffer` does.
+unsafe impl QueueBuffer for RxBuffer {
+    fn with_slices<R>(
+        &mut self,
+        f: impl for<'a, 'b> FnOnce(&'a [&'b [u8]], &'a mut [&'b mut [u8]]) -> R,
+    ) -> R {
+        f(&[], &mut [self.0.as_mut_slice()])
     }
 }
 
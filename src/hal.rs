@@ -1,8 +1,20 @@
 #[cfg(test)]
+/// A fake HAL for unit tests.
 pub mod fake;
+#[cfg(feature = "alloc")]
+pub mod pool;
 
-use crate::{Error, Result, PAGE_SIZE};
-use core::{marker::PhantomData, ptr::NonNull};
+use crate::barrier::{arch_barrier, Barrier};
+#[cfg(feature = "trace")]
+use crate::queue::trace::TraceEvent;
+use crate::{pages, Error, Result, PAGE_SIZE};
+use core::{
+    marker::PhantomData,
+    ops::{Deref, DerefMut},
+    ptr::NonNull,
+    slice,
+    time::Duration,
+};
 
 /// A physical address as used for virtio.
 pub type PhysAddr = usize;
@@ -62,6 +74,78 @@ impl<H: Hal> Drop for Dma<H> {
     }
 }
 
+/// A buffer in DMA memory, which the device can access without it being shared.
+///
+/// Drivers which accept these pass them to the device directly, avoiding the cost of
+/// [`Hal::share`] and [`Hal::unshare`] for each request, which may involve copying through a bounce
+/// buffer. Use a `DmaPool` to reuse them for many requests.
+#[derive(Debug)]
+pub struct DmaBuffer<H: Hal> {
+    dma: Dma<H>,
+    direction: BufferDirection,
+    len: usize,
+}
+
+impl<H: Hal> DmaBuffer<H> {
+    /// Allocates a zeroed buffer of the given length, to be used for DMA in the given direction.
+    ///
+    /// Returns [`Error::InvalidParam`] if the length is 0.
+    pub fn new(len: usize, direction: BufferDirection) -> Result<Self> {
+        if len == 0 {
+            return Err(Error::InvalidParam);
+        }
+        Ok(Self {
+            dma: Dma::new(pages(len), direction)?,
+            direction,
+            len,
+        })
+    }
+
+    /// Returns the physical address of the start of the buffer, as seen by devices.
+    pub fn paddr(&self) -> PhysAddr {
+        self.dma.paddr()
+    }
+
+    /// Returns the direction in which the buffer was allocated to be used.
+    pub fn direction(&self) -> BufferDirection {
+        self.direction
+    }
+
+    /// Returns the number of bytes which the buffer can hold, which may be more than its length.
+    pub fn capacity(&self) -> usize {
+        self.dma.pages * PAGE_SIZE
+    }
+
+    /// Sets the length of the buffer, which is how much of it is passed to the device.
+    ///
+    /// Returns [`Error::InvalidParam`] if the length is 0 or more than the capacity.
+    pub fn set_len(&mut self, len: usize) -> Result {
+        if len == 0 || len > self.capacity() {
+            return Err(Error::InvalidParam);
+        }
+        self.len = len;
+        Ok(())
+    }
+}
+
+impl<H: Hal> Deref for DmaBuffer<H> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        // Safe because the DMA region is valid for at least `len` bytes, and is only accessed
+        // through this buffer while the device isn't using it.
+        unsafe { slice::from_raw_parts(self.dma.vaddr(0).as_ptr(), self.len) }
+    }
+}
+
+impl<H: Hal> DerefMut for DmaBuffer<H> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        // Safe because the DMA region is valid for at least `len` bytes, and is only accessed
+        // through this buffer while the device isn't using it.
+        unsafe { slice::from_raw_parts_mut(self.dma.vaddr(0).as_ptr(), self.len) }
+    }
+}
+
 /// The interface which a particular hardware implementation must implement.
 ///
 /// # Safety
@@ -131,6 +215,53 @@ pub unsafe trait Hal {
     /// any other thread for the duration of this method call. The `paddr` must be the value
     /// previously returned by the corresponding `share` call.
     unsafe fn unshare(paddr: PhysAddr, buffer: NonNull<[u8]>, direction: BufferDirection);
+
+    /// Returns the number of bytes at the start of the given buffer which are contiguous in
+    /// physical memory, and so can be shared with the device by a single call to `share`.
+    ///
+    /// Buffers which aren't physically contiguous, such as a `Vec` which crosses a page boundary
+    /// when virtual memory isn't identity mapped, are split into segments at these points. Each
+    /// segment is shared separately, and passed to the device with its own descriptor. The value
+    /// must not change while the buffer is shared, as it is also used to split the buffer again
+    /// to unshare it.
+    ///
+    /// The default implementation returns the length of the whole buffer, which is correct if
+    /// memory is identity mapped or `share` copies buffers to physically contiguous memory.
+    fn contiguous_len(buffer: NonNull<[u8]>) -> usize {
+        buffer.len()
+    }
+
+    /// Returns the time elapsed since some fixed point in the past, according to a monotonic
+    /// clock, or `None` if there is no clock available.
+    ///
+    /// This is only used for [`Timeout::Duration`](crate::Timeout::Duration). The default
+    /// implementation returns `None`.
+    fn now() -> Option<Duration> {
+        None
+    }
+
+    /// Issues a memory barrier of the given kind, to order accesses to memory shared with a device.
+    ///
+    /// `platform` is true if `VIRTIO_F_ORDER_PLATFORM` has been negotiated, in which case the
+    /// barrier must order accesses as observed by the platform's DMA rather than only by other
+    /// CPUs.
+    ///
+    /// The default implementation calls [`arch_barrier`], which uses the appropriate instructions
+    /// for the target architecture. Platforms with other requirements, such as a non-coherent
+    /// interconnect, can override it.
+    fn barrier(barrier: Barrier, platform: bool) {
+        arch_barrier(barrier, platform)
+    }
+
+    /// Called with each descriptor chain as it is added to a virtqueue, and again when it is
+    /// popped after the device has used it.
+    ///
+    /// This can be used to log the traffic to a device when debugging it. The default
+    /// implementation does nothing.
+    #[cfg(feature = "trace")]
+    fn trace(event: &TraceEvent) {
+        let _ = event;
+    }
 }
 
 /// The direction in which a buffer is passed.
@@ -7,7 +7,9 @@ use alloc::alloc::{alloc_zeroed, dealloc, handle_alloc_error};
 use core::{
     alloc::Layout,
     ptr::{self, NonNull},
+    time::Duration,
 };
+use std::{sync::OnceLock, time::Instant};
 use zerocopy::FromZeroes;
 
 #[derive(Debug)]
@@ -60,6 +62,11 @@ unsafe impl Hal for FakeHal {
         virt_to_phys(vaddr)
     }
 
+    fn now() -> Option<Duration> {
+        static START: OnceLock<Instant> = OnceLock::new();
+        Some(START.get_or_init(Instant::now).elapsed())
+    }
+
     unsafe fn unshare(paddr: PhysAddr, buffer: NonNull<[u8]>, direction: BufferDirection) {
         assert_ne!(buffer.len(), 0);
         assert_ne!(paddr, 0);
@@ -0,0 +1,105 @@
+//! A pool of DMA buffers which can be reused for many requests.
+
+use super::{BufferDirection, DmaBuffer, Hal};
+use crate::Result;
+use alloc::vec::Vec;
+
+/// A pool of [`DmaBuffer`]s of the same length, which are kept for reuse rather than being
+/// deallocated.
+///
+/// Allocating DMA memory may be slow, so drivers sending many requests can take buffers from a
+/// pool and recycle them once the device has finished with them. Recycled buffers aren't zeroed,
+/// so they may still contain data from earlier requests.
+#[derive(Debug)]
+pub struct DmaPool<H: Hal> {
+    buffer_len: usize,
+    direction: BufferDirection,
+    /// The maximum number of unused buffers to keep.
+    max_free: usize,
+    free: Vec<DmaBuffer<H>>,
+}
+
+impl<H: Hal> DmaPool<H> {
+    /// Creates an empty pool of buffers of the given length, to be used for DMA in the given
+    /// direction, which keeps up to `max_free` unused buffers for reuse.
+    pub fn new(buffer_len: usize, direction: BufferDirection, max_free: usize) -> Self {
+        Self {
+            buffer_len,
+            direction,
+            max_free,
+            free: Vec::new(),
+        }
+    }
+
+    /// Allocates buffers until the pool has `count` unused buffers, or as many as it can keep.
+    pub fn reserve(&mut self, count: usize) -> Result {
+        while self.free.len() < count.min(self.max_free) {
+            self.free
+                .push(DmaBuffer::new(self.buffer_len, self.direction)?);
+        }
+        Ok(())
+    }
+
+    /// Takes an unused buffer from the pool, or allocates a new one if there aren't any.
+    pub fn take(&mut self) -> Result<DmaBuffer<H>> {
+        match self.free.pop() {
+            Some(buffer) => Ok(buffer),
+            None => DmaBuffer::new(self.buffer_len, self.direction),
+        }
+    }
+
+    /// Gives a buffer back to the pool for reuse, resetting its length.
+    ///
+    /// The buffer is dropped instead if the pool already has as many unused buffers as it can
+    /// keep, or if it wasn't allocated for the same length and direction as the pool.
+    pub fn recycle(&mut self, mut buffer: DmaBuffer<H>) {
+        if self.free.len() < self.max_free
+            && buffer.direction() == self.direction
+            && buffer.set_len(self.buffer_len).is_ok()
+        {
+            self.free.push(buffer);
+        }
+    }
+
+    /// Returns the number of unused buffers in the pool.
+    pub fn free_len(&self) -> usize {
+        self.free.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hal::fake::FakeHal;
+
+    #[test]
+    fn take_recycle() {
+        let mut pool = DmaPool::<FakeHal>::new(100, BufferDirection::DriverToDevice, 1);
+        pool.reserve(2).unwrap();
+        assert_eq!(pool.free_len(), 1);
+
+        let mut buffer = pool.take().unwrap();
+        assert_eq!(pool.free_len(), 0);
+        assert_eq!(buffer.len(), 100);
+        let paddr = buffer.paddr();
+        buffer.set_len(10).unwrap();
+
+        // The same buffer is handed out again once it has been recycled, at its original length.
+        pool.recycle(buffer);
+        assert_eq!(pool.free_len(), 1);
+        let buffer = pool.take().unwrap();
+        assert_eq!(buffer.paddr(), paddr);
+        assert_eq!(buffer.len(), 100);
+
+        // The pool only keeps as many buffers as it was asked to.
+        let other = pool.take().unwrap();
+        pool.recycle(buffer);
+        pool.recycle(other);
+        assert_eq!(pool.free_len(), 1);
+
+        // Buffers allocated for a different direction aren't kept.
+        pool.take().unwrap();
+        pool.recycle(DmaBuffer::new(100, BufferDirection::DeviceToDriver).unwrap());
+        assert_eq!(pool.free_len(), 0);
+    }
+}
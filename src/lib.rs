@@ -44,14 +44,25 @@
 #![cfg_attr(not(test), no_std)]
 #![deny(unused_must_use, missing_docs)]
 #![allow(clippy::identity_op)]
+// Lints added to Clippy after the code which they warn about was written.
+#![allow(
+    clippy::bool_assert_comparison,
+    clippy::manual_div_ceil,
+    clippy::manual_is_multiple_of,
+    clippy::manual_saturating_arithmetic,
+    clippy::repr_packed_without_abi,
+    clippy::unnecessary_mut_passed
+)]
 #![allow(dead_code)]
 
 #[cfg(any(feature = "alloc", test))]
 extern crate alloc;
 
+mod barrier;
 pub mod device;
 mod hal;
 mod queue;
+mod timeout;
 pub mod transport;
 mod volatile;
 
@@ -60,7 +71,14 @@ use core::{
     ptr::{self, NonNull},
 };
 
-pub use self::hal::{BufferDirection, Hal, PhysAddr};
+pub use self::barrier::{arch_barrier, Barrier};
+#[cfg(feature = "alloc")]
+pub use self::hal::pool::DmaPool;
+pub use self::hal::{BufferDirection, DmaBuffer, Hal, PhysAddr};
+#[cfg(feature = "trace")]
+pub use self::queue::trace::{QueueStats, TraceChain, TraceDescriptor, TraceEvent, TraceKind};
+pub use self::queue::{QueueCompleter, QueueSubmitter, QueueWaker};
+pub use self::timeout::Timeout;
 
 /// The page size in bytes supported by the library (4 KiB).
 pub const PAGE_SIZE: usize = 0x1000;
@@ -93,6 +111,11 @@ pub enum Error {
     ConfigSpaceMissing,
     /// Error from the socket device.
     SocketDeviceError(device::socket::SocketError),
+    /// The device wrote invalid values to a virtqueue, such as an out of range buffer ID or a used
+    /// length longer than the buffer. The virtqueue can't be used any more.
+    DeviceMisbehaved,
+    /// The device didn't respond before the timeout expired.
+    Timeout,
 }
 
 impl Display for Error {
@@ -120,6 +143,8 @@ impl Display for Error {
                 )
             }
             Self::SocketDeviceError(e) => write!(f, "Error from the socket device: {e:?}"),
+            Self::DeviceMisbehaved => write!(f, "Device wrote invalid values to a virtqueue"),
+            Self::Timeout => write!(f, "Timed out waiting for the device"),
         }
     }
 }
@@ -1,63 +1,312 @@
 #![deny(unsafe_op_in_unsafe_fn)]
 
+mod halves;
+#[cfg(feature = "alloc")]
+pub mod owning;
+mod packed;
+#[cfg(feature = "trace")]
+pub(crate) mod trace;
+mod waker;
+
+pub use self::halves::{QueueCompleter, QueueSubmitter};
+pub(crate) use self::packed::PackedDescriptor;
+#[cfg(test)]
+pub(crate) use self::packed::{fake_read_write_packed_queue, FakePackedDevice};
+use self::packed::{PackedComplete, PackedRing, PackedSubmit};
+#[cfg(feature = "trace")]
+use self::trace::{QueueCounters, QueueStats, TraceEvent, TraceKind};
+pub use self::waker::QueueWaker;
+use crate::barrier::Barrier;
+use crate::device::common::Feature;
 use crate::hal::{BufferDirection, Dma, Hal, PhysAddr};
-use crate::transport::Transport;
+use crate::timeout::{spin_until, Deadline, Timeout};
+use crate::transport::{DeviceStatus, Transport};
 use crate::{align_up, nonnull_slice_from_raw_parts, pages, Error, Result, PAGE_SIZE};
 #[cfg(feature = "alloc")]
 use alloc::boxed::Box;
-use bitflags::bitflags;
-#[cfg(test)]
+use bitflags::{bitflags, Flags};
+use core::cell::UnsafeCell;
 use core::cmp::min;
+use core::convert::TryFrom;
+use core::future::poll_fn;
 use core::hint::spin_loop;
+use core::marker::PhantomData;
 use core::mem::{size_of, take};
 #[cfg(test)]
 use core::ptr;
-use core::ptr::NonNull;
-use core::sync::atomic::{fence, Ordering};
+use core::ptr::{addr_of, addr_of_mut, NonNull};
+use core::sync::atomic::{AtomicBool, AtomicU16, Ordering};
+use core::task::{Context, Poll};
+use log::warn;
 use zerocopy::{AsBytes, FromBytes, FromZeroes};
 
 /// The mechanism for bulk data transport on virtio devices.
 ///
 /// Each device can have zero or more virtqueues.
 ///
-/// * `SIZE`: The size of the queue. This is both the number of descriptors, and the number of slots
-///   in the available and used rings.
+/// * `SIZE`: The maximum size of the queue. The actual size, which is both the number of
+///   descriptors and the number of slots in the available and used rings, is given by
+///   [`VirtQueue::size`]. It is `SIZE` unless the queue was created with
+///   [`VirtQueue::new_with_max_size`].
+///
+/// A queue can be [split](VirtQueue::split) into a submitting half and a completing half, which can
+/// be used concurrently from different CPUs.
+///
+/// The queue keeps its state in DMA memory, and on the heap with the `alloc` feature, so it is
+/// small to create and move even for large sizes.
 #[derive(Debug)]
 pub struct VirtQueue<H: Hal, const SIZE: usize> {
     /// DMA guard
     layout: VirtQueueLayout<H>,
-    /// Descriptor table
-    ///
-    /// The device may be able to modify this, even though it's not supposed to, so we shouldn't
-    /// trust values read back from it. Use `desc_shadow` instead to keep track of what we wrote to
-    /// it.
-    desc: NonNull<[Descriptor]>,
-    /// Available ring
-    ///
-    /// The device may be able to modify this, even though it's not supposed to, so we shouldn't
-    /// trust values read back from it. The only field we need to read currently is `idx`, so we
-    /// have `avail_idx` below to use instead.
-    avail: NonNull<AvailRing<SIZE>>,
-    /// Used ring
-    used: NonNull<UsedRing<SIZE>>,
+    /// The rings shared with the device, in either the split or the packed format.
+    ring: Ring,
 
     /// The index of queue
     queue_idx: u16,
-    /// The number of descriptors currently in use.
-    num_used: u16,
-    /// The head desc index of the free list.
-    free_head: u16,
-    /// Our trusted copy of `desc` that the device can't access.
-    desc_shadow: [Descriptor; SIZE],
-    /// Our trusted copy of `avail.idx`.
-    avail_idx: u16,
-    last_used_idx: u16,
+    /// The size of the queue, no more than `SIZE`.
+    size: u16,
+    /// Our trusted copy of the descriptors that the device can't access. Only the first `size` are
+    /// used.
+    ///
+    /// For the split ring this mirrors the descriptor table. For the packed ring descriptors are
+    /// written to the ring in order, so this is indexed by buffer ID instead, but the chains are
+    /// linked in the same way.
+    desc_shadow: DescShadow<H>,
+    /// The state used only by the submitting side of the queue.
+    submit: UnsafeCell<SubmitState>,
+    /// The state used only by the completing side of the queue.
+    complete: UnsafeCell<CompleteState>,
+    /// The number of free descriptors, which the submitting side may allocate.
+    num_free: AtomicU16,
+    /// The head of the list of descriptor chains which the completing side has finished with but
+    /// the submitting side hasn't yet moved to its free list, or `NO_DESCRIPTOR` if it is empty.
+    ///
+    /// The chains are linked by the `next` field of their last descriptor.
+    recycled_head: AtomicU16,
+    /// The number of descriptor chains which have been made available to the device, wrapping
+    /// around.
+    published: AtomicU16,
+    /// The index of the descriptor after the last published descriptor chain, if
+    /// `VIRTIO_F_IN_ORDER` has been negotiated.
+    published_end: AtomicU16,
     /// Whether the `VIRTIO_F_EVENT_IDX` feature has been negotiated.
     event_idx: bool,
+    /// Whether the `VIRTIO_F_IN_ORDER` feature has been negotiated.
+    in_order: bool,
+    /// Whether the `VIRTIO_F_NOTIFICATION_DATA` feature has been negotiated.
+    notification_data: bool,
+    /// Whether the device has been caught writing invalid values to the used ring, in which case
+    /// the queue can't safely be used any more.
+    broken: AtomicBool,
+    /// A pool of indirect descriptor tables, if the `VIRTIO_F_INDIRECT_DESC` feature has been
+    /// negotiated.
+    ///
+    /// These are allocated up front so that indirect descriptors don't need any allocation or
+    /// sharing when buffers are added.
+    indirect_tables: Option<IndirectPool<H>>,
+    /// The limit on the length of indirect tables which the queue was created with, to use again
+    /// when it is reset.
+    indirect_len_limit: u16,
+    /// Counters for debugging, which both sides of the queue update.
+    #[cfg(feature = "trace")]
+    counters: QueueCounters,
+}
+
+/// The memory barriers to use for a virtqueue, as provided by the HAL.
+#[derive(Clone, Copy, Debug)]
+struct Barriers {
+    barrier: fn(Barrier, bool),
+    /// Whether `VIRTIO_F_ORDER_PLATFORM` has been negotiated.
+    platform: bool,
+}
+
+impl Barriers {
+    fn new<H: Hal>(platform: bool) -> Self {
+        Self {
+            barrier: H::barrier,
+            platform,
+        }
+    }
+
+    /// Issues the given kind of memory barrier.
+    fn issue(&self, barrier: Barrier) {
+        (self.barrier)(barrier, self.platform)
+    }
+}
+
+/// The value of a descriptor index which doesn't refer to any descriptor. The queue size is at most
+/// 2^15, so this is never a valid index.
+const NO_DESCRIPTOR: u16 = u16::MAX;
+
+/// The state of a [`VirtQueue`] which is only accessed by its submitting side.
+#[derive(Debug)]
+struct SubmitState {
+    /// The driver's position in the rings for making buffers available.
+    ring: RingSubmit,
+    /// The head desc index of the free list.
+    free_head: u16,
+    /// The number of descriptor chains added, including any which haven't been published yet,
+    /// wrapping around.
+    added: u16,
+}
+
+/// The state of a [`VirtQueue`] which is only accessed by its completing side.
+#[derive(Debug)]
+struct CompleteState {
+    /// The driver's position in the rings for popping used buffers.
+    ring: RingComplete,
+    /// The buffer ID and used length reported by the device for the last buffer of the batch
+    /// currently being popped, if `VIRTIO_F_IN_ORDER` has been negotiated.
+    batch_last: Option<(u16, u32)>,
+    /// The head of the oldest descriptor chain still owned by the device, if `VIRTIO_F_IN_ORDER`
+    /// has been negotiated.
+    oldest: u16,
+    /// The number of descriptor chains popped, wrapping around.
+    popped: u16,
+}
+
+/// Our trusted copy of the descriptors of a [`VirtQueue`].
+///
+/// Each descriptor is owned by either the submitting side of the queue, while it is free or being
+/// filled in, or by the completing side, once the chain it is part of has been published. Only the
+/// owner may access it.
+///
+/// The descriptors are allocated separately from the queue, with only as many as the queue
+/// actually uses, so that large queues don't need large stack frames to set up or move around.
+/// With the `alloc` feature they are on the heap. Without it they are in memory from
+/// [`Hal::dma_alloc`], as there is nowhere else to put them, but it is never given to the device.
+#[derive(Debug)]
+struct DescShadow<H: Hal> {
+    #[cfg(feature = "alloc")]
+    descriptors: Box<[UnsafeCell<Descriptor>]>,
+    /// Whether each descriptor is the head of a chain which has been added and not yet recycled,
+    /// so that used elements with any other ID can be rejected.
+    ///
+    /// These are set by the submitter and cleared by the completer, and may be read by either.
+    #[cfg(feature = "alloc")]
+    heads: Box<[AtomicBool]>,
+    /// The descriptors followed by the head flags.
+    #[cfg(not(feature = "alloc"))]
+    dma: Dma<H>,
+    /// The number of descriptors.
+    size: u16,
+    _hal: PhantomData<H>,
+}
+
+impl<H: Hal> DescShadow<H> {
+    /// Creates `size` descriptors, all linked together in the free list.
+    fn new(size: u16) -> Result<Self> {
+        // Link descriptors together.
+        let new_descriptor = |i: u16| {
+            let mut descriptor = Descriptor::new_zeroed();
+            if i + 1 < size {
+                descriptor.next = i + 1;
+            }
+            UnsafeCell::new(descriptor)
+        };
+        #[cfg(feature = "alloc")]
+        let shadow = Self {
+            descriptors: (0..size).map(new_descriptor).collect(),
+            heads: (0..size).map(|_| AtomicBool::new(false)).collect(),
+            size,
+            _hal: PhantomData,
+        };
+        #[cfg(not(feature = "alloc"))]
+        let shadow = {
+            let size_bytes =
+                usize::from(size) * (size_of::<Descriptor>() + size_of::<AtomicBool>());
+            let shadow = Self {
+                dma: Dma::new(pages(size_bytes), BufferDirection::DriverToDevice)?,
+                size,
+                _hal: PhantomData,
+            };
+            // The memory is zeroed, so the head flags are already false.
+            for i in 0..size {
+                // Safe because nothing else has access to the descriptors yet.
+                unsafe {
+                    shadow.descriptors()[usize::from(i)]
+                        .get()
+                        .write(new_descriptor(i).into_inner());
+                }
+            }
+            shadow
+        };
+        Ok(shadow)
+    }
+
+    /// Returns all the descriptors.
     #[cfg(feature = "alloc")]
-    indirect: bool,
+    fn descriptors(&self) -> &[UnsafeCell<Descriptor>] {
+        &self.descriptors
+    }
+
+    /// Returns all the descriptors.
+    #[cfg(not(feature = "alloc"))]
+    fn descriptors(&self) -> &[UnsafeCell<Descriptor>] {
+        // Safe because the start of the DMA region is page aligned, and holds `size` descriptors
+        // which are only accessed through the `UnsafeCell`s.
+        unsafe { core::slice::from_raw_parts(self.dma.vaddr(0).as_ptr().cast(), self.size.into()) }
+    }
+
+    /// Returns the head flags of all the descriptors.
     #[cfg(feature = "alloc")]
-    indirect_lists: [Option<NonNull<[Descriptor]>>; SIZE],
+    fn heads(&self) -> &[AtomicBool] {
+        &self.heads
+    }
+
+    /// Returns the head flags of all the descriptors.
+    #[cfg(not(feature = "alloc"))]
+    fn heads(&self) -> &[AtomicBool] {
+        let offset = usize::from(self.size) * size_of::<Descriptor>();
+        // Safe because the flags follow the descriptors in the DMA region, and are only accessed
+        // atomically.
+        unsafe {
+            core::slice::from_raw_parts(self.dma.vaddr(offset).as_ptr().cast(), self.size.into())
+        }
+    }
+
+    /// Returns whether the descriptor at the given index is the head of a chain which has been
+    /// added and not yet recycled. Returns false if the index is out of range.
+    ///
+    /// If this returns true for a published chain, the completer owns the chain.
+    fn is_head(&self, index: u16) -> bool {
+        matches!(self.heads().get(usize::from(index)), Some(head) if head.load(Ordering::Relaxed))
+    }
+
+    /// Records whether the descriptor at the given index is the head of a chain which has been
+    /// added and not yet recycled.
+    ///
+    /// The caller must own the descriptor.
+    fn set_head(&self, index: u16, head: bool) {
+        self.heads()[usize::from(index)].store(head, Ordering::Relaxed);
+    }
+
+    /// Returns the descriptor at the given index.
+    ///
+    /// # Safety
+    ///
+    /// The caller must own the descriptor, and not hold a mutable reference to it.
+    unsafe fn get(&self, index: u16) -> &Descriptor {
+        // Safe because the caller owns the descriptor, so nothing else is modifying it.
+        unsafe { &*self.descriptors()[usize::from(index)].get() }
+    }
+
+    /// Returns the descriptor at the given index, for modifying.
+    ///
+    /// # Safety
+    ///
+    /// The caller must own the descriptor, and not hold any other reference to it.
+    #[allow(clippy::mut_from_ref)]
+    unsafe fn get_mut(&self, index: u16) -> &mut Descriptor {
+        // Safe because the caller owns the descriptor, so nothing else is accessing it.
+        unsafe { &mut *self.descriptors()[usize::from(index)].get() }
+    }
+
+    /// Returns all the descriptors, whoever owns them.
+    #[cfg(feature = "trace")]
+    fn as_slice(&self) -> &[UnsafeCell<Descriptor>] {
+        self.descriptors()
+    }
 }
 
 impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
@@ -68,24 +317,127 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
     /// * `event_idx`: Whether to use the `used_event` and `avail_event` fields for notification
     ///   suppression. This should be set if the `VIRTIO_F_EVENT_IDX` feature has been negotiated
     ///   with the device.
+    ///
+    /// The queue uses the split layout. Use [`VirtQueue::new_with_features`] to enable other
+    /// features such as the packed layout.
     pub fn new<T: Transport>(
         transport: &mut T,
         idx: u16,
         indirect: bool,
         event_idx: bool,
     ) -> Result<Self> {
-        if transport.queue_used(idx) {
-            return Err(Error::AlreadyUsed);
-        }
+        let mut features = Feature::empty();
+        features.set(Feature::RING_INDIRECT_DESC, indirect);
+        features.set(Feature::RING_EVENT_IDX, event_idx);
+        Self::new_with_features(transport, idx, features)
+    }
+
+    /// Creates a new VirtQueue for the given set of negotiated features.
+    ///
+    /// The layout and behaviour of the queue are chosen based on the device-independent bits of
+    /// `negotiated_features`, which should be the set of features negotiated with the device:
+    ///
+    /// * `VIRTIO_F_INDIRECT_DESC`: Use indirect descriptors for chains of more than one buffer.
+    ///   Each descriptor in the ring has its own indirect table as long as the queue, allocated up
+    ///   front. Use [`VirtQueue::new_with_max_indirect_len`] to make them shorter for large queues.
+    /// * `VIRTIO_F_EVENT_IDX`: Use the `used_event` and `avail_event` fields (or their packed
+    ///   equivalents) for notification suppression.
+    /// * `VIRTIO_F_RING_PACKED`: Use the packed virtqueue layout rather than the split one. This
+    ///   isn't supported by transports which require the legacy layout.
+    /// * `VIRTIO_F_IN_ORDER`: Allocate descriptors sequentially, and allow the device to return a
+    ///   batch of buffers with a single used element.
+    /// * `VIRTIO_F_NOTIFICATION_DATA`: Include the position of the next available descriptor when
+    ///   notifying the device.
+    ///
+    /// The queue will have exactly `SIZE` descriptors, which must be a power of two and no more
+    /// than the device supports.
+    pub fn new_with_features<T: Transport, F: Flags<Bits = u64>>(
+        transport: &mut T,
+        idx: u16,
+        negotiated_features: F,
+    ) -> Result<Self> {
         if !SIZE.is_power_of_two()
             || SIZE > u16::MAX.into()
             || transport.max_queue_size(idx) < SIZE as u32
         {
             return Err(Error::InvalidParam);
         }
-        let size = SIZE as u16;
+        Self::with_size(transport, idx, negotiated_features, SIZE as u16, u16::MAX)
+    }
+
+    /// Creates a new VirtQueue with its size chosen at runtime.
+    ///
+    /// The size will be the largest power of two which is no more than `SIZE`, the maximum queue
+    /// size which the device supports for this queue, and `max_size` if it is given. Use
+    /// [`VirtQueue::size`] to find out what was chosen.
+    ///
+    /// Returns [`Error::Unsupported`] if the transport only allows queues of the maximum size the
+    /// device supports, as for legacy PCI devices, and that is more than the other limits.
+    ///
+    /// `negotiated_features` is interpreted as for [`VirtQueue::new_with_features`].
+    pub fn new_with_max_size<T: Transport, F: Flags<Bits = u64>>(
+        transport: &mut T,
+        idx: u16,
+        negotiated_features: F,
+        max_size: Option<u16>,
+    ) -> Result<Self> {
+        Self::new_with_max_indirect_len(transport, idx, negotiated_features, max_size, u16::MAX)
+    }
+
+    /// Like [`VirtQueue::new_with_max_size`], but limits indirect descriptor tables to
+    /// `max_indirect_len` descriptors each.
+    ///
+    /// By default there is a table as long as the queue for each descriptor in the ring, which
+    /// takes 16 × size² bytes of DMA memory, or 16 MiB for a queue of 1024. This limits it to
+    /// 16 × size × `max_indirect_len` bytes, at the cost of longer chains using direct
+    /// descriptors.
+    pub fn new_with_max_indirect_len<T: Transport, F: Flags<Bits = u64>>(
+        transport: &mut T,
+        idx: u16,
+        negotiated_features: F,
+        max_size: Option<u16>,
+        max_indirect_len: u16,
+    ) -> Result<Self> {
+        let mut limit = min(SIZE, 1 << 15);
+        limit = min(limit, transport.max_queue_size(idx) as usize);
+        if let Some(max_size) = max_size {
+            limit = min(limit, max_size.into());
+        }
+        if limit == 0 {
+            return Err(Error::InvalidParam);
+        }
+        // Round down to a power of two.
+        let size = 1 << (usize::BITS - 1 - limit.leading_zeros());
+        Self::with_size(transport, idx, negotiated_features, size, max_indirect_len)
+    }
+
+    /// Creates a new VirtQueue of the given size, which must be a power of two no more than `SIZE`
+    /// and the maximum queue size supported by the device, with indirect tables of at most
+    /// `max_indirect_len` descriptors.
+    fn with_size<T: Transport, F: Flags<Bits = u64>>(
+        transport: &mut T,
+        idx: u16,
+        negotiated_features: F,
+        size: u16,
+        max_indirect_len: u16,
+    ) -> Result<Self> {
+        let negotiated_features = Feature::from_bits_truncate(negotiated_features.bits());
+        let packed = negotiated_features.contains(Feature::RING_PACKED);
+        if transport.queue_used(idx) {
+            return Err(Error::AlreadyUsed);
+        }
+        if packed && transport.requires_legacy_layout() {
+            return Err(Error::InvalidParam);
+        }
+        // Legacy PCI devices use their own queue size whatever the driver wants.
+        if transport.requires_fixed_queue_size() && u32::from(size) != transport.max_queue_size(idx)
+        {
+            return Err(Error::Unsupported);
+        }
 
-        let layout = if transport.requires_legacy_layout() {
+        let layout = if packed {
+            VirtQueueLayout::allocate_packed(size)?
+        } else if transport.requires_legacy_layout() {
             VirtQueueLayout::allocate_legacy(size)?
         } else {
             VirtQueueLayout::allocate_flexible(size)?
@@ -99,43 +451,188 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
             layout.device_area_paddr(),
         );
 
-        let desc =
-            nonnull_slice_from_raw_parts(layout.descriptors_vaddr().cast::<Descriptor>(), SIZE);
-        let avail = layout.avail_vaddr().cast();
-        let used = layout.used_vaddr().cast();
+        let desc_shadow = DescShadow::new(size)?;
 
-        let mut desc_shadow: [Descriptor; SIZE] = FromZeroes::new_zeroed();
-        // Link descriptors together.
-        for i in 0..(size - 1) {
-            desc_shadow[i as usize].next = i + 1;
-            // Safe because `desc` is properly aligned, dereferenceable, initialised, and the device
-            // won't access the descriptors for the duration of this unsafe block.
-            unsafe {
-                (*desc.as_ptr())[i as usize].next = i + 1;
-            }
-        }
+        let barriers = Barriers::new::<H>(negotiated_features.contains(Feature::ORDER_PLATFORM));
+        let (ring, ring_submit, ring_complete) = if packed {
+            let ring = PackedRing::new(&layout, size, barriers);
+            (
+                Ring::Packed(ring.clone()),
+                RingSubmit::Packed(PackedSubmit::new(ring.clone())),
+                RingComplete::Packed(PackedComplete::new(ring)),
+            )
+        } else {
+            let ring = SplitRing::new(&layout, size, &desc_shadow, barriers);
+            (
+                Ring::Split(ring.clone()),
+                RingSubmit::Split(SplitSubmit::new(ring.clone())),
+                RingComplete::Split(SplitComplete::new(ring)),
+            )
+        };
+
+        // A chain can't be longer than the queue, and a table of one descriptor would be no use.
+        let indirect_len = min(size, max_indirect_len);
+        let indirect_tables =
+            if negotiated_features.contains(Feature::RING_INDIRECT_DESC) && indirect_len > 1 {
+                Some(IndirectPool::new(size, indirect_len)?)
+            } else {
+                None
+            };
 
-        #[cfg(feature = "alloc")]
-        const NONE: Option<NonNull<[Descriptor]>> = None;
         Ok(VirtQueue {
             layout,
-            desc,
-            avail,
-            used,
+            ring,
             queue_idx: idx,
-            num_used: 0,
-            free_head: 0,
+            size,
             desc_shadow,
-            avail_idx: 0,
-            last_used_idx: 0,
-            event_idx,
-            #[cfg(feature = "alloc")]
-            indirect,
-            #[cfg(feature = "alloc")]
-            indirect_lists: [NONE; SIZE],
+            submit: UnsafeCell::new(SubmitState {
+                ring: ring_submit,
+                free_head: 0,
+                added: 0,
+            }),
+            complete: UnsafeCell::new(CompleteState {
+                ring: ring_complete,
+                batch_last: None,
+                oldest: 0,
+                popped: 0,
+            }),
+            num_free: AtomicU16::new(size),
+            recycled_head: AtomicU16::new(NO_DESCRIPTOR),
+            published: AtomicU16::new(0),
+            published_end: AtomicU16::new(0),
+            event_idx: negotiated_features.contains(Feature::RING_EVENT_IDX),
+            in_order: negotiated_features.contains(Feature::IN_ORDER),
+            notification_data: negotiated_features.contains(Feature::NOTIFICATION_DATA),
+            broken: AtomicBool::new(false),
+            indirect_tables,
+            indirect_len_limit: max_indirect_len,
+            #[cfg(feature = "trace")]
+            counters: QueueCounters::default(),
         })
     }
 
+    /// Resets the queue on the device and sets it up again from scratch, without resetting the
+    /// rest of the device.
+    ///
+    /// This requires `VIRTIO_F_RING_RESET` to have been negotiated. Any buffers which were added
+    /// and not yet popped are forgotten, and the device won't access them any more once this
+    /// returns. The new queue's size is chosen as for [`VirtQueue::new_with_max_size`].
+    ///
+    /// The queue doesn't keep the buffers which were added to it, so it can't unshare those which
+    /// weren't popped. With a HAL whose `share` allocates memory, such as for bounce buffers, that
+    /// memory is leaked. To avoid this, pop all buffers before resetting the queue, or use
+    /// [`OwningQueue::reset`](owning::OwningQueue::reset), which unshares them.
+    ///
+    /// If setting the queue up again fails then the queue is left reset on the device, and
+    /// shouldn't be used any more.
+    pub fn reset<T: Transport, F: Flags<Bits = u64>>(
+        &mut self,
+        transport: &mut T,
+        negotiated_features: F,
+        max_size: Option<u16>,
+    ) -> Result {
+        self.reset_and_reclaim(transport, negotiated_features, max_size, |_| {})
+    }
+
+    /// Like [`VirtQueue::reset`], but calls `reclaim` once the device has stopped using the queue
+    /// and before it is set up again, so that it can reclaim the buffers which weren't popped.
+    pub(crate) fn reset_and_reclaim<T: Transport, F: Flags<Bits = u64>>(
+        &mut self,
+        transport: &mut T,
+        negotiated_features: F,
+        max_size: Option<u16>,
+        reclaim: impl FnOnce(&mut Self),
+    ) -> Result {
+        if !Feature::from_bits_truncate(negotiated_features.bits()).contains(Feature::RING_RESET) {
+            return Err(Error::Unsupported);
+        }
+        transport.queue_reset(self.queue_idx)?;
+        reclaim(self);
+        *self = Self::new_with_max_indirect_len(
+            transport,
+            self.queue_idx,
+            negotiated_features,
+            max_size,
+            self.indirect_len_limit,
+        )?;
+        Ok(())
+    }
+
+    /// Returns the size of the queue.
+    ///
+    /// This is both the number of descriptors, and the number of slots in the available and used
+    /// rings.
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    /// Returns the counters of descriptor chains and notifications which have passed through the
+    /// queue since it was created or last reset.
+    #[cfg(feature = "trace")]
+    pub fn stats(&self) -> QueueStats {
+        self.counters.snapshot()
+    }
+
+    /// Passes the descriptor chain starting at `head` to [`Hal::trace`].
+    ///
+    /// The caller must own the chain.
+    #[cfg(feature = "trace")]
+    fn trace(&self, kind: TraceKind, head: u16) {
+        // Safe because our caller owns the chain.
+        let head_desc = unsafe { self.desc_shadow.get(head) };
+        let indirect_table: &[Descriptor] = if head_desc.flags.contains(DescFlags::INDIRECT) {
+            let (_, table) = self
+                .indirect_tables
+                .as_ref()
+                .unwrap()
+                .table(head, head_desc.len as usize / size_of::<Descriptor>());
+            // Safe because the table is properly aligned, dereferenceable and initialised, and the
+            // device doesn't write to it.
+            unsafe { table.as_ref() }
+        } else {
+            &[]
+        };
+        H::trace(&TraceEvent::new(
+            self.queue_idx,
+            kind,
+            head,
+            self.desc_shadow.as_slice(),
+            indirect_table,
+            matches!(self.ring, Ring::Packed(_)),
+        ));
+    }
+
+    /// Splits the queue into a submitting half and a completing half, which can be sent to
+    /// different CPUs and used at the same time.
+    ///
+    /// The submitter adds buffers and notifies the device, and the completer pops used buffers and
+    /// controls used buffer notifications. Free descriptors are handed back from the completer to
+    /// the submitter through atomics, so no lock is needed between them.
+    pub fn split(&mut self) -> (QueueSubmitter<'_, H, SIZE>, QueueCompleter<'_, H, SIZE>) {
+        // Safe because we have exclusive access to the queue for as long as the halves exist, and
+        // they each only access their own half of the state.
+        unsafe { (QueueSubmitter::new(self), QueueCompleter::new(self)) }
+    }
+
+    /// Returns the submitting half of the queue.
+    fn submitter(&mut self) -> QueueSubmitter<'_, H, SIZE> {
+        // Safe because we have exclusive access to the queue for as long as the submitter exists.
+        unsafe { QueueSubmitter::new(self) }
+    }
+
+    /// Returns the completing half of the queue, to be used only for reading its state.
+    fn completer(&self) -> QueueCompleter<'_, H, SIZE> {
+        // Safe because the completing state can only be modified through `&mut self` or the
+        // completer returned by `split`, neither of which can exist while we have `&self`.
+        unsafe { QueueCompleter::new(self) }
+    }
+
+    /// Returns the completing half of the queue.
+    fn completer_mut(&mut self) -> QueueCompleter<'_, H, SIZE> {
+        // Safe because we have exclusive access to the queue for as long as the completer exists.
+        unsafe { QueueCompleter::new(self) }
+    }
+
     /// Add buffers to the virtqueue, return a token.
     ///
     /// The buffers must not be empty.
@@ -151,138 +648,50 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
         inputs: &'a [&'b [u8]],
         outputs: &'a mut [&'b mut [u8]],
     ) -> Result<u16> {
-        if inputs.is_empty() && outputs.is_empty() {
-            return Err(Error::InvalidParam);
-        }
-        let descriptors_needed = inputs.len() + outputs.len();
-        // Only consider indirect descriptors if the alloc feature is enabled, as they require
-        // allocation.
-        #[cfg(feature = "alloc")]
-        if self.num_used as usize + 1 > SIZE
-            || descriptors_needed > SIZE
-            || (!self.indirect && self.num_used as usize + descriptors_needed > SIZE)
-        {
-            return Err(Error::QueueFull);
-        }
-        #[cfg(not(feature = "alloc"))]
-        if self.num_used as usize + descriptors_needed > SIZE {
-            return Err(Error::QueueFull);
-        }
-
-        #[cfg(feature = "alloc")]
-        let head = if self.indirect && descriptors_needed > 1 {
-            self.add_indirect(inputs, outputs)
-        } else {
-            self.add_direct(inputs, outputs)
-        };
-        #[cfg(not(feature = "alloc"))]
-        let head = self.add_direct(inputs, outputs);
-
-        let avail_slot = self.avail_idx & (SIZE as u16 - 1);
-        // Safe because self.avail is properly aligned, dereferenceable and initialised.
-        unsafe {
-            (*self.avail.as_ptr()).ring[avail_slot as usize] = head;
-        }
-
-        // Write barrier so that device sees changes to descriptor table and available ring before
-        // change to available index.
-        fence(Ordering::SeqCst);
-
-        // increase head of avail ring
-        self.avail_idx = self.avail_idx.wrapping_add(1);
-        // Safe because self.avail is properly aligned, dereferenceable and initialised.
-        unsafe {
-            (*self.avail.as_ptr()).idx = self.avail_idx;
-        }
-
-        // Write barrier so that device can see change to available index after this method returns.
-        fence(Ordering::SeqCst);
-
-        Ok(head)
+        // Safe because our caller has the same safety requirements.
+        unsafe { self.submitter().add(inputs, outputs) }
     }
 
-    fn add_direct<'a, 'b>(
+    /// Adds buffers to the virtqueue without making them available to the device yet, and returns
+    /// a token.
+    ///
+    /// Any number of buffers can be staged like this, and then made available to the device all
+    /// at once by [`VirtQueue::kick_prepare`] (or [`VirtQueue::add`]), so that the device only
+    /// needs to be notified once for the whole batch.
+    ///
+    /// The buffers must not be empty.
+    ///
+    /// # Safety
+    ///
+    /// The input and output buffers must remain valid and not be accessed until a call to
+    /// `pop_used` with the returned token succeeds.
+    pub unsafe fn stage<'a, 'b>(
         &mut self,
         inputs: &'a [&'b [u8]],
         outputs: &'a mut [&'b mut [u8]],
-    ) -> u16 {
-        // allocate descriptors from free list
-        let head = self.free_head;
-        let mut last = self.free_head;
-
-        for (buffer, direction) in InputOutputIter::new(inputs, outputs) {
-            assert_ne!(buffer.len(), 0);
-
-            // Write to desc_shadow then copy.
-            let desc = &mut self.desc_shadow[usize::from(self.free_head)];
-            // Safe because our caller promises that the buffers live at least until `pop_used`
-            // returns them.
-            unsafe {
-                desc.set_buf::<H>(buffer, direction, DescFlags::NEXT);
-            }
-            last = self.free_head;
-            self.free_head = desc.next;
-
-            self.write_desc(last);
-        }
-
-        // set last_elem.next = NULL
-        self.desc_shadow[usize::from(last)]
-            .flags
-            .remove(DescFlags::NEXT);
-        self.write_desc(last);
-
-        self.num_used += (inputs.len() + outputs.len()) as u16;
-
-        head
+    ) -> Result<u16> {
+        // Safe because our caller has the same safety requirements.
+        unsafe { self.submitter().stage(inputs, outputs) }
     }
 
-    #[cfg(feature = "alloc")]
-    fn add_indirect<'a, 'b>(
+    /// Like [`VirtQueue::stage`], but some of the buffers may already have been shared with the
+    /// device, as given by `shared`. Those buffers are passed to the device at the given physical
+    /// addresses, and not shared or unshared by the queue.
+    ///
+    /// # Safety
+    ///
+    /// The input and output buffers must remain valid and not be accessed until a call to
+    /// `pop_used_shared` with the returned token succeeds. Each physical address in `shared` must
+    /// be where the device can access the corresponding buffer until then, such as
+    /// [`DmaBuffer::paddr`](crate::DmaBuffer::paddr).
+    pub unsafe fn stage_shared<'a, 'b>(
         &mut self,
         inputs: &'a [&'b [u8]],
         outputs: &'a mut [&'b mut [u8]],
-    ) -> u16 {
-        let head = self.free_head;
-
-        // Allocate and fill in indirect descriptor list.
-        let mut indirect_list = Descriptor::new_box_slice_zeroed(inputs.len() + outputs.len());
-        for (i, (buffer, direction)) in InputOutputIter::new(inputs, outputs).enumerate() {
-            let desc = &mut indirect_list[i];
-            // Safe because our caller promises that the buffers live at least until `pop_used`
-            // returns them.
-            unsafe {
-                desc.set_buf::<H>(buffer, direction, DescFlags::NEXT);
-            }
-            desc.next = (i + 1) as u16;
-        }
-        indirect_list
-            .last_mut()
-            .unwrap()
-            .flags
-            .remove(DescFlags::NEXT);
-
-        // Need to store pointer to indirect_list too, because direct_desc.set_buf will only store
-        // the physical DMA address which might be different.
-        assert!(self.indirect_lists[usize::from(head)].is_none());
-        self.indirect_lists[usize::from(head)] = Some(indirect_list.as_mut().into());
-
-        // Write a descriptor pointing to indirect descriptor list. We use Box::leak to prevent the
-        // indirect list from being freed when this function returns; recycle_descriptors is instead
-        // responsible for freeing the memory after the buffer chain is popped.
-        let direct_desc = &mut self.desc_shadow[usize::from(head)];
-        self.free_head = direct_desc.next;
-        unsafe {
-            direct_desc.set_buf::<H>(
-                Box::leak(indirect_list).as_bytes().into(),
-                BufferDirection::DriverToDevice,
-                DescFlags::INDIRECT,
-            );
-        }
-        self.write_desc(head);
-        self.num_used += 1;
-
-        head
+        shared: SharedAddrs<'a>,
+    ) -> Result<u16> {
+        // Safe because our caller has the same safety requirements.
+        unsafe { self.submitter().stage_shared(inputs, outputs, shared) }
     }
 
     /// Add the given buffers to the virtqueue, notifies the device, blocks until the device uses
@@ -297,899 +706,2955 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
         outputs: &'a mut [&'a mut [u8]],
         transport: &mut impl Transport,
     ) -> Result<u32> {
-        // Safe because we don't return until the same token has been popped, so the buffers remain
-        // valid and are not otherwise accessed until then.
-        let token = unsafe { self.add(inputs, outputs) }?;
+        self.add_notify_wait_pop_timeout(inputs, outputs, transport, Timeout::Never)
+    }
+
+    /// Like [`VirtQueue::add_notify_wait_pop`], but gives up if the device hasn't used the buffers
+    /// before the given timeout expires.
+    ///
+    /// On timeout the whole device is reset, as that is the only way to be sure that it won't
+    /// access the buffers after this returns, and [`Error::Timeout`] is returned. The queue can't
+    /// be used any more after that, and the driver must be initialised again.
+    pub fn add_notify_wait_pop_timeout<'a>(
+        &mut self,
+        inputs: &'a [&'a [u8]],
+        outputs: &'a mut [&'a mut [u8]],
+        transport: &mut impl Transport,
+        timeout: Timeout,
+    ) -> Result<u32> {
+        // Safe because none of the buffers are already shared.
+        unsafe {
+            self.add_notify_wait_pop_shared(
+                inputs,
+                outputs,
+                SharedAddrs::default(),
+                transport,
+                timeout,
+            )
+        }
+    }
+
+    /// Like [`VirtQueue::add_notify_wait_pop_timeout`], but some of the buffers may already have
+    /// been shared with the device, as for [`VirtQueue::stage_shared`].
+    ///
+    /// # Safety
+    ///
+    /// Each physical address in `shared` must be where the device can access the corresponding
+    /// buffer.
+    pub unsafe fn add_notify_wait_pop_shared<'a>(
+        &mut self,
+        inputs: &'a [&'a [u8]],
+        outputs: &'a mut [&'a mut [u8]],
+        shared: SharedAddrs<'a>,
+        transport: &mut impl Transport,
+        timeout: Timeout,
+    ) -> Result<u32> {
+        let mut deadline = Deadline::new::<H>(timeout)?;
+
+        // Safe because we don't return until the same token has been popped or the device has been
+        // reset, so the buffers remain valid and are not otherwise accessed until then, and our
+        // caller ensures that the shared addresses are valid.
+        let token = unsafe { self.stage_shared(inputs, outputs, shared) }?;
 
         // Notify the queue.
         if self.should_notify() {
-            transport.notify(self.queue_idx);
+            self.notify(transport);
         }
 
         // Wait until there is at least one element in the used ring.
         while !self.can_pop() {
+            if deadline.expired() {
+                let error = self.give_up(transport);
+                // Safe because these are the same buffers as we passed to `stage_shared` above, and
+                // the device has been reset so it won't access them any more.
+                unsafe { self.reclaim(token, inputs, outputs, shared) };
+                return Err(error);
+            }
             spin_loop();
         }
 
-        // Safe because these are the same buffers as we passed to `add` above and they are still
-        // valid.
-        unsafe { self.pop_used(token, inputs, outputs) }
+        // Safe because these are the same buffers as we passed to `stage_shared` above and they are
+        // still valid.
+        unsafe { self.pop_used_shared(token, inputs, outputs, shared) }
     }
 
-    /// Returns whether the driver should notify the device after adding a new buffer to the
-    /// virtqueue.
+    /// Gives up waiting for the device to use buffers, by resetting it so that it won't access any
+    /// buffers which were added to the queue. The queue can't be used any more after this.
     ///
-    /// This will be false if the device has supressed notifications.
-    pub fn should_notify(&self) -> bool {
-        // Read barrier, so we read a fresh value from the device.
-        fence(Ordering::SeqCst);
-
-        if self.event_idx {
-            // Safe because self.used points to a valid, aligned, initialised, dereferenceable, readable
-            // instance of UsedRing.
-            let avail_event = unsafe { (*self.used.as_ptr()).avail_event };
-            self.avail_idx >= avail_event.wrapping_add(1)
-        } else {
-            // Safe because self.used points to a valid, aligned, initialised, dereferenceable, readable
-            // instance of UsedRing.
-            unsafe { (*self.used.as_ptr()).flags & 0x0001 == 0 }
+    /// The caller should then pass each chain which wasn't popped to [`VirtQueue::reclaim`], so
+    /// that its buffers are unshared.
+    ///
+    /// Returns [`Error::Timeout`], for convenience.
+    pub(crate) fn give_up(&mut self, transport: &mut impl Transport) -> Error {
+        self.broken.store(true, Ordering::Relaxed);
+        transport.set_status(DeviceStatus::empty());
+        if spin_until(|| transport.get_status() == DeviceStatus::empty()).is_err() {
+            warn!("Timed out waiting for device to reset");
         }
+        Error::Timeout
     }
 
-    /// Copies the descriptor at the given index from `desc_shadow` to `desc`, so it can be seen by
-    /// the device.
-    fn write_desc(&mut self, index: u16) {
-        let index = usize::from(index);
-        // Safe because self.desc is properly aligned, dereferenceable and initialised, and nothing
-        // else reads or writes the descriptor during this block.
-        unsafe {
-            (*self.desc.as_ptr())[index] = self.desc_shadow[index].clone();
+    /// Unshares the buffers of the chain with the given token, without waiting for the device to
+    /// use it, after the queue or the whole device has been reset.
+    ///
+    /// # Safety
+    ///
+    /// The buffers must be the same as were passed when the chain was added, and the device must
+    /// no longer access them.
+    pub(crate) unsafe fn reclaim<'a, 'b>(
+        &mut self,
+        token: u16,
+        inputs: &'a [&'b [u8]],
+        outputs: &'a mut [&'b mut [u8]],
+        shared: SharedAddrs<'a>,
+    ) {
+        // Safe because our caller ensures that the buffers match the chain, and the device is
+        // finished with it.
+        let result = unsafe {
+            self.completer_mut()
+                .recycle_descriptors(token, inputs, outputs, shared)
+        };
+        if result.is_err() {
+            warn!("Buffers to reclaim didn't match descriptor chain {}", token);
         }
     }
 
-    /// Returns whether there is a used element that can be popped.
-    pub fn can_pop(&self) -> bool {
-        // Read barrier, so we read a fresh value from the device.
-        fence(Ordering::SeqCst);
+    /// Adds the given buffers to the virtqueue and notifies the device, then waits without blocking
+    /// for the device to use them, and pops them.
+    ///
+    /// This is the asynchronous version of [`VirtQueue::add_notify_wait_pop`]. While waiting the
+    /// task is registered with `waker`, which should be woken when the device raises an interrupt
+    /// for the queue.
+    ///
+    /// This assumes that the device isn't processing any other buffers at the same time.
+    ///
+    /// The buffers must not be empty.
+    ///
+    /// # Safety
+    ///
+    /// The returned future must be polled to completion once it has been polled for the first
+    /// time. If it is dropped or leaked before then, the device may still access the buffers after
+    /// they have been freed.
+    pub async unsafe fn add_notify_pop<'a, 'b>(
+        &mut self,
+        inputs: &'a [&'b [u8]],
+        outputs: &'a mut [&'b mut [u8]],
+        transport: &mut impl Transport,
+        waker: &QueueWaker,
+    ) -> Result<u32> {
+        // Safe because the caller ensures that the future isn't dropped until the same token has
+        // been popped, so the buffers remain valid and are not otherwise accessed until then.
+        let token = unsafe { self.add(inputs, outputs) }?;
 
-        // Safe because self.used points to a valid, aligned, initialised, dereferenceable, readable
-        // instance of UsedRing.
-        self.last_used_idx != unsafe { (*self.used.as_ptr()).idx }
+        if self.should_notify() {
+            self.notify(transport);
+        }
+
+        poll_fn(|cx| self.poll_used(waker, cx)).await;
+
+        // Safe because these are the same buffers as we passed to `add` above and they are still
+        // valid.
+        unsafe { self.pop_used(token, inputs, outputs) }
     }
 
-    /// Returns the descriptor index (a.k.a. token) of the next used element without popping it, or
-    /// `None` if the used ring is empty.
-    pub fn peek_used(&self) -> Option<u16> {
-        if self.can_pop() {
-            let last_used_slot = self.last_used_idx & (SIZE as u16 - 1);
-            // Safe because self.used points to a valid, aligned, initialised, dereferenceable,
-            // readable instance of UsedRing.
-            Some(unsafe { (*self.used.as_ptr()).ring[last_used_slot as usize].id as u16 })
-        } else {
-            None
-        }
+    /// Returns the token of the next used element if there is one, or otherwise registers the
+    /// current task with `waker` to be woken when the device uses some buffers.
+    ///
+    /// This is the asynchronous counterpart of [`VirtQueue::peek_used`].
+    pub fn poll_used(&self, waker: &QueueWaker, cx: &mut Context) -> Poll<u16> {
+        self.completer().poll_used(waker, cx)
     }
 
-    /// Returns the number of free descriptors.
-    pub fn available_desc(&self) -> usize {
-        #[cfg(feature = "alloc")]
-        if self.indirect {
-            return if usize::from(self.num_used) == SIZE {
-                0
-            } else {
-                SIZE
-            };
-        }
+    /// Returns whether the driver should notify the device after adding new buffers to the
+    /// virtqueue.
+    ///
+    /// This makes any staged buffers available to the device, and then considers all buffers
+    /// added since the last call, so it is the same as [`VirtQueue::kick_prepare`].
+    ///
+    /// This will be false if the device has supressed notifications.
+    pub fn should_notify(&mut self) -> bool {
+        self.kick_prepare()
+    }
+
+    /// Makes all buffers staged with [`VirtQueue::stage`] available to the device, and returns
+    /// whether the driver should notify the device about the buffers added since the last call.
+    ///
+    /// With `VIRTIO_F_EVENT_IDX` this checks whether the device asked to be notified about any of
+    /// the buffers in the batch, rather than just the last one.
+    ///
+    /// Ref: linux virtio_ring.c virtqueue_kick_prepare
+    pub fn kick_prepare(&mut self) -> bool {
+        self.submitter().kick_prepare()
+    }
 
-        SIZE - usize::from(self.num_used)
+    /// Makes all staged buffers available to the device, and notifies it if necessary.
+    ///
+    /// Ref: linux virtio_ring.c virtqueue_kick
+    pub fn kick(&mut self, transport: &mut impl Transport) {
+        self.submitter().kick(transport)
     }
 
-    /// Unshares buffers in the list starting at descriptor index `head` and adds them to the free
-    /// list. Unsharing may involve copying data back to the original buffers, so they must be
-    /// passed in too.
+    /// Notifies the device that there are new buffers available in this queue.
     ///
-    /// This will push all linked descriptors at the front of the free list.
+    /// If `VIRTIO_F_NOTIFICATION_DATA` has been negotiated then the notification includes the
+    /// position in the ring of the next buffer which will be made available, and for a packed
+    /// queue the driver's wrap counter.
     ///
-    /// # Safety
+    /// This should be called after [`VirtQueue::should_notify`] or [`VirtQueue::kick_prepare`]
+    /// returns true.
     ///
-    /// The buffers in `inputs` and `outputs` must match the set of buffers originally added to the
-    /// queue by `add`.
-    unsafe fn recycle_descriptors<'a>(
-        &mut self,
-        head: u16,
-        inputs: &'a [&'a [u8]],
-        outputs: &'a mut [&'a mut [u8]],
-    ) {
-        let original_free_head = self.free_head;
-        self.free_head = head;
-
-        let head_desc = &mut self.desc_shadow[usize::from(head)];
-        if head_desc.flags.contains(DescFlags::INDIRECT) {
-            #[cfg(feature = "alloc")]
-            {
-                // Find the indirect descriptor list, unshare it and move its descriptor to the free
-                // list.
-                let indirect_list = self.indirect_lists[usize::from(head)].take().unwrap();
-                // SAFETY: We allocated the indirect list in `add_indirect`, and the device has
-                // finished accessing it by this point.
-                let mut indirect_list = unsafe { Box::from_raw(indirect_list.as_ptr()) };
-                let paddr = head_desc.addr;
-                head_desc.unset_buf();
-                self.num_used -= 1;
-                head_desc.next = original_free_head;
+    /// Ref: virtio 2.9 Driver Notifications
+    pub fn notify(&self, transport: &mut impl Transport) {
+        // Safe because the submitting state can only be modified through `&mut self` or the
+        // submitter returned by `split`, neither of which can exist while we have `&self`, and
+        // `notify` only reads it.
+        unsafe { QueueSubmitter::new(self) }.notify(transport)
+    }
 
-                unsafe {
-                    H::unshare(
-                        paddr as usize,
-                        indirect_list.as_bytes_mut().into(),
-                        BufferDirection::DriverToDevice,
-                    );
-                }
+    /// Asks the device not to send used buffer notifications (i.e. interrupts) for this queue.
+    ///
+    /// This is only a hint, and the device may still send notifications. It is useful for drivers
+    /// which poll the queue for a while, such as a network driver handling a burst of packets.
+    pub fn disable_callbacks(&mut self) {
+        self.completer_mut().disable_callbacks()
+    }
 
-                // Unshare the buffers in the indirect descriptor list, and free it.
-                assert_eq!(indirect_list.len(), inputs.len() + outputs.len());
-                for (i, (buffer, direction)) in InputOutputIter::new(inputs, outputs).enumerate() {
-                    assert_ne!(buffer.len(), 0);
-
-                    // SAFETY: The caller ensures that the buffer is valid and matches the
-                    // descriptor from which we got `paddr`.
-                    unsafe {
-                        // Unshare the buffer (and perhaps copy its contents back to the original
-                        // buffer).
-                        H::unshare(indirect_list[i].addr as usize, buffer, direction);
-                    }
-                }
-                drop(indirect_list);
-            }
-        } else {
-            let mut next = Some(head);
+    /// Asks the device to send a used buffer notification the next time it uses a buffer.
+    ///
+    /// Returns false if the device has already used some buffers which haven't been popped, in
+    /// which case the driver should pop them (and perhaps call this again) rather than waiting for
+    /// a notification which may not come.
+    ///
+    /// Ref: linux virtio_ring.c virtqueue_enable_cb
+    pub fn enable_callbacks(&mut self) -> bool {
+        self.completer_mut().enable_callbacks()
+    }
 
-            for (buffer, direction) in InputOutputIter::new(inputs, outputs) {
-                assert_ne!(buffer.len(), 0);
+    /// Like [`VirtQueue::enable_callbacks`], but if `VIRTIO_F_EVENT_IDX` has been negotiated asks
+    /// the device to wait until it has used about three quarters of the buffers currently
+    /// available to it before sending a notification.
+    ///
+    /// Returns false if the device has already used that many buffers.
+    ///
+    /// Ref: linux virtio_ring.c virtqueue_enable_cb_delayed
+    pub fn enable_callbacks_delayed(&mut self) -> bool {
+        self.completer_mut().enable_callbacks_delayed()
+    }
 
-                let desc_index = next.expect("Descriptor chain was shorter than expected.");
-                let desc = &mut self.desc_shadow[usize::from(desc_index)];
+    /// Returns whether there is a used element that can be popped.
+    pub fn can_pop(&self) -> bool {
+        self.completer().can_pop()
+    }
 
-                let paddr = desc.addr;
-                desc.unset_buf();
-                self.num_used -= 1;
-                next = desc.next();
-                if next.is_none() {
-                    desc.next = original_free_head;
-                }
+    /// Returns the descriptor index (a.k.a. token) of the next used element without popping it, or
+    /// `None` if the used ring is empty.
+    pub fn peek_used(&self) -> Option<u16> {
+        self.completer().peek_used()
+    }
 
-                self.write_desc(desc_index);
+    /// Returns the number of free descriptors.
+    ///
+    /// If indirect descriptors are used then a chain of up to [`VirtQueue::max_indirect_len`]
+    /// buffers only needs one of these, so this is also the number of such chains which can be
+    /// added.
+    pub fn available_desc(&self) -> usize {
+        self.num_free.load(Ordering::Relaxed).into()
+    }
 
-                // SAFETY: The caller ensures that the buffer is valid and matches the descriptor
-                // from which we got `paddr`.
-                unsafe {
-                    // Unshare the buffer (and perhaps copy its contents back to the original buffer).
-                    H::unshare(paddr as usize, buffer, direction);
-                }
-            }
+    /// Returns the number of buffers which fit in an indirect descriptor table, or 0 if indirect
+    /// descriptors aren't used.
+    ///
+    /// Chains of more buffers than this, after splitting them into physically contiguous segments,
+    /// use a descriptor in the ring for each segment.
+    pub fn max_indirect_len(&self) -> usize {
+        self.indirect_tables
+            .as_ref()
+            .map_or(0, |tables| tables.table_len)
+    }
 
-            if next.is_some() {
-                panic!("Descriptor chain was longer than expected.");
-            }
-        }
+    /// Returns whether the given device-readable buffers could be added to the queue now, taking
+    /// into account how many segments they are split into and whether they fit in an indirect
+    /// table.
+    pub(crate) fn has_room_for(&self, inputs: &[&[u8]]) -> bool {
+        let segments = SegmentIter::<H>::new(inputs, &mut [], SharedAddrs::default()).count();
+        let ring_descriptors_needed = match &self.indirect_tables {
+            Some(tables) if segments > 1 && tables.fits(segments) => 1,
+            _ => segments,
+        };
+        usize::from(self.num_free.load(Ordering::Relaxed)) >= ring_descriptors_needed
     }
 
     /// If the given token is next on the device used queue, pops it and returns the total buffer
     /// length which was used (written) by the device.
     ///
+    /// If `VIRTIO_F_IN_ORDER` has been negotiated and the device used a batch of buffers at once,
+    /// it only reports the used length of the last buffer in the batch. For the other buffers in
+    /// the batch the total length of `outputs` is returned instead.
+    ///
+    /// The values written by the device are checked, so that a buggy or malicious device can't
+    /// cause memory corruption. If they are invalid then [`Error::DeviceMisbehaved`] is returned,
+    /// and the queue can't be used any more.
+    ///
     /// Ref: linux virtio_ring.c virtqueue_get_buf_ctx
     ///
     /// # Safety
     ///
     /// The buffers in `inputs` and `outputs` must match the set of buffers originally added to the
     /// queue by `add` when it returned the token being passed in here.
-    pub unsafe fn pop_used<'a>(
+    pub unsafe fn pop_used<'a, 'b>(
         &mut self,
         token: u16,
-        inputs: &'a [&'a [u8]],
-        outputs: &'a mut [&'a mut [u8]],
+        inputs: &'a [&'b [u8]],
+        outputs: &'a mut [&'b mut [u8]],
     ) -> Result<u32> {
-        if !self.can_pop() {
-            return Err(Error::NotReady);
-        }
-        // Read barrier not necessary, as can_pop already has one.
-
-        // Get the index of the start of the descriptor chain for the next element in the used ring.
-        let last_used_slot = self.last_used_idx & (SIZE as u16 - 1);
-        let index;
-        let len;
-        // Safe because self.used points to a valid, aligned, initialised, dereferenceable, readable
-        // instance of UsedRing.
-        unsafe {
-            index = (*self.used.as_ptr()).ring[last_used_slot as usize].id as u16;
-            len = (*self.used.as_ptr()).ring[last_used_slot as usize].len;
-        }
-
-        if index != token {
-            // The device used a different descriptor chain to the one we were expecting.
-            return Err(Error::WrongToken);
-        }
+        // Safe because our caller has the same safety requirements.
+        unsafe { self.completer_mut().pop_used(token, inputs, outputs) }
+    }
 
-        // Safe because the caller ensures the buffers are valid and match the descriptor.
+    /// Like [`VirtQueue::pop_used`], for buffers which were added by
+    /// [`VirtQueue::stage_shared`].
+    ///
+    /// # Safety
+    ///
+    /// The buffers in `inputs` and `outputs` and the addresses in `shared` must match those
+    /// originally added to the queue when it returned the token being passed in here.
+    pub unsafe fn pop_used_shared<'a, 'b>(
+        &mut self,
+        token: u16,
+        inputs: &'a [&'b [u8]],
+        outputs: &'a mut [&'b mut [u8]],
+        shared: SharedAddrs<'a>,
+    ) -> Result<u32> {
+        // Safe because our caller has the same safety requirements.
         unsafe {
-            self.recycle_descriptors(index, inputs, outputs);
+            self.completer_mut()
+                .pop_used_shared(token, inputs, outputs, shared)
         }
-        self.last_used_idx = self.last_used_idx.wrapping_add(1);
-
-        Ok(len)
     }
 }
 
-/// The inner layout of a VirtQueue.
-///
-/// Ref: 2.6 Split Virtqueues
+/// The rings of a virtqueue which are shared with the device.
 #[derive(Debug)]
-enum VirtQueueLayout<H: Hal> {
-    Legacy {
-        dma: Dma<H>,
-        avail_offset: usize,
-        used_offset: usize,
-    },
-    Modern {
-        /// The region used for the descriptor area and driver area.
-        driver_to_device_dma: Dma<H>,
-        /// The region used for the device area.
-        device_to_driver_dma: Dma<H>,
-        /// The offset from the start of the `driver_to_device_dma` region to the driver area
-        /// (available ring).
-        avail_offset: usize,
-    },
+enum Ring {
+    /// Ref: 2.7 Split Virtqueues
+    Split(SplitRing),
+    /// Ref: 2.8 Packed Virtqueues
+    Packed(PackedRing),
 }
 
-impl<H: Hal> VirtQueueLayout<H> {
-    /// Allocates a single DMA region containing all parts of the virtqueue, following the layout
-    /// required by legacy interfaces.
+/// The driver's position in the rings of a virtqueue for making buffers available to the device.
+#[derive(Debug)]
+enum RingSubmit {
+    Split(SplitSubmit),
+    Packed(PackedSubmit),
+}
+
+impl RingSubmit {
+    /// Stages the descriptor chain starting at `head` in `desc_shadow`, to be made available to the
+    /// device by `publish`.
     ///
-    /// Ref: 2.6.2 Legacy Interfaces: A Note on Virtqueue Layout
-    fn allocate_legacy(queue_size: u16) -> Result<Self> {
-        let (desc, avail, used) = queue_part_sizes(queue_size);
-        let size = align_up(desc + avail) + align_up(used);
-        // Allocate contiguous pages.
-        let dma = Dma::new(size / PAGE_SIZE, BufferDirection::Both)?;
-        Ok(Self::Legacy {
-            dma,
-            avail_offset: desc,
-            used_offset: align_up(desc + avail),
-        })
+    /// # Safety
+    ///
+    /// The caller must own the descriptors of the chain in `desc_shadow`.
+    unsafe fn add<H: Hal>(&mut self, desc_shadow: &DescShadow<H>, head: u16) {
+        // Safe because our caller owns the chain.
+        unsafe {
+            match self {
+                Self::Split(split) => split.add(desc_shadow, head),
+                Self::Packed(packed) => packed.add(desc_shadow, head),
+            }
+        }
     }
 
-    /// Allocates separate DMA regions for the the different parts of the virtqueue, as supported by
-    /// non-legacy interfaces.
-    ///
-    /// This is preferred over `allocate_legacy` where possible as it reduces memory fragmentation
-    /// and allows the HAL to know which DMA regions are used in which direction.
-    fn allocate_flexible(queue_size: u16) -> Result<Self> {
-        let (desc, avail, used) = queue_part_sizes(queue_size);
-        let driver_to_device_dma = Dma::new(pages(desc + avail), BufferDirection::DriverToDevice)?;
-        let device_to_driver_dma = Dma::new(pages(used), BufferDirection::DeviceToDriver)?;
-        Ok(Self::Modern {
-            driver_to_device_dma,
-            device_to_driver_dma,
-            avail_offset: desc,
-        })
+    /// Makes all staged descriptor chains available to the device.
+    fn publish(&mut self) {
+        match self {
+            Self::Split(split) => split.publish(),
+            Self::Packed(packed) => packed.publish(),
+        }
     }
 
-    /// Returns the physical address of the descriptor area.
-    fn descriptors_paddr(&self) -> PhysAddr {
+    /// Returns the position at which the next descriptor chain will be made available, to be sent
+    /// to the device as notification data.
+    ///
+    /// For a split ring this is the available ring index. For a packed ring it is the offset in the
+    /// descriptor ring, with the driver's wrap counter in bit 15.
+    ///
+    /// Ref: linux virtio_ring.c vring_notification_data
+    fn next_avail(&self) -> u16 {
         match self {
-            Self::Legacy { dma, .. } => dma.paddr(),
-            Self::Modern {
-                driver_to_device_dma,
-                ..
-            } => driver_to_device_dma.paddr(),
+            Self::Split(split) => split.avail_idx,
+            Self::Packed(packed) => packed.next_avail_off_wrap(),
         }
     }
 
-    /// Returns a pointer to the descriptor table (in the descriptor area).
-    fn descriptors_vaddr(&self) -> NonNull<u8> {
+    /// Returns whether the driver should notify the device about the descriptor chains added since
+    /// the last call.
+    fn kick_prepare(&mut self, event_idx: bool) -> bool {
         match self {
-            Self::Legacy { dma, .. } => dma.vaddr(0),
-            Self::Modern {
-                driver_to_device_dma,
-                ..
-            } => driver_to_device_dma.vaddr(0),
+            Self::Split(split) => split.kick_prepare(event_idx),
+            Self::Packed(packed) => packed.kick_prepare(),
         }
     }
+}
 
-    /// Returns the physical address of the driver area.
-    fn driver_area_paddr(&self) -> PhysAddr {
+/// The driver's position in the rings of a virtqueue for popping buffers used by the device.
+#[derive(Debug)]
+enum RingComplete {
+    Split(SplitComplete),
+    Packed(PackedComplete),
+}
+
+impl RingComplete {
+    /// Checks that the device hasn't claimed to have used more buffers than the `in_flight` which
+    /// were made available to it.
+    fn check_used(&self, in_flight: u16) -> Result {
         match self {
-            Self::Legacy {
-                dma, avail_offset, ..
-            } => dma.paddr() + avail_offset,
-            Self::Modern {
-                driver_to_device_dma,
-                avail_offset,
-                ..
-            } => driver_to_device_dma.paddr() + avail_offset,
+            Self::Split(split) => split.check_used(in_flight),
+            // The packed ring has no used index, and is only read one slot at a time.
+            Self::Packed(_) => Ok(()),
         }
     }
 
-    /// Returns a pointer to the available ring (in the driver area).
-    fn avail_vaddr(&self) -> NonNull<u8> {
+    /// Returns the ID and used length of the next used buffer, if there is one.
+    ///
+    /// The ID is read from memory written by the device, so may not be valid.
+    fn peek_used(&self) -> Option<(u16, u32)> {
         match self {
-            Self::Legacy {
-                dma, avail_offset, ..
-            } => dma.vaddr(*avail_offset),
-            Self::Modern {
-                driver_to_device_dma,
-                avail_offset,
-                ..
-            } => driver_to_device_dma.vaddr(*avail_offset),
+            Self::Split(split) => split.peek_used(),
+            Self::Packed(packed) => packed.peek_used(),
         }
     }
 
-    /// Returns the physical address of the device area.
-    fn device_area_paddr(&self) -> PhysAddr {
+    /// Moves past the next used buffer, which consisted of a chain of `chain_len` descriptors.
+    fn pop_used(&mut self, chain_len: u16, event_idx: bool) {
         match self {
-            Self::Legacy {
-                used_offset, dma, ..
-            } => dma.paddr() + used_offset,
-            Self::Modern {
-                device_to_driver_dma,
-                ..
-            } => device_to_driver_dma.paddr(),
+            Self::Split(split) => split.pop_used(event_idx),
+            Self::Packed(packed) => packed.pop_used(chain_len),
         }
     }
 
-    /// Returns a pointer to the used ring (in the driver area).
-    fn used_vaddr(&self) -> NonNull<u8> {
+    /// Asks the device not to send used buffer notifications.
+    fn disable_callbacks(&mut self, event_idx: bool) {
         match self {
-            Self::Legacy {
-                dma, used_offset, ..
-            } => dma.vaddr(*used_offset),
-            Self::Modern {
-                device_to_driver_dma,
-                ..
-            } => device_to_driver_dma.vaddr(0),
+            Self::Split(split) => split.disable_callbacks(event_idx),
+            Self::Packed(packed) => packed.disable_callbacks(),
         }
     }
-}
 
-/// Returns the size in bytes of the descriptor table, available ring and used ring for a given
-/// queue size.
-///
-/// Ref: 2.6 Split Virtqueues
-fn queue_part_sizes(queue_size: u16) -> (usize, usize, usize) {
-    assert!(
-        queue_size.is_power_of_two(),
-        "queue size should be a power of 2"
-    );
-    let queue_size = queue_size as usize;
-    let desc = size_of::<Descriptor>() * queue_size;
-    let avail = size_of::<u16>() * (3 + queue_size);
-    let used = size_of::<u16>() * 3 + size_of::<UsedElem>() * queue_size;
-    (desc, avail, used)
+    /// Asks the device to send a used buffer notification once it has used the buffer `delay`
+    /// positions after the next one to be popped.
+    ///
+    /// Returns false if the device has already used that buffer, so the notification may not
+    /// come.
+    fn enable_callbacks(&mut self, event_idx: bool, delay: u16) -> bool {
+        match self {
+            Self::Split(split) => split.enable_callbacks(event_idx, delay),
+            Self::Packed(packed) => packed.enable_callbacks(event_idx, delay),
+        }
+    }
 }
 
-#[repr(C, align(16))]
-#[derive(AsBytes, Clone, Debug, FromBytes, FromZeroes)]
-pub(crate) struct Descriptor {
-    addr: u64,
-    len: u32,
-    flags: DescFlags,
-    next: u16,
+/// The descriptor table, available ring and used ring of a split virtqueue.
+///
+/// Ref: 2.7 Split Virtqueues
+#[derive(Clone, Debug)]
+struct SplitRing {
+    /// Descriptor table
+    ///
+    /// The device may be able to modify this, even though it's not supposed to, so we shouldn't
+    /// trust values read back from it. Use `desc_shadow` instead to keep track of what we wrote to
+    /// it.
+    desc: NonNull<[Descriptor]>,
+    /// Available ring
+    ///
+    /// The device may be able to modify this, even though it's not supposed to, so we shouldn't
+    /// trust values read back from it. The only field we need to read currently is `idx`, so we
+    /// have `avail_idx` in `SplitSubmit` to use instead.
+    avail: NonNull<AvailRing>,
+    /// Used ring
+    used: NonNull<UsedRing>,
+    /// The number of descriptors in the table, and of slots in each ring.
+    size: u16,
+    /// The memory barriers to use when accessing the rings.
+    barriers: Barriers,
 }
 
-impl Descriptor {
-    /// Sets the buffer address, length and flags, and shares it with the device.
+impl SplitRing {
+    fn new<H: Hal>(
+        layout: &VirtQueueLayout<H>,
+        size: u16,
+        desc_shadow: &DescShadow<H>,
+        barriers: Barriers,
+    ) -> Self {
+        let desc = nonnull_slice_from_raw_parts(
+            layout.descriptors_vaddr().cast::<Descriptor>(),
+            size.into(),
+        );
+        let avail = nonnull_slice_from_raw_parts(layout.avail_vaddr().cast::<u16>(), size.into());
+        let used =
+            nonnull_slice_from_raw_parts(layout.used_vaddr().cast::<UsedElem>(), size.into());
+        let ring = Self {
+            desc,
+            avail: NonNull::new(avail.as_ptr() as *mut AvailRing).unwrap(),
+            used: NonNull::new(used.as_ptr() as *mut UsedRing).unwrap(),
+            size,
+            barriers,
+        };
+        for i in 0..size {
+            // Safe because the queue is still being created, so we own all of the descriptors.
+            unsafe {
+                ring.write_desc(i, desc_shadow);
+            }
+        }
+        ring
+    }
+
+    /// Copies the descriptor at the given index from `desc_shadow` to `desc`, so it can be seen by
+    /// the device.
     ///
     /// # Safety
     ///
-    /// The caller must ensure that the buffer lives at least as long as the descriptor is active.
-    unsafe fn set_buf<H: Hal>(
-        &mut self,
-        buf: NonNull<[u8]>,
-        direction: BufferDirection,
-        extra_flags: DescFlags,
-    ) {
-        // Safe because our caller promises that the buffer is valid.
+    /// The caller must own the descriptor in `desc_shadow`.
+    unsafe fn write_desc<H: Hal>(&self, index: u16, desc_shadow: &DescShadow<H>) {
+        // Safe because self.desc is properly aligned, dereferenceable and initialised, and nothing
+        // else reads or writes the descriptor during this block, as our caller owns it.
         unsafe {
-            self.addr = H::share(buf, direction) as u64;
+            (*self.desc.as_ptr())[usize::from(index)] = desc_shadow.get(index).clone();
         }
-        self.len = buf.len() as u32;
-        self.flags = extra_flags
-            | match direction {
-                BufferDirection::DeviceToDriver => DescFlags::WRITE,
-                BufferDirection::DriverToDevice => DescFlags::empty(),
-                BufferDirection::Both => {
-                    panic!("Buffer passed to device should never use BufferDirection::Both.")
-                }
-            };
     }
 
-    /// Sets the buffer address and length to 0.
-    ///
-    /// This must only be called once the device has finished using the descriptor.
-    fn unset_buf(&mut self) {
-        self.addr = 0;
-        self.len = 0;
+    /// Returns a pointer to the `used_event` field which follows the available ring.
+    fn used_event(&self) -> *mut u16 {
+        // Safe because self.avail points to an available ring with `self.size` elements, which is
+        // followed by the `used_event` field.
+        unsafe {
+            addr_of_mut!((*self.avail.as_ptr()).ring)
+                .cast::<u16>()
+                .add(self.size.into())
+        }
     }
 
-    /// Returns the index of the next descriptor in the chain if the `NEXT` flag is set, or `None`
-    /// if it is not (and thus this descriptor is the end of the chain).
-    fn next(&self) -> Option<u16> {
-        if self.flags.contains(DescFlags::NEXT) {
-            Some(self.next)
-        } else {
-            None
+    /// Returns a pointer to the `avail_event` field which follows the used ring.
+    fn avail_event(&self) -> *mut u16 {
+        // Safe because self.used points to a used ring with `self.size` elements, which is followed
+        // by the `avail_event` field.
+        unsafe {
+            addr_of_mut!((*self.used.as_ptr()).ring)
+                .cast::<UsedElem>()
+                .add(self.size.into())
+                .cast()
         }
     }
 }
 
-/// Descriptor flags
-#[derive(AsBytes, Copy, Clone, Debug, Default, Eq, FromBytes, FromZeroes, PartialEq)]
-#[repr(transparent)]
-struct DescFlags(u16);
+/// The driver's position in a split virtqueue for making buffers available to the device.
+#[derive(Debug)]
+struct SplitSubmit {
+    ring: SplitRing,
+    /// Our trusted copy of `avail.idx`, including any staged chains which haven't been published
+    /// yet.
+    avail_idx: u16,
+    /// The number of chains added since the last call to `kick_prepare`.
+    num_added: u16,
+}
 
-bitflags! {
-    impl DescFlags: u16 {
-        const NEXT = 1;
-        const WRITE = 2;
-        const INDIRECT = 4;
+impl SplitSubmit {
+    fn new(ring: SplitRing) -> Self {
+        Self {
+            ring,
+            avail_idx: 0,
+            num_added: 0,
+        }
     }
-}
 
-/// The driver uses the available ring to offer buffers to the device:
-/// each ring entry refers to the head of a descriptor chain.
-/// It is only written by the driver and read by the device.
-#[repr(C)]
-#[derive(Debug)]
-struct AvailRing<const SIZE: usize> {
-    flags: u16,
-    /// A driver MUST NOT decrement the idx.
-    idx: u16,
-    ring: [u16; SIZE],
-    used_event: u16, // unused
-}
+    /// # Safety
+    ///
+    /// The caller must own the descriptors of the chain in `desc_shadow`.
+    unsafe fn add<H: Hal>(&mut self, desc_shadow: &DescShadow<H>, head: u16) {
+        let mut next = Some(head);
+        while let Some(index) = next {
+            // Safe because our caller owns the chain.
+            unsafe {
+                self.ring.write_desc(index, desc_shadow);
+                next = desc_shadow.get(index).next();
+            }
+        }
 
-/// The used ring is where the device returns buffers once it is done with them:
-/// it is only written to by the device, and read by the driver.
-#[repr(C)]
-#[derive(Debug)]
-struct UsedRing<const SIZE: usize> {
-    flags: u16,
-    idx: u16,
-    ring: [UsedElem; SIZE],
-    /// Only used if `VIRTIO_F_EVENT_IDX` is negotiated.
-    avail_event: u16,
-}
+        let avail_slot = self.avail_idx & (self.ring.size - 1);
+        // Safe because self.avail is properly aligned, dereferenceable and initialised.
+        unsafe {
+            (*self.ring.avail.as_ptr()).ring[avail_slot as usize] = head;
+        }
 
-#[repr(C)]
-#[derive(Debug)]
-struct UsedElem {
-    id: u32,
-    len: u32,
-}
+        // increase head of avail ring, but don't let the device see it until `publish`.
+        self.avail_idx = self.avail_idx.wrapping_add(1);
+        self.num_added = self.num_added.wrapping_add(1);
+    }
 
-struct InputOutputIter<'a, 'b> {
-    inputs: &'a [&'b [u8]],
-    outputs: &'a mut [&'b mut [u8]],
-}
+    fn publish(&mut self) {
+        // Write barrier so that device sees changes to descriptor table and available ring before
+        // change to available index.
+        self.ring.barriers.issue(Barrier::Write);
 
-impl<'a, 'b> InputOutputIter<'a, 'b> {
-    fn new(inputs: &'a [&'b [u8]], outputs: &'a mut [&'b mut [u8]]) -> Self {
-        Self { inputs, outputs }
+        // Safe because self.avail is properly aligned, dereferenceable and initialised.
+        unsafe {
+            (*self.ring.avail.as_ptr()).idx = self.avail_idx;
+        }
     }
-}
 
-impl<'a, 'b> Iterator for InputOutputIter<'a, 'b> {
-    type Item = (NonNull<[u8]>, BufferDirection);
+    /// Ref: linux virtio_ring.c virtqueue_kick_prepare_split
+    fn kick_prepare(&mut self, event_idx: bool) -> bool {
+        // Full barrier so that the device sees the new available index before we check whether it
+        // wants to be notified.
+        self.ring.barriers.issue(Barrier::Full);
 
-    fn next(&mut self) -> Option<Self::Item> {
-        if let Some(input) = take_first(&mut self.inputs) {
-            Some(((*input).into(), BufferDirection::DriverToDevice))
+        let new = self.avail_idx;
+        let old = new.wrapping_sub(self.num_added);
+        self.num_added = 0;
+
+        if event_idx {
+            // Safe because self.used points to a valid, aligned, initialised, dereferenceable, readable
+            // instance of UsedRing.
+            let avail_event = unsafe { *self.ring.avail_event() };
+            // If the event index is in the range of chains added since the last kick, then the
+            // device wants to be notified.
+            new.wrapping_sub(avail_event).wrapping_sub(1) < new.wrapping_sub(old)
         } else {
-            let output = take_first_mut(&mut self.outputs)?;
-            Some(((*output).into(), BufferDirection::DeviceToDriver))
+            // Safe because self.used points to a valid, aligned, initialised, dereferenceable, readable
+            // instance of UsedRing.
+            unsafe { (*self.ring.used.as_ptr()).flags & 0x0001 == 0 }
         }
     }
 }
 
-// TODO: Use `slice::take_first` once it is stable
-// (https://github.com/rust-lang/rust/issues/62280).
-fn take_first<'a, T>(slice: &mut &'a [T]) -> Option<&'a T> {
-    let (first, rem) = slice.split_first()?;
-    *slice = rem;
-    Some(first)
-}
-
-// TODO: Use `slice::take_first_mut` once it is stable
-// (https://github.com/rust-lang/rust/issues/62280).
-fn take_first_mut<'a, T>(slice: &mut &'a mut [T]) -> Option<&'a mut T> {
-    let (first, rem) = take(slice).split_first_mut()?;
-    *slice = rem;
-    Some(first)
+/// The driver's position in a split virtqueue for popping buffers used by the device.
+#[derive(Debug)]
+struct SplitComplete {
+    ring: SplitRing,
+    last_used_idx: u16,
+    /// Whether the driver wants used buffer notifications.
+    callbacks_enabled: bool,
 }
 
-/// Simulates the device reading from a VirtIO queue and writing a response back, for use in tests.
-///
-/// The fake device always uses descriptors in order.
-#[cfg(test)]
-pub(crate) fn fake_read_write_queue<const QUEUE_SIZE: usize>(
-    descriptors: *const [Descriptor; QUEUE_SIZE],
-    queue_driver_area: *const u8,
-    queue_device_area: *mut u8,
-    handler: impl FnOnce(Vec<u8>) -> Vec<u8>,
-) {
-    use core::{ops::Deref, slice};
+impl SplitComplete {
+    fn new(ring: SplitRing) -> Self {
+        Self {
+            ring,
+            last_used_idx: 0,
+            callbacks_enabled: true,
+        }
+    }
 
-    let available_ring = queue_driver_area as *const AvailRing<QUEUE_SIZE>;
-    let used_ring = queue_device_area as *mut UsedRing<QUEUE_SIZE>;
+    fn peek_used(&self) -> Option<(u16, u32)> {
+        // Safe because self.used points to a valid, aligned, initialised, dereferenceable, readable
+        // instance of UsedRing. The read is volatile so that polling sees new values written by the
+        // device.
+        if self.last_used_idx == unsafe { addr_of!((*self.ring.used.as_ptr()).idx).read_volatile() }
+        {
+            return None;
+        }
+        // Read barrier so that the used element is read after the index.
+        self.ring.barriers.issue(Barrier::Read);
+
+        let last_used_slot = self.last_used_idx & (self.ring.size - 1);
+        // Safe because self.used points to a valid, aligned, initialised, dereferenceable,
+        // readable instance of UsedRing.
+        let (id, len) = unsafe {
+            let elem = &(*self.ring.used.as_ptr()).ring[last_used_slot as usize];
+            (elem.id, elem.len)
+        };
+        // Don't let an out of range ID be truncated to a valid one. The queue size is at most
+        // 2^15, so `u16::MAX` is never a valid ID.
+        Some((u16::try_from(id).unwrap_or(u16::MAX), len))
+    }
 
-    // Safe because the various pointers are properly aligned, dereferenceable, initialised, and
-    // nothing else accesses them during this block.
-    unsafe {
-        // Make sure there is actually at least one descriptor available to read from.
-        assert_ne!((*available_ring).idx, (*used_ring).idx);
-        // The fake device always uses descriptors in order, like VIRTIO_F_IN_ORDER, so
-        // `used_ring.idx` marks the next descriptor we should take from the available ring.
-        let next_slot = (*used_ring).idx & (QUEUE_SIZE as u16 - 1);
-        let head_descriptor_index = (*available_ring).ring[next_slot as usize];
-        let mut descriptor = &(*descriptors)[head_descriptor_index as usize];
+    /// Checks that the used index hasn't moved past the `in_flight` buffers which are available to
+    /// the device.
+    fn check_used(&self, in_flight: u16) -> Result {
+        // Safe because self.used points to a valid, aligned, initialised, dereferenceable, readable
+        // instance of UsedRing.
+        let used_idx = unsafe { (*self.ring.used.as_ptr()).idx };
+        if used_idx.wrapping_sub(self.last_used_idx) > in_flight {
+            Err(Error::DeviceMisbehaved)
+        } else {
+            Ok(())
+        }
+    }
 
-        let input_length;
-        let output;
-        if descriptor.flags.contains(DescFlags::INDIRECT) {
-            // The descriptor shouldn't have any other flags if it is indirect.
-            assert_eq!(descriptor.flags, DescFlags::INDIRECT);
+    fn pop_used(&mut self, event_idx: bool) {
+        self.last_used_idx = self.last_used_idx.wrapping_add(1);
 
-            // Loop through all input descriptors in the indirect descriptor list, reading data from
-            // them.
-            let indirect_descriptor_list: &[Descriptor] = zerocopy::Ref::new_slice(
-                slice::from_raw_parts(descriptor.addr as *const u8, descriptor.len as usize),
-            )
-            .unwrap()
-            .into_slice();
-            let mut input = Vec::new();
-            let mut indirect_descriptor_index = 0;
-            while indirect_descriptor_index < indirect_descriptor_list.len() {
-                let indirect_descriptor = &indirect_descriptor_list[indirect_descriptor_index];
-                if indirect_descriptor.flags.contains(DescFlags::WRITE) {
-                    break;
-                }
+        if event_idx && self.callbacks_enabled {
+            // Ask for a notification for the next used buffer too.
+            // Safe because self.avail is properly aligned, dereferenceable and initialised.
+            unsafe {
+                *self.ring.used_event() = self.last_used_idx;
+            }
+        }
+    }
 
-                input.extend_from_slice(slice::from_raw_parts(
-                    indirect_descriptor.addr as *const u8,
-                    indirect_descriptor.len as usize,
-                ));
+    /// Ref: linux virtio_ring.c virtqueue_disable_cb_split
+    fn disable_callbacks(&mut self, event_idx: bool) {
+        self.callbacks_enabled = false;
+        // Safe because self.avail is properly aligned, dereferenceable and initialised.
+        unsafe {
+            if event_idx {
+                // The device only sends a notification when the used index moves past
+                // `used_event`, which it can't do before it wraps all the way around.
+                *self.ring.used_event() = self.last_used_idx.wrapping_sub(1);
+            } else {
+                (*self.ring.avail.as_ptr()).flags = VIRTQ_AVAIL_F_NO_INTERRUPT;
+            }
+        }
+    }
 
-                indirect_descriptor_index += 1;
+    /// Ref: linux virtio_ring.c virtqueue_enable_cb_prepare_split
+    fn enable_callbacks(&mut self, event_idx: bool, delay: u16) -> bool {
+        self.callbacks_enabled = true;
+        // Safe because self.avail is properly aligned, dereferenceable and initialised.
+        unsafe {
+            if event_idx {
+                *self.ring.used_event() = self.last_used_idx.wrapping_add(delay);
+            } else {
+                (*self.ring.avail.as_ptr()).flags = 0;
             }
-            input_length = input.len();
+        }
 
-            // Let the test handle the request.
-            output = handler(input);
+        // Barrier so that the device sees the new event index before we check the used index.
+        self.ring.barriers.issue(Barrier::Full);
 
-            // Write the response to the remaining descriptors.
-            let mut remaining_output = output.deref();
-            while indirect_descriptor_index < indirect_descriptor_list.len() {
-                let indirect_descriptor = &indirect_descriptor_list[indirect_descriptor_index];
-                assert!(indirect_descriptor.flags.contains(DescFlags::WRITE));
+        // Safe because self.used points to a valid, aligned, initialised, dereferenceable, readable
+        // instance of UsedRing.
+        let used_idx = unsafe { (*self.ring.used.as_ptr()).idx };
+        used_idx.wrapping_sub(self.last_used_idx) <= delay
+    }
+}
 
-                let length_to_write = min(remaining_output.len(), indirect_descriptor.len as usize);
-                ptr::copy(
-                    remaining_output.as_ptr(),
-                    indirect_descriptor.addr as *mut u8,
-                    length_to_write,
-                );
-                remaining_output = &remaining_output[length_to_write..];
+/// Returns the total length of the given buffers, which the device may write to.
+fn writable_len(outputs: &[&mut [u8]]) -> u32 {
+    outputs
+        .iter()
+        .map(|output| output.len() as u32)
+        .fold(0, u32::saturating_add)
+}
 
-                indirect_descriptor_index += 1;
-            }
-            assert_eq!(remaining_output.len(), 0);
-        } else {
-            // Loop through all input descriptors in the chain, reading data from them.
-            let mut input = Vec::new();
-            while !descriptor.flags.contains(DescFlags::WRITE) {
-                input.extend_from_slice(slice::from_raw_parts(
-                    descriptor.addr as *const u8,
-                    descriptor.len as usize,
-                ));
+/// A pool of indirect descriptor tables, one for each descriptor in the ring.
+///
+/// A chain whose head is descriptor `i` uses table `i`, so a table is always available when a
+/// descriptor is, and it is freed along with the descriptor.
+#[derive(Debug)]
+struct IndirectPool<H: Hal> {
+    dma: Dma<H>,
+    /// The number of descriptors in each table.
+    table_len: usize,
+}
 
-                if let Some(next) = descriptor.next() {
-                    descriptor = &(*descriptors)[next as usize];
-                } else {
-                    break;
-                }
-            }
-            input_length = input.len();
+impl<H: Hal> IndirectPool<H> {
+    /// Allocates a pool of tables of `table_len` descriptors each for a queue of the given size.
+    fn new(queue_size: u16, table_len: u16) -> Result<Self> {
+        let table_len = usize::from(table_len);
+        let dma = Dma::new(
+            pages(usize::from(queue_size) * table_len * size_of::<Descriptor>()),
+            BufferDirection::DriverToDevice,
+        )?;
+        Ok(Self { dma, table_len })
+    }
 
-            // Let the test handle the request.
-            output = handler(input);
+    /// Returns whether a chain of the given number of descriptors fits in a table.
+    fn fits(&self, len: usize) -> bool {
+        len <= self.table_len
+    }
 
-            // Write the response to the remaining descriptors.
-            let mut remaining_output = output.deref();
-            if descriptor.flags.contains(DescFlags::WRITE) {
-                loop {
-                    assert!(descriptor.flags.contains(DescFlags::WRITE));
+    /// Returns the physical address of and a pointer to the first `len` entries of the table for
+    /// the chain whose head is the given descriptor.
+    fn table(&self, head: u16, len: usize) -> (PhysAddr, NonNull<[Descriptor]>) {
+        assert!(len <= self.table_len);
+        let offset = usize::from(head) * self.table_len * size_of::<Descriptor>();
+        let table = nonnull_slice_from_raw_parts(self.dma.vaddr(offset).cast::<Descriptor>(), len);
+        (self.dma.paddr() + offset, table)
+    }
+}
 
-                    let length_to_write = min(remaining_output.len(), descriptor.len as usize);
-                    ptr::copy(
-                        remaining_output.as_ptr(),
-                        descriptor.addr as *mut u8,
-                        length_to_write,
-                    );
-                    remaining_output = &remaining_output[length_to_write..];
+/// The inner layout of a VirtQueue.
+///
+/// Ref: 2.6 Split Virtqueues, 2.8 Packed Virtqueues
+#[derive(Debug)]
+enum VirtQueueLayout<H: Hal> {
+    Legacy {
+        dma: Dma<H>,
+        avail_offset: usize,
+        used_offset: usize,
+    },
+    Modern {
+        /// The region used for the descriptor area and driver area.
+        driver_to_device_dma: Dma<H>,
+        /// The region used for the device area.
+        device_to_driver_dma: Dma<H>,
+        /// The offset from the start of the `driver_to_device_dma` region to the driver area
+        /// (available ring).
+        avail_offset: usize,
+    },
+    Packed {
+        /// The region used for the descriptor ring and both event suppression structures. The
+        /// device writes used descriptors back to the ring, so this is shared in both directions.
+        dma: Dma<H>,
+        /// The offset from the start of the region to the driver event suppression structure.
+        driver_event_offset: usize,
+        /// The offset from the start of the region to the device event suppression structure.
+        device_event_offset: usize,
+    },
+}
 
-                    if let Some(next) = descriptor.next() {
-                        descriptor = &(*descriptors)[next as usize];
-                    } else {
-                        break;
-                    }
+impl<H: Hal> VirtQueueLayout<H> {
+    /// Allocates a single DMA region containing all parts of the virtqueue, following the layout
+    /// required by legacy interfaces.
+    ///
+    /// Ref: 2.6.2 Legacy Interfaces: A Note on Virtqueue Layout
+    fn allocate_legacy(queue_size: u16) -> Result<Self> {
+        let (desc, avail, used) = queue_part_sizes(queue_size);
+        let size = align_up(desc + avail) + align_up(used);
+        // Allocate contiguous pages.
+        let dma = Dma::new(size / PAGE_SIZE, BufferDirection::Both)?;
+        Ok(Self::Legacy {
+            dma,
+            avail_offset: desc,
+            used_offset: align_up(desc + avail),
+        })
+    }
+
+    /// Allocates separate DMA regions for the the different parts of the virtqueue, as supported by
+    /// non-legacy interfaces.
+    ///
+    /// This is preferred over `allocate_legacy` where possible as it reduces memory fragmentation
+    /// and allows the HAL to know which DMA regions are used in which direction.
+    fn allocate_flexible(queue_size: u16) -> Result<Self> {
+        let (desc, avail, used) = queue_part_sizes(queue_size);
+        let driver_to_device_dma = Dma::new(pages(desc + avail), BufferDirection::DriverToDevice)?;
+        let device_to_driver_dma = Dma::new(pages(used), BufferDirection::DeviceToDriver)?;
+        Ok(Self::Modern {
+            driver_to_device_dma,
+            device_to_driver_dma,
+            avail_offset: desc,
+        })
+    }
+
+    /// Allocates a single DMA region containing the descriptor ring and event suppression
+    /// structures of a packed virtqueue.
+    ///
+    /// Ref: 2.8.10 Packed Virtqueue Layout
+    fn allocate_packed(queue_size: u16) -> Result<Self> {
+        let desc = size_of::<PackedDescriptor>() * usize::from(queue_size);
+        let event = size_of::<packed::EventSuppression>();
+        let dma = Dma::new(pages(desc + 2 * event), BufferDirection::Both)?;
+        Ok(Self::Packed {
+            dma,
+            driver_event_offset: desc,
+            device_event_offset: desc + event,
+        })
+    }
+
+    /// Returns the physical address of the descriptor area.
+    fn descriptors_paddr(&self) -> PhysAddr {
+        match self {
+            Self::Legacy { dma, .. } => dma.paddr(),
+            Self::Modern {
+                driver_to_device_dma,
+                ..
+            } => driver_to_device_dma.paddr(),
+            Self::Packed { dma, .. } => dma.paddr(),
+        }
+    }
+
+    /// Returns a pointer to the descriptor table (in the descriptor area).
+    fn descriptors_vaddr(&self) -> NonNull<u8> {
+        match self {
+            Self::Legacy { dma, .. } => dma.vaddr(0),
+            Self::Modern {
+                driver_to_device_dma,
+                ..
+            } => driver_to_device_dma.vaddr(0),
+            Self::Packed { dma, .. } => dma.vaddr(0),
+        }
+    }
+
+    /// Returns the physical address of the driver area.
+    fn driver_area_paddr(&self) -> PhysAddr {
+        match self {
+            Self::Legacy {
+                dma, avail_offset, ..
+            } => dma.paddr() + avail_offset,
+            Self::Modern {
+                driver_to_device_dma,
+                avail_offset,
+                ..
+            } => driver_to_device_dma.paddr() + avail_offset,
+            Self::Packed {
+                dma,
+                driver_event_offset,
+                ..
+            } => dma.paddr() + driver_event_offset,
+        }
+    }
+
+    /// Returns a pointer to the available ring, or the driver event suppression structure for a
+    /// packed virtqueue (in the driver area).
+    fn avail_vaddr(&self) -> NonNull<u8> {
+        match self {
+            Self::Legacy {
+                dma, avail_offset, ..
+            } => dma.vaddr(*avail_offset),
+            Self::Modern {
+                driver_to_device_dma,
+                avail_offset,
+                ..
+            } => driver_to_device_dma.vaddr(*avail_offset),
+            Self::Packed {
+                dma,
+                driver_event_offset,
+                ..
+            } => dma.vaddr(*driver_event_offset),
+        }
+    }
+
+    /// Returns the physical address of the device area.
+    fn device_area_paddr(&self) -> PhysAddr {
+        match self {
+            Self::Legacy {
+                used_offset, dma, ..
+            } => dma.paddr() + used_offset,
+            Self::Modern {
+                device_to_driver_dma,
+                ..
+            } => device_to_driver_dma.paddr(),
+            Self::Packed {
+                dma,
+                device_event_offset,
+                ..
+            } => dma.paddr() + device_event_offset,
+        }
+    }
+
+    /// Returns a pointer to the used ring, or the device event suppression structure for a packed
+    /// virtqueue (in the device area).
+    fn used_vaddr(&self) -> NonNull<u8> {
+        match self {
+            Self::Legacy {
+                dma, used_offset, ..
+            } => dma.vaddr(*used_offset),
+            Self::Modern {
+                device_to_driver_dma,
+                ..
+            } => device_to_driver_dma.vaddr(0),
+            Self::Packed {
+                dma,
+                device_event_offset,
+                ..
+            } => dma.vaddr(*device_event_offset),
+        }
+    }
+}
+
+/// Returns the size in bytes of the descriptor table, available ring and used ring for a given
+/// queue size.
+///
+/// Ref: 2.6 Split Virtqueues
+fn queue_part_sizes(queue_size: u16) -> (usize, usize, usize) {
+    assert!(
+        queue_size.is_power_of_two(),
+        "queue size should be a power of 2"
+    );
+    let queue_size = queue_size as usize;
+    let desc = size_of::<Descriptor>() * queue_size;
+    let avail = size_of::<u16>() * (3 + queue_size);
+    let used = size_of::<u16>() * 3 + size_of::<UsedElem>() * queue_size;
+    (desc, avail, used)
+}
+
+#[repr(C, align(16))]
+#[derive(AsBytes, Clone, Debug, FromBytes, FromZeroes)]
+pub(crate) struct Descriptor {
+    addr: u64,
+    len: u32,
+    flags: DescFlags,
+    next: u16,
+}
+
+impl Descriptor {
+    /// Sets the buffer address, length and flags, and shares it with the device unless it has
+    /// already been shared at `paddr`.
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure that the buffer lives at least as long as the descriptor is active,
+    /// and that the device can access it at `paddr` if it is given.
+    unsafe fn set_buf<H: Hal>(
+        &mut self,
+        buf: NonNull<[u8]>,
+        direction: BufferDirection,
+        paddr: Option<PhysAddr>,
+        extra_flags: DescFlags,
+    ) {
+        self.addr = match paddr {
+            Some(paddr) => paddr as u64,
+            // Safe because our caller promises that the buffer is valid.
+            None => unsafe { H::share(buf, direction) as u64 },
+        };
+        self.len = buf.len() as u32;
+        self.flags = extra_flags
+            | match direction {
+                BufferDirection::DeviceToDriver => DescFlags::WRITE,
+                BufferDirection::DriverToDevice => DescFlags::empty(),
+                BufferDirection::Both => {
+                    panic!("Buffer passed to device should never use BufferDirection::Both.")
                 }
-            }
-            assert_eq!(remaining_output.len(), 0);
+            };
+    }
+
+    /// Sets the buffer address and length to 0.
+    ///
+    /// This must only be called once the device has finished using the descriptor.
+    fn unset_buf(&mut self) {
+        self.addr = 0;
+        self.len = 0;
+    }
+
+    /// Returns the index of the next descriptor in the chain if the `NEXT` flag is set, or `None`
+    /// if it is not (and thus this descriptor is the end of the chain).
+    fn next(&self) -> Option<u16> {
+        if self.flags.contains(DescFlags::NEXT) {
+            Some(self.next)
+        } else {
+            None
         }
+    }
+}
 
-        // Mark the buffer as used.
-        (*used_ring).ring[next_slot as usize].id = head_descriptor_index as u32;
-        (*used_ring).ring[next_slot as usize].len = (input_length + output.len()) as u32;
-        (*used_ring).idx += 1;
+/// Descriptor flags
+#[derive(AsBytes, Copy, Clone, Debug, Default, Eq, FromBytes, FromZeroes, PartialEq)]
+#[repr(transparent)]
+struct DescFlags(u16);
+
+bitflags! {
+    impl DescFlags: u16 {
+        const NEXT = 1;
+        const WRITE = 2;
+        const INDIRECT = 4;
+        /// Only used by packed virtqueues.
+        const AVAIL = 1 << 7;
+        /// Only used by packed virtqueues.
+        const USED = 1 << 15;
     }
 }
 
-#[cfg(test)]
+/// The flag in `AvailRing::flags` which asks the device not to send used buffer notifications,
+/// if `VIRTIO_F_EVENT_IDX` hasn't been negotiated.
+const VIRTQ_AVAIL_F_NO_INTERRUPT: u16 = 1;
+
+/// The driver uses the available ring to offer buffers to the device:
+/// each ring entry refers to the head of a descriptor chain.
+/// It is only written by the driver and read by the device.
+///
+/// The ring is followed by a `used_event` field, which is only used if `VIRTIO_F_EVENT_IDX` is
+/// negotiated. Use `SplitRing::used_event` to access it.
+#[repr(C)]
+#[derive(Debug)]
+struct AvailRing {
+    flags: u16,
+    /// A driver MUST NOT decrement the idx.
+    idx: u16,
+    ring: [u16],
+}
+
+/// The used ring is where the device returns buffers once it is done with them:
+/// it is only written to by the device, and read by the driver.
+///
+/// The ring is followed by an `avail_event` field, which is only used if `VIRTIO_F_EVENT_IDX` is
+/// negotiated. Use `SplitRing::avail_event` to access it.
+#[repr(C)]
+#[derive(Debug)]
+struct UsedRing {
+    flags: u16,
+    idx: u16,
+    ring: [UsedElem],
+}
+
+#[repr(C)]
+#[derive(Debug)]
+struct UsedElem {
+    id: u32,
+    len: u32,
+}
+
+/// The physical addresses of buffers in a descriptor chain which have already been shared with
+/// the device, such as [`DmaBuffer`](crate::DmaBuffer)s.
+///
+/// Entries correspond to the input and output buffers passed along with this, in order. Buffers
+/// without an entry, or with `None`, are shared and unshared by the queue as usual.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SharedAddrs<'a> {
+    /// The physical addresses of the input buffers.
+    pub inputs: &'a [Option<PhysAddr>],
+    /// The physical addresses of the output buffers.
+    pub outputs: &'a [Option<PhysAddr>],
+}
+
+struct InputOutputIter<'a, 'b> {
+    inputs: &'a [&'b [u8]],
+    outputs: &'a mut [&'b mut [u8]],
+    shared: SharedAddrs<'a>,
+}
+
+impl<'a, 'b> InputOutputIter<'a, 'b> {
+    fn new(
+        inputs: &'a [&'b [u8]],
+        outputs: &'a mut [&'b mut [u8]],
+        shared: SharedAddrs<'a>,
+    ) -> Self {
+        Self {
+            inputs,
+            outputs,
+            shared,
+        }
+    }
+}
+
+impl<'a, 'b> Iterator for InputOutputIter<'a, 'b> {
+    type Item = (NonNull<[u8]>, BufferDirection, Option<PhysAddr>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(input) = take_first(&mut self.inputs) {
+            let paddr = take_first(&mut self.shared.inputs).copied().flatten();
+            Some(((*input).into(), BufferDirection::DriverToDevice, paddr))
+        } else {
+            let output = take_first_mut(&mut self.outputs)?;
+            let paddr = take_first(&mut self.shared.outputs).copied().flatten();
+            Some(((*output).into(), BufferDirection::DeviceToDriver, paddr))
+        }
+    }
+}
+
+/// An iterator over the physically contiguous segments of a set of input and output buffers, as
+/// given by [`Hal::contiguous_len`].
+///
+/// Each segment is shared with the device separately, with its own descriptor. Buffers which have
+/// already been shared are assumed to be contiguous, so they aren't split.
+struct SegmentIter<'a, 'b, H: Hal> {
+    buffers: InputOutputIter<'a, 'b>,
+    /// The rest of the buffer currently being split, if it has more segments.
+    remaining: Option<(NonNull<[u8]>, BufferDirection)>,
+    _hal: PhantomData<H>,
+}
+
+impl<'a, 'b, H: Hal> SegmentIter<'a, 'b, H> {
+    fn new(
+        inputs: &'a [&'b [u8]],
+        outputs: &'a mut [&'b mut [u8]],
+        shared: SharedAddrs<'a>,
+    ) -> Self {
+        Self {
+            buffers: InputOutputIter::new(inputs, outputs, shared),
+            remaining: None,
+            _hal: PhantomData,
+        }
+    }
+}
+
+impl<'a, 'b, H: Hal> Iterator for SegmentIter<'a, 'b, H> {
+    type Item = (NonNull<[u8]>, BufferDirection, Option<PhysAddr>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (buffer, direction) = match self.remaining.take() {
+            Some(remaining) => remaining,
+            None => match self.buffers.next()? {
+                (buffer, direction, None) => (buffer, direction),
+                shared => return Some(shared),
+            },
+        };
+        // Always make progress, even if the HAL returns 0, and never go past the end.
+        let len = H::contiguous_len(buffer).max(1);
+        if len >= buffer.len() {
+            return Some((buffer, direction, None));
+        }
+        let start = buffer.cast::<u8>();
+        // Safe because `len` is less than the length of the buffer, so the result is still within
+        // it.
+        let rest = unsafe { NonNull::new_unchecked(start.as_ptr().add(len)) };
+        self.remaining = Some((
+            nonnull_slice_from_raw_parts(rest, buffer.len() - len),
+            direction,
+        ));
+        Some((nonnull_slice_from_raw_parts(start, len), direction, None))
+    }
+}
+
+// TODO: Use `slice::take_first` once it is stable
+// (https://github.com/rust-lang/rust/issues/62280).
+fn take_first<'a, T>(slice: &mut &'a [T]) -> Option<&'a T> {
+    let (first, rem) = slice.split_first()?;
+    *slice = rem;
+    Some(first)
+}
+
+// TODO: Use `slice::take_first_mut` once it is stable
+// (https://github.com/rust-lang/rust/issues/62280).
+fn take_first_mut<'a, T>(slice: &mut &'a mut [T]) -> Option<&'a mut T> {
+    let (first, rem) = take(slice).split_first_mut()?;
+    *slice = rem;
+    Some(first)
+}
+
+/// Simulates the device reading from a VirtIO queue and writing a response back, for use in tests.
+///
+/// The fake device always uses descriptors in order.
+#[cfg(test)]
+pub(crate) fn fake_read_write_queue(
+    descriptors: *const [Descriptor],
+    queue_driver_area: *const u8,
+    queue_device_area: *mut u8,
+    handler: impl FnOnce(Vec<u8>) -> Vec<u8>,
+) {
+    use core::{ops::Deref, slice};
+
+    let queue_size = descriptors.len();
+    let available_ring =
+        ptr::slice_from_raw_parts(queue_driver_area as *const u16, queue_size) as *const AvailRing;
+    let used_ring = ptr::slice_from_raw_parts_mut(queue_device_area as *mut UsedElem, queue_size)
+        as *mut UsedRing;
+
+    // Safe because the various pointers are properly aligned, dereferenceable, initialised, and
+    // nothing else accesses them during this block.
+    unsafe {
+        // Make sure there is actually at least one descriptor available to read from.
+        assert_ne!((*available_ring).idx, (*used_ring).idx);
+        // The fake device always uses descriptors in order, like VIRTIO_F_IN_ORDER, so
+        // `used_ring.idx` marks the next descriptor we should take from the available ring.
+        let next_slot = (*used_ring).idx & (queue_size as u16 - 1);
+        let head_descriptor_index = (*available_ring).ring[next_slot as usize];
+        let mut descriptor = &(*descriptors)[head_descriptor_index as usize];
+
+        let output;
+        if descriptor.flags.contains(DescFlags::INDIRECT) {
+            // The descriptor shouldn't have any other flags if it is indirect.
+            assert_eq!(descriptor.flags, DescFlags::INDIRECT);
+
+            // Loop through all input descriptors in the indirect descriptor list, reading data from
+            // them.
+            let indirect_descriptor_list: &[Descriptor] = zerocopy::Ref::new_slice(
+                slice::from_raw_parts(descriptor.addr as *const u8, descriptor.len as usize),
+            )
+            .unwrap()
+            .into_slice();
+            let mut input = Vec::new();
+            let mut indirect_descriptor_index = 0;
+            while indirect_descriptor_index < indirect_descriptor_list.len() {
+                let indirect_descriptor = &indirect_descriptor_list[indirect_descriptor_index];
+                if indirect_descriptor.flags.contains(DescFlags::WRITE) {
+                    break;
+                }
+
+                input.extend_from_slice(slice::from_raw_parts(
+                    indirect_descriptor.addr as *const u8,
+                    indirect_descriptor.len as usize,
+                ));
+
+                indirect_descriptor_index += 1;
+            }
+
+            // Let the test handle the request.
+            output = handler(input);
+
+            // Write the response to the remaining descriptors.
+            let mut remaining_output = output.deref();
+            while indirect_descriptor_index < indirect_descriptor_list.len() {
+                let indirect_descriptor = &indirect_descriptor_list[indirect_descriptor_index];
+                assert!(indirect_descriptor.flags.contains(DescFlags::WRITE));
+
+                let length_to_write = min(remaining_output.len(), indirect_descriptor.len as usize);
+                ptr::copy(
+                    remaining_output.as_ptr(),
+                    indirect_descriptor.addr as *mut u8,
+                    length_to_write,
+                );
+                remaining_output = &remaining_output[length_to_write..];
+
+                indirect_descriptor_index += 1;
+            }
+            assert_eq!(remaining_output.len(), 0);
+        } else {
+            // Loop through all input descriptors in the chain, reading data from them.
+            let mut input = Vec::new();
+            while !descriptor.flags.contains(DescFlags::WRITE) {
+                input.extend_from_slice(slice::from_raw_parts(
+                    descriptor.addr as *const u8,
+                    descriptor.len as usize,
+                ));
+
+                if let Some(next) = descriptor.next() {
+                    descriptor = &(*descriptors)[next as usize];
+                } else {
+                    break;
+                }
+            }
+
+            // Let the test handle the request.
+            output = handler(input);
+
+            // Write the response to the remaining descriptors.
+            let mut remaining_output = output.deref();
+            if descriptor.flags.contains(DescFlags::WRITE) {
+                loop {
+                    assert!(descriptor.flags.contains(DescFlags::WRITE));
+
+                    let length_to_write = min(remaining_output.len(), descriptor.len as usize);
+                    ptr::copy(
+                        remaining_output.as_ptr(),
+                        descriptor.addr as *mut u8,
+                        length_to_write,
+                    );
+                    remaining_output = &remaining_output[length_to_write..];
+
+                    if let Some(next) = descriptor.next() {
+                        descriptor = &(*descriptors)[next as usize];
+                    } else {
+                        break;
+                    }
+                }
+            }
+            assert_eq!(remaining_output.len(), 0);
+        }
+
+        // Mark the buffer as used.
+        (*used_ring).ring[next_slot as usize].id = head_descriptor_index as u32;
+        (*used_ring).ring[next_slot as usize].len = output.len() as u32;
+        (*used_ring).idx += 1;
+    }
+}
+
+#[cfg(test)]
 mod tests {
+    #[cfg(feature = "trace")]
+    use super::trace::TraceDescriptor;
     use super::*;
     use crate::{
+        barrier::arch_barrier,
         device::common::Feature,
-        hal::fake::FakeHal,
+        hal::{fake::FakeHal, DmaBuffer},
         transport::{
             fake::{FakeTransport, QueueStatus, State},
-            mmio::{MmioTransport, VirtIOHeader, MODERN_VERSION},
+            mmio::{MmioTransport, VirtIOHeader, LEGACY_VERSION, MODERN_VERSION},
             DeviceType,
         },
     };
     use core::ptr::NonNull;
     use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    /// Returns the split rings of the given queue, panicking if it is a packed queue.
+    fn split<H: Hal, const SIZE: usize>(queue: &VirtQueue<H, SIZE>) -> &SplitRing {
+        match &queue.ring {
+            Ring::Split(split) => split,
+            Ring::Packed(_) => panic!("Expected a split virtqueue."),
+        }
+    }
+
+    #[test]
+    fn invalid_queue_size() {
+        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0, 4);
+        let mut transport = unsafe { MmioTransport::new(NonNull::from(&mut header)) }.unwrap();
+        // Size not a power of 2.
+        assert_eq!(
+            VirtQueue::<FakeHal, 3>::new(&mut transport, 0, false, false).unwrap_err(),
+            Error::InvalidParam
+        );
+    }
+
+    #[test]
+    fn queue_too_big() {
+        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0, 4);
+        let mut transport = unsafe { MmioTransport::new(NonNull::from(&mut header)) }.unwrap();
+        assert_eq!(
+            VirtQueue::<FakeHal, 8>::new(&mut transport, 0, false, false).unwrap_err(),
+            Error::InvalidParam
+        );
+    }
+
+    /// Tests that the size of a queue created with `new_with_max_size` is limited by the device,
+    /// the caller and `SIZE`, and rounded down to a power of two.
+    #[test]
+    fn queue_size_negotiated() {
+        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0, 12);
+        let mut transport = unsafe { MmioTransport::new(NonNull::from(&mut header)) }.unwrap();
+        let queue =
+            VirtQueue::<FakeHal, 16>::new_with_max_size(&mut transport, 0, Feature::empty(), None)
+                .unwrap();
+        assert_eq!(queue.size(), 8);
+        assert_eq!(queue.available_desc(), 8);
+
+        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0, 256);
+        let mut transport = unsafe { MmioTransport::new(NonNull::from(&mut header)) }.unwrap();
+        let queue = VirtQueue::<FakeHal, 16>::new_with_max_size(
+            &mut transport,
+            0,
+            Feature::empty(),
+            Some(4),
+        )
+        .unwrap();
+        assert_eq!(queue.size(), 4);
+
+        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0, 256);
+        let mut transport = unsafe { MmioTransport::new(NonNull::from(&mut header)) }.unwrap();
+        let queue =
+            VirtQueue::<FakeHal, 16>::new_with_max_size(&mut transport, 0, Feature::empty(), None)
+                .unwrap();
+        assert_eq!(queue.size(), 16);
+
+        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0, 0);
+        let mut transport = unsafe { MmioTransport::new(NonNull::from(&mut header)) }.unwrap();
+        assert_eq!(
+            VirtQueue::<FakeHal, 16>::new_with_max_size(&mut transport, 0, Feature::empty(), None)
+                .unwrap_err(),
+            Error::InvalidParam
+        );
+    }
+
+    /// Tests adding and popping buffers on a queue which is smaller than `SIZE`, enough times for
+    /// the rings to wrap around.
+    #[test]
+    fn add_pop_smaller_queue() {
+        let mut config_space = ();
+        for features in [Feature::empty(), Feature::RING_PACKED] {
+            let (mut transport, state) = fake_transport(&mut config_space, features);
+            transport.max_queue_size = 2;
+            let mut queue =
+                VirtQueue::<FakeHal, 8>::new_with_max_size(&mut transport, 0, features, None)
+                    .unwrap();
+            assert_eq!(queue.size(), 2);
+            assert_eq!(state.lock().unwrap().queues[0].size, 2);
+
+            for i in 0..5u8 {
+                let mut response = [0; 1];
+                let token = unsafe { queue.add(&[&[i]], &mut [&mut response]) }.unwrap();
+                state
+                    .lock()
+                    .unwrap()
+                    .read_write_queue(0, |input| vec![input[0] + 1]);
+                unsafe { queue.pop_used(token, &[&[i]], &mut [&mut response]) }.unwrap();
+                assert_eq!(response, [i + 1]);
+            }
+        }
+    }
+
+    #[test]
+    fn reset() {
+        let mut config_space = ();
+        for features in [
+            Feature::RING_RESET,
+            Feature::RING_RESET | Feature::RING_PACKED,
+        ] {
+            let (mut transport, state) = fake_transport(&mut config_space, features);
+            let mut queue =
+                VirtQueue::<FakeHal, 4>::new_with_features(&mut transport, 0, features).unwrap();
+            let mut response = [0; 1];
+            unsafe { queue.add(&[&[1]], &mut [&mut response]) }.unwrap();
+            assert_eq!(queue.available_desc(), 2);
+
+            queue.reset(&mut transport, features, Some(2)).unwrap();
+            assert_eq!(state.lock().unwrap().queues_reset, 1);
+            assert_eq!(state.lock().unwrap().queues[0].size, 2);
+            assert_eq!(queue.size(), 2);
+            assert_eq!(queue.available_desc(), 2);
+            assert!(!queue.can_pop());
+
+            // The new queue works from the start.
+            let token = unsafe { queue.add(&[&[2]], &mut [&mut response]) }.unwrap();
+            assert_eq!(token, 0);
+            state
+                .lock()
+                .unwrap()
+                .read_write_queue(0, |input| vec![input[0] + 1]);
+            unsafe { queue.pop_used(token, &[&[2]], &mut [&mut response]) }.unwrap();
+            assert_eq!(response, [3]);
+        }
+    }
+
+    #[test]
+    fn reset_not_negotiated() {
+        let mut config_space = ();
+        let (mut transport, state) = fake_transport(&mut config_space, Feature::empty());
+        let mut queue = VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false).unwrap();
+        assert_eq!(
+            queue
+                .reset(&mut transport, Feature::empty(), None)
+                .unwrap_err(),
+            Error::Unsupported
+        );
+        assert_eq!(state.lock().unwrap().queues_reset, 0);
+    }
+
+    #[test]
+    fn queue_already_used() {
+        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0, 4);
+        let mut transport = unsafe { MmioTransport::new(NonNull::from(&mut header)) }.unwrap();
+        VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false).unwrap();
+        assert_eq!(
+            VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false).unwrap_err(),
+            Error::AlreadyUsed
+        );
+    }
+
+    #[test]
+    fn add_empty() {
+        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0, 4);
+        let mut transport = unsafe { MmioTransport::new(NonNull::from(&mut header)) }.unwrap();
+        let mut queue = VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false).unwrap();
+        assert_eq!(
+            unsafe { queue.add(&[], &mut []) }.unwrap_err(),
+            Error::InvalidParam
+        );
+    }
+
+    #[test]
+    fn add_too_many() {
+        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0, 4);
+        let mut transport = unsafe { MmioTransport::new(NonNull::from(&mut header)) }.unwrap();
+        let mut queue = VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false).unwrap();
+        assert_eq!(queue.available_desc(), 4);
+        assert_eq!(
+            unsafe { queue.add(&[&[], &[], &[]], &mut [&mut [], &mut []]) }.unwrap_err(),
+            Error::QueueFull
+        );
+    }
+
+    #[test]
+    fn add_buffers() {
+        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0, 4);
+        let mut transport = unsafe { MmioTransport::new(NonNull::from(&mut header)) }.unwrap();
+        let mut queue = VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false).unwrap();
+        assert_eq!(queue.available_desc(), 4);
+
+        // Add a buffer chain consisting of two device-readable parts followed by two
+        // device-writable parts.
+        let token = unsafe { queue.add(&[&[1, 2], &[3]], &mut [&mut [0, 0], &mut [0]]) }.unwrap();
+
+        assert_eq!(queue.available_desc(), 0);
+        assert!(!queue.can_pop());
+
+        // Safe because the various parts of the queue are properly aligned, dereferenceable and
+        // initialised, and nothing else is accessing them at the same time.
+        unsafe {
+            let first_descriptor_index = (*split(&queue).avail.as_ptr()).ring[0];
+            assert_eq!(first_descriptor_index, token);
+            assert_eq!(
+                (*split(&queue).desc.as_ptr())[first_descriptor_index as usize].len,
+                2
+            );
+            assert_eq!(
+                (*split(&queue).desc.as_ptr())[first_descriptor_index as usize].flags,
+                DescFlags::NEXT
+            );
+            let second_descriptor_index =
+                (*split(&queue).desc.as_ptr())[first_descriptor_index as usize].next;
+            assert_eq!(
+                (*split(&queue).desc.as_ptr())[second_descriptor_index as usize].len,
+                1
+            );
+            assert_eq!(
+                (*split(&queue).desc.as_ptr())[second_descriptor_index as usize].flags,
+                DescFlags::NEXT
+            );
+            let third_descriptor_index =
+                (*split(&queue).desc.as_ptr())[second_descriptor_index as usize].next;
+            assert_eq!(
+                (*split(&queue).desc.as_ptr())[third_descriptor_index as usize].len,
+                2
+            );
+            assert_eq!(
+                (*split(&queue).desc.as_ptr())[third_descriptor_index as usize].flags,
+                DescFlags::NEXT | DescFlags::WRITE
+            );
+            let fourth_descriptor_index =
+                (*split(&queue).desc.as_ptr())[third_descriptor_index as usize].next;
+            assert_eq!(
+                (*split(&queue).desc.as_ptr())[fourth_descriptor_index as usize].len,
+                1
+            );
+            assert_eq!(
+                (*split(&queue).desc.as_ptr())[fourth_descriptor_index as usize].flags,
+                DescFlags::WRITE
+            );
+        }
+    }
+
+    #[test]
+    fn add_buffers_indirect() {
+        use core::ptr::slice_from_raw_parts;
+
+        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0, 4);
+        let mut transport = unsafe { MmioTransport::new(NonNull::from(&mut header)) }.unwrap();
+        let mut queue = VirtQueue::<FakeHal, 4>::new(&mut transport, 0, true, false).unwrap();
+        assert_eq!(queue.available_desc(), 4);
+
+        // Add a buffer chain consisting of two device-readable parts followed by two
+        // device-writable parts.
+        let token = unsafe { queue.add(&[&[1, 2], &[3]], &mut [&mut [0, 0], &mut [0]]) }.unwrap();
+
+        // The chain only uses one descriptor in the ring.
+        assert_eq!(queue.available_desc(), 3);
+        assert!(!queue.can_pop());
+
+        // Safe because the various parts of the queue are properly aligned, dereferenceable and
+        // initialised, and nothing else is accessing them at the same time.
+        unsafe {
+            let indirect_descriptor_index = (*split(&queue).avail.as_ptr()).ring[0];
+            assert_eq!(indirect_descriptor_index, token);
+            assert_eq!(
+                (*split(&queue).desc.as_ptr())[indirect_descriptor_index as usize].len as usize,
+                4 * size_of::<Descriptor>()
+            );
+            assert_eq!(
+                (*split(&queue).desc.as_ptr())[indirect_descriptor_index as usize].flags,
+                DescFlags::INDIRECT
+            );
+
+            let indirect_descriptors = slice_from_raw_parts(
+                (*split(&queue).desc.as_ptr())[indirect_descriptor_index as usize].addr
+                    as *const Descriptor,
+                4,
+            );
+            assert_eq!((*indirect_descriptors)[0].len, 2);
+            assert_eq!((*indirect_descriptors)[0].flags, DescFlags::NEXT);
+            assert_eq!((*indirect_descriptors)[0].next, 1);
+            assert_eq!((*indirect_descriptors)[1].len, 1);
+            assert_eq!((*indirect_descriptors)[1].flags, DescFlags::NEXT);
+            assert_eq!((*indirect_descriptors)[1].next, 2);
+            assert_eq!((*indirect_descriptors)[2].len, 2);
+            assert_eq!(
+                (*indirect_descriptors)[2].flags,
+                DescFlags::NEXT | DescFlags::WRITE
+            );
+            assert_eq!((*indirect_descriptors)[2].next, 3);
+            assert_eq!((*indirect_descriptors)[3].len, 1);
+            assert_eq!((*indirect_descriptors)[3].flags, DescFlags::WRITE);
+        }
+    }
+
+    #[test]
+    fn add_pop_indirect() {
+        let mut config_space = ();
+        let (mut transport, state) = fake_transport(&mut config_space, Feature::RING_INDIRECT_DESC);
+        let mut queue = VirtQueue::<FakeHal, 4>::new(&mut transport, 0, true, false).unwrap();
+
+        // Each chain only uses one descriptor in the ring, so more chains than the size of the
+        // queue can be added and popped one after another without running out of tables.
+        for i in 0..8 {
+            let request = [1, 2, i];
+            let mut response = [0; 2];
+            let token = unsafe { queue.add(&[&request[..1], &request[1..]], &mut [&mut response]) }
+                .unwrap();
+            assert_eq!(queue.available_desc(), 3);
+
+            state.lock().unwrap().read_write_queue(0, |input| {
+                assert_eq!(input, vec![1, 2, i]);
+                vec![3, i]
+            });
+
+            assert_eq!(
+                unsafe {
+                    queue.pop_used(token, &[&request[..1], &request[1..]], &mut [&mut response])
+                }
+                .unwrap(),
+                2
+            );
+            assert_eq!(response, [3, i]);
+            assert_eq!(queue.available_desc(), 4);
+        }
+    }
+
+    /// Tests that a chain which is too long for an indirect table uses direct descriptors.
+    #[test]
+    fn add_pop_indirect_too_long() {
+        let mut config_space = ();
+        let features = Feature::RING_INDIRECT_DESC;
+        let (mut transport, state) = fake_transport(&mut config_space, features);
+        transport.max_queue_size = 64;
+        let mut queue = VirtQueue::<FakeHal, 64>::new_with_max_indirect_len(
+            &mut transport,
+            0,
+            features,
+            None,
+            32,
+        )
+        .unwrap();
+        assert_eq!(queue.max_indirect_len(), 32);
+
+        let request = [42; 33];
+        let inputs: Vec<&[u8]> = request.chunks(1).collect();
+        let token = unsafe { queue.add(&inputs, &mut []) }.unwrap();
+        assert_eq!(queue.available_desc(), 64 - 33);
+        // Safe because the descriptors are properly aligned, dereferenceable and initialised, and
+        // nothing else is accessing them at the same time.
+        unsafe {
+            assert_eq!(
+                (*split(&queue).desc.as_ptr())[usize::from(token)].flags,
+                DescFlags::NEXT
+            );
+        }
+
+        state.lock().unwrap().read_write_queue(0, |input| {
+            assert_eq!(input, request.to_vec());
+            vec![]
+        });
+
+        assert_eq!(
+            unsafe { queue.pop_used(token, &inputs, &mut []) }.unwrap(),
+            0
+        );
+        assert_eq!(queue.available_desc(), 64);
+    }
+
+    /// Tests that every descriptor in the ring can have an indirect chain as long as the queue,
+    /// and that `available_desc` counts the descriptors left for more.
+    #[test]
+    fn add_pop_indirect_full_queue() {
+        let mut config_space = ();
+        let (mut transport, state) = fake_transport(&mut config_space, Feature::RING_INDIRECT_DESC);
+        transport.max_queue_size = 64;
+        let mut queue = VirtQueue::<FakeHal, 64>::new(&mut transport, 0, true, false).unwrap();
+        assert_eq!(queue.max_indirect_len(), 64);
+
+        let request = [7; 64];
+        let inputs: Vec<&[u8]> = request.chunks(1).collect();
+        let mut tokens = Vec::new();
+        for i in 0..64 {
+            assert_eq!(queue.available_desc(), 64 - i);
+            tokens.push(unsafe { queue.add(&inputs, &mut []) }.unwrap());
+        }
+        assert_eq!(queue.available_desc(), 0);
+        assert_eq!(
+            unsafe { queue.add(&inputs[..2], &mut []) },
+            Err(Error::QueueFull)
+        );
+        // Safe because the descriptors are properly aligned, dereferenceable and initialised, and
+        // nothing else is accessing them at the same time.
+        unsafe {
+            for &token in &tokens {
+                assert_eq!(
+                    (*split(&queue).desc.as_ptr())[usize::from(token)].flags,
+                    DescFlags::INDIRECT
+                );
+            }
+        }
+
+        // Popping a chain frees its descriptor, and the table that goes with it.
+        state.lock().unwrap().read_write_queue(0, |input| {
+            assert_eq!(input, request.to_vec());
+            vec![]
+        });
+        assert_eq!(
+            unsafe { queue.pop_used(tokens[0], &inputs, &mut []) }.unwrap(),
+            0
+        );
+        assert_eq!(queue.available_desc(), 1);
+        let token = unsafe { queue.add(&inputs, &mut []) }.unwrap();
+        assert_eq!(token, tokens[0]);
+        assert_eq!(queue.available_desc(), 0);
+    }
+
+    #[test]
+    fn add_notify_wait_pop_timeout() {
+        let mut config_space = ();
+        for timeout in [
+            Timeout::Spins(100),
+            Timeout::Duration(core::time::Duration::from_millis(10)),
+        ] {
+            let (mut transport, state) = fake_transport(&mut config_space, Feature::empty());
+            state.lock().unwrap().status = DeviceStatus::DRIVER_OK;
+            let mut queue = VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false).unwrap();
+
+            // The device never uses the buffer.
+            assert_eq!(
+                queue.add_notify_wait_pop_timeout(&[&[1]], &mut [], &mut transport, timeout),
+                Err(Error::Timeout)
+            );
+            // The device has been reset, and the queue can't be used any more.
+            assert_eq!(state.lock().unwrap().status, DeviceStatus::empty());
+            assert_eq!(
+                unsafe { queue.add(&[&[2]], &mut []) },
+                Err(Error::DeviceMisbehaved)
+            );
+        }
+    }
+
+    /// Tests that the queue notifies the device about added buffers, if it hasn't suppressed
+    /// notifications.
+    #[test]
+    fn add_notify() {
+        let mut config_space = ();
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default()],
+            ..Default::default()
+        }));
+        let mut transport = FakeTransport {
+            device_type: DeviceType::Block,
+            max_queue_size: 4,
+            device_features: 0,
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut queue = VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false).unwrap();
+
+        // Add a buffer chain with a single device-readable part.
+        unsafe { queue.add(&[&[42]], &mut []) }.unwrap();
+
+        // Check that the transport would be notified.
+        assert!(queue.should_notify());
+
+        // SAFETY: the various parts of the queue are properly aligned, dereferenceable and
+        // initialised, and nothing else is accessing them at the same time.
+        unsafe {
+            // Suppress notifications.
+            (*split(&queue).used.as_ptr()).flags = 0x01;
+        }
+
+        // Check that the transport would not be notified.
+        assert!(!queue.should_notify());
+    }
+
+    /// Tests that the queue notifies the device about added buffers, if it hasn't suppressed
+    /// notifications with the `avail_event` index.
+    #[test]
+    fn add_notify_event_idx() {
+        let mut config_space = ();
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default()],
+            ..Default::default()
+        }));
+        let mut transport = FakeTransport {
+            device_type: DeviceType::Block,
+            max_queue_size: 4,
+            device_features: Feature::RING_EVENT_IDX.bits(),
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut queue = VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, true).unwrap();
+
+        // Add a buffer chain with a single device-readable part.
+        assert_eq!(unsafe { queue.add(&[&[42]], &mut []) }.unwrap(), 0);
+
+        // Check that the transport would be notified.
+        assert!(queue.should_notify());
+
+        // SAFETY: the various parts of the queue are properly aligned, dereferenceable and
+        // initialised, and nothing else is accessing them at the same time.
+        unsafe {
+            // Suppress notifications.
+            *split(&queue).avail_event() = 1;
+        }
+
+        // Check that the transport would not be notified.
+        assert!(!queue.should_notify());
+
+        // Add another buffer chain.
+        assert_eq!(unsafe { queue.add(&[&[42]], &mut []) }.unwrap(), 1);
+
+        // Check that the transport should be notified again now.
+        assert!(queue.should_notify());
+    }
+
+    /// Tests that the driver asks the device not to send used buffer notifications with the
+    /// `NO_INTERRUPT` flag.
+    #[test]
+    fn disable_enable_callbacks() {
+        let mut config_space = ();
+        let (mut transport, state) = fake_transport(&mut config_space, Feature::empty());
+        let mut queue = VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false).unwrap();
+
+        // SAFETY: the various parts of the queue are properly aligned, dereferenceable and
+        // initialised, and nothing else is accessing them at the same time.
+        assert_eq!(unsafe { (*split(&queue).avail.as_ptr()).flags }, 0);
+        queue.disable_callbacks();
+        assert_eq!(unsafe { (*split(&queue).avail.as_ptr()).flags }, 0x1);
+        assert!(queue.enable_callbacks());
+        assert_eq!(unsafe { (*split(&queue).avail.as_ptr()).flags }, 0);
+
+        // Once the device has used a buffer, enabling callbacks reports that it is waiting.
+        let token = unsafe { queue.add(&[&[42]], &mut []) }.unwrap();
+        state.lock().unwrap().read_write_queue(0, |_| Vec::new());
+        assert!(!queue.enable_callbacks());
+        unsafe { queue.pop_used(token, &[&[42]], &mut []) }.unwrap();
+        assert!(queue.enable_callbacks());
+    }
+
+    /// Tests that the driver asks for used buffer notifications with the `used_event` index.
+    #[test]
+    fn disable_enable_callbacks_event_idx() {
+        let mut config_space = ();
+        let features = Feature::RING_EVENT_IDX;
+        let (mut transport, state) = fake_transport(&mut config_space, features);
+        let mut queue =
+            VirtQueue::<FakeHal, 4>::new_with_features(&mut transport, 0, features).unwrap();
+
+        // SAFETY: the various parts of the queue are properly aligned, dereferenceable and
+        // initialised, and nothing else is accessing them at the same time.
+        queue.disable_callbacks();
+        assert_eq!(unsafe { *split(&queue).used_event() }, 0xffff);
+        assert_eq!(unsafe { (*split(&queue).avail.as_ptr()).flags }, 0);
+
+        let token = unsafe { queue.add(&[&[42]], &mut []) }.unwrap();
+        state.lock().unwrap().read_write_queue(0, |_| Vec::new());
+        assert!(!queue.enable_callbacks());
+        assert_eq!(unsafe { *split(&queue).used_event() }, 0);
+
+        // Popping the buffer asks for a notification for the next one.
+        unsafe { queue.pop_used(token, &[&[42]], &mut []) }.unwrap();
+        assert_eq!(unsafe { *split(&queue).used_event() }, 1);
+
+        // With four buffers in flight, a delayed notification is requested after the third.
+        for _ in 0..4 {
+            unsafe { queue.add(&[&[42]], &mut []) }.unwrap();
+        }
+        assert!(queue.enable_callbacks_delayed());
+        assert_eq!(unsafe { *split(&queue).used_event() }, 4);
+    }
+
+    /// Tests that the driver event suppression structure of a packed queue is updated.
+    #[test]
+    fn disable_enable_callbacks_packed() {
+        let mut config_space = ();
+        let features = Feature::RING_PACKED | Feature::RING_EVENT_IDX;
+        let (mut transport, state) = fake_transport(&mut config_space, features);
+        let mut queue =
+            VirtQueue::<FakeHal, 4>::new_with_features(&mut transport, 0, features).unwrap();
+
+        // SAFETY: the various parts of the queue are properly aligned, dereferenceable and
+        // initialised, and nothing else is accessing them at the same time.
+        queue.disable_callbacks();
+        assert_eq!(
+            unsafe { (*packed(&queue).driver_event.as_ptr()).flags },
+            0x1
+        );
+
+        assert!(queue.enable_callbacks());
+        unsafe {
+            assert_eq!((*packed(&queue).driver_event.as_ptr()).flags, 0x2);
+            assert_eq!((*packed(&queue).driver_event.as_ptr()).off_wrap, 1 << 15);
+        }
+
+        let token = unsafe { queue.add(&[&[42]], &mut []) }.unwrap();
+        state.lock().unwrap().read_write_queue(0, |_| Vec::new());
+        assert!(!queue.enable_callbacks());
+
+        // Popping the buffer asks for a notification for the next one.
+        unsafe { queue.pop_used(token, &[&[42]], &mut []) }.unwrap();
+        assert_eq!(
+            unsafe { (*packed(&queue).driver_event.as_ptr()).off_wrap },
+            1 | 1 << 15
+        );
+
+        // With four descriptors in flight, a delayed notification is requested after the third,
+        // which is in the next lap of the ring.
+        for _ in 0..4 {
+            unsafe { queue.add(&[&[42]], &mut []) }.unwrap();
+        }
+        assert!(queue.enable_callbacks_delayed());
+        assert_eq!(
+            unsafe { (*packed(&queue).driver_event.as_ptr()).off_wrap },
+            0
+        );
+    }
+
+    #[test]
+    fn notification_data() {
+        let mut config_space = ();
+        let features = Feature::NOTIFICATION_DATA;
+        let (mut transport, state) = fake_transport(&mut config_space, features);
+        let mut queue =
+            VirtQueue::<FakeHal, 4>::new_with_features(&mut transport, 0, features).unwrap();
+
+        for i in 0..2 {
+            unsafe { queue.add(&[&[i]], &mut []) }.unwrap();
+        }
+        queue.kick(&mut transport);
+        // The queue index, and the index of the next slot in the available ring.
+        assert_eq!(
+            state.lock().unwrap().queues[0].notification_data,
+            Some(2 << 16)
+        );
+    }
+
+    #[test]
+    fn notification_data_packed() {
+        let mut config_space = ();
+        let features = Feature::NOTIFICATION_DATA | Feature::RING_PACKED;
+        let (mut transport, state) = fake_transport(&mut config_space, features);
+        let mut queue =
+            VirtQueue::<FakeHal, 4>::new_with_features(&mut transport, 0, features).unwrap();
+
+        unsafe { queue.add(&[&[1], &[2]], &mut []) }.unwrap();
+        queue.kick(&mut transport);
+        // The queue index, then the offset of the next descriptor and the wrap counter.
+        assert_eq!(
+            state.lock().unwrap().queues[0].notification_data,
+            Some((2 | 1 << 15) << 16)
+        );
+
+        // Wrap around the ring.
+        assert_eq!(state.lock().unwrap().read_from_queue(0), vec![1, 2]);
+        unsafe { queue.pop_used(0, &[&[1], &[2]], &mut []) }.unwrap();
+        for i in 0..2 {
+            unsafe { queue.add(&[&[i]], &mut []) }.unwrap();
+        }
+        queue.kick(&mut transport);
+        assert_eq!(state.lock().unwrap().queues[0].notification_data, Some(0));
+    }
+
+    #[test]
+    fn notification_data_not_negotiated() {
+        let mut config_space = ();
+        let (mut transport, state) = fake_transport(&mut config_space, Feature::empty());
+        let mut queue = VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false).unwrap();
+
+        unsafe { queue.add(&[&[1]], &mut []) }.unwrap();
+        queue.kick(&mut transport);
+        let state = state.lock().unwrap();
+        assert!(state.queues[0].notified.load(Ordering::SeqCst));
+        assert_eq!(state.queues[0].notification_data, None);
+    }
+
+    /// Tests that staged buffers are only made available to the device by `kick_prepare`, which
+    /// checks the event index over the whole batch.
+    #[test]
+    fn stage_kick_batch() {
+        let mut config_space = ();
+        let features = Feature::RING_EVENT_IDX;
+        let (mut transport, state) = fake_transport(&mut config_space, features);
+        let mut queue =
+            VirtQueue::<FakeHal, 4>::new_with_features(&mut transport, 0, features).unwrap();
+
+        for i in 0..3 {
+            assert_eq!(unsafe { queue.stage(&[&[i]], &mut []) }.unwrap(), i.into());
+        }
+        // SAFETY: the various parts of the queue are properly aligned, dereferenceable and
+        // initialised, and nothing else is accessing them at the same time.
+        unsafe {
+            assert_eq!((*split(&queue).avail.as_ptr()).idx, 0);
+            // Ask for a notification once the second buffer is available.
+            *split(&queue).avail_event() = 1;
+        }
+
+        assert!(queue.kick_prepare());
+        assert_eq!(unsafe { (*split(&queue).avail.as_ptr()).idx }, 3);
+        for i in 0..3 {
+            assert_eq!(state.lock().unwrap().read_from_queue(0), vec![i]);
+        }
+
+        // The event index is now behind the batch, so no notification is needed.
+        unsafe { queue.stage(&[&[3]], &mut []) }.unwrap();
+        assert!(!queue.kick_prepare());
+        assert_eq!(unsafe { (*split(&queue).avail.as_ptr()).idx }, 4);
+    }
+
+    /// Tests that staged buffers in a packed queue are only made available to the device by
+    /// `kick_prepare`.
+    #[test]
+    fn stage_kick_batch_packed() {
+        let mut config_space = ();
+        let features = Feature::RING_PACKED | Feature::RING_EVENT_IDX;
+        let (mut transport, state) = fake_transport(&mut config_space, features);
+        let mut queue =
+            VirtQueue::<FakeHal, 4>::new_with_features(&mut transport, 0, features).unwrap();
+
+        unsafe {
+            // Only ask for a notification once the descriptor at offset 1 is made available.
+            (*packed(&queue).device_event.as_ptr()).flags = 0x2;
+            (*packed(&queue).device_event.as_ptr()).off_wrap = 1 | 1 << 15;
+        }
+
+        assert_eq!(unsafe { queue.stage(&[&[1]], &mut []) }.unwrap(), 0);
+        assert_eq!(unsafe { queue.stage(&[&[2]], &mut []) }.unwrap(), 1);
+        // SAFETY: the various parts of the queue are properly aligned, dereferenceable and
+        // initialised, and nothing else is accessing them at the same time.
+        unsafe {
+            let descriptors = &*packed(&queue).desc.as_ptr();
+            // The first head isn't available yet, which hides the second chain from the device.
+            assert!(!descriptors[0].flags.contains(DescFlags::AVAIL));
+            assert!(descriptors[1].flags.contains(DescFlags::AVAIL));
+        }
+
+        assert!(queue.kick_prepare());
+        unsafe {
+            assert!((*packed(&queue).desc.as_ptr())[0]
+                .flags
+                .contains(DescFlags::AVAIL));
+        }
+        assert_eq!(state.lock().unwrap().read_from_queue(0), vec![1]);
+        assert_eq!(state.lock().unwrap().read_from_queue(0), vec![2]);
+    }
+
+    /// Returns the packed ring of the given queue, panicking if it is a split queue.
+    fn packed<H: Hal, const SIZE: usize>(queue: &VirtQueue<H, SIZE>) -> &PackedRing {
+        match &queue.ring {
+            Ring::Packed(packed) => packed,
+            Ring::Split(_) => panic!("Expected a packed virtqueue."),
+        }
+    }
+
+    fn fake_transport(
+        config_space: &mut (),
+        features: Feature,
+    ) -> (FakeTransport<()>, Arc<Mutex<State>>) {
+        let state = Arc::new(Mutex::new(State {
+            driver_features: features.bits(),
+            queues: vec![QueueStatus::default()],
+            ..Default::default()
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::Block,
+            max_queue_size: 4,
+            device_features: features.bits(),
+            config_space: NonNull::from(config_space),
+            state: state.clone(),
+        };
+        (transport, state)
+    }
+
+    #[test]
+    fn packed_requires_modern_layout() {
+        let mut header = VirtIOHeader::make_fake_header(LEGACY_VERSION, 1, 0, 0, 4);
+        let mut transport = unsafe { MmioTransport::new(NonNull::from(&mut header)) }.unwrap();
+        assert_eq!(
+            VirtQueue::<FakeHal, 4>::new_with_features(&mut transport, 0, Feature::RING_PACKED)
+                .unwrap_err(),
+            Error::InvalidParam
+        );
+    }
+
+    #[test]
+    fn add_buffers_packed() {
+        let mut config_space = ();
+        let (mut transport, _state) = fake_transport(&mut config_space, Feature::RING_PACKED);
+        let mut queue =
+            VirtQueue::<FakeHal, 4>::new_with_features(&mut transport, 0, Feature::RING_PACKED)
+                .unwrap();
+
+        // Add a buffer chain consisting of two device-readable parts followed by two
+        // device-writable parts.
+        let token = unsafe { queue.add(&[&[1, 2], &[3]], &mut [&mut [0, 0], &mut [0]]) }.unwrap();
+
+        assert_eq!(queue.available_desc(), 0);
+        assert!(!queue.can_pop());
+
+        // Safe because the various parts of the queue are properly aligned, dereferenceable and
+        // initialised, and nothing else is accessing them at the same time.
+        unsafe {
+            let desc = &*packed(&queue).desc.as_ptr();
+            assert_eq!(desc[0].len, 2);
+            assert_eq!(desc[0].flags, DescFlags::NEXT | DescFlags::AVAIL);
+            assert_eq!(desc[1].len, 1);
+            assert_eq!(desc[1].flags, DescFlags::NEXT | DescFlags::AVAIL);
+            assert_eq!(desc[2].len, 2);
+            assert_eq!(
+                desc[2].flags,
+                DescFlags::NEXT | DescFlags::WRITE | DescFlags::AVAIL
+            );
+            assert_eq!(desc[3].len, 1);
+            assert_eq!(desc[3].flags, DescFlags::WRITE | DescFlags::AVAIL);
+            assert_eq!(desc[3].id, token);
+        }
+    }
+
+    /// Tests adding and popping buffers on a packed queue enough times for the ring to wrap
+    /// around.
+    #[test]
+    fn add_pop_packed_wrap_around() {
+        let mut config_space = ();
+        let (mut transport, state) = fake_transport(&mut config_space, Feature::RING_PACKED);
+        let mut queue =
+            VirtQueue::<FakeHal, 4>::new_with_features(&mut transport, 0, Feature::RING_PACKED)
+                .unwrap();
+
+        for i in 0..10u8 {
+            let request = [i, 1, 2];
+            let mut response = [0; 2];
+            let token = unsafe { queue.add(&[&request], &mut [&mut response]) }.unwrap();
+            assert!(queue.should_notify());
+            assert_eq!(queue.peek_used(), None);
+
+            state.lock().unwrap().read_write_queue(0, |input| {
+                assert_eq!(input, vec![i, 1, 2]);
+                vec![i, 42]
+            });
+
+            assert_eq!(queue.peek_used(), Some(token));
+            assert_eq!(
+                unsafe { queue.pop_used(token, &[&request], &mut [&mut response]) }.unwrap(),
+                2
+            );
+            assert_eq!(response, [i, 42]);
+            assert_eq!(queue.available_desc(), 4);
+        }
+    }
+
+    #[test]
+    fn add_pop_packed_indirect() {
+        let mut config_space = ();
+        let features = Feature::RING_PACKED | Feature::RING_INDIRECT_DESC;
+        let (mut transport, state) = fake_transport(&mut config_space, features);
+        let mut queue =
+            VirtQueue::<FakeHal, 4>::new_with_features(&mut transport, 0, features).unwrap();
+
+        for i in 0..6u8 {
+            let mut response = [0; 3];
+            let (first, second) = response.split_at_mut(1);
+            let token = unsafe { queue.add(&[&[i], &[2, 3]], &mut [first, second]) }.unwrap();
+            assert_eq!(queue.available_desc(), 3);
+
+            state.lock().unwrap().read_write_queue(0, |input| {
+                assert_eq!(input, vec![i, 2, 3]);
+                vec![4, 5, i]
+            });
+
+            let (first, second) = response.split_at_mut(1);
+            assert_eq!(
+                unsafe { queue.pop_used(token, &[&[i], &[2, 3]], &mut [first, second]) }.unwrap(),
+                3
+            );
+            assert_eq!(response, [4, 5, i]);
+        }
+    }
+
+    /// Tests that the packed queue notifies the device about added buffers, according to the
+    /// device event suppression structure.
+    #[test]
+    fn add_notify_packed() {
+        let mut config_space = ();
+        let features = Feature::RING_PACKED | Feature::RING_EVENT_IDX;
+        let (mut transport, _state) = fake_transport(&mut config_space, features);
+        let mut queue =
+            VirtQueue::<FakeHal, 4>::new_with_features(&mut transport, 0, features).unwrap();
+
+        // Add a buffer chain with a single device-readable part.
+        assert_eq!(unsafe { queue.add(&[&[42]], &mut []) }.unwrap(), 0);
+        assert!(queue.should_notify());
+
+        // SAFETY: the various parts of the queue are properly aligned, dereferenceable and
+        // initialised, and nothing else is accessing them at the same time.
+        unsafe {
+            // Suppress notifications.
+            (*packed(&queue).device_event.as_ptr()).flags = 0x1;
+        }
+        assert!(!queue.should_notify());
+
+        unsafe {
+            // Only ask for a notification once the descriptor at offset 1 is made available.
+            (*packed(&queue).device_event.as_ptr()).flags = 0x2;
+            (*packed(&queue).device_event.as_ptr()).off_wrap = 1 | 1 << 15;
+        }
+        assert!(!queue.should_notify());
+
+        // Add another buffer chain, in the descriptor at offset 1.
+        assert_eq!(unsafe { queue.add(&[&[42]], &mut []) }.unwrap(), 1);
+        assert!(queue.should_notify());
+    }
+
+    /// Tests that with `VIRTIO_F_IN_ORDER` descriptors are allocated sequentially, and a single
+    /// used element can cover a batch of buffers.
+    #[test]
+    fn pop_batch_in_order() {
+        let mut config_space = ();
+        let (mut transport, state) = fake_transport(&mut config_space, Feature::IN_ORDER);
+        let mut queue =
+            VirtQueue::<FakeHal, 4>::new_with_features(&mut transport, 0, Feature::IN_ORDER)
+                .unwrap();
+
+        let mut response = [0; 3];
+        let token = unsafe { queue.add(&[&[1]], &mut []) }.unwrap();
+        assert_eq!(token, 0);
+        state.lock().unwrap().read_write_queue(0, |_| Vec::new());
+        unsafe { queue.pop_used(token, &[&[1]], &mut []) }.unwrap();
+
+        // The next buffers use the following descriptors, rather than reusing the first one.
+        let first = unsafe { queue.add(&[&[2]], &mut []) }.unwrap();
+        let second = unsafe { queue.add(&[&[3, 4]], &mut [&mut response]) }.unwrap();
+        assert_eq!((first, second), (1, 2));
+        assert!(!queue.can_pop());
+
+        // SAFETY: the various parts of the queue are properly aligned, dereferenceable and
+        // initialised, and nothing else is accessing them at the same time.
+        unsafe {
+            // Use both buffers with a single used element for the second one.
+            let used = split(&queue).used.as_ptr();
+            (*used).ring[1] = UsedElem {
+                id: second.into(),
+                len: 2,
+            };
+            (*used).idx = 3;
+        }
+
+        assert_eq!(queue.peek_used(), Some(first));
+        assert_eq!(
+            unsafe { queue.pop_used(second, &[&[3, 4]], &mut [&mut response]) },
+            Err(Error::WrongToken)
+        );
+        assert_eq!(unsafe { queue.pop_used(first, &[&[2]], &mut []) }, Ok(0));
+        assert_eq!(queue.peek_used(), Some(second));
+        assert_eq!(
+            unsafe { queue.pop_used(second, &[&[3, 4]], &mut [&mut response]) },
+            Ok(2)
+        );
+        assert!(!queue.can_pop());
+        assert_eq!(queue.available_desc(), 4);
+
+        // Allocation carries on from where it left off, and wraps around.
+        assert_eq!(unsafe { queue.add(&[&[5], &[6]], &mut []) }.unwrap(), 0);
+        // SAFETY: Nothing else is accessing the submitting state of the queue.
+        assert_eq!(unsafe { &*queue.submit.get() }.free_head, 2);
+    }
+
+    /// Tests that a packed queue with `VIRTIO_F_IN_ORDER` skips over all the descriptors of a
+    /// batch used with a single used descriptor.
+    #[test]
+    fn pop_batch_in_order_packed() {
+        let mut config_space = ();
+        let features = Feature::RING_PACKED | Feature::IN_ORDER;
+        let (mut transport, _state) = fake_transport(&mut config_space, features);
+        let mut queue =
+            VirtQueue::<FakeHal, 4>::new_with_features(&mut transport, 0, features).unwrap();
+
+        let mut response = [0; 2];
+        let first = unsafe { queue.add(&[&[1], &[2]], &mut []) }.unwrap();
+        let second = unsafe { queue.add(&[&[3]], &mut [&mut response]) }.unwrap();
+        assert_eq!((first, second), (0, 2));
+
+        // SAFETY: the various parts of the queue are properly aligned, dereferenceable and
+        // initialised, and nothing else is accessing them at the same time.
+        unsafe {
+            // Use both buffers with a single used descriptor for the second one, in the slot of
+            // the first.
+            let desc = &mut (*packed(&queue).desc.as_ptr())[0];
+            desc.id = second;
+            desc.len = 1;
+            desc.flags = DescFlags::AVAIL | DescFlags::USED;
+        }
+
+        assert_eq!(
+            unsafe { queue.pop_used(first, &[&[1], &[2]], &mut []) },
+            Ok(0)
+        );
+        assert_eq!(
+            unsafe { queue.pop_used(second, &[&[3]], &mut [&mut response]) },
+            Ok(1)
+        );
+        assert!(!queue.can_pop());
+
+        // The used position has moved past all the descriptors of the batch, wrapping around.
+        // SAFETY: Nothing else is accessing the completing state of the queue.
+        match unsafe { &(*queue.complete.get()).ring } {
+            RingComplete::Packed(packed) => {
+                assert_eq!(packed.next_used, 0);
+                assert!(!packed.used_wrap_counter);
+            }
+            RingComplete::Split(_) => panic!("Expected a packed virtqueue."),
+        }
+        assert_eq!(unsafe { queue.add(&[&[4], &[5]], &mut []) }.unwrap(), 0);
+    }
+
+    /// Sets up a split queue with a single buffer added, and lets the test write a used element
+    /// for it, as a misbehaving device might.
+    fn misbehaving_device(features: Feature, id: u32, len: u32, used_idx: u16) {
+        let mut config_space = ();
+        let (mut transport, _state) = fake_transport(&mut config_space, features);
+        let mut queue =
+            VirtQueue::<FakeHal, 4>::new_with_features(&mut transport, 0, features).unwrap();
+
+        let mut response = [0; 2];
+        let token = unsafe { queue.add(&[&[1]], &mut [&mut response]) }.unwrap();
+        // SAFETY: the various parts of the queue are properly aligned, dereferenceable and
+        // initialised, and nothing else is accessing them at the same time.
+        unsafe {
+            let used = split(&queue).used.as_ptr();
+            (*used).ring[0] = UsedElem { id, len };
+            (*used).idx = used_idx;
+        }
+
+        assert!(queue.can_pop());
+        assert_eq!(
+            unsafe { queue.pop_used(token, &[&[1]], &mut [&mut response]) },
+            Err(Error::DeviceMisbehaved)
+        );
+        // The queue can't be used any more.
+        assert_eq!(
+            unsafe { queue.add(&[&[2]], &mut []) },
+            Err(Error::DeviceMisbehaved)
+        );
+        assert_eq!(
+            unsafe { queue.pop_used(token, &[&[1]], &mut [&mut response]) },
+            Err(Error::DeviceMisbehaved)
+        );
+    }
+
+    #[test]
+    fn used_id_out_of_range() {
+        misbehaving_device(Feature::empty(), 4, 0, 1);
+        // This would be truncated to a valid ID.
+        misbehaving_device(Feature::empty(), 0x1_0000, 0, 1);
+        misbehaving_device(Feature::IN_ORDER, 4, 0, 1);
+    }
+
+    #[test]
+    fn used_id_not_in_flight_in_order() {
+        // Descriptor 1 is in range but isn't the head of a chain owned by the device.
+        misbehaving_device(Feature::IN_ORDER, 1, 0, 1);
+        // Descriptor 2 is in range but hasn't been published.
+        misbehaving_device(Feature::IN_ORDER, 2, 0, 1);
+    }
+
+    #[test]
+    fn used_len_too_long() {
+        misbehaving_device(Feature::empty(), 0, 3, 1);
+        misbehaving_device(Feature::IN_ORDER, 0, 3, 1);
+    }
+
+    #[test]
+    fn used_idx_jump() {
+        misbehaving_device(Feature::empty(), 0, 0, 2);
+        misbehaving_device(Feature::empty(), 0, 0, 0x8000);
+    }
+
+    /// Tests that popping a chain with buffers which don't match those it was added with fails
+    /// rather than panicking.
+    #[test]
+    fn pop_used_mismatched_buffers() {
+        for features in [
+            Feature::empty(),
+            Feature::RING_INDIRECT_DESC,
+            Feature::IN_ORDER,
+            Feature::RING_PACKED,
+        ] {
+            let mut config_space = ();
+            let (mut transport, state) = fake_transport(&mut config_space, features);
+            let mut queue =
+                VirtQueue::<FakeHal, 4>::new_with_features(&mut transport, 0, features).unwrap();
+
+            let mut response = [0; 2];
+            let token = unsafe { queue.add(&[&[1]], &mut [&mut response]) }.unwrap();
+            state.lock().unwrap().read_write_queue(0, |input| {
+                assert_eq!(input, vec![1]);
+                vec![]
+            });
+
+            assert_eq!(
+                unsafe { queue.pop_used(token, &[&[1]], &mut []) },
+                Err(Error::InvalidParam)
+            );
+            assert_eq!(
+                unsafe { queue.pop_used(token, &[&[1], &[]], &mut [&mut response]) },
+                Err(Error::DeviceMisbehaved)
+            );
+        }
+    }
+
+    /// Makes the device write a used descriptor with the given ID and length for a chain which
+    /// was added to a packed queue, and checks that the queue rejects it.
+    fn misbehaving_packed_device(id: u16, len: u32) {
+        let mut config_space = ();
+        let features = Feature::RING_PACKED;
+        let (mut transport, _state) = fake_transport(&mut config_space, features);
+        let mut queue =
+            VirtQueue::<FakeHal, 4>::new_with_features(&mut transport, 0, features).unwrap();
+
+        let mut response = [0; 2];
+        let token = unsafe { queue.add(&[&[1]], &mut [&mut response]) }.unwrap();
+        // SAFETY: the various parts of the queue are properly aligned, dereferenceable and
+        // initialised, and nothing else is accessing them at the same time.
+        unsafe {
+            let desc = &mut (*packed(&queue).desc.as_ptr())[0];
+            desc.id = id;
+            desc.len = len;
+            desc.flags = DescFlags::AVAIL | DescFlags::USED;
+        }
+
+        assert_eq!(queue.peek_used(), Some(id));
+        assert_eq!(
+            unsafe { queue.pop_used(token, &[&[1]], &mut [&mut response]) },
+            Err(Error::DeviceMisbehaved)
+        );
+        // The queue can't be used any more.
+        assert_eq!(
+            unsafe { queue.add(&[&[2]], &mut []) },
+            Err(Error::DeviceMisbehaved)
+        );
+    }
+
+    #[test]
+    fn used_id_out_of_range_packed() {
+        misbehaving_packed_device(4, 0);
+        misbehaving_packed_device(42, 0);
+    }
+
+    #[test]
+    fn used_id_not_in_flight_packed() {
+        // Buffer ID 1 is in range but isn't the head of a chain owned by the device.
+        misbehaving_packed_device(1, 0);
+        // Buffer ID 2 was never made available.
+        misbehaving_packed_device(2, 0);
+    }
+
+    #[test]
+    fn used_len_too_long_packed() {
+        misbehaving_packed_device(0, 3);
+    }
 
     #[test]
-    fn invalid_queue_size() {
-        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0, 4);
-        let mut transport = unsafe { MmioTransport::new(NonNull::from(&mut header)) }.unwrap();
-        // Size not a power of 2.
+    fn used_without_buffers_packed() {
+        let mut config_space = ();
+        let features = Feature::RING_PACKED;
+        let (mut transport, _state) = fake_transport(&mut config_space, features);
+        let mut queue =
+            VirtQueue::<FakeHal, 4>::new_with_features(&mut transport, 0, features).unwrap();
+
+        // SAFETY: the various parts of the queue are properly aligned, dereferenceable and
+        // initialised, and nothing else is accessing them at the same time.
+        unsafe {
+            (*packed(&queue).desc.as_ptr())[0].flags = DescFlags::AVAIL | DescFlags::USED;
+        }
+
+        assert_eq!(queue.peek_used(), Some(0));
         assert_eq!(
-            VirtQueue::<FakeHal, 3>::new(&mut transport, 0, false, false).unwrap_err(),
-            Error::InvalidParam
+            unsafe { queue.pop_used(0, &[&[1]], &mut []) },
+            Err(Error::DeviceMisbehaved)
         );
     }
 
+    /// Tests that buffers which are already shared are passed to the device at their own physical
+    /// address, rather than being shared again.
     #[test]
-    fn queue_too_big() {
-        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0, 4);
-        let mut transport = unsafe { MmioTransport::new(NonNull::from(&mut header)) }.unwrap();
+    fn add_pop_shared() {
+        let mut config_space = ();
+        let (mut transport, state) = fake_transport(&mut config_space, Feature::empty());
+        let mut queue = VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false).unwrap();
+
+        let request = [1, 2];
+        let mut response = DmaBuffer::<FakeHal>::new(3, BufferDirection::DeviceToDriver).unwrap();
+        let paddr = response.paddr();
+        let inputs = [&request[..]];
+        let shared = [Some(paddr)];
+        let shared = SharedAddrs {
+            inputs: &[],
+            outputs: &shared,
+        };
+        let token = unsafe { queue.stage_shared(&inputs, &mut [&mut response], shared) }.unwrap();
+        queue.kick(&mut transport);
+
+        // Safe because the various parts of the queue are properly aligned, dereferenceable and
+        // initialised, and nothing else is accessing them at the same time.
+        unsafe {
+            let first_desc = &(*split(&queue).desc.as_ptr())[0];
+            assert_ne!(first_desc.addr, request.as_ptr() as u64);
+            let second_desc = &(*split(&queue).desc.as_ptr())[1];
+            assert_eq!(second_desc.addr, paddr as u64);
+            assert_eq!(second_desc.len, 3);
+        }
+
+        state.lock().unwrap().read_write_queue(0, |input| {
+            assert_eq!(input, vec![1, 2]);
+            vec![3, 4, 5]
+        });
+
         assert_eq!(
-            VirtQueue::<FakeHal, 8>::new(&mut transport, 0, false, false).unwrap_err(),
-            Error::InvalidParam
+            unsafe { queue.pop_used_shared(token, &inputs, &mut [&mut response], shared) }.unwrap(),
+            3
         );
+        assert_eq!(&response[..], &[3, 4, 5]);
+        assert_eq!(queue.available_desc(), 4);
     }
 
-    #[test]
-    fn queue_already_used() {
-        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0, 4);
-        let mut transport = unsafe { MmioTransport::new(NonNull::from(&mut header)) }.unwrap();
-        VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false).unwrap();
+    /// A HAL which treats memory as physically contiguous for at most 2 bytes at a time.
+    struct SegmentingHal;
+
+    unsafe impl Hal for SegmentingHal {
+        fn dma_alloc(pages: usize, direction: BufferDirection) -> (PhysAddr, NonNull<u8>) {
+            FakeHal::dma_alloc(pages, direction)
+        }
+
+        unsafe fn dma_dealloc(paddr: PhysAddr, vaddr: NonNull<u8>, pages: usize) -> i32 {
+            // SAFETY: Our caller upholds the same requirements.
+            unsafe { FakeHal::dma_dealloc(paddr, vaddr, pages) }
+        }
+
+        unsafe fn mmio_phys_to_virt(paddr: PhysAddr, size: usize) -> NonNull<u8> {
+            // SAFETY: Our caller upholds the same requirements.
+            unsafe { FakeHal::mmio_phys_to_virt(paddr, size) }
+        }
+
+        unsafe fn share(buffer: NonNull<[u8]>, direction: BufferDirection) -> PhysAddr {
+            assert!(buffer.len() <= 2);
+            // SAFETY: Our caller upholds the same requirements.
+            unsafe { FakeHal::share(buffer, direction) }
+        }
+
+        unsafe fn unshare(paddr: PhysAddr, buffer: NonNull<[u8]>, direction: BufferDirection) {
+            assert!(buffer.len() <= 2);
+            // SAFETY: Our caller upholds the same requirements.
+            unsafe { FakeHal::unshare(paddr, buffer, direction) }
+        }
+
+        fn contiguous_len(buffer: NonNull<[u8]>) -> usize {
+            min(buffer.len(), 2)
+        }
+    }
+
+    fn add_pop_segmented(features: Feature, available_after_add: usize) {
+        let mut config_space = ();
+        let (mut transport, state) = fake_transport(&mut config_space, features);
+        let mut queue =
+            VirtQueue::<SegmentingHal, 4>::new_with_features(&mut transport, 0, features).unwrap();
+
+        let request = [1, 2, 3];
+        let mut response = [0; 3];
+        let token = unsafe { queue.add(&[&request], &mut [&mut response]) }.unwrap();
+        assert_eq!(queue.available_desc(), available_after_add);
+
+        state.lock().unwrap().read_write_queue(0, |input| {
+            assert_eq!(input, vec![1, 2, 3]);
+            vec![4, 5, 6]
+        });
+
         assert_eq!(
-            VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false).unwrap_err(),
-            Error::AlreadyUsed
+            unsafe { queue.pop_used(token, &[&request], &mut [&mut response]) }.unwrap(),
+            3
         );
+        assert_eq!(response, [4, 5, 6]);
+        assert_eq!(queue.available_desc(), 4);
     }
 
+    /// Tests that buffers which aren't physically contiguous are split into a descriptor for each
+    /// segment.
     #[test]
-    fn add_empty() {
-        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0, 4);
-        let mut transport = unsafe { MmioTransport::new(NonNull::from(&mut header)) }.unwrap();
-        let mut queue = VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false).unwrap();
-        assert_eq!(
-            unsafe { queue.add(&[], &mut []) }.unwrap_err(),
-            Error::InvalidParam
-        );
+    fn add_pop_segmented_direct() {
+        add_pop_segmented(Feature::empty(), 0);
+        add_pop_segmented(Feature::RING_PACKED, 0);
+        add_pop_segmented(Feature::IN_ORDER, 0);
     }
 
     #[test]
-    fn add_too_many() {
-        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0, 4);
-        let mut transport = unsafe { MmioTransport::new(NonNull::from(&mut header)) }.unwrap();
-        let mut queue = VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false).unwrap();
-        assert_eq!(queue.available_desc(), 4);
+    fn add_pop_segmented_indirect() {
+        add_pop_segmented(Feature::RING_INDIRECT_DESC, 3);
+        add_pop_segmented(Feature::RING_PACKED | Feature::RING_INDIRECT_DESC, 3);
+    }
+
+    #[test]
+    fn add_segmented_too_many() {
+        let mut config_space = ();
+        let (mut transport, _state) = fake_transport(&mut config_space, Feature::empty());
+        let mut queue =
+            VirtQueue::<SegmentingHal, 4>::new(&mut transport, 0, false, false).unwrap();
         assert_eq!(
-            unsafe { queue.add(&[&[], &[], &[]], &mut [&mut [], &mut []]) }.unwrap_err(),
+            unsafe { queue.add(&[&[1, 2, 3, 4, 5]], &mut [&mut [0; 4]]) }.unwrap_err(),
             Error::QueueFull
         );
+        assert_eq!(queue.available_desc(), 4);
     }
 
-    #[test]
-    fn add_buffers() {
-        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0, 4);
-        let mut transport = unsafe { MmioTransport::new(NonNull::from(&mut header)) }.unwrap();
-        let mut queue = VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false).unwrap();
-        assert_eq!(queue.available_desc(), 4);
+    /// The memory barriers which `BarrierHal` has been asked for, and whether each was a platform
+    /// barrier.
+    static BARRIERS: Mutex<Vec<(Barrier, bool)>> = Mutex::new(Vec::new());
 
-        // Add a buffer chain consisting of two device-readable parts followed by two
-        // device-writable parts.
-        let token = unsafe { queue.add(&[&[1, 2], &[3]], &mut [&mut [0, 0], &mut [0]]) }.unwrap();
+    /// A HAL which records the memory barriers which it is asked for.
+    struct BarrierHal;
 
-        assert_eq!(queue.available_desc(), 0);
-        assert!(!queue.can_pop());
+    unsafe impl Hal for BarrierHal {
+        fn dma_alloc(pages: usize, direction: BufferDirection) -> (PhysAddr, NonNull<u8>) {
+            FakeHal::dma_alloc(pages, direction)
+        }
 
-        // Safe because the various parts of the queue are properly aligned, dereferenceable and
-        // initialised, and nothing else is accessing them at the same time.
-        unsafe {
-            let first_descriptor_index = (*queue.avail.as_ptr()).ring[0];
-            assert_eq!(first_descriptor_index, token);
-            assert_eq!(
-                (*queue.desc.as_ptr())[first_descriptor_index as usize].len,
-                2
-            );
-            assert_eq!(
-                (*queue.desc.as_ptr())[first_descriptor_index as usize].flags,
-                DescFlags::NEXT
-            );
-            let second_descriptor_index =
-                (*queue.desc.as_ptr())[first_descriptor_index as usize].next;
-            assert_eq!(
-                (*queue.desc.as_ptr())[second_descriptor_index as usize].len,
-                1
-            );
-            assert_eq!(
-                (*queue.desc.as_ptr())[second_descriptor_index as usize].flags,
-                DescFlags::NEXT
-            );
-            let third_descriptor_index =
-                (*queue.desc.as_ptr())[second_descriptor_index as usize].next;
-            assert_eq!(
-                (*queue.desc.as_ptr())[third_descriptor_index as usize].len,
-                2
-            );
-            assert_eq!(
-                (*queue.desc.as_ptr())[third_descriptor_index as usize].flags,
-                DescFlags::NEXT | DescFlags::WRITE
-            );
-            let fourth_descriptor_index =
-                (*queue.desc.as_ptr())[third_descriptor_index as usize].next;
-            assert_eq!(
-                (*queue.desc.as_ptr())[fourth_descriptor_index as usize].len,
-                1
-            );
-            assert_eq!(
-                (*queue.desc.as_ptr())[fourth_descriptor_index as usize].flags,
-                DescFlags::WRITE
-            );
+        unsafe fn dma_dealloc(paddr: PhysAddr, vaddr: NonNull<u8>, pages: usize) -> i32 {
+            // SAFETY: Our caller upholds the same requirements.
+            unsafe { FakeHal::dma_dealloc(paddr, vaddr, pages) }
+        }
+
+        unsafe fn mmio_phys_to_virt(paddr: PhysAddr, size: usize) -> NonNull<u8> {
+            // SAFETY: Our caller upholds the same requirements.
+            unsafe { FakeHal::mmio_phys_to_virt(paddr, size) }
+        }
+
+        unsafe fn share(buffer: NonNull<[u8]>, direction: BufferDirection) -> PhysAddr {
+            // SAFETY: Our caller upholds the same requirements.
+            unsafe { FakeHal::share(buffer, direction) }
+        }
+
+        unsafe fn unshare(paddr: PhysAddr, buffer: NonNull<[u8]>, direction: BufferDirection) {
+            // SAFETY: Our caller upholds the same requirements.
+            unsafe { FakeHal::unshare(paddr, buffer, direction) }
+        }
+
+        fn barrier(barrier: Barrier, platform: bool) {
+            BARRIERS.lock().unwrap().push((barrier, platform));
+            arch_barrier(barrier, platform);
         }
     }
 
-    #[cfg(feature = "alloc")]
+    /// Tests that the queue asks the HAL for platform barriers if and only if
+    /// `VIRTIO_F_ORDER_PLATFORM` has been negotiated.
     #[test]
-    fn add_buffers_indirect() {
-        use core::ptr::slice_from_raw_parts;
+    fn barriers_order_platform() {
+        for (features, platform) in [
+            (Feature::empty(), false),
+            (Feature::ORDER_PLATFORM, true),
+            (Feature::RING_PACKED | Feature::ORDER_PLATFORM, true),
+        ] {
+            let mut config_space = ();
+            let (mut transport, state) = fake_transport(&mut config_space, features);
+            let mut queue =
+                VirtQueue::<BarrierHal, 4>::new_with_features(&mut transport, 0, features).unwrap();
+            BARRIERS.lock().unwrap().clear();
+
+            let token = unsafe { queue.stage(&[&[1]], &mut []) }.unwrap();
+            queue.kick_prepare();
+            assert_eq!(state.lock().unwrap().read_from_queue(0), vec![1]);
+            unsafe { queue.pop_used(token, &[&[1]], &mut []) }.unwrap();
+
+            let barriers = take(&mut *BARRIERS.lock().unwrap());
+            for barrier in [Barrier::Read, Barrier::Write, Barrier::Full] {
+                assert!(barriers.contains(&(barrier, platform)));
+            }
+            assert!(barriers.iter().all(|&(_, p)| p == platform));
+        }
+    }
 
-        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0, 4);
-        let mut transport = unsafe { MmioTransport::new(NonNull::from(&mut header)) }.unwrap();
-        let mut queue = VirtQueue::<FakeHal, 4>::new(&mut transport, 0, true, false).unwrap();
-        assert_eq!(queue.available_desc(), 4);
+    /// The descriptor chains which `TraceHal` has been asked to trace.
+    #[cfg(feature = "trace")]
+    static TRACES: Mutex<Vec<(TraceKind, u16, Vec<TraceDescriptor>)>> = Mutex::new(Vec::new());
 
-        // Add a buffer chain consisting of two device-readable parts followed by two
-        // device-writable parts.
-        let token = unsafe { queue.add(&[&[1, 2], &[3]], &mut [&mut [0, 0], &mut [0]]) }.unwrap();
+    /// A HAL which records the descriptor chains which it is asked to trace.
+    #[cfg(feature = "trace")]
+    struct TraceHal;
 
-        assert_eq!(queue.available_desc(), 4);
-        assert!(!queue.can_pop());
+    #[cfg(feature = "trace")]
+    unsafe impl Hal for TraceHal {
+        fn dma_alloc(pages: usize, direction: BufferDirection) -> (PhysAddr, NonNull<u8>) {
+            FakeHal::dma_alloc(pages, direction)
+        }
 
-        // Safe because the various parts of the queue are properly aligned, dereferenceable and
-        // initialised, and nothing else is accessing them at the same time.
-        unsafe {
-            let indirect_descriptor_index = (*queue.avail.as_ptr()).ring[0];
-            assert_eq!(indirect_descriptor_index, token);
-            assert_eq!(
-                (*queue.desc.as_ptr())[indirect_descriptor_index as usize].len as usize,
-                4 * size_of::<Descriptor>()
-            );
-            assert_eq!(
-                (*queue.desc.as_ptr())[indirect_descriptor_index as usize].flags,
-                DescFlags::INDIRECT
-            );
+        unsafe fn dma_dealloc(paddr: PhysAddr, vaddr: NonNull<u8>, pages: usize) -> i32 {
+            // SAFETY: Our caller upholds the same requirements.
+            unsafe { FakeHal::dma_dealloc(paddr, vaddr, pages) }
+        }
 
-            let indirect_descriptors = slice_from_raw_parts(
-                (*queue.desc.as_ptr())[indirect_descriptor_index as usize].addr
-                    as *const Descriptor,
-                4,
-            );
-            assert_eq!((*indirect_descriptors)[0].len, 2);
-            assert_eq!((*indirect_descriptors)[0].flags, DescFlags::NEXT);
-            assert_eq!((*indirect_descriptors)[0].next, 1);
-            assert_eq!((*indirect_descriptors)[1].len, 1);
-            assert_eq!((*indirect_descriptors)[1].flags, DescFlags::NEXT);
-            assert_eq!((*indirect_descriptors)[1].next, 2);
-            assert_eq!((*indirect_descriptors)[2].len, 2);
+        unsafe fn mmio_phys_to_virt(paddr: PhysAddr, size: usize) -> NonNull<u8> {
+            // SAFETY: Our caller upholds the same requirements.
+            unsafe { FakeHal::mmio_phys_to_virt(paddr, size) }
+        }
+
+        unsafe fn share(buffer: NonNull<[u8]>, direction: BufferDirection) -> PhysAddr {
+            // SAFETY: Our caller upholds the same requirements.
+            unsafe { FakeHal::share(buffer, direction) }
+        }
+
+        unsafe fn unshare(paddr: PhysAddr, buffer: NonNull<[u8]>, direction: BufferDirection) {
+            // SAFETY: Our caller upholds the same requirements.
+            unsafe { FakeHal::unshare(paddr, buffer, direction) }
+        }
+
+        fn trace(event: &TraceEvent) {
+            TRACES
+                .lock()
+                .unwrap()
+                .push((event.kind, event.token, event.descriptors().collect()));
+        }
+    }
+
+    /// Tests that each chain is traced when it is added and when it is popped, including the
+    /// contents of indirect tables.
+    #[cfg(feature = "trace")]
+    #[test]
+    fn trace_add_pop() {
+        for features in [
+            Feature::empty(),
+            Feature::RING_INDIRECT_DESC,
+            Feature::RING_PACKED,
+            Feature::RING_PACKED | Feature::RING_INDIRECT_DESC,
+        ] {
+            let mut config_space = ();
+            let (mut transport, state) = fake_transport(&mut config_space, features);
+            let mut queue =
+                VirtQueue::<TraceHal, 4>::new_with_features(&mut transport, 0, features).unwrap();
+            TRACES.lock().unwrap().clear();
+
+            let mut response = [0; 1];
+            let token = unsafe { queue.add(&[&[1, 2]], &mut [&mut response]) }.unwrap();
+            state.lock().unwrap().read_write_queue(0, |request| {
+                assert_eq!(request, vec![1, 2]);
+                vec![3]
+            });
             assert_eq!(
-                (*indirect_descriptors)[2].flags,
-                DescFlags::NEXT | DescFlags::WRITE
+                unsafe { queue.pop_used(token, &[&[1, 2]], &mut [&mut response]) },
+                Ok(1)
             );
-            assert_eq!((*indirect_descriptors)[2].next, 3);
-            assert_eq!((*indirect_descriptors)[3].len, 1);
-            assert_eq!((*indirect_descriptors)[3].flags, DescFlags::WRITE);
+
+            let traces = take(&mut *TRACES.lock().unwrap());
+            assert_eq!(traces.len(), 2);
+            let (added, popped) = (&traces[0], &traces[1]);
+            assert_eq!((added.0, added.1), (TraceKind::Add, token));
+            assert_eq!((popped.0, popped.1), (TraceKind::Pop { len: 1 }, token));
+            assert_eq!(added.2, popped.2);
+
+            let mut descriptors = added.2.as_slice();
+            if features.contains(Feature::RING_INDIRECT_DESC) {
+                assert!(descriptors[0].is_indirect());
+                assert_eq!(descriptors[0].len as usize, 2 * size_of::<Descriptor>());
+                descriptors = &descriptors[1..];
+            }
+            assert_eq!(descriptors.len(), 2);
+            assert_eq!(descriptors[0].len, 2);
+            assert_eq!(descriptors[0].flags, DescFlags::NEXT.bits());
+            assert_eq!(descriptors[1].len, 1);
+            assert!(descriptors[1].is_write());
+            assert_eq!(descriptors[1].flags, DescFlags::WRITE.bits());
         }
     }
 
-    /// Tests that the queue notifies the device about added buffers, if it hasn't suppressed
-    /// notifications.
+    #[cfg(feature = "trace")]
     #[test]
-    fn add_notify() {
+    fn stats() {
         let mut config_space = ();
-        let state = Arc::new(Mutex::new(State {
-            queues: vec![QueueStatus::default()],
-            ..Default::default()
-        }));
-        let mut transport = FakeTransport {
-            device_type: DeviceType::Block,
-            max_queue_size: 4,
-            device_features: 0,
-            config_space: NonNull::from(&mut config_space),
-            state: state.clone(),
-        };
+        let (mut transport, state) = fake_transport(&mut config_space, Feature::empty());
         let mut queue = VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false).unwrap();
 
-        // Add a buffer chain with a single device-readable part.
-        unsafe { queue.add(&[&[42]], &mut []) }.unwrap();
-
-        // Check that the transport would be notified.
-        assert_eq!(queue.should_notify(), true);
-
-        // SAFETY: the various parts of the queue are properly aligned, dereferenceable and
-        // initialised, and nothing else is accessing them at the same time.
-        unsafe {
-            // Suppress notifications.
-            (*queue.used.as_ptr()).flags = 0x01;
+        let mut tokens = Vec::new();
+        for i in 0..4 {
+            tokens.push(unsafe { queue.stage(&[&[i]], &mut []) }.unwrap());
+        }
+        assert_eq!(
+            unsafe { queue.stage(&[&[4]], &mut []) },
+            Err(Error::QueueFull)
+        );
+        queue.kick(&mut transport);
+        for (i, token) in tokens.into_iter().enumerate() {
+            assert_eq!(state.lock().unwrap().read_from_queue(0), vec![i as u8]);
+            unsafe { queue.pop_used(token, &[&[i as u8]], &mut []) }.unwrap();
         }
 
-        // Check that the transport would not be notified.
-        assert_eq!(queue.should_notify(), false);
+        assert_eq!(
+            queue.stats(),
+            QueueStats {
+                adds: 4,
+                notifies: 1,
+                notifies_suppressed: 0,
+                completions: 4,
+                queue_full: 1,
+                max_in_flight: 4,
+            }
+        );
     }
 
-    /// Tests that the queue notifies the device about added buffers, if it hasn't suppressed
-    /// notifications with the `avail_event` index.
-    #[test]
-    fn add_notify_event_idx() {
+    /// Adds and pops buffers from different threads at the same time, through the two halves of a
+    /// split queue.
+    fn split_concurrent(features: Feature) {
+        const COUNT: u8 = 100;
         let mut config_space = ();
-        let state = Arc::new(Mutex::new(State {
-            queues: vec![QueueStatus::default()],
-            ..Default::default()
-        }));
-        let mut transport = FakeTransport {
-            device_type: DeviceType::Block,
-            max_queue_size: 4,
-            device_features: Feature::RING_EVENT_IDX.bits(),
-            config_space: NonNull::from(&mut config_space),
-            state: state.clone(),
-        };
-        let mut queue = VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, true).unwrap();
-
-        // Add a buffer chain with a single device-readable part.
-        assert_eq!(unsafe { queue.add(&[&[42]], &mut []) }.unwrap(), 0);
-
-        // Check that the transport would be notified.
-        assert_eq!(queue.should_notify(), true);
+        let (mut transport, state) = fake_transport(&mut config_space, features);
+        let mut queue =
+            VirtQueue::<FakeHal, 4>::new_with_features(&mut transport, 0, features).unwrap();
+        let requests: Vec<[u8; 2]> = (0..COUNT).map(|i| [i, !i]).collect();
+
+        let (mut submitter, mut completer) = queue.split();
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                for request in &requests {
+                    let (first, second) = request.split_at(1);
+                    loop {
+                        match unsafe { submitter.add(&[first, second], &mut []) } {
+                            Err(Error::QueueFull) => thread::yield_now(),
+                            result => {
+                                result.unwrap();
+                                break;
+                            }
+                        }
+                    }
+                    // Use the buffer straight away, so the completer can pop it while the next one
+                    // is being added.
+                    state.lock().unwrap().read_write_queue(0, |input| {
+                        assert_eq!(input, request);
+                        Vec::new()
+                    });
+                }
+            });
+            scope.spawn(|| {
+                // The fake device uses buffers in order.
+                for request in &requests {
+                    let (first, second) = request.split_at(1);
+                    let token = loop {
+                        if let Some(token) = completer.peek_used() {
+                            break token;
+                        }
+                        thread::yield_now();
+                    };
+                    assert_eq!(
+                        unsafe { completer.pop_used(token, &[first, second], &mut []) },
+                        Ok(0)
+                    );
+                }
+            });
+        });
 
-        // SAFETY: the various parts of the queue are properly aligned, dereferenceable and
-        // initialised, and nothing else is accessing them at the same time.
-        unsafe {
-            // Suppress notifications.
-            (*queue.used.as_ptr()).avail_event = 1;
-        }
+        assert!(!queue.can_pop());
+        assert_eq!(queue.available_desc(), 4);
+    }
 
-        // Check that the transport would not be notified.
-        assert_eq!(queue.should_notify(), false);
+    #[test]
+    fn split_concurrent_add_pop() {
+        split_concurrent(Feature::empty());
+        split_concurrent(Feature::RING_INDIRECT_DESC);
+        split_concurrent(Feature::IN_ORDER);
+    }
 
-        // Add another buffer chain.
-        assert_eq!(unsafe { queue.add(&[&[42]], &mut []) }.unwrap(), 1);
+    #[test]
+    fn split_concurrent_add_pop_packed() {
+        split_concurrent(Feature::RING_PACKED);
+        split_concurrent(Feature::RING_PACKED | Feature::IN_ORDER);
+    }
 
-        // Check that the transport should be notified again now.
-        assert_eq!(queue.should_notify(), true);
+    /// Builds and uses a queue of the maximum size on a thread with a small stack, which would
+    /// overflow if the queue's shadow state were built or moved on the stack.
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn large_queue_small_stack() {
+        const LARGE_SIZE: usize = 32768;
+        for features in [Feature::empty(), Feature::RING_PACKED] {
+            thread::Builder::new()
+                .stack_size(64 * 1024)
+                .spawn(move || {
+                    let mut config_space = ();
+                    let (mut transport, state) = fake_transport(&mut config_space, features);
+                    transport.max_queue_size = LARGE_SIZE as u32;
+                    let mut queue = VirtQueue::<FakeHal, LARGE_SIZE>::new_with_features(
+                        &mut transport,
+                        0,
+                        features,
+                    )
+                    .unwrap();
+                    assert_eq!(queue.size(), LARGE_SIZE as u16);
+                    assert_eq!(queue.available_desc(), LARGE_SIZE);
+
+                    let mut response = [0; 2];
+                    let token = unsafe { queue.add(&[&[1, 2]], &mut [&mut response]) }.unwrap();
+                    queue.kick(&mut transport);
+                    state.lock().unwrap().read_write_queue(0, |request| {
+                        assert_eq!(request, vec![1, 2]);
+                        vec![3, 4]
+                    });
+                    assert_eq!(
+                        unsafe { queue.pop_used(token, &[&[1, 2]], &mut [&mut response]) },
+                        Ok(2)
+                    );
+                    assert_eq!(response, [3, 4]);
+                    assert_eq!(queue.available_desc(), LARGE_SIZE);
+                })
+                .unwrap()
+                .join()
+                .unwrap();
+        }
     }
 }
@@ -0,0 +1,835 @@
+//! Separate handles for the submitting and completing sides of a virtqueue, so that they can be
+//! used at the same time from different CPUs.
+
+#[cfg(feature = "trace")]
+use super::trace::TraceKind;
+use super::{
+    writable_len, CompleteState, DescFlags, Descriptor, QueueWaker, Ring, SegmentIter, SharedAddrs,
+    SubmitState, VirtQueue, NO_DESCRIPTOR,
+};
+use crate::hal::Hal;
+use crate::transport::Transport;
+use crate::{Error, Result};
+use core::mem::size_of;
+use core::sync::atomic::Ordering;
+use core::task::{Context, Poll};
+use zerocopy::FromZeroes;
+
+/// The submitting side of a virtqueue, which adds buffers to the available ring and notifies
+/// the device about them.
+///
+/// This is handed out along with a [`QueueCompleter`] for the same queue, for example by
+/// [`VirtIOBlk::split`](crate::device::blk::VirtIOBlk::split). The two may be sent to different
+/// CPUs and used concurrently, without any locking between them.
+#[derive(Debug)]
+pub struct QueueSubmitter<'a, H: Hal, const SIZE: usize> {
+    queue: &'a VirtQueue<H, SIZE>,
+}
+
+/// The completing side of a virtqueue, which pops buffers from the used ring and controls used
+/// buffer notifications.
+///
+/// This is handed out along with a [`QueueSubmitter`] for the same queue.
+#[derive(Debug)]
+pub struct QueueCompleter<'a, H: Hal, const SIZE: usize> {
+    queue: &'a VirtQueue<H, SIZE>,
+}
+
+// Safe because the submitter only accesses the queue's submitting state and the descriptors which
+// it has allocated but not yet published, and the completer only accesses the completing state and
+// the descriptors of published chains. Descriptors are handed between them through atomics, with
+// the appropriate ordering.
+unsafe impl<H: Hal, const SIZE: usize> Send for QueueSubmitter<'_, H, SIZE> {}
+unsafe impl<H: Hal, const SIZE: usize> Send for QueueCompleter<'_, H, SIZE> {}
+
+impl<'a, H: Hal, const SIZE: usize> QueueSubmitter<'a, H, SIZE> {
+    /// Creates a submitter for the given queue.
+    ///
+    /// # Safety
+    ///
+    /// Nothing else may access the submitting state of the queue while the submitter exists, except
+    /// for reading it through `notify`.
+    pub(super) unsafe fn new(queue: &'a VirtQueue<H, SIZE>) -> Self {
+        Self { queue }
+    }
+
+    /// Adds buffers to the virtqueue and makes them available to the device. See
+    /// `VirtQueue::add`.
+    ///
+    /// # Safety
+    ///
+    /// The input and output buffers must remain valid and not be accessed until a call to
+    /// `pop_used` with the returned token succeeds.
+    pub unsafe fn add<'b, 'c>(
+        &mut self,
+        inputs: &'b [&'c [u8]],
+        outputs: &'b mut [&'c mut [u8]],
+    ) -> Result<u16> {
+        // Safe because our caller has the same safety requirements.
+        let head = unsafe { self.stage(inputs, outputs) }?;
+        self.publish();
+        Ok(head)
+    }
+
+    /// Adds buffers to the virtqueue without making them available to the device yet. See
+    /// `VirtQueue::stage`.
+    ///
+    /// # Safety
+    ///
+    /// The input and output buffers must remain valid and not be accessed until a call to
+    /// `pop_used` with the returned token succeeds.
+    pub unsafe fn stage<'b, 'c>(
+        &mut self,
+        inputs: &'b [&'c [u8]],
+        outputs: &'b mut [&'c mut [u8]],
+    ) -> Result<u16> {
+        // Safe because our caller has the same safety requirements.
+        unsafe { self.stage_shared(inputs, outputs, SharedAddrs::default()) }
+    }
+
+    /// Like [`QueueSubmitter::stage`], for buffers some of which may already have been shared with
+    /// the device. See `VirtQueue::stage_shared`.
+    ///
+    /// # Safety
+    ///
+    /// The input and output buffers must remain valid and not be accessed until a call to
+    /// `pop_used_shared` with the returned token succeeds. Each physical address in `shared` must
+    /// be where the device can access the corresponding buffer until then.
+    pub unsafe fn stage_shared<'b, 'c>(
+        &mut self,
+        inputs: &'b [&'c [u8]],
+        outputs: &'b mut [&'c mut [u8]],
+        shared: SharedAddrs<'b>,
+    ) -> Result<u16> {
+        let queue = self.queue;
+        if inputs.is_empty() && outputs.is_empty() {
+            return Err(Error::InvalidParam);
+        }
+        if queue.broken.load(Ordering::Relaxed) {
+            return Err(Error::DeviceMisbehaved);
+        }
+        // Buffers which aren't physically contiguous need a descriptor for each segment.
+        let descriptors_needed = SegmentIter::<H>::new(inputs, outputs, shared).count();
+        // Chains which are too long for an indirect table fall back to direct descriptors.
+        let indirect = match &queue.indirect_tables {
+            Some(tables) => descriptors_needed > 1 && tables.fits(descriptors_needed),
+            None => false,
+        };
+        let ring_descriptors_needed = if indirect { 1 } else { descriptors_needed };
+        // This synchronises with the completer returning descriptors, so they are ours to reuse.
+        if usize::from(queue.num_free.load(Ordering::Acquire)) < ring_descriptors_needed {
+            #[cfg(feature = "trace")]
+            queue.counters.queue_full();
+            return Err(Error::QueueFull);
+        }
+        if !queue.in_order {
+            self.take_recycled();
+        }
+
+        let head = if indirect {
+            self.add_indirect(inputs, outputs, shared, descriptors_needed)
+        } else {
+            self.add_direct(inputs, outputs, shared, descriptors_needed)
+        };
+
+        let state = self.state();
+        // Safe because we own the chain until it is published.
+        unsafe {
+            state.ring.add(&queue.desc_shadow, head);
+        }
+        queue.desc_shadow.set_head(head, true);
+        state.added = state.added.wrapping_add(1);
+
+        #[cfg(feature = "trace")]
+        {
+            queue.counters.add();
+            queue.trace(TraceKind::Add, head);
+        }
+
+        Ok(head)
+    }
+
+    /// Moves all the descriptors which the completer has recycled since the last call to the front
+    /// of our free list.
+    ///
+    /// The most recently recycled chain ends up first, so descriptors are reused in the same order
+    /// as if they had been added to the free list directly.
+    fn take_recycled(&mut self) {
+        let queue = self.queue;
+        // This synchronises with the completer pushing chains, so they are ours once we see them.
+        let head = queue.recycled_head.swap(NO_DESCRIPTOR, Ordering::Acquire);
+        if head == NO_DESCRIPTOR {
+            return;
+        }
+        let mut tail = head;
+        loop {
+            // Safe because the recycled descriptors now belong to us.
+            let next = unsafe { queue.desc_shadow.get(tail) }.next;
+            if next == NO_DESCRIPTOR {
+                break;
+            }
+            tail = next;
+        }
+        let state = self.state();
+        // Safe because the recycled descriptors now belong to us.
+        unsafe {
+            queue.desc_shadow.get_mut(tail).next = state.free_head;
+        }
+        state.free_head = head;
+    }
+
+    /// Allocates descriptors from the free list for the given buffers, which have `len` segments
+    /// between them, and fills them in, in `desc_shadow`.
+    ///
+    /// Returns the index of the first descriptor in the chain.
+    fn add_direct<'b, 'c>(
+        &mut self,
+        inputs: &'b [&'c [u8]],
+        outputs: &'b mut [&'c mut [u8]],
+        shared: SharedAddrs<'b>,
+        len: usize,
+    ) -> u16 {
+        let queue = self.queue;
+        let state = self.state();
+        // allocate descriptors from free list
+        let head = state.free_head;
+        let mut last = state.free_head;
+
+        for (buffer, direction, paddr) in SegmentIter::<H>::new(inputs, outputs, shared) {
+            assert_ne!(buffer.len(), 0);
+
+            // Safe because the descriptor is on our free list.
+            let desc = unsafe { queue.desc_shadow.get_mut(state.free_head) };
+            // Safe because our caller promises that the buffers live at least until `pop_used`
+            // returns them.
+            unsafe {
+                desc.set_buf::<H>(buffer, direction, paddr, DescFlags::NEXT);
+            }
+            last = state.free_head;
+            state.free_head = desc.next;
+        }
+
+        // set last_elem.next = NULL
+        // Safe because the descriptor was on our free list, and hasn't been published yet.
+        unsafe { queue.desc_shadow.get_mut(last) }
+            .flags
+            .remove(DescFlags::NEXT);
+
+        // The descriptors were already acquired by the load in `stage_shared`.
+        queue.num_free.fetch_sub(len as u16, Ordering::Relaxed);
+
+        head
+    }
+
+    /// Fills in the indirect descriptor table of the next free descriptor with the given buffers,
+    /// which have `len` segments between them, and points the descriptor at it.
+    ///
+    /// Returns the index of the descriptor pointing to the indirect table.
+    fn add_indirect<'b, 'c>(
+        &mut self,
+        inputs: &'b [&'c [u8]],
+        outputs: &'b mut [&'c mut [u8]],
+        shared: SharedAddrs<'b>,
+        len: usize,
+    ) -> u16 {
+        let queue = self.queue;
+        let state = self.state();
+        let head = state.free_head;
+        let (table_paddr, table) = queue.indirect_tables.as_ref().unwrap().table(head, len);
+        let packed = matches!(queue.ring, Ring::Packed(_));
+
+        for (i, (buffer, direction, paddr)) in
+            SegmentIter::<H>::new(inputs, outputs, shared).enumerate()
+        {
+            let mut desc = Descriptor::new_zeroed();
+            // Safe because our caller promises that the buffers live at least until `pop_used`
+            // returns them.
+            unsafe {
+                desc.set_buf::<H>(buffer, direction, paddr, DescFlags::NEXT);
+            }
+            desc.next = (i + 1) as u16;
+            if i == len - 1 {
+                desc.flags.remove(DescFlags::NEXT);
+            }
+            if packed {
+                // Packed indirect tables are laid out like the packed ring, with the flags where
+                // the split descriptor has `next`, and chained implicitly by position rather than
+                // with `VIRTQ_DESC_F_NEXT`. The address is in the same place in both formats,
+                // which is all that `recycle_descriptors` needs.
+                desc.next = (desc.flags - DescFlags::NEXT).bits();
+                desc.flags = DescFlags::empty();
+            }
+            // Safe because the table is properly aligned, dereferenceable and initialised, and the
+            // device won't access it until the descriptor pointing to it is made available.
+            unsafe {
+                (*table.as_ptr())[i] = desc;
+            }
+        }
+
+        // Write a descriptor pointing to the indirect table. The table was already shared with the
+        // device when the pool was allocated.
+        // Safe because the descriptor is on our free list.
+        let direct_desc = unsafe { queue.desc_shadow.get_mut(head) };
+        state.free_head = direct_desc.next;
+        direct_desc.addr = table_paddr as u64;
+        direct_desc.len = (len * size_of::<Descriptor>()) as u32;
+        direct_desc.flags = DescFlags::INDIRECT;
+        // The descriptor was already acquired by the load in `stage_shared`.
+        queue.num_free.fetch_sub(1, Ordering::Relaxed);
+
+        head
+    }
+
+    /// Makes all staged descriptor chains available to the device.
+    pub(super) fn publish(&mut self) {
+        let queue = self.queue;
+        let state = self.state();
+        // Let the completer know about the chains before the device can use them, so that it
+        // doesn't mistake their used elements for invalid ones.
+        if queue.in_order {
+            queue
+                .published_end
+                .store(state.free_head, Ordering::Release);
+        }
+        queue.published.store(state.added, Ordering::Release);
+        state.ring.publish();
+    }
+
+    /// Makes all staged buffers available to the device, and returns whether the driver should
+    /// notify the device. See `VirtQueue::kick_prepare`.
+    pub fn kick_prepare(&mut self) -> bool {
+        self.publish();
+        let event_idx = self.queue.event_idx;
+        let notify = self.state().ring.kick_prepare(event_idx);
+        #[cfg(feature = "trace")]
+        if !notify {
+            self.queue.counters.notify_suppressed();
+        }
+        notify
+    }
+
+    /// Makes all staged buffers available to the device, and notifies it if necessary.
+    pub fn kick(&mut self, transport: &mut impl Transport) {
+        if self.kick_prepare() {
+            self.notify(transport);
+        }
+    }
+
+    /// Notifies the device that there are new buffers available in this queue. See
+    /// `VirtQueue::notify`.
+    pub fn notify(&self, transport: &mut impl Transport) {
+        let queue = self.queue;
+        #[cfg(feature = "trace")]
+        queue.counters.notify();
+        if queue.notification_data {
+            // Safe because nothing else modifies the submitting state while we exist.
+            let next_avail = unsafe { &*queue.submit.get() }.ring.next_avail();
+            let data = u32::from(queue.queue_idx) | u32::from(next_avail) << 16;
+            transport.notify_with_data(queue.queue_idx, data);
+        } else {
+            transport.notify(queue.queue_idx);
+        }
+    }
+
+    /// Returns the number of free descriptors. See `VirtQueue::available_desc`.
+    ///
+    /// This may increase at any time, as the completer pops buffers.
+    pub fn available_desc(&self) -> usize {
+        self.queue.available_desc()
+    }
+
+    /// Returns the submitting state of the queue.
+    fn state(&mut self) -> &mut SubmitState {
+        // Safe because we have exclusive access to the submitting state while we exist.
+        unsafe { &mut *self.queue.submit.get() }
+    }
+}
+
+impl<'a, H: Hal, const SIZE: usize> QueueCompleter<'a, H, SIZE> {
+    /// Creates a completer for the given queue.
+    ///
+    /// # Safety
+    ///
+    /// Nothing else may access the completing state of the queue while the completer exists,
+    /// except for reading it through the methods which take `&self`.
+    pub(super) unsafe fn new(queue: &'a VirtQueue<H, SIZE>) -> Self {
+        Self { queue }
+    }
+
+    /// Returns whether there is a used element that can be popped.
+    pub fn can_pop(&self) -> bool {
+        self.peek_used().is_some()
+    }
+
+    /// Returns the token of the next used element without popping it, or `None` if the used ring
+    /// is empty. See `VirtQueue::peek_used`.
+    pub fn peek_used(&self) -> Option<u16> {
+        if self.queue.in_order {
+            self.peek_used_in_order().map(|(oldest, _)| oldest)
+        } else {
+            self.state().ring.peek_used().map(|(id, _)| id)
+        }
+    }
+
+    /// Returns the token of the next used element if there is one, or otherwise registers the
+    /// current task with `waker`. See `VirtQueue::poll_used`.
+    pub fn poll_used(&self, waker: &QueueWaker, cx: &mut Context) -> Poll<u16> {
+        if let Some(token) = self.peek_used() {
+            return Poll::Ready(token);
+        }
+        waker.register(cx.waker());
+        // Check again, in case the device used some buffers before the waker was registered.
+        match self.peek_used() {
+            Some(token) => Poll::Ready(token),
+            None => Poll::Pending,
+        }
+    }
+
+    /// Returns the number of descriptor chains which have been made available to the device but
+    /// not popped yet.
+    fn in_flight(&self) -> u16 {
+        // This synchronises with the submitter publishing chains, so we can read their descriptors.
+        self.queue
+            .published
+            .load(Ordering::Acquire)
+            .wrapping_sub(self.state().popped)
+    }
+
+    /// Returns the head of the oldest descriptor chain still owned by the device, along with the
+    /// ID and used length of the last buffer in the batch which the device has used, if it has
+    /// used any.
+    ///
+    /// When `VIRTIO_F_IN_ORDER` has been negotiated the device may use a whole batch of buffers
+    /// with a single used element, for the last buffer in the batch. The batch is remembered in
+    /// `batch_last` until all of the buffers in it have been popped.
+    fn peek_used_in_order(&self) -> Option<(u16, (u16, u32))> {
+        if self.in_flight() == 0 {
+            return None;
+        }
+        let state = self.state();
+        let last = match state.batch_last {
+            Some(last) => last,
+            None => state.ring.peek_used()?,
+        };
+        Some((state.oldest, last))
+    }
+
+    /// Returns whether `index` is one of the descriptors owned by the device, when
+    /// `VIRTIO_F_IN_ORDER` has been negotiated and `oldest` is the head of the oldest chain.
+    ///
+    /// There must be at least one chain in flight.
+    fn is_in_flight_in_order(&self, oldest: u16, index: u16) -> bool {
+        let size = self.queue.size;
+        if index >= size {
+            return false;
+        }
+        // Descriptors are allocated sequentially, so the published chains are all the descriptors
+        // from `oldest` up to `published_end`, wrapping around. If they are equal then every
+        // descriptor is in flight.
+        let published_end = self.queue.published_end.load(Ordering::Acquire);
+        let published_len = match published_end.wrapping_sub(oldest) & (size - 1) {
+            0 => size,
+            len => len,
+        };
+        index.wrapping_sub(oldest) & (size - 1) < published_len
+    }
+
+    /// Returns the number of descriptors in the chain starting at `head`, counting an indirect
+    /// descriptor as one.
+    ///
+    /// The chain must have been published.
+    fn chain_len(&self, head: u16) -> u16 {
+        let desc_shadow = &self.queue.desc_shadow;
+        let mut len = 1;
+        // Safe because the chain has been published, so the submitter won't modify it until we
+        // recycle it.
+        let mut next = unsafe { desc_shadow.get(head) }.next();
+        while let Some(index) = next {
+            len += 1;
+            // Safe because the chain has been published.
+            next = unsafe { desc_shadow.get(index) }.next();
+        }
+        len
+    }
+
+    /// Unshares buffers in the list starting at descriptor index `head` and returns them to the
+    /// submitter. Unsharing may involve copying data back to the original buffers, so they must be
+    /// passed in too.
+    ///
+    /// The descriptors are pushed onto the recycled list as a single chain, which the submitter
+    /// moves to the front of its free list the next time it adds buffers.
+    ///
+    /// # Safety
+    ///
+    /// The buffers in `inputs` and `outputs` must match the set of buffers originally added to the
+    /// queue by `add`.
+    ///
+    /// If the buffers don't match the chain then this returns [`Error::InvalidParam`] without
+    /// changing anything, and marks the queue as broken.
+    ///
+    /// If `VIRTIO_F_IN_ORDER` has been negotiated then descriptors are allocated sequentially, so
+    /// they are left in place rather than being moved to the free list, and the chain is recycled
+    /// without following the `next` fields.
+    pub(super) unsafe fn recycle_descriptors<'b, 'c>(
+        &mut self,
+        head: u16,
+        inputs: &'b [&'c [u8]],
+        outputs: &'b mut [&'c mut [u8]],
+        shared: SharedAddrs<'b>,
+    ) -> Result {
+        let queue = self.queue;
+        let desc_shadow = &queue.desc_shadow;
+        let Some(segments) = self.matching_segments(head, inputs, outputs, shared) else {
+            queue.broken.store(true, Ordering::Relaxed);
+            return Err(Error::InvalidParam);
+        };
+
+        desc_shadow.set_head(head, false);
+        // Safe because the chain has been published, and the device has finished with it.
+        let head_desc = unsafe { desc_shadow.get_mut(head) };
+        let (tail, len) = if head_desc.flags.contains(DescFlags::INDIRECT) {
+            // Move the descriptor pointing to the indirect table to the free list. The table goes
+            // with it.
+            head_desc.unset_buf();
+
+            // Unshare the buffers in the indirect descriptor table.
+            let (_, table) = queue
+                .indirect_tables
+                .as_ref()
+                .unwrap()
+                .table(head, segments);
+            for (i, (buffer, direction, shared_paddr)) in
+                SegmentIter::<H>::new(inputs, outputs, shared).enumerate()
+            {
+                if shared_paddr.is_some() {
+                    // The caller shared the buffer, so it is up to them to unshare it.
+                    continue;
+                }
+
+                // Safe because the table is properly aligned, dereferenceable and initialised, and
+                // the device has finished with it.
+                let paddr = unsafe { (*table.as_ptr())[i].addr };
+                // SAFETY: The caller ensures that the buffer is valid and matches the descriptor
+                // from which we got `paddr`.
+                unsafe {
+                    // Unshare the buffer (and perhaps copy its contents back to the original
+                    // buffer).
+                    H::unshare(paddr as usize, buffer, direction);
+                }
+            }
+            (head, 1)
+        } else {
+            let mut next = head;
+            let mut tail = head;
+
+            for (i, (buffer, direction, shared_paddr)) in
+                SegmentIter::<H>::new(inputs, outputs, shared).enumerate()
+            {
+                let desc_index = if queue.in_order {
+                    head.wrapping_add(i as u16) & (queue.size - 1)
+                } else {
+                    next
+                };
+                // Safe because the chain has been published, and the device has finished with it.
+                let desc = unsafe { desc_shadow.get_mut(desc_index) };
+
+                let paddr = desc.addr;
+                next = desc.next;
+                desc.unset_buf();
+                tail = desc_index;
+
+                if let Ring::Split(split) = &queue.ring {
+                    // Safe because we still own the descriptor.
+                    unsafe {
+                        split.write_desc(desc_index, desc_shadow);
+                    }
+                }
+
+                if shared_paddr.is_some() {
+                    // The caller shared the buffer, so it is up to them to unshare it.
+                    continue;
+                }
+                // SAFETY: The caller ensures that the buffer is valid and matches the descriptor
+                // from which we got `paddr`.
+                unsafe {
+                    // Unshare the buffer (and perhaps copy its contents back to the original buffer).
+                    H::unshare(paddr as usize, buffer, direction);
+                }
+            }
+
+            (tail, segments as u16)
+        };
+
+        if !queue.in_order {
+            let mut recycled_head = queue.recycled_head.load(Ordering::Relaxed);
+            loop {
+                // Safe because we own the chain until it is pushed.
+                let tail_desc = unsafe { desc_shadow.get_mut(tail) };
+                tail_desc.next = recycled_head;
+                if let Ring::Split(split) = &queue.ring {
+                    // Safe because we own the chain until it is pushed.
+                    unsafe {
+                        split.write_desc(tail, desc_shadow);
+                    }
+                }
+                // This synchronises with the submitter taking the recycled list, so it sees the
+                // descriptors as we left them.
+                match queue.recycled_head.compare_exchange_weak(
+                    recycled_head,
+                    head,
+                    Ordering::Release,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => break,
+                    Err(current) => recycled_head = current,
+                }
+            }
+        }
+        // This synchronises with the submitter checking how many descriptors are free, so that
+        // it doesn't reuse them before we have finished with them.
+        queue.num_free.fetch_add(len, Ordering::Release);
+        Ok(())
+    }
+
+    /// Returns the number of segments in the given buffers, if they are all non-empty and there is
+    /// a descriptor for each of them in the published chain starting at `head`, or in its indirect
+    /// table.
+    fn matching_segments<'b, 'c>(
+        &self,
+        head: u16,
+        inputs: &'b [&'c [u8]],
+        outputs: &'b mut [&'c mut [u8]],
+        shared: SharedAddrs<'b>,
+    ) -> Option<usize> {
+        let queue = self.queue;
+        let mut segments = 0;
+        for (buffer, _, _) in SegmentIter::<H>::new(inputs, outputs, shared) {
+            if buffer.is_empty() {
+                return None;
+            }
+            segments += 1;
+        }
+
+        // Safe because the chain has been published, so the submitter won't modify it until we
+        // recycle it.
+        let head_desc = unsafe { queue.desc_shadow.get(head) };
+        if head_desc.flags.contains(DescFlags::INDIRECT) {
+            let fits = matches!(&queue.indirect_tables, Some(tables) if tables.fits(segments));
+            return (fits && head_desc.len as usize == segments * size_of::<Descriptor>())
+                .then_some(segments);
+        }
+        // A chain can't be longer than the queue, so don't follow a loop forever.
+        let mut len = 1;
+        let mut next = head_desc.next();
+        while let Some(index) = next {
+            if len >= usize::from(queue.size) || index >= queue.size {
+                return None;
+            }
+            len += 1;
+            // Safe because the chain has been published.
+            next = unsafe { queue.desc_shadow.get(index) }.next();
+        }
+        (len == segments).then_some(segments)
+    }
+
+    /// Pops the used element for the given token. See `VirtQueue::pop_used`.
+    ///
+    /// # Safety
+    ///
+    /// The buffers in `inputs` and `outputs` must match the set of buffers originally added to the
+    /// queue by `add` when it returned the token being passed in here.
+    pub unsafe fn pop_used<'b, 'c>(
+        &mut self,
+        token: u16,
+        inputs: &'b [&'c [u8]],
+        outputs: &'b mut [&'c mut [u8]],
+    ) -> Result<u32> {
+        // Safe because our caller has the same safety requirements.
+        unsafe { self.pop_used_shared(token, inputs, outputs, SharedAddrs::default()) }
+    }
+
+    /// Like [`QueueCompleter::pop_used`], for buffers which were added by `stage_shared`.
+    ///
+    /// # Safety
+    ///
+    /// The buffers in `inputs` and `outputs` and the addresses in `shared` must match those
+    /// originally added to the queue when it returned the token being passed in here.
+    pub unsafe fn pop_used_shared<'b, 'c>(
+        &mut self,
+        token: u16,
+        inputs: &'b [&'c [u8]],
+        outputs: &'b mut [&'c mut [u8]],
+        shared: SharedAddrs<'b>,
+    ) -> Result<u32> {
+        let queue = self.queue;
+        if queue.broken.load(Ordering::Relaxed) {
+            return Err(Error::DeviceMisbehaved);
+        }
+        let in_flight = self.in_flight();
+        if let Err(e) = self.state().ring.check_used(in_flight) {
+            queue.broken.store(true, Ordering::Relaxed);
+            return Err(e);
+        }
+        if queue.in_order {
+            // Safe because the caller ensures the buffers are valid and match the descriptor.
+            return unsafe { self.pop_used_in_order(token, inputs, outputs, shared) };
+        }
+
+        // Get the index of the start of the descriptor chain for the next element in the used ring.
+        let Some((index, len)) = self.state().ring.peek_used() else {
+            return Err(Error::NotReady);
+        };
+
+        if !queue.desc_shadow.is_head(index) {
+            // The device claims to have used a chain which it doesn't own.
+            queue.broken.store(true, Ordering::Relaxed);
+            return Err(Error::DeviceMisbehaved);
+        }
+        if index != token {
+            // The device used a different descriptor chain to the one we were expecting.
+            return Err(Error::WrongToken);
+        }
+        if len > writable_len(outputs) {
+            queue.broken.store(true, Ordering::Relaxed);
+            return Err(Error::DeviceMisbehaved);
+        }
+
+        // This must be worked out before the descriptors are returned to the free list.
+        let chain_len = self.chain_len(index);
+        #[cfg(feature = "trace")]
+        {
+            queue.trace(TraceKind::Pop { len }, index);
+            queue.counters.complete();
+        }
+        // Safe because the caller ensures the buffers are valid and match the descriptor.
+        unsafe {
+            self.recycle_descriptors(index, inputs, outputs, shared)?;
+        }
+        let state = self.state_mut();
+        state.ring.pop_used(chain_len, queue.event_idx);
+        state.popped = state.popped.wrapping_add(1);
+
+        Ok(len)
+    }
+
+    /// Implements `pop_used` for when `VIRTIO_F_IN_ORDER` has been negotiated.
+    ///
+    /// Ref: linux virtio_ring.c virtqueue_get_buf_ctx_split_in_order
+    ///
+    /// # Safety
+    ///
+    /// The buffers in `inputs` and `outputs` must match the set of buffers originally added to the
+    /// queue by `add` when it returned the token being passed in here.
+    unsafe fn pop_used_in_order<'b, 'c>(
+        &mut self,
+        token: u16,
+        inputs: &'b [&'c [u8]],
+        outputs: &'b mut [&'c mut [u8]],
+        shared: SharedAddrs<'b>,
+    ) -> Result<u32> {
+        let queue = self.queue;
+        let Some((oldest, (last, last_len))) = self.peek_used_in_order() else {
+            return Err(Error::NotReady);
+        };
+
+        if self.state().batch_last.is_none() && !self.is_in_flight_in_order(oldest, last) {
+            // The device claims to have used a chain which it doesn't own.
+            queue.broken.store(true, Ordering::Relaxed);
+            return Err(Error::DeviceMisbehaved);
+        }
+        if oldest != token {
+            // Buffers are always used in order, so this can't be the next one.
+            return Err(Error::WrongToken);
+        }
+        // The chain length is known without walking it, as descriptors are allocated sequentially.
+        // Safe because the chain has been published.
+        let chain_len = if unsafe { queue.desc_shadow.get(oldest) }
+            .flags
+            .contains(DescFlags::INDIRECT)
+        {
+            1
+        } else {
+            SegmentIter::<H>::new(inputs, outputs, shared).count() as u16
+        };
+        if oldest != last && last.wrapping_sub(oldest) & (queue.size - 1) < chain_len {
+            // The device claims to have used a batch which ends partway through this chain.
+            queue.broken.store(true, Ordering::Relaxed);
+            return Err(Error::DeviceMisbehaved);
+        }
+        if oldest == last && last_len > writable_len(outputs) {
+            queue.broken.store(true, Ordering::Relaxed);
+            return Err(Error::DeviceMisbehaved);
+        }
+
+        let len = if oldest == last {
+            // This is the end of the batch, or a buffer which was used on its own.
+            self.state_mut().batch_last = None;
+            last_len
+        } else {
+            // The device didn't report the length of buffers before the end of the batch.
+            self.state_mut().batch_last = Some((last, last_len));
+            outputs.iter().map(|output| output.len() as u32).sum()
+        };
+
+        #[cfg(feature = "trace")]
+        {
+            queue.trace(TraceKind::Pop { len }, oldest);
+            queue.counters.complete();
+        }
+        // Safe because the caller ensures the buffers are valid and match the descriptor.
+        unsafe {
+            self.recycle_descriptors(oldest, inputs, outputs, shared)?;
+        }
+        let state = self.state_mut();
+        state.ring.pop_used(chain_len, queue.event_idx);
+        state.popped = state.popped.wrapping_add(1);
+        state.oldest = oldest.wrapping_add(chain_len) & (queue.size - 1);
+
+        Ok(len)
+    }
+
+    /// Asks the device not to send used buffer notifications for this queue. See
+    /// `VirtQueue::disable_callbacks`.
+    pub fn disable_callbacks(&mut self) {
+        let event_idx = self.queue.event_idx;
+        self.state_mut().ring.disable_callbacks(event_idx);
+    }
+
+    /// Asks the device to send a used buffer notification the next time it uses a buffer. See
+    /// `VirtQueue::enable_callbacks`.
+    pub fn enable_callbacks(&mut self) -> bool {
+        let event_idx = self.queue.event_idx;
+        let state = self.state_mut();
+        state.ring.enable_callbacks(event_idx, 0) && state.batch_last.is_none()
+    }
+
+    /// Like [`QueueCompleter::enable_callbacks`], but asks the device to wait until it has used
+    /// about three quarters of the buffers available to it. See
+    /// `VirtQueue::enable_callbacks_delayed`.
+    pub fn enable_callbacks_delayed(&mut self) -> bool {
+        let queue = self.queue;
+        // Packed rings count descriptors rather than buffers, as used descriptors are written back
+        // to the slots of the descriptors which were made available.
+        let in_flight = match &queue.ring {
+            Ring::Split(_) => self.in_flight(),
+            Ring::Packed(_) => queue.size - queue.num_free.load(Ordering::Relaxed),
+        };
+        let delay = (u32::from(in_flight) * 3 / 4) as u16;
+        self.state_mut()
+            .ring
+            .enable_callbacks(queue.event_idx, delay)
+    }
+
+    /// Returns the completing state of the queue, for reading.
+    fn state(&self) -> &CompleteState {
+        // Safe because nothing else modifies the completing state while we exist, and we don't
+        // hand out a mutable reference to it at the same time.
+        unsafe { &*self.queue.complete.get() }
+    }
+
+    /// Returns the completing state of the queue.
+    fn state_mut(&mut self) -> &mut CompleteState {
+        // Safe because we have exclusive access to the completing state while we exist.
+        unsafe { &mut *self.queue.complete.get() }
+    }
+}
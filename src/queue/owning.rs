@@ -0,0 +1,334 @@
+//! A safe wrapper around a [`VirtQueue`] which takes ownership of the buffers added to it.
+
+use super::{SharedAddrs, VirtQueue};
+use crate::hal::Hal;
+use crate::transport::Transport;
+#[cfg(feature = "trace")]
+use crate::QueueStats;
+use crate::{Error, Result};
+use alloc::vec::Vec;
+use bitflags::Flags;
+
+/// A buffer, or group of buffers, which can be moved into an [`OwningQueue`] and handed back once
+/// the device has used it.
+///
+/// # Safety
+///
+/// The slices passed to the closure by `with_slices` must not be stored inline in `Self`: they
+/// must stay valid and at the same address when the value is moved, until it is dropped. Every
+/// call to `with_slices` must pass the same slices, in the same order, unless the value has been
+/// mutated through some other method in between.
+pub unsafe trait QueueBuffer {
+    /// Calls `f` with the parts of the buffer which the device may read and the parts which it may
+    /// write.
+    fn with_slices<R>(
+        &mut self,
+        f: impl for<'a, 'b> FnOnce(&'a [&'b [u8]], &'a mut [&'b mut [u8]]) -> R,
+    ) -> R;
+}
+
+/// A [`VirtQueue`] which owns the buffers it has made available to the device.
+///
+/// Adding a buffer moves it into the queue, and popping a used element hands the same buffer back
+/// along with the length used by the device, so the token bookkeeping which `VirtQueue::add` and
+/// `VirtQueue::pop_used` leave to the caller can't go wrong.
+#[derive(Debug)]
+pub struct OwningQueue<H: Hal, const SIZE: usize, B: QueueBuffer> {
+    queue: VirtQueue<H, SIZE>,
+    /// The buffers currently owned by the device, indexed by token.
+    ///
+    /// This is on the heap rather than inline so that large queues don't need large stack frames.
+    buffers: Vec<Option<B>>,
+}
+
+impl<H: Hal, const SIZE: usize, B: QueueBuffer> OwningQueue<H, SIZE, B> {
+    /// Wraps the given virtqueue, which must not have any buffers in it yet.
+    pub fn new(queue: VirtQueue<H, SIZE>) -> Self {
+        let buffers = empty_buffers(queue.size());
+        Self { queue, buffers }
+    }
+
+    /// Moves the given buffer into the virtqueue, and returns a token identifying it.
+    ///
+    /// The buffer is dropped if it can't be added.
+    pub fn add(&mut self, mut buffer: B) -> Result<u16> {
+        let queue = &mut self.queue;
+        // Safe because the `QueueBuffer` contract ensures that the slices stay valid while the
+        // buffer is moved into `buffers`, and it isn't accessed again until it is popped.
+        let token = buffer.with_slices(|inputs, outputs| unsafe { queue.add(inputs, outputs) })?;
+        let previous = self.buffers[usize::from(token)].replace(buffer);
+        // The token of a chain which the device still owns can't be given out again.
+        assert!(previous.is_none());
+        Ok(token)
+    }
+
+    /// Pops the next used element from the virtqueue, returning the buffer which was added for it
+    /// and the length used by the device.
+    ///
+    /// Returns [`Error::NotReady`] if the device hasn't used any buffers, or
+    /// [`Error::DeviceMisbehaved`] if it claims to have used a buffer which it doesn't own.
+    pub fn pop_used(&mut self) -> Result<(B, u32)> {
+        let token = self.queue.peek_used().ok_or(Error::NotReady)?;
+        let mut buffer = self
+            .buffers
+            .get_mut(usize::from(token))
+            .and_then(Option::take)
+            .ok_or(Error::DeviceMisbehaved)?;
+
+        let queue = &mut self.queue;
+        // Safe because the buffer was stored under this token when it was added, and the
+        // `QueueBuffer` contract ensures that it passes the same slices as it did then.
+        match buffer
+            .with_slices(|inputs, outputs| unsafe { queue.pop_used(token, inputs, outputs) })
+        {
+            Ok(len) => Ok((buffer, len)),
+            Err(e) => {
+                self.buffers[usize::from(token)] = Some(buffer);
+                Err(e)
+            }
+        }
+    }
+
+    /// Resets the queue on the device and sets it up again from scratch, unsharing and dropping all
+    /// the buffers which the device owned.
+    ///
+    /// This requires `VIRTIO_F_RING_RESET` to have been negotiated. See [`VirtQueue::reset`].
+    pub fn reset<T: Transport, F: Flags<Bits = u64>>(
+        &mut self,
+        transport: &mut T,
+        negotiated_features: F,
+        max_size: Option<u16>,
+    ) -> Result {
+        let buffers = &mut self.buffers;
+        self.queue
+            .reset_and_reclaim(transport, negotiated_features, max_size, |queue| {
+                for (token, buffer) in buffers.iter_mut().enumerate() {
+                    if let Some(buffer) = buffer {
+                        // Safe because the buffer was stored under this token when it was added,
+                        // the `QueueBuffer` contract ensures that it passes the same slices as it
+                        // did then, and the queue has been reset so the device won't access them.
+                        buffer.with_slices(|inputs, outputs| unsafe {
+                            queue.reclaim(token as u16, inputs, outputs, SharedAddrs::default())
+                        });
+                    }
+                }
+            })?;
+        self.buffers = empty_buffers(self.queue.size());
+        Ok(())
+    }
+
+    /// Makes all added buffers available to the device, and notifies it if necessary.
+    pub fn kick(&mut self, transport: &mut impl Transport) {
+        self.queue.kick(transport)
+    }
+
+    /// Asks the device not to send used buffer notifications for this queue.
+    pub fn disable_callbacks(&mut self) {
+        self.queue.disable_callbacks()
+    }
+
+    /// Asks the device to send a used buffer notification the next time it uses a buffer.
+    ///
+    /// Returns false if there are already used buffers waiting to be popped.
+    pub fn enable_callbacks(&mut self) -> bool {
+        self.queue.enable_callbacks()
+    }
+
+    /// Asks the device to send a used buffer notification once it has used about three quarters of
+    /// the buffers available to it, if `VIRTIO_F_EVENT_IDX` has been negotiated.
+    ///
+    /// Returns false if it has already used that many buffers.
+    pub fn enable_callbacks_delayed(&mut self) -> bool {
+        self.queue.enable_callbacks_delayed()
+    }
+
+    /// Returns whether there is a used element that can be popped.
+    pub fn can_pop(&self) -> bool {
+        self.queue.can_pop()
+    }
+
+    /// Returns the token of the next used element without popping it, or `None` if the used ring
+    /// is empty.
+    pub fn peek_used(&self) -> Option<u16> {
+        self.queue.peek_used()
+    }
+
+    /// Returns the number of free descriptors.
+    pub fn available_desc(&self) -> usize {
+        self.queue.available_desc()
+    }
+
+    /// Returns the size of the queue, as negotiated with the device.
+    pub fn size(&self) -> u16 {
+        self.queue.size()
+    }
+
+    /// Returns the counters of the queue. See [`VirtQueue::stats`].
+    #[cfg(feature = "trace")]
+    pub fn stats(&self) -> QueueStats {
+        self.queue.stats()
+    }
+}
+
+/// Returns a slot for the buffer of each token of a queue with the given size.
+fn empty_buffers<B>(size: u16) -> Vec<Option<B>> {
+    let mut buffers = Vec::new();
+    buffers.resize_with(usize::from(size), || None);
+    buffers
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        device::common::Feature,
+        hal::{fake::FakeHal, BufferDirection, PhysAddr},
+        transport::{
+            fake::{FakeTransport, QueueStatus, State},
+            DeviceType,
+        },
+    };
+    use core::{
+        ptr::NonNull,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+    use std::sync::{Arc, Mutex};
+
+    /// The number of buffers which `CountingHal` has shared and not yet unshared.
+    static SHARED_BUFFERS: AtomicUsize = AtomicUsize::new(0);
+
+    /// A HAL which counts how many buffers are shared.
+    struct CountingHal;
+
+    unsafe impl Hal for CountingHal {
+        fn dma_alloc(pages: usize, direction: BufferDirection) -> (PhysAddr, NonNull<u8>) {
+            FakeHal::dma_alloc(pages, direction)
+        }
+
+        unsafe fn dma_dealloc(paddr: PhysAddr, vaddr: NonNull<u8>, pages: usize) -> i32 {
+            // SAFETY: Our caller upholds the same requirements.
+            unsafe { FakeHal::dma_dealloc(paddr, vaddr, pages) }
+        }
+
+        unsafe fn mmio_phys_to_virt(paddr: PhysAddr, size: usize) -> NonNull<u8> {
+            // SAFETY: Our caller upholds the same requirements.
+            unsafe { FakeHal::mmio_phys_to_virt(paddr, size) }
+        }
+
+        unsafe fn share(buffer: NonNull<[u8]>, direction: BufferDirection) -> PhysAddr {
+            SHARED_BUFFERS.fetch_add(1, Ordering::Relaxed);
+            // SAFETY: Our caller upholds the same requirements.
+            unsafe { FakeHal::share(buffer, direction) }
+        }
+
+        unsafe fn unshare(paddr: PhysAddr, buffer: NonNull<[u8]>, direction: BufferDirection) {
+            SHARED_BUFFERS.fetch_sub(1, Ordering::Relaxed);
+            // SAFETY: Our caller upholds the same requirements.
+            unsafe { FakeHal::unshare(paddr, buffer, direction) }
+        }
+    }
+
+    /// A request and a response buffer, both stored on the heap.
+    #[derive(Debug)]
+    struct TestBuffer {
+        request: Box<[u8]>,
+        response: Box<[u8]>,
+    }
+
+    unsafe impl QueueBuffer for TestBuffer {
+        fn with_slices<R>(
+            &mut self,
+            f: impl for<'a, 'b> FnOnce(&'a [&'b [u8]], &'a mut [&'b mut [u8]]) -> R,
+        ) -> R {
+            f(&[&self.request], &mut [&mut self.response])
+        }
+    }
+
+    fn test_buffer(request: &[u8]) -> TestBuffer {
+        TestBuffer {
+            request: request.into(),
+            response: vec![0; 4].into(),
+        }
+    }
+
+    #[test]
+    fn add_pop() {
+        let mut config_space = ();
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default()],
+            ..Default::default()
+        }));
+        let mut transport = FakeTransport {
+            device_type: DeviceType::Block,
+            max_queue_size: 4,
+            device_features: 0,
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut queue = OwningQueue::<FakeHal, 4, TestBuffer>::new(
+            VirtQueue::new(&mut transport, 0, false, false).unwrap(),
+        );
+
+        assert_eq!(queue.pop_used().unwrap_err(), Error::NotReady);
+        assert_eq!(queue.add(test_buffer(&[1, 2])).unwrap(), 0);
+        assert_eq!(queue.add(test_buffer(&[3])).unwrap(), 2);
+        assert_eq!(queue.available_desc(), 0);
+
+        state.lock().unwrap().read_write_queue(0, |request| {
+            assert_eq!(request, vec![1, 2]);
+            vec![4, 5, 6, 7]
+        });
+
+        // The first buffer is handed back with the response written by the device.
+        assert_eq!(queue.peek_used(), Some(0));
+        let (buffer, len) = queue.pop_used().unwrap();
+        assert_eq!(&*buffer.request, &[1, 2]);
+        assert_eq!(&*buffer.response, &[4, 5, 6, 7]);
+        assert_eq!(len, 4);
+
+        // The second buffer is still owned by the device.
+        assert!(!queue.can_pop());
+        assert_eq!(queue.pop_used().unwrap_err(), Error::NotReady);
+
+        state.lock().unwrap().read_write_queue(0, |request| {
+            assert_eq!(request, vec![3]);
+            vec![8]
+        });
+        let (buffer, _) = queue.pop_used().unwrap();
+        assert_eq!(&*buffer.request, &[3]);
+        assert_eq!(&*buffer.response, &[8, 0, 0, 0]);
+        assert_eq!(queue.available_desc(), 4);
+    }
+
+    /// Tests that resetting the queue unshares the buffers which the device hadn't used.
+    #[test]
+    fn reset_unshares() {
+        let mut config_space = ();
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default()],
+            ..Default::default()
+        }));
+        let mut transport = FakeTransport {
+            device_type: DeviceType::Block,
+            max_queue_size: 4,
+            device_features: Feature::RING_RESET.bits(),
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut queue = OwningQueue::<CountingHal, 4, TestBuffer>::new(
+            VirtQueue::new_with_features(&mut transport, 0, Feature::RING_RESET).unwrap(),
+        );
+
+        queue.add(test_buffer(&[1, 2])).unwrap();
+        queue.add(test_buffer(&[3])).unwrap();
+        assert_eq!(SHARED_BUFFERS.load(Ordering::Relaxed), 4);
+
+        queue
+            .reset(&mut transport, Feature::RING_RESET, None)
+            .unwrap();
+        assert_eq!(state.lock().unwrap().queues_reset, 1);
+        assert_eq!(SHARED_BUFFERS.load(Ordering::Relaxed), 0);
+        assert_eq!(queue.available_desc(), 4);
+        assert_eq!(queue.pop_used().unwrap_err(), Error::NotReady);
+    }
+}
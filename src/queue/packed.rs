@@ -0,0 +1,485 @@
+//! The packed virtqueue layout.
+//!
+//! Ref: 2.8 Packed Virtqueues
+
+use super::{Barriers, DescFlags, DescShadow, VirtQueueLayout};
+use crate::barrier::Barrier;
+use crate::hal::Hal;
+use crate::nonnull_slice_from_raw_parts;
+use core::ptr::{addr_of, NonNull};
+use zerocopy::{AsBytes, FromBytes, FromZeroes};
+
+/// A descriptor in the packed ring.
+///
+/// The same descriptor is written by the driver to make a buffer available, and then overwritten
+/// by the device to mark a buffer as used.
+///
+/// Ref: 2.8.13 Packed Virtqueue Descriptor Format
+#[repr(C, align(16))]
+#[derive(AsBytes, Clone, Debug, FromBytes, FromZeroes)]
+pub(crate) struct PackedDescriptor {
+    pub(super) addr: u64,
+    pub(super) len: u32,
+    pub(super) id: u16,
+    pub(super) flags: DescFlags,
+}
+
+/// The structure used by both the driver and the device to suppress notifications from the other.
+///
+/// Ref: 2.8.14 Event Suppression Structure Format
+#[repr(C)]
+#[derive(AsBytes, Clone, Debug, FromBytes, FromZeroes)]
+pub(crate) struct EventSuppression {
+    /// The descriptor ring offset (bits 0-14) and wrap counter (bit 15) at which an event is
+    /// requested, if `flags` is `RING_EVENT_FLAGS_DESC`.
+    pub(super) off_wrap: u16,
+    pub(super) flags: u16,
+}
+
+/// Events are enabled.
+const RING_EVENT_FLAGS_ENABLE: u16 = 0x0;
+/// Events are disabled.
+const RING_EVENT_FLAGS_DISABLE: u16 = 0x1;
+/// Events are enabled for a specific descriptor, given by `off_wrap`. Only valid if
+/// `VIRTIO_F_EVENT_IDX` has been negotiated.
+const RING_EVENT_FLAGS_DESC: u16 = 0x2;
+
+/// The descriptor ring and event suppression structures of a packed virtqueue.
+#[derive(Clone, Debug)]
+pub(crate) struct PackedRing {
+    /// Descriptor ring
+    ///
+    /// Both the driver and the device write to this, so the driver only reads back used
+    /// descriptors, and never trusts anything else it finds here.
+    pub(super) desc: NonNull<[PackedDescriptor]>,
+    /// Driver event suppression structure, written by the driver.
+    pub(super) driver_event: NonNull<EventSuppression>,
+    /// Device event suppression structure, written by the device.
+    pub(super) device_event: NonNull<EventSuppression>,
+    /// The number of descriptors in the ring.
+    size: u16,
+    /// The memory barriers to use when accessing the ring.
+    barriers: Barriers,
+}
+
+impl PackedRing {
+    pub(crate) fn new<H: Hal>(layout: &VirtQueueLayout<H>, size: u16, barriers: Barriers) -> Self {
+        let desc = nonnull_slice_from_raw_parts(
+            layout.descriptors_vaddr().cast::<PackedDescriptor>(),
+            size.into(),
+        );
+        let driver_event = layout.avail_vaddr().cast();
+        let device_event = layout.used_vaddr().cast();
+        // Safe because the layout has just been allocated, and so nothing else is accessing it yet.
+        unsafe {
+            for i in 0..usize::from(size) {
+                (*desc.as_ptr())[i] = PackedDescriptor::new_zeroed();
+            }
+            *driver_event.as_ptr() = EventSuppression {
+                off_wrap: 0,
+                flags: RING_EVENT_FLAGS_ENABLE,
+            };
+            *device_event.as_ptr() = EventSuppression::new_zeroed();
+        }
+        Self {
+            desc,
+            driver_event,
+            device_event,
+            size,
+            barriers,
+        }
+    }
+}
+
+/// The driver's position in a packed ring for making descriptors available to the device.
+#[derive(Debug)]
+pub(crate) struct PackedSubmit {
+    pub(super) ring: PackedRing,
+    /// The position in the ring at which the next available descriptor will be written.
+    next_avail: u16,
+    /// The driver's ring wrap counter, flipped each time `next_avail` wraps around.
+    avail_wrap_counter: bool,
+    /// The number of descriptors added since the last call to `kick_prepare`.
+    num_added: u16,
+    /// The slot and flags of the head descriptor of the first chain added since the last call to
+    /// `publish`.
+    ///
+    /// The device reads available descriptors in order, so it won't see any of the staged chains
+    /// until this is written.
+    staged_head: Option<(u16, DescFlags)>,
+}
+
+impl PackedSubmit {
+    pub(crate) fn new(ring: PackedRing) -> Self {
+        Self {
+            ring,
+            next_avail: 0,
+            avail_wrap_counter: true,
+            num_added: 0,
+            staged_head: None,
+        }
+    }
+
+    /// Writes the descriptor chain starting at `head` in `desc_shadow` to consecutive slots of the
+    /// ring, using `head` as the buffer ID, to be made available to the device by `publish`.
+    ///
+    /// The caller must make sure that there are enough free slots in the ring for the whole chain.
+    ///
+    /// # Safety
+    ///
+    /// The caller must own the descriptors of the chain in `desc_shadow`.
+    pub(crate) unsafe fn add<H: Hal>(&mut self, desc_shadow: &DescShadow<H>, head: u16) {
+        let head_slot = self.next_avail;
+        // Safe because our caller owns the chain.
+        let head_flags = unsafe { desc_shadow.get(head) }.flags | self.avail_used_flags();
+        let mut count = 0;
+        let mut next = Some(head);
+        while let Some(index) = next {
+            // Safe because our caller owns the chain.
+            let desc = unsafe { desc_shadow.get(index) };
+            let slot = usize::from(self.next_avail);
+            // Safe because self.desc is properly aligned, dereferenceable and initialised, and the
+            // device doesn't access this slot until the head descriptor is made available below.
+            unsafe {
+                let packed = &mut (*self.ring.desc.as_ptr())[slot];
+                packed.addr = desc.addr;
+                packed.len = desc.len;
+                packed.id = head;
+                if index != head {
+                    packed.flags = desc.flags | self.avail_used_flags();
+                }
+            }
+            count += 1;
+            self.next_avail += 1;
+            if self.next_avail == self.ring.size {
+                self.next_avail = 0;
+                self.avail_wrap_counter = !self.avail_wrap_counter;
+            }
+            next = desc.next();
+        }
+        self.num_added += count;
+
+        if self.staged_head.is_none() {
+            self.staged_head = Some((head_slot, head_flags));
+        } else {
+            // The device won't read this until the first staged head is published.
+            // Safe because self.desc is properly aligned, dereferenceable and initialised.
+            unsafe {
+                (*self.ring.desc.as_ptr())[usize::from(head_slot)].flags = head_flags;
+            }
+        }
+    }
+
+    /// Makes all staged descriptor chains available to the device.
+    pub(crate) fn publish(&mut self) {
+        let Some((head_slot, head_flags)) = self.staged_head.take() else {
+            return;
+        };
+
+        // Write barrier so that device sees the rest of the chains before the first head
+        // descriptor is made available.
+        self.ring.barriers.issue(Barrier::Write);
+
+        // Safe because self.desc is properly aligned, dereferenceable and initialised.
+        unsafe {
+            (*self.ring.desc.as_ptr())[usize::from(head_slot)].flags = head_flags;
+        }
+    }
+
+    /// Returns the position of the next available descriptor, with the driver's wrap counter in
+    /// bit 15.
+    pub(crate) fn next_avail_off_wrap(&self) -> u16 {
+        self.next_avail | u16::from(self.avail_wrap_counter) << 15
+    }
+
+    /// Returns the `AVAIL` and `USED` flags to mark a descriptor available with the current driver
+    /// wrap counter.
+    fn avail_used_flags(&self) -> DescFlags {
+        if self.avail_wrap_counter {
+            DescFlags::AVAIL
+        } else {
+            DescFlags::USED
+        }
+    }
+
+    /// Returns whether the driver should notify the device about the descriptors added since the
+    /// last call.
+    ///
+    /// Ref: linux virtio_ring.c virtqueue_kick_prepare_packed
+    pub(crate) fn kick_prepare(&mut self) -> bool {
+        // Full barrier so that the device sees the new available descriptors before we check
+        // whether it wants to be notified.
+        self.ring.barriers.issue(Barrier::Full);
+
+        // Safe because self.device_event points to a valid, aligned, initialised, dereferenceable,
+        // readable instance of EventSuppression.
+        let (off_wrap, flags) = unsafe {
+            let device_event = &*self.ring.device_event.as_ptr();
+            (device_event.off_wrap, device_event.flags)
+        };
+        let num_added = self.num_added;
+        self.num_added = 0;
+        if flags != RING_EVENT_FLAGS_DESC {
+            return flags != RING_EVENT_FLAGS_DISABLE;
+        }
+
+        let new = self.next_avail;
+        let old = new.wrapping_sub(num_added);
+        let mut event_idx = off_wrap & !(1 << 15);
+        if (off_wrap >> 15 == 1) != self.avail_wrap_counter {
+            event_idx = event_idx.wrapping_sub(self.ring.size);
+        }
+        // If the event index is in the range of descriptors just added, then the device wants to
+        // be notified.
+        new.wrapping_sub(event_idx).wrapping_sub(1) < new.wrapping_sub(old)
+    }
+}
+
+/// The driver's position in a packed ring for popping descriptors used by the device.
+#[derive(Debug)]
+pub(crate) struct PackedComplete {
+    pub(super) ring: PackedRing,
+    /// Our copy of `driver_event.flags`.
+    driver_event_flags: u16,
+    /// The position in the ring at which the device will write the next used descriptor.
+    pub(super) next_used: u16,
+    /// The wrap counter which the next used descriptor will have, flipped each time `next_used`
+    /// wraps around.
+    pub(super) used_wrap_counter: bool,
+}
+
+impl PackedComplete {
+    pub(crate) fn new(ring: PackedRing) -> Self {
+        Self {
+            ring,
+            driver_event_flags: RING_EVENT_FLAGS_ENABLE,
+            next_used: 0,
+            used_wrap_counter: true,
+        }
+    }
+
+    /// Returns the buffer ID and used length of the next used descriptor, if there is one.
+    pub(crate) fn peek_used(&self) -> Option<(u16, u32)> {
+        let slot = usize::from(self.next_used);
+        if !self.is_used(self.next_used, self.used_wrap_counter) {
+            return None;
+        }
+
+        // Read barrier so that the ID and length are read after the flags.
+        self.ring.barriers.issue(Barrier::Read);
+
+        // Safe because self.desc is properly aligned, dereferenceable and initialised.
+        unsafe {
+            let desc = &(*self.ring.desc.as_ptr())[slot];
+            Some((desc.id, desc.len))
+        }
+    }
+
+    /// Returns whether the descriptor in the given slot has been used by the device, in the lap of
+    /// the ring with the given wrap counter.
+    fn is_used(&self, slot: u16, wrap_counter: bool) -> bool {
+        // Safe because self.desc is properly aligned, dereferenceable and initialised. The read is
+        // volatile so that polling sees new values written by the device.
+        let flags = unsafe {
+            addr_of!((*self.ring.desc.as_ptr())[usize::from(slot)].flags).read_volatile()
+        };
+        let avail = flags.contains(DescFlags::AVAIL);
+        let used = flags.contains(DescFlags::USED);
+        avail == used && used == wrap_counter
+    }
+
+    /// Moves past the next used descriptor, which was for a chain of `chain_len` descriptors.
+    pub(crate) fn pop_used(&mut self, chain_len: u16) {
+        self.next_used += chain_len;
+        if self.next_used >= self.ring.size {
+            self.next_used -= self.ring.size;
+            self.used_wrap_counter = !self.used_wrap_counter;
+        }
+
+        if self.driver_event_flags == RING_EVENT_FLAGS_DESC {
+            // Ask for a notification for the next used descriptor too.
+            self.write_driver_event_off_wrap(self.next_used, self.used_wrap_counter);
+        }
+    }
+
+    /// Asks the device not to send used buffer notifications.
+    ///
+    /// Ref: linux virtio_ring.c virtqueue_disable_cb_packed
+    pub(crate) fn disable_callbacks(&mut self) {
+        if self.driver_event_flags != RING_EVENT_FLAGS_DISABLE {
+            self.write_driver_event_flags(RING_EVENT_FLAGS_DISABLE);
+        }
+    }
+
+    /// Asks the device to send a used buffer notification once it has used the descriptor `delay`
+    /// slots after the next one to be popped, or for every used descriptor if
+    /// `VIRTIO_F_EVENT_IDX` hasn't been negotiated.
+    ///
+    /// Returns false if the device has already used that descriptor, so the notification may not
+    /// come.
+    ///
+    /// Ref: linux virtio_ring.c virtqueue_enable_cb_prepare_packed and
+    /// virtqueue_enable_cb_delayed_packed
+    pub(crate) fn enable_callbacks(&mut self, event_idx: bool, delay: u16) -> bool {
+        let mut slot = self.next_used + delay;
+        let mut wrap_counter = self.used_wrap_counter;
+        if slot >= self.ring.size {
+            slot -= self.ring.size;
+            wrap_counter = !wrap_counter;
+        }
+
+        if event_idx {
+            self.write_driver_event_off_wrap(slot, wrap_counter);
+            // Barrier so that the device sees the new offset before the flags.
+            self.ring.barriers.issue(Barrier::Write);
+            self.write_driver_event_flags(RING_EVENT_FLAGS_DESC);
+        } else {
+            self.write_driver_event_flags(RING_EVENT_FLAGS_ENABLE);
+        }
+
+        // Barrier so that the device sees the new flags before we check the descriptor.
+        self.ring.barriers.issue(Barrier::Full);
+
+        !self.is_used(slot, wrap_counter)
+    }
+
+    /// Sets the flags of the driver event suppression structure.
+    fn write_driver_event_flags(&mut self, flags: u16) {
+        self.driver_event_flags = flags;
+        // Safe because self.driver_event is properly aligned, dereferenceable and initialised.
+        unsafe {
+            (*self.ring.driver_event.as_ptr()).flags = flags;
+        }
+    }
+
+    /// Sets the descriptor ring offset and wrap counter of the driver event suppression structure.
+    fn write_driver_event_off_wrap(&mut self, slot: u16, wrap_counter: bool) {
+        // Safe because self.driver_event is properly aligned, dereferenceable and initialised.
+        unsafe {
+            (*self.ring.driver_event.as_ptr()).off_wrap = slot | u16::from(wrap_counter) << 15;
+        }
+    }
+}
+
+/// The state of a fake device's view of a packed virtqueue, for use in tests.
+#[cfg(test)]
+#[derive(Debug)]
+pub(crate) struct FakePackedDevice {
+    /// The position in the ring of the next descriptor the device will read.
+    next: u16,
+    /// The device's ring wrap counter.
+    wrap_counter: bool,
+}
+
+#[cfg(test)]
+impl Default for FakePackedDevice {
+    fn default() -> Self {
+        Self {
+            next: 0,
+            wrap_counter: true,
+        }
+    }
+}
+
+/// Simulates the device reading from a packed VirtIO queue and writing a response back, for use in
+/// tests.
+///
+/// The fake device always uses descriptors in order.
+#[cfg(test)]
+pub(crate) fn fake_read_write_packed_queue(
+    descriptors: *mut [PackedDescriptor],
+    device: &mut FakePackedDevice,
+    handler: impl FnOnce(Vec<u8>) -> Vec<u8>,
+) {
+    use core::{cmp::min, ptr, slice};
+
+    // Safe because the various pointers are properly aligned, dereferenceable, initialised, and
+    // nothing else accesses them during this block.
+    unsafe {
+        let queue_size = descriptors.len();
+        let head = &(*descriptors)[usize::from(device.next)];
+        // Make sure there is actually a descriptor available to read from.
+        assert_eq!(head.flags.contains(DescFlags::AVAIL), device.wrap_counter);
+        assert_ne!(head.flags.contains(DescFlags::USED), device.wrap_counter);
+
+        // Collect the buffers of the chain, either from the indirect table or from consecutive
+        // slots of the ring.
+        let mut chain: Vec<PackedDescriptor> = Vec::new();
+        let id;
+        let ring_descriptors;
+        if head.flags.contains(DescFlags::INDIRECT) {
+            // The descriptor shouldn't have any other flags if it is indirect.
+            assert_eq!(
+                head.flags - DescFlags::AVAIL - DescFlags::USED,
+                DescFlags::INDIRECT
+            );
+            let indirect_descriptor_list: &[PackedDescriptor] = zerocopy::Ref::new_slice(
+                slice::from_raw_parts(head.addr as *const u8, head.len as usize),
+            )
+            .unwrap()
+            .into_slice();
+            chain.extend_from_slice(indirect_descriptor_list);
+            id = head.id;
+            ring_descriptors = 1;
+        } else {
+            // The buffer ID is only required to be set in the last descriptor of the chain.
+            let mut slot = usize::from(device.next);
+            loop {
+                let descriptor = (*descriptors)[slot].clone();
+                let has_next = descriptor.flags.contains(DescFlags::NEXT);
+                chain.push(descriptor);
+                slot = (slot + 1) % queue_size;
+                if !has_next {
+                    break;
+                }
+            }
+            id = chain.last().unwrap().id;
+            ring_descriptors = chain.len() as u16;
+        }
+
+        // Read data from the device-readable buffers.
+        let mut input = Vec::new();
+        let mut index = 0;
+        while index < chain.len() && !chain[index].flags.contains(DescFlags::WRITE) {
+            input.extend_from_slice(slice::from_raw_parts(
+                chain[index].addr as *const u8,
+                chain[index].len as usize,
+            ));
+            index += 1;
+        }
+
+        // Let the test handle the request.
+        let output = handler(input);
+
+        // Write the response to the remaining descriptors.
+        let mut remaining_output = &output[..];
+        while index < chain.len() {
+            assert!(chain[index].flags.contains(DescFlags::WRITE));
+            let length_to_write = min(remaining_output.len(), chain[index].len as usize);
+            ptr::copy(
+                remaining_output.as_ptr(),
+                chain[index].addr as *mut u8,
+                length_to_write,
+            );
+            remaining_output = &remaining_output[length_to_write..];
+            index += 1;
+        }
+        assert_eq!(remaining_output.len(), 0);
+
+        // Mark the buffer as used, in the slot where the chain started.
+        let used = &mut (*descriptors)[usize::from(device.next)];
+        used.id = id;
+        used.len = output.len() as u32;
+        used.flags = if device.wrap_counter {
+            DescFlags::AVAIL | DescFlags::USED
+        } else {
+            DescFlags::empty()
+        };
+
+        device.next += ring_descriptors;
+        if usize::from(device.next) >= queue_size {
+            device.next -= queue_size as u16;
+            device.wrap_counter = !device.wrap_counter;
+        }
+    }
+}
@@ -0,0 +1,217 @@
+//! Statistics and tracing of the descriptor chains which pass through a virtqueue, for debugging
+//! interoperability problems with devices.
+
+use super::{DescFlags, Descriptor};
+use core::cell::UnsafeCell;
+use core::fmt::{self, Debug, Formatter};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// A snapshot of the counters of a virtqueue, since it was created or last reset.
+///
+/// Counters wrap around on overflow.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct QueueStats {
+    /// The number of descriptor chains added to the queue.
+    pub adds: usize,
+    /// The number of times the device was notified about new buffers.
+    pub notifies: usize,
+    /// The number of times the driver didn't need to notify the device after making new buffers
+    /// available, because the device had asked not to be notified.
+    pub notifies_suppressed: usize,
+    /// The number of used descriptor chains popped from the queue.
+    pub completions: usize,
+    /// The number of times buffers couldn't be added because there weren't enough free
+    /// descriptors.
+    pub queue_full: usize,
+    /// The largest number of descriptor chains which have been added but not yet popped at once.
+    pub max_in_flight: usize,
+}
+
+/// The live counters of a virtqueue, which may be updated by both its submitting and its completing
+/// side at the same time.
+#[derive(Debug, Default)]
+pub(crate) struct QueueCounters {
+    adds: AtomicUsize,
+    notifies: AtomicUsize,
+    notifies_suppressed: AtomicUsize,
+    completions: AtomicUsize,
+    queue_full: AtomicUsize,
+    in_flight: AtomicUsize,
+    max_in_flight: AtomicUsize,
+}
+
+impl QueueCounters {
+    /// Counts a descriptor chain being added.
+    pub(crate) fn add(&self) {
+        self.adds.fetch_add(1, Ordering::Relaxed);
+        let in_flight = self.in_flight.fetch_add(1, Ordering::Relaxed) + 1;
+        self.max_in_flight.fetch_max(in_flight, Ordering::Relaxed);
+    }
+
+    /// Counts a notification being sent to the device.
+    pub(crate) fn notify(&self) {
+        self.notifies.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a notification which the device asked not to be sent.
+    pub(crate) fn notify_suppressed(&self) {
+        self.notifies_suppressed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a used descriptor chain being popped.
+    pub(crate) fn complete(&self) {
+        self.completions.fetch_add(1, Ordering::Relaxed);
+        self.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Counts an attempt to add buffers to a full queue.
+    pub(crate) fn queue_full(&self) {
+        self.queue_full.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns the current values of the counters.
+    pub(crate) fn snapshot(&self) -> QueueStats {
+        QueueStats {
+            adds: self.adds.load(Ordering::Relaxed),
+            notifies: self.notifies.load(Ordering::Relaxed),
+            notifies_suppressed: self.notifies_suppressed.load(Ordering::Relaxed),
+            completions: self.completions.load(Ordering::Relaxed),
+            queue_full: self.queue_full.load(Ordering::Relaxed),
+            max_in_flight: self.max_in_flight.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// What happened to the descriptor chain of a [`TraceEvent`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum TraceKind {
+    /// The chain was added to the queue by the driver.
+    Add,
+    /// The chain was used by the device and popped by the driver.
+    Pop {
+        /// The number of bytes which the device wrote to the chain's buffers.
+        len: u32,
+    },
+}
+
+/// A descriptor chain being added to or popped from a virtqueue, passed to
+/// [`Hal::trace`](crate::Hal::trace).
+#[derive(Debug)]
+pub struct TraceEvent<'a> {
+    /// The index of the queue within the device.
+    pub queue_idx: u16,
+    /// The token identifying the chain, as returned when it was added.
+    pub token: u16,
+    /// Whether the chain is being added or popped.
+    pub kind: TraceKind,
+    descriptors: TraceChain<'a>,
+}
+
+impl<'a> TraceEvent<'a> {
+    /// Creates an event for the chain starting at `head` in `descriptors`, which must be owned by
+    /// the caller. If the head is an indirect descriptor then `indirect_table` must be the table
+    /// which it points to.
+    pub(crate) fn new(
+        queue_idx: u16,
+        kind: TraceKind,
+        head: u16,
+        descriptors: &'a [UnsafeCell<Descriptor>],
+        indirect_table: &'a [Descriptor],
+        packed: bool,
+    ) -> Self {
+        Self {
+            queue_idx,
+            token: head,
+            kind,
+            descriptors: TraceChain {
+                descriptors,
+                next: Some(head),
+                indirect_table,
+                packed,
+            },
+        }
+    }
+
+    /// Returns an iterator over the descriptors in the chain, in order.
+    ///
+    /// An indirect descriptor is followed by the descriptors in the table which it points to.
+    pub fn descriptors(&self) -> TraceChain<'a> {
+        self.descriptors.clone()
+    }
+}
+
+/// A descriptor in a chain passed to [`Hal::trace`](crate::Hal::trace).
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct TraceDescriptor {
+    /// The physical address of the buffer, as seen by the device.
+    pub addr: u64,
+    /// The length of the buffer in bytes.
+    pub len: u32,
+    /// The `VIRTQ_DESC_F_NEXT`, `VIRTQ_DESC_F_WRITE` and `VIRTQ_DESC_F_INDIRECT` flags of the
+    /// descriptor, in the split virtqueue format.
+    pub flags: u16,
+}
+
+impl TraceDescriptor {
+    /// Returns whether the device may write to the buffer, rather than read from it.
+    pub fn is_write(&self) -> bool {
+        self.flags & DescFlags::WRITE.bits() != 0
+    }
+
+    /// Returns whether the buffer is an indirect descriptor table.
+    pub fn is_indirect(&self) -> bool {
+        self.flags & DescFlags::INDIRECT.bits() != 0
+    }
+}
+
+/// An iterator over the descriptors of a chain in a [`TraceEvent`].
+#[derive(Clone)]
+pub struct TraceChain<'a> {
+    descriptors: &'a [UnsafeCell<Descriptor>],
+    /// The index of the next descriptor to return from `descriptors`, if any.
+    next: Option<u16>,
+    /// The remaining descriptors to return from the indirect table, once the indirect descriptor
+    /// has been returned.
+    indirect_table: &'a [Descriptor],
+    packed: bool,
+}
+
+impl Iterator for TraceChain<'_> {
+    type Item = TraceDescriptor;
+
+    fn next(&mut self) -> Option<TraceDescriptor> {
+        if let Some(index) = self.next {
+            // Safe because the owner of the chain created the event, and is waiting for the trace
+            // hook to return before doing anything else with it.
+            let desc = unsafe { &*self.descriptors[usize::from(index)].get() };
+            self.next = desc.next();
+            return Some(TraceDescriptor {
+                addr: desc.addr,
+                len: desc.len,
+                flags: desc.flags.bits(),
+            });
+        }
+        let (desc, rest) = self.indirect_table.split_first()?;
+        self.indirect_table = rest;
+        let flags = if self.packed {
+            // Packed indirect tables have the flags where split descriptors have `next`, and are
+            // chained by position.
+            let mut flags = DescFlags::from_bits_retain(desc.next);
+            flags.set(DescFlags::NEXT, !rest.is_empty());
+            flags
+        } else {
+            desc.flags
+        };
+        Some(TraceDescriptor {
+            addr: desc.addr,
+            len: desc.len,
+            flags: flags.bits(),
+        })
+    }
+}
+
+impl Debug for TraceChain<'_> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_list().entries(self.clone()).finish()
+    }
+}
@@ -0,0 +1,164 @@
+//! Registration of a task waker for a virtqueue, which an interrupt handler can wake.
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicU8, Ordering};
+use core::task::Waker;
+
+/// Nobody is registering or waking the waker.
+const WAITING: u8 = 0;
+/// A waker is being registered.
+const REGISTERING: u8 = 1 << 0;
+/// The registered waker is being woken.
+const WAKING: u8 = 1 << 1;
+
+/// A slot for the waker of the task waiting on a virtqueue.
+///
+/// Futures waiting for the device to use buffers register their task here, and the interrupt
+/// handler for the device calls [`QueueWaker::wake`] to wake it. Waking never blocks, so it is
+/// safe to call from an interrupt handler which interrupted a call to [`QueueWaker::register`].
+///
+/// Only the most recently registered waker is kept, so only one task at a time should wait on a
+/// given queue.
+pub struct QueueWaker {
+    state: AtomicU8,
+    waker: UnsafeCell<Option<Waker>>,
+}
+
+// Safe because access to `waker` is synchronised by `state`.
+unsafe impl Send for QueueWaker {}
+unsafe impl Sync for QueueWaker {}
+
+impl QueueWaker {
+    /// Creates a new empty slot.
+    pub const fn new() -> Self {
+        Self {
+            state: AtomicU8::new(WAITING),
+            waker: UnsafeCell::new(None),
+        }
+    }
+
+    /// Registers the given waker to be woken by the next call to [`QueueWaker::wake`], replacing
+    /// any waker registered previously.
+    pub fn register(&self, waker: &Waker) {
+        match self.state.compare_exchange(
+            WAITING,
+            REGISTERING,
+            Ordering::Acquire,
+            Ordering::Acquire,
+        ) {
+            Ok(_) => {
+                // Safe because we have exclusive access to `waker` while in the `REGISTERING`
+                // state.
+                unsafe {
+                    let slot = &mut *self.waker.get();
+                    match slot {
+                        Some(old) if old.will_wake(waker) => {}
+                        _ => *slot = Some(waker.clone()),
+                    }
+                }
+                if self
+                    .state
+                    .compare_exchange(REGISTERING, WAITING, Ordering::AcqRel, Ordering::Acquire)
+                    .is_err()
+                {
+                    // `wake` was called while we were registering, but left the waking to us.
+                    // Safe because `wake` doesn't touch `waker` if it sees `REGISTERING`.
+                    let waker = unsafe { (*self.waker.get()).take() };
+                    self.state.swap(WAITING, Ordering::AcqRel);
+                    if let Some(waker) = waker {
+                        waker.wake();
+                    }
+                }
+            }
+            Err(WAKING) => {
+                // The previous waker is being woken, so make sure the new one is too.
+                waker.wake_by_ref();
+            }
+            Err(_) => {
+                // Another call to `register` is in progress, which isn't supported.
+            }
+        }
+    }
+
+    /// Wakes the registered waker, if any.
+    pub fn wake(&self) {
+        if let Some(waker) = self.take() {
+            waker.wake();
+        }
+    }
+
+    /// Takes the registered waker out of the slot, if any.
+    fn take(&self) -> Option<Waker> {
+        match self.state.fetch_or(WAKING, Ordering::AcqRel) {
+            WAITING => {
+                // Safe because we have exclusive access to `waker` while only in the `WAKING`
+                // state.
+                let waker = unsafe { (*self.waker.get()).take() };
+                self.state.fetch_and(!WAKING, Ordering::Release);
+                waker
+            }
+            // Either `register` is in progress and will wake its waker itself once it sees
+            // `WAKING`, or another call to `wake` is already in progress.
+            _ => None,
+        }
+    }
+}
+
+impl Default for QueueWaker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl core::fmt::Debug for QueueWaker {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.debug_struct("QueueWaker").finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Arc;
+    use std::task::Wake;
+
+    #[derive(Default)]
+    struct CountingWaker(AtomicUsize);
+
+    impl Wake for CountingWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn wake_registered() {
+        let queue_waker = QueueWaker::new();
+        let count = Arc::new(CountingWaker::default());
+
+        // Waking with nothing registered does nothing.
+        queue_waker.wake();
+
+        queue_waker.register(&Waker::from(count.clone()));
+        queue_waker.wake();
+        assert_eq!(count.0.load(Ordering::SeqCst), 1);
+
+        // The waker is only woken once per registration.
+        queue_waker.wake();
+        assert_eq!(count.0.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn register_replaces() {
+        let queue_waker = QueueWaker::new();
+        let first = Arc::new(CountingWaker::default());
+        let second = Arc::new(CountingWaker::default());
+
+        queue_waker.register(&Waker::from(first.clone()));
+        queue_waker.register(&Waker::from(second.clone()));
+        queue_waker.wake();
+        assert_eq!(first.0.load(Ordering::SeqCst), 0);
+        assert_eq!(second.0.load(Ordering::SeqCst), 1);
+    }
+}
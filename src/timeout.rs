@@ -0,0 +1,128 @@
+//! Timeouts for operations which wait for the device.
+
+use crate::hal::Hal;
+use crate::{Error, Result};
+use core::time::Duration;
+
+/// The number of times to poll a device register before giving up, for transport operations which
+/// have no other way to time out.
+pub(crate) const TRANSPORT_SPIN_LIMIT: u64 = 10_000_000;
+
+/// How long to wait for the device before giving up.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum Timeout {
+    /// Wait forever.
+    #[default]
+    Never,
+    /// Give up after polling the device this many times.
+    ///
+    /// This doesn't need a clock, but how long it takes depends on the speed of the CPU and the
+    /// device.
+    Spins(u64),
+    /// Give up once this much time has passed, according to [`Hal::now`].
+    ///
+    /// Operations return [`Error::Unsupported`] if the HAL doesn't provide a clock.
+    Duration(Duration),
+}
+
+/// The point at which a wait with a [`Timeout`] should give up.
+#[derive(Debug)]
+pub(crate) struct Deadline {
+    kind: DeadlineKind,
+}
+
+#[derive(Debug)]
+enum DeadlineKind {
+    Never,
+    Spins(u64),
+    Time {
+        end: Duration,
+        now: fn() -> Option<Duration>,
+    },
+}
+
+impl Deadline {
+    /// Starts waiting for the given timeout, using the clock provided by `H` if necessary.
+    pub(crate) fn new<H: Hal>(timeout: Timeout) -> Result<Self> {
+        let kind = match timeout {
+            Timeout::Never => DeadlineKind::Never,
+            Timeout::Spins(spins) => DeadlineKind::Spins(spins),
+            Timeout::Duration(duration) => DeadlineKind::Time {
+                end: H::now().ok_or(Error::Unsupported)?.saturating_add(duration),
+                now: H::now,
+            },
+        };
+        Ok(Self { kind })
+    }
+
+    /// Starts waiting for at most the given number of polls.
+    pub(crate) fn spins(spins: u64) -> Self {
+        Self {
+            kind: DeadlineKind::Spins(spins),
+        }
+    }
+
+    /// Returns whether the deadline has passed. This should be called once for each time the
+    /// device is polled.
+    pub(crate) fn expired(&mut self) -> bool {
+        match &mut self.kind {
+            DeadlineKind::Never => false,
+            DeadlineKind::Spins(remaining) => {
+                if *remaining == 0 {
+                    true
+                } else {
+                    *remaining -= 1;
+                    false
+                }
+            }
+            DeadlineKind::Time { end, now } => match now() {
+                Some(now) => now >= *end,
+                // The clock has stopped working, so don't wait forever.
+                None => true,
+            },
+        }
+    }
+}
+
+/// Polls `ready` until it returns true, giving up after [`TRANSPORT_SPIN_LIMIT`] attempts.
+///
+/// Returns [`Error::Timeout`] if it never does.
+pub(crate) fn spin_until(mut ready: impl FnMut() -> bool) -> Result {
+    let mut deadline = Deadline::spins(TRANSPORT_SPIN_LIMIT);
+    while !ready() {
+        if deadline.expired() {
+            return Err(Error::Timeout);
+        }
+        core::hint::spin_loop();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hal::fake::FakeHal;
+
+    #[test]
+    fn spins() {
+        let mut deadline = Deadline::new::<FakeHal>(Timeout::Spins(2)).unwrap();
+        assert!(!deadline.expired());
+        assert!(!deadline.expired());
+        assert!(deadline.expired());
+    }
+
+    #[test]
+    fn duration() {
+        let mut deadline =
+            Deadline::new::<FakeHal>(Timeout::Duration(Duration::from_millis(10))).unwrap();
+        assert!(!deadline.expired());
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(deadline.expired());
+    }
+
+    #[test]
+    fn spin_until_times_out() {
+        assert_eq!(spin_until(|| false), Err(Error::Timeout));
+        assert_eq!(spin_until(|| true), Ok(()));
+    }
+}
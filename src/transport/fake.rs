@@ -1,27 +1,41 @@
-use super::{DeviceStatus, DeviceType, Transport};
+use super::{check_config_space_access, DeviceStatus, DeviceType, Transport};
 use crate::{
-    queue::{fake_read_write_queue, Descriptor},
+    device::common::Feature,
+    queue::{
+        fake_read_write_packed_queue, fake_read_write_queue, Descriptor, FakePackedDevice,
+        PackedDescriptor,
+    },
     PhysAddr, Result,
 };
 use alloc::{sync::Arc, vec::Vec};
 use core::{
-    any::TypeId,
-    ptr::NonNull,
+    mem::size_of,
+    ptr::{self, NonNull},
     sync::atomic::{AtomicBool, Ordering},
     time::Duration,
 };
 use std::{sync::Mutex, thread};
+use zerocopy::{AsBytes, FromBytes};
 
 /// A fake implementation of [`Transport`] for unit tests.
 #[derive(Debug)]
 pub struct FakeTransport<C: 'static> {
+    /// The type of device to report.
     pub device_type: DeviceType,
+    /// The maximum queue size to report for every queue.
     pub max_queue_size: u32,
+    /// The features offered by the device.
     pub device_features: u64,
+    /// The device-specific configuration space.
     pub config_space: NonNull<C>,
+    /// The state shared with the simulated device.
     pub state: Arc<Mutex<State>>,
 }
 
+// Safe because the config space is only accessed through volatile reads and writes, as a real
+// device's would be.
+unsafe impl<C> Send for FakeTransport<C> {}
+
 impl<C> Transport for FakeTransport<C> {
     fn device_type(&self) -> DeviceType {
         self.device_type
@@ -45,6 +59,14 @@ impl<C> Transport for FakeTransport<C> {
             .store(true, Ordering::SeqCst);
     }
 
+    fn notify_with_data(&mut self, queue: u16, data: u32) {
+        let mut state = self.state.lock().unwrap();
+        state.queues[queue as usize].notification_data = Some(data);
+        state.queues[queue as usize]
+            .notified
+            .store(true, Ordering::SeqCst);
+    }
+
     fn get_status(&self) -> DeviceStatus {
         self.state.lock().unwrap().status
     }
@@ -74,6 +96,7 @@ impl<C> Transport for FakeTransport<C> {
         state.queues[queue as usize].descriptors = descriptors;
         state.queues[queue as usize].driver_area = driver_area;
         state.queues[queue as usize].device_area = device_area;
+        state.queues[queue as usize].packed_device = FakePackedDevice::default();
     }
 
     fn queue_unset(&mut self, queue: u16) {
@@ -84,6 +107,13 @@ impl<C> Transport for FakeTransport<C> {
         state.queues[queue as usize].device_area = 0;
     }
 
+    fn queue_reset(&mut self, queue: u16) -> Result {
+        let mut state = self.state.lock().unwrap();
+        state.queues[queue as usize] = QueueStatus::default();
+        state.queues_reset += 1;
+        Ok(())
+    }
+
     fn queue_used(&mut self, queue: u16) -> bool {
         self.state.lock().unwrap().queues[queue as usize].descriptors != 0
     }
@@ -97,40 +127,67 @@ impl<C> Transport for FakeTransport<C> {
         pending
     }
 
-    fn config_space<T: 'static>(&self) -> Result<NonNull<T>> {
-        if TypeId::of::<T>() == TypeId::of::<C>() {
-            Ok(self.config_space.cast())
-        } else {
-            panic!("Unexpected config space type.");
+    fn read_config_space<T: AsBytes + FromBytes>(&self, offset: usize) -> Result<T> {
+        check_config_space_access::<T>(offset, size_of::<C>())?;
+        // Safe because the test promises that the config space pointer is valid, and we checked
+        // that the value is within it and aligned.
+        Ok(unsafe { self.config_space_ptr::<T>(offset).read_volatile() })
+    }
+
+    fn write_config_space<T: AsBytes>(&mut self, offset: usize, value: T) -> Result {
+        check_config_space_access::<T>(offset, size_of::<C>())?;
+        // Safe because the test promises that the config space pointer is valid, and we checked
+        // that the value is within it and aligned.
+        unsafe {
+            self.config_space_ptr::<T>(offset).write_volatile(value);
         }
+        Ok(())
+    }
+
+    fn config_space<T: 'static>(&self) -> Result<NonNull<T>> {
+        Ok(self.config_space.cast())
+    }
+
+    fn config_generation(&self) -> u32 {
+        self.state.lock().unwrap().config_generation
     }
 }
 
+impl<C> FakeTransport<C> {
+    /// Returns a pointer to a value of type `T` at the given offset within the config space.
+    fn config_space_ptr<T>(&self, offset: usize) -> *mut T {
+        (self.config_space.as_ptr() as usize + offset) as *mut T
+    }
+}
+
+/// The state of a fake device, shared between the transport and the test.
 #[derive(Debug, Default)]
 pub struct State {
+    /// The device status last written by the driver.
     pub status: DeviceStatus,
+    /// The features last written by the driver.
     pub driver_features: u64,
+    /// The guest page size last written by the driver.
     pub guest_page_size: u32,
+    /// Whether an interrupt is pending, to be returned by `ack_interrupt`.
     pub interrupt_pending: bool,
+    /// The state of each queue.
     pub queues: Vec<QueueStatus>,
+    /// The number of times a single queue has been reset by the driver.
+    pub queues_reset: usize,
+    /// The configuration generation to report.
+    pub config_generation: u32,
 }
 
 impl State {
     /// Simulates the device writing to the given queue.
     ///
     /// The fake device always uses descriptors in order.
-    pub fn write_to_queue<const QUEUE_SIZE: usize>(&mut self, queue_index: u16, data: &[u8]) {
-        let queue = &self.queues[queue_index as usize];
-        assert_ne!(queue.descriptors, 0);
-        fake_read_write_queue(
-            queue.descriptors as *const [Descriptor; QUEUE_SIZE],
-            queue.driver_area as *const u8,
-            queue.device_area as *mut u8,
-            |input| {
-                assert_eq!(input, Vec::new());
-                data.to_owned()
-            },
-        );
+    pub fn write_to_queue(&mut self, queue_index: u16, data: &[u8]) {
+        self.read_write_queue(queue_index, |input| {
+            assert_eq!(input, Vec::new());
+            data.to_owned()
+        });
     }
 
     /// Simulates the device reading from the given queue.
@@ -138,42 +195,49 @@ impl State {
     /// Data is read into the `data` buffer passed in. Returns the number of bytes actually read.
     ///
     /// The fake device always uses descriptors in order.
-    pub fn read_from_queue<const QUEUE_SIZE: usize>(&mut self, queue_index: u16) -> Vec<u8> {
-        let queue = &self.queues[queue_index as usize];
-        assert_ne!(queue.descriptors, 0);
-
+    pub fn read_from_queue(&mut self, queue_index: u16) -> Vec<u8> {
         let mut ret = None;
 
         // Read data from the queue but don't write any response.
-        fake_read_write_queue(
-            queue.descriptors as *const [Descriptor; QUEUE_SIZE],
-            queue.driver_area as *const u8,
-            queue.device_area as *mut u8,
-            |input| {
-                ret = Some(input);
-                Vec::new()
-            },
-        );
+        self.read_write_queue(queue_index, |input| {
+            ret = Some(input);
+            Vec::new()
+        });
 
         ret.unwrap()
     }
 
     /// Simulates the device reading data from the given queue and then writing a response back.
     ///
+    /// The queue is treated as a packed virtqueue if the driver negotiated
+    /// `VIRTIO_F_RING_PACKED`, otherwise as a split virtqueue.
+    ///
     /// The fake device always uses descriptors in order.
-    pub fn read_write_queue<const QUEUE_SIZE: usize>(
-        &mut self,
-        queue_index: u16,
-        handler: impl FnOnce(Vec<u8>) -> Vec<u8>,
-    ) {
-        let queue = &self.queues[queue_index as usize];
+    pub fn read_write_queue(&mut self, queue_index: u16, handler: impl FnOnce(Vec<u8>) -> Vec<u8>) {
+        let packed =
+            Feature::from_bits_truncate(self.driver_features).contains(Feature::RING_PACKED);
+        let queue = &mut self.queues[queue_index as usize];
         assert_ne!(queue.descriptors, 0);
-        fake_read_write_queue(
-            queue.descriptors as *const [Descriptor; QUEUE_SIZE],
-            queue.driver_area as *const u8,
-            queue.device_area as *mut u8,
-            handler,
-        )
+        if packed {
+            fake_read_write_packed_queue(
+                ptr::slice_from_raw_parts_mut(
+                    queue.descriptors as *mut PackedDescriptor,
+                    queue.size as usize,
+                ),
+                &mut queue.packed_device,
+                handler,
+            )
+        } else {
+            fake_read_write_queue(
+                ptr::slice_from_raw_parts(
+                    queue.descriptors as *const Descriptor,
+                    queue.size as usize,
+                ),
+                queue.driver_area as *const u8,
+                queue.device_area as *mut u8,
+                handler,
+            )
+        }
     }
 
     /// Waits until the given queue is notified.
@@ -187,11 +251,21 @@ impl State {
     }
 }
 
+/// The state of a single queue of a fake device.
 #[derive(Debug, Default)]
 pub struct QueueStatus {
+    /// The queue size set by the driver, or 0 if the queue isn't set up.
     pub size: u32,
+    /// The physical address of the descriptor area.
     pub descriptors: PhysAddr,
+    /// The physical address of the driver area.
     pub driver_area: PhysAddr,
+    /// The physical address of the device area.
     pub device_area: PhysAddr,
+    /// Whether the driver has notified the device about the queue since this was last cleared.
     pub notified: AtomicBool,
+    /// The data sent with the last notification, if `VIRTIO_F_NOTIFICATION_DATA` was negotiated.
+    pub notification_data: Option<u32>,
+    /// The device's position in the queue, if it is a packed virtqueue.
+    pub(crate) packed_device: FakePackedDevice,
 }
@@ -1,18 +1,22 @@
 //! MMIO transport for VirtIO.
 
-use super::{DeviceStatus, DeviceType, Transport};
+use super::{check_config_space_access, DeviceStatus, DeviceType, Transport};
 use crate::{
     align_up,
+    barrier::{arch_barrier, Barrier},
     queue::Descriptor,
+    timeout::spin_until,
     volatile::{volread, volwrite, ReadOnly, Volatile, WriteOnly},
     Error, PhysAddr, PAGE_SIZE,
 };
 use core::{
     convert::{TryFrom, TryInto},
     fmt::{self, Display, Formatter},
-    mem::{align_of, size_of},
+    mem::size_of,
     ptr::NonNull,
 };
+use log::warn;
+use zerocopy::{AsBytes, FromBytes};
 
 const MAGIC_VALUE: u32 = 0x7472_6976;
 pub(crate) const LEGACY_VERSION: u32 = 1;
@@ -215,7 +219,14 @@ pub struct VirtIOHeader {
     queue_device_high: WriteOnly<u32>,
 
     /// Reserved
-    __r9: [ReadOnly<u32>; 21],
+    __r9: [ReadOnly<u32>; 6],
+
+    /// Writing 1 resets the selected queue, and reads 1 once the reset has completed. Only present
+    /// if `VIRTIO_F_RING_RESET` has been negotiated.
+    queue_reset: Volatile<u32>,
+
+    /// Reserved
+    __r10: [ReadOnly<u32>; 14],
 
     config_generation: ReadOnly<u32>,
 }
@@ -265,6 +276,8 @@ impl VirtIOHeader {
             queue_device_low: Default::default(),
             queue_device_high: Default::default(),
             __r9: Default::default(),
+            queue_reset: Default::default(),
+            __r10: Default::default(),
             config_generation: Default::default(),
         }
     }
@@ -308,8 +321,18 @@ impl MmioTransport {
         // Safe because self.header points to a valid VirtIO MMIO region.
         unsafe { volread!(self.header, vendor_id) }
     }
+
+    /// Returns a pointer to a value of type `T` at the given offset within the device-specific
+    /// configuration space.
+    fn config_space_ptr<T>(&self, offset: usize) -> *mut T {
+        (self.header.as_ptr() as usize + CONFIG_SPACE_OFFSET + offset) as *mut T
+    }
 }
 
+// Safe because the MMIO region which the transport points to may be accessed from any CPU, and the
+// transport needs `&mut self` to change anything in it.
+unsafe impl Send for MmioTransport {}
+
 impl Transport for MmioTransport {
     fn device_type(&self) -> DeviceType {
         // Safe because self.header points to a valid VirtIO MMIO region.
@@ -347,12 +370,23 @@ impl Transport for MmioTransport {
     }
 
     fn notify(&mut self, queue: u16) {
+        // Make sure the device sees the queue as it was before the notification.
+        arch_barrier(Barrier::Write, true);
         // Safe because self.header points to a valid VirtIO MMIO region.
         unsafe {
             volwrite!(self.header, queue_notify, queue.into());
         }
     }
 
+    fn notify_with_data(&mut self, _queue: u16, data: u32) {
+        // Make sure the device sees the queue as it was before the notification.
+        arch_barrier(Barrier::Write, true);
+        // Safe because self.header points to a valid VirtIO MMIO region.
+        unsafe {
+            volwrite!(self.header, queue_notify, data);
+        }
+    }
+
     fn get_status(&self) -> DeviceStatus {
         // Safe because self.header points to a valid VirtIO MMIO region.
         unsafe { volread!(self.header, status) }
@@ -453,7 +487,12 @@ impl Transport for MmioTransport {
 
                     volwrite!(self.header, queue_ready, 0);
                     // Wait until we read the same value back, to ensure synchronisation (see 4.2.2.2).
-                    while volread!(self.header, queue_ready) != 0 {}
+                    if spin_until(|| volread!(self.header, queue_ready) == 0).is_err() {
+                        warn!(
+                            "Timed out waiting for virtio MMIO queue {} to be disabled",
+                            queue
+                        );
+                    }
 
                     volwrite!(self.header, queue_num, 0);
                     volwrite!(self.header, queue_desc_low, 0);
@@ -467,6 +506,21 @@ impl Transport for MmioTransport {
         }
     }
 
+    fn queue_reset(&mut self, queue: u16) -> Result<(), Error> {
+        match self.version {
+            MmioVersion::Legacy => Err(Error::Unsupported),
+            MmioVersion::Modern => {
+                // Safe because self.header points to a valid VirtIO MMIO region.
+                unsafe {
+                    volwrite!(self.header, queue_sel, queue.into());
+                    volwrite!(self.header, queue_reset, 1);
+                    // Wait until the device has finished resetting the queue (see 4.2.3.2.1).
+                    spin_until(|| volread!(self.header, queue_reset) == 1)
+                }
+            }
+        }
+    }
+
     fn queue_used(&mut self, queue: u16) -> bool {
         // Safe because self.header points to a valid VirtIO MMIO region.
         unsafe {
@@ -491,15 +545,36 @@ impl Transport for MmioTransport {
         }
     }
 
-    fn config_space<T>(&self) -> Result<NonNull<T>, Error> {
-        if align_of::<T>() > 4 {
-            // Panic as this should only happen if the driver is written incorrectly.
-            panic!(
-                "Driver expected config space alignment of {} bytes, but VirtIO only guarantees 4 byte alignment.",
-                align_of::<T>()
-            );
+    fn read_config_space<T: AsBytes + FromBytes>(&self, offset: usize) -> Result<T, Error> {
+        // The MMIO transport doesn't tell us how big the config space is, so only check alignment.
+        check_config_space_access::<T>(offset, usize::MAX)?;
+        // Safe because the config space follows the header in the MMIO region, and we checked
+        // above that the offset is aligned.
+        Ok(unsafe { self.config_space_ptr::<T>(offset).read_volatile() })
+    }
+
+    fn write_config_space<T: AsBytes>(&mut self, offset: usize, value: T) -> Result<(), Error> {
+        check_config_space_access::<T>(offset, usize::MAX)?;
+        // Safe because the config space follows the header in the MMIO region, and we checked
+        // above that the offset is aligned.
+        unsafe {
+            self.config_space_ptr::<T>(offset).write_volatile(value);
+        }
+        Ok(())
+    }
+
+    fn config_space<T: 'static>(&self) -> Result<NonNull<T>, Error> {
+        check_config_space_access::<T>(0, usize::MAX)?;
+        Ok(NonNull::new(self.config_space_ptr(0)).unwrap())
+    }
+
+    fn config_generation(&self) -> u32 {
+        match self.version {
+            // The legacy interface has no configuration generation register.
+            MmioVersion::Legacy => 0,
+            // Safe because self.header points to a valid VirtIO MMIO region.
+            MmioVersion::Modern => unsafe { volread!(self.header, config_generation) },
         }
-        Ok(NonNull::new((self.header.as_ptr() as usize + CONFIG_SPACE_OFFSET) as _).unwrap())
     }
 }
 
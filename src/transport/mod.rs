@@ -1,14 +1,22 @@
 //! VirtIO transports.
 
 #[cfg(test)]
+/// A fake transport for unit tests.
 pub mod fake;
 pub mod mmio;
 pub mod pci;
 
-use crate::{PhysAddr, Result, PAGE_SIZE};
+use crate::timeout::spin_until;
+use crate::{Error, PhysAddr, Result, PAGE_SIZE};
 use bitflags::{bitflags, Flags};
-use core::{fmt::Debug, ops::BitAnd, ptr::NonNull};
+use core::{
+    fmt::Debug,
+    mem::{align_of, size_of},
+    ops::BitAnd,
+    ptr::NonNull,
+};
 use log::debug;
+use zerocopy::{AsBytes, FromBytes};
 
 /// A VirtIO transport layer.
 pub trait Transport {
@@ -27,6 +35,19 @@ pub trait Transport {
     /// Notifies the given queue on the device.
     fn notify(&mut self, queue: u16);
 
+    /// Notifies the given queue on the device, sending the given notification data with it.
+    ///
+    /// This is used instead of [`Transport::notify`] if `VIRTIO_F_NOTIFICATION_DATA` has been
+    /// negotiated. The queue index is in the low 16 bits of `data`, and the position of the next
+    /// available descriptor (with the wrap counter in bit 31 for a packed queue) in the high 16
+    /// bits. The default implementation ignores the data.
+    ///
+    /// Ref: virtio 2.9 Driver Notifications
+    fn notify_with_data(&mut self, queue: u16, data: u32) {
+        let _ = data;
+        self.notify(queue);
+    }
+
     /// Gets the device status.
     fn get_status(&self) -> DeviceStatus;
 
@@ -54,6 +75,17 @@ pub trait Transport {
     /// Disables and resets the given queue.
     fn queue_unset(&mut self, queue: u16);
 
+    /// Resets the given queue, without resetting the rest of the device, so that it can be set up
+    /// again with `queue_set`.
+    ///
+    /// This requires `VIRTIO_F_RING_RESET` to have been negotiated. Returns
+    /// [`Error::Unsupported`] if the transport doesn't support it.
+    ///
+    /// Ref: virtio 2.6.1 Virtqueue Reset
+    fn queue_reset(&mut self, _queue: u16) -> Result {
+        Err(Error::Unsupported)
+    }
+
     /// Returns whether the queue is in use, i.e. has a nonzero PFN or is marked as ready.
     fn queue_used(&mut self, queue: u16) -> bool;
 
@@ -98,8 +130,120 @@ pub trait Transport {
         );
     }
 
+    /// Reads a value from the device-specific configuration space, at the given offset in bytes.
+    ///
+    /// Returns [`Error::ConfigSpaceMissing`] if the device has no configuration space, or
+    /// [`Error::ConfigSpaceTooSmall`] if the value doesn't fit in it.
+    ///
+    /// Panics if `T` needs more than 4 byte alignment, as virtio only guarantees that much, or if
+    /// `offset` isn't aligned for `T`.
+    ///
+    /// The default implementation reads through [`Transport::config_space`], which doesn't give
+    /// the size of the configuration space, so it can't check that the value fits. Transports
+    /// which know the size should override it.
+    fn read_config_space<T: AsBytes + FromBytes>(&self, offset: usize) -> Result<T> {
+        check_config_space_access::<T>(offset, usize::MAX)?;
+        #[allow(deprecated)]
+        let config_space = self.config_space::<u8>()?;
+        // Safe because the transport promises that the config space pointer is valid, and the
+        // caller that the value is within it. We checked that it is aligned.
+        Ok(unsafe {
+            config_space
+                .as_ptr()
+                .add(offset)
+                .cast::<T>()
+                .read_volatile()
+        })
+    }
+
+    /// Writes a value to the device-specific configuration space, at the given offset in bytes.
+    ///
+    /// Errors and panics as for [`Transport::read_config_space`], and the default implementation
+    /// has the same limitation.
+    fn write_config_space<T: AsBytes>(&mut self, offset: usize, value: T) -> Result {
+        check_config_space_access::<T>(offset, usize::MAX)?;
+        #[allow(deprecated)]
+        let config_space = self.config_space::<u8>()?;
+        // Safe because the transport promises that the config space pointer is valid, and the
+        // caller that the value is within it. We checked that it is aligned.
+        unsafe {
+            config_space
+                .as_ptr()
+                .add(offset)
+                .cast::<T>()
+                .write_volatile(value);
+        }
+        Ok(())
+    }
+
     /// Gets the pointer to the config space.
+    ///
+    /// Transports which don't map the configuration space into memory, such as
+    /// [`LegacyPciTransport`](pci::legacy::LegacyPciTransport), return [`Error::Unsupported`].
+    #[deprecated(
+        note = "Use `read_config_space` and `write_config_space` instead, which work with every transport."
+    )]
     fn config_space<T: 'static>(&self) -> Result<NonNull<T>>;
+
+    /// Returns the configuration generation of the device, which it changes whenever it changes
+    /// its device-specific configuration space.
+    ///
+    /// Transports without one, such as the legacy interfaces, return 0.
+    ///
+    /// Ref: 2.5.1 Driver Requirements: Device Configuration Space
+    fn config_generation(&self) -> u32 {
+        0
+    }
+
+    /// Calls `read` to read one or more values from the device-specific configuration space, and
+    /// retries until the configuration generation is the same before and after it, so that the
+    /// values it returns can't be torn by the device changing them at the same time.
+    ///
+    /// This should be used whenever a value wider than 32 bits or several related fields are read.
+    /// Returns [`Error::Timeout`] if the device keeps changing the configuration for too long.
+    fn read_config_atomically<R>(&self, mut read: impl FnMut(&Self) -> Result<R>) -> Result<R> {
+        let mut result = None;
+        spin_until(|| {
+            let generation = self.config_generation();
+            let value = read(self);
+            if value.is_err() || self.config_generation() == generation {
+                result = Some(value);
+                true
+            } else {
+                false
+            }
+        })?;
+        result.unwrap()
+    }
+
+    /// Returns whether queues must have exactly the maximum size given by
+    /// [`Transport::max_queue_size`], because the device doesn't allow the driver to choose a
+    /// smaller one.
+    ///
+    /// Ref: 4.1.4.8 Legacy Interfaces: A Note on PCI Device Layout
+    fn requires_fixed_queue_size(&self) -> bool {
+        false
+    }
+}
+
+/// Checks that a value of type `T` at the given offset in bytes fits within a configuration space
+/// of `len` bytes and is suitably aligned.
+///
+/// Panics if `T` needs more than 4 byte alignment, or `offset` isn't aligned for it.
+pub(crate) fn check_config_space_access<T>(offset: usize, len: usize) -> Result {
+    if align_of::<T>() > 4 {
+        // Panic as this should only happen if the driver is written incorrectly.
+        panic!(
+            "Driver expected config space alignment of {} bytes, but VirtIO only guarantees 4 byte alignment.",
+            align_of::<T>()
+        );
+    }
+    assert_eq!(offset % align_of::<T>(), 0);
+    if offset > len || size_of::<T>() > len - offset {
+        Err(Error::ConfigSpaceTooSmall)
+    } else {
+        Ok(())
+    }
 }
 
 bitflags! {
@@ -203,3 +347,70 @@ impl From<u8> for DeviceType {
         u32::from(virtio_device_id).into()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        fake::{FakeTransport, State},
+        *,
+    };
+    use alloc::sync::Arc;
+    use core::ptr::NonNull;
+    use std::sync::Mutex;
+
+    #[test]
+    fn read_config_atomically_retries() {
+        let mut config_space = [1u32, 2];
+        let state = Arc::new(Mutex::new(State::default()));
+        let transport = FakeTransport {
+            device_type: DeviceType::Block,
+            max_queue_size: 4,
+            device_features: 0,
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+
+        let mut reads = 0;
+        let value = transport
+            .read_config_atomically(|transport| {
+                reads += 1;
+                let low: u32 = transport.read_config_space(0)?;
+                if reads == 1 {
+                    // Simulate the device changing its configuration between the two reads.
+                    // Safe because the transport only accesses the config space through volatile
+                    // reads, and not at the same time.
+                    unsafe {
+                        transport.config_space.as_ptr().write_volatile([3, 4]);
+                    }
+                    state.lock().unwrap().config_generation += 1;
+                }
+                let high: u32 = transport.read_config_space(4)?;
+                Ok((low, high))
+            })
+            .unwrap();
+        assert_eq!(value, (3, 4));
+        assert_eq!(reads, 2);
+    }
+
+    #[test]
+    fn read_config_atomically_gives_up() {
+        let mut config_space = [1u32, 2];
+        let state = Arc::new(Mutex::new(State::default()));
+        let transport = FakeTransport {
+            device_type: DeviceType::Block,
+            max_queue_size: 4,
+            device_features: 0,
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+
+        // The device changes its configuration during every read.
+        assert_eq!(
+            transport.read_config_atomically(|transport| {
+                state.lock().unwrap().config_generation += 1;
+                transport.read_config_space::<u32>(0)
+            }),
+            Err(Error::Timeout)
+        );
+    }
+}
@@ -1,14 +1,21 @@
 //! PCI transport for VirtIO.
 
 pub mod bus;
+pub mod legacy;
 
-use self::bus::{DeviceFunction, DeviceFunctionInfo, PciError, PciRoot, PCI_CAP_ID_VNDR};
-use super::{DeviceStatus, DeviceType, Transport};
+use self::bus::{
+    ConfigurationAccess, DeviceFunction, DeviceFunctionInfo, MsixInfo, MsixTable, PciError,
+    PciRoot, PCI_CAP_ID_VNDR,
+};
+use super::{check_config_space_access, DeviceStatus, DeviceType, Transport};
 use crate::{
+    barrier::{arch_barrier, Barrier},
     hal::{Hal, PhysAddr},
     nonnull_slice_from_raw_parts,
+    timeout::spin_until,
     volatile::{
-        volread, volwrite, ReadOnly, Volatile, VolatileReadable, VolatileWritable, WriteOnly,
+        field_offset, volread, volwrite, ReadOnly, Volatile, VolatileReadable, VolatileWritable,
+        WriteOnly,
     },
     Error,
 };
@@ -17,10 +24,16 @@ use core::{
     mem::{align_of, size_of},
     ptr::{addr_of_mut, NonNull},
 };
+use log::warn;
+use zerocopy::{AsBytes, FromBytes};
 
 /// The PCI vendor ID for VirtIO devices.
 const VIRTIO_VENDOR_ID: u16 = 0x1af4;
 
+/// The value of an MSI-X vector register meaning that no vector is used, which the device also
+/// returns if it couldn't allocate the vector which the driver asked for.
+pub const VIRTIO_MSI_NO_VECTOR: u16 = 0xffff;
+
 /// The offset to add to a VirtIO device ID to get the corresponding PCI device ID.
 const PCI_DEVICE_ID_OFFSET: u16 = 0x1040;
 
@@ -33,13 +46,13 @@ const TRANSITIONAL_ENTROPY_SOURCE: u16 = 0x1005;
 const TRANSITIONAL_9P_TRANSPORT: u16 = 0x1009;
 
 /// The offset of the bar field within `virtio_pci_cap`.
-const CAP_BAR_OFFSET: u8 = 4;
+const CAP_BAR_OFFSET: u16 = 4;
 /// The offset of the offset field with `virtio_pci_cap`.
-const CAP_BAR_OFFSET_OFFSET: u8 = 8;
+const CAP_BAR_OFFSET_OFFSET: u16 = 8;
 /// The offset of the `length` field within `virtio_pci_cap`.
-const CAP_LENGTH_OFFSET: u8 = 12;
+const CAP_LENGTH_OFFSET: u16 = 12;
 /// The offset of the`notify_off_multiplier` field within `virtio_pci_notify_cap`.
-const CAP_NOTIFY_OFF_MULTIPLIER_OFFSET: u8 = 16;
+const CAP_NOTIFY_OFF_MULTIPLIER_OFFSET: u16 = 16;
 
 /// Common configuration.
 const VIRTIO_PCI_CAP_COMMON_CFG: u8 = 1;
@@ -86,6 +99,9 @@ pub struct PciTransport {
     device_function: DeviceFunction,
     /// The common configuration structure within some BAR.
     common_cfg: NonNull<CommonCfg>,
+    /// The length of the common configuration structure, which may not include the fields added
+    /// in virtio 1.2.
+    common_cfg_len: usize,
     /// The start of the queue notification region within some BAR.
     notify_region: NonNull<[WriteOnly<u16>]>,
     notify_off_multiplier: u32,
@@ -93,6 +109,8 @@ pub struct PciTransport {
     isr_status: NonNull<Volatile<u8>>,
     /// The VirtIO device-specific configuration within some BAR.
     config_space: Option<NonNull<[u32]>>,
+    /// The MSI-X table within some BAR, if the device supports MSI-X.
+    msix_table: Option<MsixTable>,
 }
 
 impl PciTransport {
@@ -101,10 +119,10 @@ impl PciTransport {
     ///
     /// The PCI device must already have had its BARs allocated.
     pub fn new<H: Hal>(
-        root: &mut PciRoot,
+        root: &mut PciRoot<impl ConfigurationAccess>,
         device_function: DeviceFunction,
     ) -> Result<Self, VirtioPciError> {
-        let device_vendor = root.config_read_word(device_function, 0);
+        let device_vendor = root.read_register(device_function, 0);
         let device_id = (device_vendor >> 16) as u16;
         let vendor_id = device_vendor as u16;
         if vendor_id != VIRTIO_VENDOR_ID {
@@ -128,12 +146,18 @@ impl PciTransport {
                 continue;
             }
             let struct_info = VirtioCapabilityInfo {
-                bar: root.config_read_word(device_function, capability.offset + CAP_BAR_OFFSET)
-                    as u8,
-                offset: root
-                    .config_read_word(device_function, capability.offset + CAP_BAR_OFFSET_OFFSET),
-                length: root
-                    .config_read_word(device_function, capability.offset + CAP_LENGTH_OFFSET),
+                bar: root.read_register(
+                    device_function,
+                    u16::from(capability.offset) + CAP_BAR_OFFSET,
+                ) as u8,
+                offset: root.read_register(
+                    device_function,
+                    u16::from(capability.offset) + CAP_BAR_OFFSET_OFFSET,
+                ),
+                length: root.read_register(
+                    device_function,
+                    u16::from(capability.offset) + CAP_LENGTH_OFFSET,
+                ),
             };
 
             match cfg_type {
@@ -142,9 +166,9 @@ impl PciTransport {
                 }
                 VIRTIO_PCI_CAP_NOTIFY_CFG if cap_len >= 20 && notify_cfg.is_none() => {
                     notify_cfg = Some(struct_info);
-                    notify_off_multiplier = root.config_read_word(
+                    notify_off_multiplier = root.read_register(
                         device_function,
-                        capability.offset + CAP_NOTIFY_OFF_MULTIPLIER_OFFSET,
+                        u16::from(capability.offset) + CAP_NOTIFY_OFF_MULTIPLIER_OFFSET,
                     );
                 }
                 VIRTIO_PCI_CAP_ISR_CFG if isr_cfg.is_none() => {
@@ -157,10 +181,13 @@ impl PciTransport {
             }
         }
 
-        let common_cfg = get_bar_region::<H, _>(
+        let common_cfg = common_cfg.ok_or(VirtioPciError::MissingCommonConfig)?;
+        let common_cfg_len = common_cfg.length as usize;
+        let common_cfg = get_bar_region_min_size::<H, _>(
             root,
             device_function,
-            &common_cfg.ok_or(VirtioPciError::MissingCommonConfig)?,
+            &common_cfg,
+            COMMON_CFG_V1_0_SIZE,
         )?;
 
         let notify_cfg = notify_cfg.ok_or(VirtioPciError::MissingNotifyConfig)?;
@@ -187,18 +214,99 @@ impl PciTransport {
             None
         };
 
+        // The device still works without MSI-X, so don't fail if its table can't be mapped.
+        let msix_table = match root.msix_info(device_function) {
+            Some(msix_info) => match get_msix_table::<H>(root, device_function, &msix_info) {
+                Ok(msix_table) => Some(msix_table),
+                Err(e) => {
+                    warn!(
+                        "Failed to map MSI-X table of virtio PCI device {}: {}",
+                        device_function, e
+                    );
+                    None
+                }
+            },
+            None => None,
+        };
+
         Ok(Self {
             device_type,
             device_function,
             common_cfg,
+            common_cfg_len,
             notify_region,
             notify_off_multiplier,
             isr_status,
             config_space,
+            msix_table,
         })
     }
+
+    /// Returns the MSI-X table of the device, or `None` if it doesn't support MSI-X or its table
+    /// couldn't be mapped.
+    ///
+    /// Entries must be programmed with a message address and data from the platform's interrupt
+    /// controller before their index is passed to [`Self::set_config_msix_vector`] or
+    /// [`Self::set_queue_msix_vector`], and MSI-X must be enabled with
+    /// [`PciRoot::set_msix_enabled`].
+    pub fn msix_table(&mut self) -> Option<&mut MsixTable> {
+        self.msix_table.as_mut()
+    }
+
+    /// Sets the index of the MSI-X table entry used for configuration change notifications, or
+    /// [`VIRTIO_MSI_NO_VECTOR`] to not use MSI-X for them.
+    ///
+    /// Returns the vector which the device accepted, which is `VIRTIO_MSI_NO_VECTOR` if it
+    /// couldn't allocate the one asked for.
+    pub fn set_config_msix_vector(&mut self, vector: u16) -> u16 {
+        // Safe because the common config pointer is valid and we checked in get_bar_region that it
+        // was aligned.
+        unsafe {
+            volwrite!(self.common_cfg, msix_config, vector);
+            volread!(self.common_cfg, msix_config)
+        }
+    }
+
+    /// Sets the index of the MSI-X table entry used for used buffer notifications from the given
+    /// queue, or [`VIRTIO_MSI_NO_VECTOR`] to not use MSI-X for them.
+    ///
+    /// This should be called before the queue is created. Returns the vector which the device
+    /// accepted, which is `VIRTIO_MSI_NO_VECTOR` if it couldn't allocate the one asked for.
+    pub fn set_queue_msix_vector(&mut self, queue: u16, vector: u16) -> u16 {
+        // Safe because the common config pointer is valid and we checked in get_bar_region that it
+        // was aligned.
+        unsafe {
+            volwrite!(self.common_cfg, queue_select, queue);
+            volwrite!(self.common_cfg, queue_msix_vector, vector);
+            volread!(self.common_cfg, queue_msix_vector)
+        }
+    }
+
+    /// Returns the offset in bytes within the notify region at which to notify the given queue.
+    fn notify_offset(&mut self, queue: u16) -> usize {
+        // Safe because the common config pointer is valid and we checked in get_bar_region that it
+        // was aligned.
+        let queue_notify_off = unsafe {
+            volwrite!(self.common_cfg, queue_select, queue);
+            // TODO: Consider caching this somewhere (per queue).
+            volread!(self.common_cfg, queue_notify_off)
+        };
+        usize::from(queue_notify_off) * self.notify_off_multiplier as usize
+    }
+
+    /// Returns a pointer to a value of type `T` at the given offset within the device-specific
+    /// configuration space, after checking that it fits and is aligned.
+    fn config_space_ptr<T>(&self, offset: usize) -> Result<*mut T, Error> {
+        let config_space = self.config_space.ok_or(Error::ConfigSpaceMissing)?;
+        check_config_space_access::<T>(offset, config_space.len() * size_of::<u32>())?;
+        Ok((config_space.as_ptr() as *mut u32 as usize + offset) as *mut T)
+    }
 }
 
+// Safe because the BAR regions which the transport points to may be accessed from any CPU, and the
+// transport needs `&mut self` to change anything in them.
+unsafe impl Send for PciTransport {}
+
 impl Transport for PciTransport {
     fn device_type(&self) -> DeviceType {
         self.device_type
@@ -241,19 +349,43 @@ impl Transport for PciTransport {
     }
 
     fn notify(&mut self, queue: u16) {
-        // Safe because the common config and notify region pointers are valid and we checked in
-        // get_bar_region that they were aligned.
+        let index = self.notify_offset(queue) / size_of::<u16>();
+        // Make sure the device sees the queue as it was before the notification.
+        arch_barrier(Barrier::Write, true);
+        // Safe because the notify region pointer is valid and we checked in get_bar_region that it
+        // was aligned.
         unsafe {
-            volwrite!(self.common_cfg, queue_select, queue);
-            // TODO: Consider caching this somewhere (per queue).
-            let queue_notify_off = volread!(self.common_cfg, queue_notify_off);
-
-            let offset_bytes = usize::from(queue_notify_off) * self.notify_off_multiplier as usize;
-            let index = offset_bytes / size_of::<u16>();
             addr_of_mut!((*self.notify_region.as_ptr())[index]).vwrite(queue);
         }
     }
 
+    fn notify_with_data(&mut self, queue: u16, data: u32) {
+        let offset_bytes = self.notify_offset(queue);
+        let index = offset_bytes / size_of::<u16>();
+        // The notification data is 32 bits, so it must fit in the notify region.
+        assert!(index + 1 < self.notify_region.len());
+        // Make sure the device sees the queue as it was before the notification.
+        arch_barrier(Barrier::Write, true);
+        if offset_bytes % size_of::<u32>() == 0 {
+            // Safe because the notify region pointer is valid, and we checked above that the
+            // address is in bounds and aligned.
+            unsafe {
+                let address = addr_of_mut!((*self.notify_region.as_ptr())[index]);
+                (address as *mut WriteOnly<u32>).vwrite(data);
+            }
+        } else {
+            // `notify_off_multiplier` is only guaranteed to be even, so the address may not be
+            // aligned for a 32-bit write. Write the high half first, so that the notification
+            // proper is the write of the queue index in the low half.
+            // Safe because the notify region pointer is valid, and we checked above that both
+            // halves are in bounds.
+            unsafe {
+                addr_of_mut!((*self.notify_region.as_ptr())[index + 1]).vwrite((data >> 16) as u16);
+                addr_of_mut!((*self.notify_region.as_ptr())[index]).vwrite(data as u16);
+            }
+        }
+    }
+
     fn get_status(&self) -> DeviceStatus {
         // Safe because the common config pointer is valid and we checked in get_bar_region that it
         // was aligned.
@@ -311,6 +443,23 @@ impl Transport for PciTransport {
         }
     }
 
+    fn queue_reset(&mut self, queue: u16) -> Result<(), Error> {
+        // `CommonCfg` has padding after `queue_reset`, which the device needn't provide.
+        if self.common_cfg_len < field_offset!(CommonCfg, queue_reset) + size_of::<u16>() {
+            return Err(Error::Unsupported);
+        }
+        // Safe because the common config pointer is valid and we checked in get_bar_region that it
+        // was aligned, and above that it is big enough to include `queue_reset`.
+        unsafe {
+            volwrite!(self.common_cfg, queue_select, queue);
+            volwrite!(self.common_cfg, queue_reset, 1);
+        }
+        // Wait until the device has finished resetting the queue (see 4.1.4.3.2).
+        // Safe because the common config pointer is valid and we checked in get_bar_region that it
+        // was aligned and big enough.
+        spin_until(|| unsafe { volread!(self.common_cfg, queue_reset) } == 1)
+    }
+
     fn ack_interrupt(&mut self) -> bool {
         // Safe because the common config pointer is valid and we checked in get_bar_region that it
         // was aligned.
@@ -320,24 +469,31 @@ impl Transport for PciTransport {
         isr_status & 0x3 != 0
     }
 
-    fn config_space<T>(&self) -> Result<NonNull<T>, Error> {
-        if let Some(config_space) = self.config_space {
-            if size_of::<T>() > config_space.len() * size_of::<u32>() {
-                Err(Error::ConfigSpaceTooSmall)
-            } else if align_of::<T>() > 4 {
-                // Panic as this should only happen if the driver is written incorrectly.
-                panic!(
-                    "Driver expected config space alignment of {} bytes, but VirtIO only guarantees 4 byte alignment.",
-                    align_of::<T>()
-                );
-            } else {
-                // TODO: Use NonNull::as_non_null_ptr once it is stable.
-                let config_space_ptr = NonNull::new(config_space.as_ptr() as *mut u32).unwrap();
-                Ok(config_space_ptr.cast())
-            }
-        } else {
-            Err(Error::ConfigSpaceMissing)
+    fn read_config_space<T: AsBytes + FromBytes>(&self, offset: usize) -> Result<T, Error> {
+        let config_space = self.config_space_ptr::<T>(offset)?;
+        // Safe because the config space pointer is valid and we checked that the value is within
+        // it and aligned.
+        Ok(unsafe { config_space.read_volatile() })
+    }
+
+    fn write_config_space<T: AsBytes>(&mut self, offset: usize, value: T) -> Result<(), Error> {
+        let config_space = self.config_space_ptr::<T>(offset)?;
+        // Safe because the config space pointer is valid and we checked that the value is within
+        // it and aligned.
+        unsafe {
+            config_space.write_volatile(value);
         }
+        Ok(())
+    }
+
+    fn config_space<T: 'static>(&self) -> Result<NonNull<T>, Error> {
+        Ok(NonNull::new(self.config_space_ptr::<T>(0)?).unwrap())
+    }
+
+    fn config_generation(&self) -> u32 {
+        // Safe because the common config pointer is valid and we checked in get_bar_region that it
+        // was aligned.
+        u32::from(unsafe { volread!(self.common_cfg, config_generation) })
     }
 }
 
@@ -345,7 +501,10 @@ impl Drop for PciTransport {
     fn drop(&mut self) {
         // Reset the device when the transport is dropped.
         self.set_status(DeviceStatus::empty());
-        while self.get_status() != DeviceStatus::empty() {}
+        // Don't hang forever if the device never finishes resetting.
+        if spin_until(|| self.get_status() == DeviceStatus::empty()).is_err() {
+            warn!("Timed out waiting for virtio PCI device to reset");
+        }
     }
 }
 
@@ -368,8 +527,17 @@ struct CommonCfg {
     queue_desc: Volatile<u64>,
     queue_driver: Volatile<u64>,
     queue_device: Volatile<u64>,
+    // Only present since virtio 1.2.
+    queue_notify_data: ReadOnly<u16>,
+    queue_reset: Volatile<u16>,
 }
 
+/// The size in bytes of an entry in an MSI-X table.
+const MSIX_TABLE_ENTRY_SIZE: u32 = 16;
+
+/// The size of `CommonCfg` without the fields added in virtio 1.2, which devices need not have.
+const COMMON_CFG_V1_0_SIZE: usize = 56;
+
 /// Information about a VirtIO structure within some BAR, as provided by a `virtio_pci_cap`.
 #[derive(Clone, Debug, Eq, PartialEq)]
 struct VirtioCapabilityInfo {
@@ -382,9 +550,20 @@ struct VirtioCapabilityInfo {
 }
 
 fn get_bar_region<H: Hal, T>(
-    root: &mut PciRoot,
+    root: &mut PciRoot<impl ConfigurationAccess>,
+    device_function: DeviceFunction,
+    struct_info: &VirtioCapabilityInfo,
+) -> Result<NonNull<T>, VirtioPciError> {
+    get_bar_region_min_size::<H, T>(root, device_function, struct_info, size_of::<T>())
+}
+
+/// Like `get_bar_region`, but only requires the region to be `min_size` bytes long rather than
+/// the full size of `T`, for structures which have grown in later versions of the spec.
+fn get_bar_region_min_size<H: Hal, T>(
+    root: &mut PciRoot<impl ConfigurationAccess>,
     device_function: DeviceFunction,
     struct_info: &VirtioCapabilityInfo,
+    min_size: usize,
 ) -> Result<NonNull<T>, VirtioPciError> {
     let bar_info = root.bar_info(device_function, struct_info.bar)?;
     let (bar_address, bar_size) = bar_info
@@ -393,8 +572,7 @@ fn get_bar_region<H: Hal, T>(
     if bar_address == 0 {
         return Err(VirtioPciError::BarNotAllocated(struct_info.bar));
     }
-    if struct_info.offset + struct_info.length > bar_size
-        || size_of::<T>() > struct_info.length as usize
+    if struct_info.offset + struct_info.length > bar_size || min_size > struct_info.length as usize
     {
         return Err(VirtioPciError::BarOffsetOutOfRange);
     }
@@ -412,7 +590,7 @@ fn get_bar_region<H: Hal, T>(
 }
 
 fn get_bar_region_slice<H: Hal, T>(
-    root: &mut PciRoot,
+    root: &mut PciRoot<impl ConfigurationAccess>,
     device_function: DeviceFunction,
     struct_info: &VirtioCapabilityInfo,
 ) -> Result<NonNull<[T]>, VirtioPciError> {
@@ -423,6 +601,23 @@ fn get_bar_region_slice<H: Hal, T>(
     ))
 }
 
+/// Maps the MSI-X table described by the given capability.
+fn get_msix_table<H: Hal>(
+    root: &mut PciRoot<impl ConfigurationAccess>,
+    device_function: DeviceFunction,
+    msix_info: &MsixInfo,
+) -> Result<MsixTable, VirtioPciError> {
+    let table_info = VirtioCapabilityInfo {
+        bar: msix_info.table_bar,
+        offset: msix_info.table_offset,
+        length: u32::from(msix_info.table_size) * MSIX_TABLE_ENTRY_SIZE,
+    };
+    let entries = get_bar_region::<H, u32>(root, device_function, &table_info)?;
+    // Safe because get_bar_region checked that the table is within the BAR, which is mapped as
+    // MMIO, and aligned. The transport is the only user of the device's MSI-X table.
+    Ok(unsafe { MsixTable::new(entries.cast(), msix_info.table_size) })
+}
+
 /// An error encountered initialising a VirtIO PCI transport.
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum VirtioPciError {
@@ -439,6 +634,10 @@ pub enum VirtioPciError {
     MissingIsrConfig,
     /// An IO BAR was provided rather than a memory BAR.
     UnexpectedIoBar,
+    /// A memory BAR was provided rather than an IO BAR.
+    UnexpectedMemoryBar,
+    /// The PCI device ID was not one of the transitional IDs which support the legacy interface.
+    NotTransitional(u16),
     /// A BAR which we need was not allocated an address.
     BarNotAllocated(u8),
     /// The offset for some capability was greater than the length of the BAR.
@@ -481,6 +680,12 @@ impl Display for VirtioPciError {
                 write!(f, "No valid `VIRTIO_PCI_CAP_ISR_CFG` capability was found.")
             }
             Self::UnexpectedIoBar => write!(f, "Unexpected IO BAR (expected memory BAR)."),
+            Self::UnexpectedMemoryBar => write!(f, "Unexpected memory BAR (expected IO BAR)."),
+            Self::NotTransitional(device_id) => write!(
+                f,
+                "PCI device ID {:#06x} is not a transitional VirtIO device ID.",
+                device_id
+            ),
             Self::BarNotAllocated(bar_index) => write!(f, "Bar {} not allocated.", bar_index),
             Self::BarOffsetOutOfRange => write!(f, "Capability offset greater than BAR length."),
             Self::Misaligned { vaddr, alignment } => write!(
@@ -501,7 +706,269 @@ impl From<PciError> for VirtioPciError {
 
 #[cfg(test)]
 mod tests {
+    use super::bus::fake::{FakeConfigSpace, FakeFunction};
     use super::*;
+    use crate::hal::fake::FakeHal;
+
+    /// The memory of a fake BAR, which `FakeHal` maps at its physical address.
+    #[repr(C, align(4096))]
+    struct FakeBar([u32; 1024]);
+
+    const COMMON_CFG_OFFSET: u32 = 0x000;
+    const NOTIFY_CFG_OFFSET: u32 = 0x100;
+    const ISR_CFG_OFFSET: u32 = 0x200;
+    const MSIX_TABLE_OFFSET: u32 = 0x400;
+    const MSIX_PBA_OFFSET: u32 = 0x800;
+
+    /// Adds a modern virtio block device with an MSI-X table of 4 entries and a common
+    /// configuration structure of `common_cfg_len` bytes to the given configuration space, with
+    /// its BAR0 backed by the returned memory.
+    fn fake_virtio_device(
+        config_space: &FakeConfigSpace,
+        device_function: DeviceFunction,
+        common_cfg_len: u32,
+    ) -> *mut FakeBar {
+        let bar = Box::into_raw(Box::new(FakeBar([0; 1024])));
+        let virtio_cap = |cfg_type: u8, cap_len: u8| u16::from(cfg_type) << 8 | u16::from(cap_len);
+        let function = FakeFunction::new(
+            device_function,
+            &DeviceFunctionInfo {
+                vendor_id: VIRTIO_VENDOR_ID,
+                device_id: PCI_DEVICE_ID_OFFSET + 2,
+                class: 0x01,
+                subclass: 0x00,
+                prog_if: 0x00,
+                revision: 0x01,
+                header_type: bus::HeaderType::Standard,
+            },
+        )
+        .memory_bar_64(0, 0x1000, false)
+        .capability(
+            0x40,
+            PCI_CAP_ID_VNDR,
+            virtio_cap(VIRTIO_PCI_CAP_COMMON_CFG, 16),
+            &[0, COMMON_CFG_OFFSET, common_cfg_len],
+        )
+        .capability(
+            0x50,
+            PCI_CAP_ID_VNDR,
+            virtio_cap(VIRTIO_PCI_CAP_NOTIFY_CFG, 20),
+            &[0, NOTIFY_CFG_OFFSET, 0x40, 4],
+        )
+        .capability(
+            0x64,
+            PCI_CAP_ID_VNDR,
+            virtio_cap(VIRTIO_PCI_CAP_ISR_CFG, 16),
+            &[0, ISR_CFG_OFFSET, 1],
+        )
+        .capability(
+            0x74,
+            bus::PCI_CAP_ID_MSIX,
+            3,
+            &[MSIX_TABLE_OFFSET, MSIX_PBA_OFFSET],
+        );
+        config_space.add(function);
+        PciRoot::new(config_space.clone()).set_bar_64(device_function, 0, bar as u64);
+        bar
+    }
+
+    /// Reads the given word of a fake BAR.
+    fn bar_word(bar: *mut FakeBar, offset: u32) -> u32 {
+        // Safe because the BAR is still allocated, and the transport isn't accessing it.
+        unsafe { (*bar).0.as_ptr().add(offset as usize / 4).read_volatile() }
+    }
+
+    #[test]
+    fn msix() {
+        let device_function = DeviceFunction {
+            bus: 0,
+            device: 4,
+            function: 0,
+        };
+        let config_space = FakeConfigSpace::default();
+        let bar = fake_virtio_device(
+            &config_space,
+            device_function,
+            size_of::<CommonCfg>() as u32,
+        );
+        let mut root = PciRoot::new(config_space);
+
+        let msix_info = root.msix_info(device_function).unwrap();
+        assert_eq!(
+            msix_info,
+            MsixInfo {
+                offset: 0x74,
+                table_size: 4,
+                table_bar: 0,
+                table_offset: MSIX_TABLE_OFFSET,
+                pba_bar: 0,
+                pba_offset: MSIX_PBA_OFFSET,
+            }
+        );
+        assert!(!root.msix_enabled(device_function, &msix_info));
+        root.set_msix_enabled(device_function, &msix_info, true, false);
+        assert!(root.msix_enabled(device_function, &msix_info));
+
+        let mut transport = PciTransport::new::<FakeHal>(&mut root, device_function).unwrap();
+        let table = transport.msix_table().unwrap();
+        assert_eq!(table.len(), 4);
+        table.set_entry(1, 0x1_fee0_1000, 0x41);
+        assert!(!table.masked(1));
+        table.set_masked(1, true);
+        assert!(table.masked(1));
+        assert_eq!(bar_word(bar, MSIX_TABLE_OFFSET + 16), 0xfee0_1000);
+        assert_eq!(bar_word(bar, MSIX_TABLE_OFFSET + 20), 0x1);
+        assert_eq!(bar_word(bar, MSIX_TABLE_OFFSET + 24), 0x41);
+        assert_eq!(bar_word(bar, MSIX_TABLE_OFFSET + 28), 0x1);
+
+        // The fake device accepts whatever vector it is given.
+        assert_eq!(transport.set_config_msix_vector(0), 0);
+        assert_eq!(transport.set_queue_msix_vector(2, 1), 1);
+        // `msix_config` is the low half of the word at 16, `queue_select` the high half of the word
+        // at 20, and `queue_msix_vector` the high half of the word at 24.
+        assert_eq!(bar_word(bar, COMMON_CFG_OFFSET + 16), 0x0000_0000);
+        assert_eq!(bar_word(bar, COMMON_CFG_OFFSET + 20) >> 16, 2);
+        assert_eq!(bar_word(bar, COMMON_CFG_OFFSET + 24), 0x0001_0000);
+
+        drop(transport);
+        // Safe because the transport using the BAR has been dropped.
+        drop(unsafe { Box::from_raw(bar) });
+    }
+
+    #[test]
+    fn unmappable_msix_table() {
+        let device_function = DeviceFunction {
+            bus: 0,
+            device: 4,
+            function: 0,
+        };
+        let mut config_space = FakeConfigSpace::default();
+        let bar = fake_virtio_device(
+            &config_space,
+            device_function,
+            size_of::<CommonCfg>() as u32,
+        );
+        // Move the MSI-X table past the end of BAR0.
+        config_space.write_word(device_function, 0x78, 0x1000);
+        let mut root = PciRoot::new(config_space);
+        assert_eq!(
+            root.msix_info(device_function).unwrap().table_offset,
+            0x1000
+        );
+
+        let mut transport = PciTransport::new::<FakeHal>(&mut root, device_function).unwrap();
+        assert!(transport.msix_table().is_none());
+
+        drop(transport);
+        // Safe because the transport using the BAR has been dropped.
+        drop(unsafe { Box::from_raw(bar) });
+    }
+
+    #[test]
+    fn config_generation() {
+        let device_function = DeviceFunction {
+            bus: 0,
+            device: 4,
+            function: 0,
+        };
+        let config_space = FakeConfigSpace::default();
+        let bar = fake_virtio_device(
+            &config_space,
+            device_function,
+            size_of::<CommonCfg>() as u32,
+        );
+        let mut root = PciRoot::new(config_space);
+
+        let transport = PciTransport::new::<FakeHal>(&mut root, device_function).unwrap();
+        assert_eq!(transport.config_generation(), 0);
+        // `config_generation` is the second byte of the word at 20.
+        // Safe because the transport only reads the BAR through volatile accesses.
+        unsafe {
+            (*bar).0[(COMMON_CFG_OFFSET + 20) as usize / 4] = 0x0000_0700;
+        }
+        assert_eq!(transport.config_generation(), 7);
+
+        drop(transport);
+        // Safe because the transport using the BAR has been dropped.
+        drop(unsafe { Box::from_raw(bar) });
+    }
+
+    #[test]
+    fn notify_with_data_unaligned() {
+        let device_function = DeviceFunction {
+            bus: 0,
+            device: 4,
+            function: 0,
+        };
+        let config_space = FakeConfigSpace::default();
+        let bar = fake_virtio_device(
+            &config_space,
+            device_function,
+            size_of::<CommonCfg>() as u32,
+        );
+        let mut root = PciRoot::new(config_space);
+
+        let mut transport = PciTransport::new::<FakeHal>(&mut root, device_function).unwrap();
+        // A multiplier of 2 and an odd `queue_notify_off`, which is the high half of the word at 28,
+        // put the notify address for the queue at 6 bytes into the notify region.
+        transport.notify_off_multiplier = 2;
+        // Safe because the transport only accesses the BAR through volatile accesses.
+        unsafe {
+            (*bar).0[(COMMON_CFG_OFFSET + 28) as usize / 4] = 0x0003_0000;
+        }
+        transport.notify_with_data(1, 0x8005_0001);
+        assert_eq!(bar_word(bar, NOTIFY_CFG_OFFSET + 4), 0x0001_0000);
+        assert_eq!(bar_word(bar, NOTIFY_CFG_OFFSET + 8), 0x0000_8005);
+
+        drop(transport);
+        // Safe because the transport using the BAR has been dropped.
+        drop(unsafe { Box::from_raw(bar) });
+    }
+
+    /// Tests that queues can be reset on a device whose common configuration structure ends right
+    /// after `queue_reset`, without the padding which `CommonCfg` has.
+    #[test]
+    fn queue_reset() {
+        let device_function = DeviceFunction {
+            bus: 0,
+            device: 4,
+            function: 0,
+        };
+        let config_space = FakeConfigSpace::default();
+        let bar = fake_virtio_device(&config_space, device_function, 60);
+        let mut root = PciRoot::new(config_space);
+
+        let mut transport = PciTransport::new::<FakeHal>(&mut root, device_function).unwrap();
+        // The fake device finishes resetting the queue straight away.
+        transport.queue_reset(3).unwrap();
+        // `queue_select` is the high half of the word at 20, and `queue_reset` the high half of the
+        // word at 56.
+        assert_eq!(bar_word(bar, COMMON_CFG_OFFSET + 20) >> 16, 3);
+        assert_eq!(bar_word(bar, COMMON_CFG_OFFSET + 56) >> 16, 1);
+
+        drop(transport);
+        // Safe because the transport using the BAR has been dropped.
+        drop(unsafe { Box::from_raw(bar) });
+    }
+
+    #[test]
+    fn queue_reset_unsupported() {
+        let device_function = DeviceFunction {
+            bus: 0,
+            device: 4,
+            function: 0,
+        };
+        let config_space = FakeConfigSpace::default();
+        let bar = fake_virtio_device(&config_space, device_function, COMMON_CFG_V1_0_SIZE as u32);
+        let mut root = PciRoot::new(config_space);
+
+        let mut transport = PciTransport::new::<FakeHal>(&mut root, device_function).unwrap();
+        assert_eq!(transport.queue_reset(3), Err(Error::Unsupported));
+
+        drop(transport);
+        // Safe because the transport using the BAR has been dropped.
+        drop(unsafe { Box::from_raw(bar) });
+    }
 
     #[test]
     fn transitional_device_ids() {
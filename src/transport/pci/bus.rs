@@ -1,12 +1,27 @@
 //! Module for dealing with a PCI bus in general, without anything specific to VirtIO.
 
+use crate::volatile::{volread, volwrite, Volatile};
 use bitflags::bitflags;
 use core::{
     convert::TryFrom,
     fmt::{self, Display, Formatter},
+    ptr::NonNull,
 };
 use log::warn;
 
+mod allocator;
+mod extended;
+#[cfg(test)]
+/// A fake PCI configuration space for unit tests.
+pub mod fake;
+
+pub use self::allocator::{BarAllocator, PciWindow, PciWindows};
+pub use self::extended::{
+    Acs, AcsInfo, AerCorrectable, AerInfo, AerUncorrectable, ExtendedCapabilityInfo,
+    ExtendedCapabilityIterator, SrIovControl, SrIovInfo, PCI_EXT_CAP_ID_ACS, PCI_EXT_CAP_ID_AER,
+    PCI_EXT_CAP_ID_SRIOV,
+};
+
 const INVALID_READ: u32 = 0xffffffff;
 
 /// The maximum number of devices on a bus.
@@ -15,12 +30,21 @@ const MAX_DEVICES: u8 = 32;
 const MAX_FUNCTIONS: u8 = 8;
 
 /// The offset in bytes to the status and command fields within PCI configuration space.
-const STATUS_COMMAND_OFFSET: u8 = 0x04;
+const STATUS_COMMAND_OFFSET: u16 = 0x04;
 /// The offset in bytes to BAR0 within PCI configuration space.
-const BAR0_OFFSET: u8 = 0x10;
+const BAR0_OFFSET: u16 = 0x10;
+/// The offset in bytes to the primary, secondary and subordinate bus numbers within the
+/// configuration space of a PCI-to-PCI bridge.
+const BRIDGE_BUS_NUMBERS_OFFSET: u16 = 0x18;
+
+/// The maximum number of PCI-to-PCI bridges between the root bus and a device which
+/// [`PciRoot::enumerate_tree`] will follow.
+pub const MAX_BRIDGE_DEPTH: usize = 16;
 
 /// ID for vendor-specific PCI capabilities.
 pub const PCI_CAP_ID_VNDR: u8 = 0x09;
+/// ID for MSI-X capabilities.
+pub const PCI_CAP_ID_MSIX: u8 = 0x11;
 
 bitflags! {
     /// The status register in PCI configuration space.
@@ -82,24 +106,98 @@ bitflags! {
 
 /// Errors accessing a PCI device.
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
 pub enum PciError {
     /// The device reported an invalid BAR type.
     InvalidBarType,
+    /// There were more PCI-to-PCI bridges than bus numbers to assign to the buses behind them.
+    BusNumbersExhausted,
+    /// There was no space left in the memory windows for the given BAR of the given device
+    /// function.
+    NoSpaceForBar(DeviceFunction, u8),
+    /// The given configuration space register offset wasn't word-aligned, or was beyond the
+    /// configuration space reachable through the configuration access mechanism.
+    InvalidRegisterOffset(u16),
 }
 
 impl Display for PciError {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         match self {
             Self::InvalidBarType => write!(f, "Invalid PCI BAR type."),
+            Self::BusNumbersExhausted => write!(f, "Ran out of PCI bus numbers for bridges."),
+            Self::NoSpaceForBar(device_function, bar_index) => write!(
+                f,
+                "No space in PCI memory windows for BAR {} of {}.",
+                bar_index, device_function
+            ),
+            Self::InvalidRegisterOffset(offset) => {
+                write!(
+                    f,
+                    "Invalid PCI configuration register offset {:#x}.",
+                    offset
+                )
+            }
         }
     }
 }
 
+/// Access to the PCI I/O port address space, such as the `in` and `out` instructions on x86.
+///
+/// This is used for devices with I/O BARs and for [`PortCam`]. Port addresses are as given by
+/// [`BarInfo::IO`], and values are in the CPU's native byte order.
+pub trait PortIo {
+    /// Reads a byte from the given I/O port.
+    fn read8(&self, port: u32) -> u8;
+    /// Reads 2 bytes from the given I/O port, which is 2-byte aligned.
+    fn read16(&self, port: u32) -> u16;
+    /// Reads 4 bytes from the given I/O port, which is 4-byte aligned.
+    fn read32(&self, port: u32) -> u32;
+    /// Writes a byte to the given I/O port.
+    fn write8(&self, port: u32, value: u8);
+    /// Writes 2 bytes to the given I/O port, which is 2-byte aligned.
+    fn write16(&self, port: u32, value: u16);
+    /// Writes 4 bytes to the given I/O port, which is 4-byte aligned.
+    fn write32(&self, port: u32, value: u32);
+}
+
+/// A mechanism for accessing the PCI configuration space of device functions.
+///
+/// This is implemented by [`MmioCam`] for the memory-mapped CAM and ECAM, and by [`PortCam`] for
+/// the x86 I/O port mechanism. Platforms where configuration space is only reachable some other
+/// way, such as through hypervisor calls, can implement it themselves.
+pub trait ConfigurationAccess {
+    /// Reads 4 bytes from the configuration space of the given device function.
+    ///
+    /// `register_offset` is a multiple of 4, and less than [`config_space_size`](Self::config_space_size).
+    fn read_word(&self, device_function: DeviceFunction, register_offset: u16) -> u32;
+
+    /// Writes 4 bytes to the configuration space of the given device function.
+    ///
+    /// `register_offset` is a multiple of 4, and less than [`config_space_size`](Self::config_space_size).
+    fn write_word(&mut self, device_function: DeviceFunction, register_offset: u16, data: u32);
+
+    /// Returns the size in bytes of the configuration space of each device function which can be
+    /// accessed through this mechanism.
+    ///
+    /// This is 256 bytes for conventional PCI, or 4 KiB if the PCIe extended configuration space
+    /// is reachable.
+    fn config_space_size(&self) -> u16 {
+        0x100
+    }
+
+    /// Makes a clone of the `ConfigurationAccess`, accessing the same configuration space.
+    ///
+    /// # Safety
+    ///
+    /// This allows concurrent mutable access to the configuration space. To avoid this causing
+    /// problems, the returned instance must only be used to read read-only fields.
+    unsafe fn unsafe_clone(&self) -> Self;
+}
+
 /// The root complex of a PCI bus.
 #[derive(Debug)]
-pub struct PciRoot {
-    mmio_base: *mut u32,
-    cam: Cam,
+pub struct PciRoot<C: ConfigurationAccess> {
+    config_access: C,
 }
 
 /// A PCI Configuration Access Mechanism.
@@ -125,8 +223,15 @@ impl Cam {
     }
 }
 
-impl PciRoot {
-    /// Wraps the PCI root complex with the given MMIO base address.
+/// Configuration space access through a memory-mapped CAM or ECAM region.
+#[derive(Debug)]
+pub struct MmioCam {
+    mmio_base: *mut u32,
+    cam: Cam,
+}
+
+impl MmioCam {
+    /// Wraps the CAM or ECAM region with the given MMIO base address.
     ///
     /// Panics if the base address is not aligned to a 4-byte boundary.
     ///
@@ -144,21 +249,9 @@ impl PciRoot {
         }
     }
 
-    /// Makes a clone of the `PciRoot`, pointing at the same MMIO region.
-    ///
-    /// # Safety
-    ///
-    /// This function allows concurrent mutable access to the PCI CAM. To avoid this causing
-    /// problems, the returned `PciRoot` instance must only be used to read read-only fields.
-    unsafe fn unsafe_clone(&self) -> Self {
-        Self {
-            mmio_base: self.mmio_base,
-            cam: self.cam,
-        }
-    }
-
-    fn cam_offset(&self, device_function: DeviceFunction, register_offset: u8) -> u32 {
+    fn cam_offset(&self, device_function: DeviceFunction, register_offset: u16) -> u32 {
         assert!(device_function.valid());
+        assert!(register_offset < self.config_space_size());
 
         let bdf = (device_function.bus as u32) << 8
             | (device_function.device as u32) << 3
@@ -174,13 +267,10 @@ impl PciRoot {
         assert!(address & 0x3 == 0);
         address
     }
+}
 
-    /// Reads 4 bytes from configuration space using the appropriate CAM.
-    pub(crate) fn config_read_word(
-        &self,
-        device_function: DeviceFunction,
-        register_offset: u8,
-    ) -> u32 {
+impl ConfigurationAccess for MmioCam {
+    fn read_word(&self, device_function: DeviceFunction, register_offset: u16) -> u32 {
         let address = self.cam_offset(device_function, register_offset);
         // Safe because both the `mmio_base` and the address offset are properly aligned, and the
         // resulting pointer is within the MMIO range of the CAM.
@@ -190,13 +280,7 @@ impl PciRoot {
         }
     }
 
-    /// Writes 4 bytes to configuration space using the appropriate CAM.
-    pub(crate) fn config_write_word(
-        &mut self,
-        device_function: DeviceFunction,
-        register_offset: u8,
-        data: u32,
-    ) {
+    fn write_word(&mut self, device_function: DeviceFunction, register_offset: u16, data: u32) {
         let address = self.cam_offset(device_function, register_offset);
         // Safe because both the `mmio_base` and the address offset are properly aligned, and the
         // resulting pointer is within the MMIO range of the CAM.
@@ -206,8 +290,158 @@ impl PciRoot {
         }
     }
 
+    fn config_space_size(&self) -> u16 {
+        match self.cam {
+            Cam::MmioCam => 0x100,
+            Cam::Ecam => 0x1000,
+        }
+    }
+
+    unsafe fn unsafe_clone(&self) -> Self {
+        Self {
+            mmio_base: self.mmio_base,
+            cam: self.cam,
+        }
+    }
+}
+
+/// The I/O port to which `PortCam` writes the address of the register to access.
+const CONFIG_ADDRESS_PORT: u32 = 0xcf8;
+/// The I/O port through which `PortCam` reads or writes the register selected by the address.
+const CONFIG_DATA_PORT: u32 = 0xcfc;
+
+/// Configuration space access through the x86 I/O ports 0xCF8 and 0xCFC, known as configuration
+/// mechanism #1, for machines without memory-mapped configuration space.
+///
+/// This provides access to 256 bytes of configuration space per device function. Each access
+/// writes the address port and then accesses the data port, so the caller must make sure that
+/// nothing else uses the ports at the same time.
+#[derive(Clone, Debug)]
+pub struct PortCam<P: PortIo + Clone> {
+    ports: P,
+}
+
+impl<P: PortIo + Clone> PortCam<P> {
+    /// Accesses configuration space through the given I/O port accessor.
+    pub fn new(ports: P) -> Self {
+        Self { ports }
+    }
+
+    /// Selects the given register of the given device function, ready to access it through the
+    /// data port.
+    fn select(&self, device_function: DeviceFunction, register_offset: u16) {
+        assert!(device_function.valid());
+        assert!(register_offset < 0x100);
+        assert!(register_offset & 0x3 == 0);
+        let address = 1 << 31
+            | (device_function.bus as u32) << 16
+            | (device_function.device as u32) << 11
+            | (device_function.function as u32) << 8
+            | register_offset as u32;
+        self.ports.write32(CONFIG_ADDRESS_PORT, address);
+    }
+}
+
+impl<P: PortIo + Clone> ConfigurationAccess for PortCam<P> {
+    fn read_word(&self, device_function: DeviceFunction, register_offset: u16) -> u32 {
+        self.select(device_function, register_offset);
+        self.ports.read32(CONFIG_DATA_PORT)
+    }
+
+    fn write_word(&mut self, device_function: DeviceFunction, register_offset: u16, data: u32) {
+        self.select(device_function, register_offset);
+        self.ports.write32(CONFIG_DATA_PORT, data);
+    }
+
+    unsafe fn unsafe_clone(&self) -> Self {
+        self.clone()
+    }
+}
+
+impl<C: ConfigurationAccess> PciRoot<C> {
+    /// Wraps the PCI root complex whose configuration space is accessed through `config_access`.
+    pub fn new(config_access: C) -> Self {
+        Self { config_access }
+    }
+
+    /// Makes a clone of the `PciRoot`, accessing the same configuration space.
+    ///
+    /// # Safety
+    ///
+    /// This function allows concurrent mutable access to the configuration space. To avoid this
+    /// causing problems, the returned `PciRoot` instance must only be used to read read-only
+    /// fields.
+    unsafe fn unsafe_clone(&self) -> Self {
+        Self {
+            config_access: self.config_access.unsafe_clone(),
+        }
+    }
+
+    /// Reads 4 bytes from the configuration space of the given device function.
+    ///
+    /// `register_offset` must be a multiple of 4, and within the configuration space reachable
+    /// through the configuration access mechanism, or [`PciError::InvalidRegisterOffset`] is
+    /// returned. Offsets of 0x100 and above are in the PCIe extended configuration space, which is
+    /// only available through ECAM.
+    pub fn config_read_word(
+        &self,
+        device_function: DeviceFunction,
+        register_offset: u16,
+    ) -> Result<u32, PciError> {
+        self.check_register_offset(register_offset)?;
+        Ok(self.read_register(device_function, register_offset))
+    }
+
+    /// Writes 4 bytes to the configuration space of the given device function.
+    ///
+    /// The same restrictions on `register_offset` apply as for
+    /// [`config_read_word`](Self::config_read_word).
+    pub fn config_write_word(
+        &mut self,
+        device_function: DeviceFunction,
+        register_offset: u16,
+        data: u32,
+    ) -> Result<(), PciError> {
+        self.check_register_offset(register_offset)?;
+        self.write_register(device_function, register_offset, data);
+        Ok(())
+    }
+
+    /// Returns an error unless `register_offset` is word-aligned and within the configuration
+    /// space reachable through the configuration access mechanism.
+    fn check_register_offset(&self, register_offset: u16) -> Result<(), PciError> {
+        if register_offset % 4 == 0 && register_offset < self.config_access.config_space_size() {
+            Ok(())
+        } else {
+            Err(PciError::InvalidRegisterOffset(register_offset))
+        }
+    }
+
+    /// Reads 4 bytes from the configuration space of the given device function, at an offset
+    /// which is known to be valid.
+    pub(crate) fn read_register(
+        &self,
+        device_function: DeviceFunction,
+        register_offset: u16,
+    ) -> u32 {
+        self.config_access
+            .read_word(device_function, register_offset)
+    }
+
+    /// Writes 4 bytes to the configuration space of the given device function, at an offset which
+    /// is known to be valid.
+    pub(crate) fn write_register(
+        &mut self,
+        device_function: DeviceFunction,
+        register_offset: u16,
+        data: u32,
+    ) {
+        self.config_access
+            .write_word(device_function, register_offset, data)
+    }
+
     /// Enumerates PCI devices on the given bus.
-    pub fn enumerate_bus(&self, bus: u8) -> BusDeviceIterator {
+    pub fn enumerate_bus(&self, bus: u8) -> BusDeviceIterator<C> {
         // Safe because the BusDeviceIterator only reads read-only fields.
         let root = unsafe { self.unsafe_clone() };
         BusDeviceIterator {
@@ -220,9 +454,83 @@ impl PciRoot {
         }
     }
 
+    /// Enumerates PCI devices on the given bus and, recursively, on the buses behind any
+    /// PCI-to-PCI bridges on it, along with the path of bridges through which each was found.
+    ///
+    /// This relies on the bridges' secondary bus numbers, so they must already have been assigned,
+    /// either by firmware or with [`PciRoot::assign_bus_numbers`]. Bridges more than
+    /// [`MAX_BRIDGE_DEPTH`] deep are not followed.
+    pub fn enumerate_tree(&self, bus: u8) -> TreeDeviceIterator<C> {
+        let mut stack = core::array::from_fn(|_| None);
+        stack[0] = Some(self.enumerate_bus(bus));
+        TreeDeviceIterator {
+            stack,
+            path: PciPath::default(),
+        }
+    }
+
+    /// Assigns bus numbers to the PCI-to-PCI bridges on the given bus and recursively behind them,
+    /// numbering buses depth-first from `bus + 1`.
+    ///
+    /// This is needed on platforms without firmware to do it, before the devices behind the
+    /// bridges can be accessed. Returns the highest bus number assigned, which is `bus` if there
+    /// are no bridges. Any bus numbers which the bridges already had, such as from firmware, are
+    /// replaced.
+    pub fn assign_bus_numbers(&mut self, bus: u8) -> Result<u8, PciError> {
+        // Clear stale bus numbers first, so that a bridge later on the bus doesn't also forward the
+        // buses we assign behind its siblings.
+        for (device_function, info) in self.enumerate_bus(bus) {
+            if info.header_type == HeaderType::PciPciBridge {
+                self.set_bridge_bus_numbers(device_function, bus, 0, 0);
+            }
+        }
+        let mut last_bus = bus;
+        for (device_function, info) in self.enumerate_bus(bus) {
+            if info.header_type != HeaderType::PciPciBridge {
+                continue;
+            }
+            let secondary = last_bus
+                .checked_add(1)
+                .ok_or(PciError::BusNumbersExhausted)?;
+            // Forward all bus numbers above the secondary bus until we know how many are behind
+            // this bridge.
+            self.set_bridge_bus_numbers(device_function, bus, secondary, 0xff);
+            last_bus = self.assign_bus_numbers(secondary)?;
+            self.set_bridge_bus_numbers(device_function, bus, secondary, last_bus);
+        }
+        Ok(last_bus)
+    }
+
+    /// Reads the primary, secondary and subordinate bus numbers of the given PCI-to-PCI bridge.
+    pub fn bridge_bus_numbers(&self, device_function: DeviceFunction) -> (u8, u8, u8) {
+        let bus_numbers = self.read_register(device_function, BRIDGE_BUS_NUMBERS_OFFSET);
+        (
+            bus_numbers as u8,
+            (bus_numbers >> 8) as u8,
+            (bus_numbers >> 16) as u8,
+        )
+    }
+
+    /// Sets the primary, secondary and subordinate bus numbers of the given PCI-to-PCI bridge.
+    pub fn set_bridge_bus_numbers(
+        &mut self,
+        device_function: DeviceFunction,
+        primary: u8,
+        secondary: u8,
+        subordinate: u8,
+    ) {
+        let bus_numbers = self.read_register(device_function, BRIDGE_BUS_NUMBERS_OFFSET);
+        // Keep the secondary latency timer in the top byte.
+        let bus_numbers = bus_numbers & 0xff000000
+            | u32::from(subordinate) << 16
+            | u32::from(secondary) << 8
+            | u32::from(primary);
+        self.write_register(device_function, BRIDGE_BUS_NUMBERS_OFFSET, bus_numbers);
+    }
+
     /// Reads the status and command registers of the given device function.
     pub fn get_status_command(&self, device_function: DeviceFunction) -> (Status, Command) {
-        let status_command = self.config_read_word(device_function, STATUS_COMMAND_OFFSET);
+        let status_command = self.read_register(device_function, STATUS_COMMAND_OFFSET);
         let status = Status::from_bits_truncate((status_command >> 16) as u16);
         let command = Command::from_bits_truncate(status_command as u16);
         (status, command)
@@ -230,7 +538,7 @@ impl PciRoot {
 
     /// Sets the command register of the given device function.
     pub fn set_command(&mut self, device_function: DeviceFunction, command: Command) {
-        self.config_write_word(
+        self.write_register(
             device_function,
             STATUS_COMMAND_OFFSET,
             command.bits().into(),
@@ -238,7 +546,7 @@ impl PciRoot {
     }
 
     /// Gets an iterator over the capabilities of the given device function.
-    pub fn capabilities(&self, device_function: DeviceFunction) -> CapabilityIterator {
+    pub fn capabilities(&self, device_function: DeviceFunction) -> CapabilityIterator<'_, C> {
         CapabilityIterator {
             root: self,
             device_function,
@@ -252,17 +560,17 @@ impl PciRoot {
         device_function: DeviceFunction,
         bar_index: u8,
     ) -> Result<BarInfo, PciError> {
-        let bar_orig = self.config_read_word(device_function, BAR0_OFFSET + 4 * bar_index);
+        let bar_orig = self.read_register(device_function, bar_offset(bar_index));
 
         // Get the size of the BAR.
-        self.config_write_word(device_function, BAR0_OFFSET + 4 * bar_index, 0xffffffff);
-        let size_mask = self.config_read_word(device_function, BAR0_OFFSET + 4 * bar_index);
+        self.write_register(device_function, bar_offset(bar_index), 0xffffffff);
+        let size_mask = self.read_register(device_function, bar_offset(bar_index));
         // A wrapping add is necessary to correctly handle the case of unused BARs, which read back
         // as 0, and should be treated as size 0.
         let size = (!(size_mask & 0xfffffff0)).wrapping_add(1);
 
         // Restore the original value.
-        self.config_write_word(device_function, BAR0_OFFSET + 4 * bar_index, bar_orig);
+        self.write_register(device_function, bar_offset(bar_index), bar_orig);
 
         if bar_orig & 0x00000001 == 0x00000001 {
             // I/O space
@@ -277,8 +585,7 @@ impl PciRoot {
                 if bar_index >= 5 {
                     return Err(PciError::InvalidBarType);
                 }
-                let address_top =
-                    self.config_read_word(device_function, BAR0_OFFSET + 4 * (bar_index + 1));
+                let address_top = self.read_register(device_function, bar_offset(bar_index + 1));
                 address |= u64::from(address_top) << 32;
             }
             Ok(BarInfo::Memory {
@@ -292,30 +599,212 @@ impl PciRoot {
 
     /// Sets the address of the given 32-bit memory or I/O BAR of the given device function.
     pub fn set_bar_32(&mut self, device_function: DeviceFunction, bar_index: u8, address: u32) {
-        self.config_write_word(device_function, BAR0_OFFSET + 4 * bar_index, address);
+        self.write_register(device_function, bar_offset(bar_index), address);
     }
 
     /// Sets the address of the given 64-bit memory BAR of the given device function.
     pub fn set_bar_64(&mut self, device_function: DeviceFunction, bar_index: u8, address: u64) {
-        self.config_write_word(device_function, BAR0_OFFSET + 4 * bar_index, address as u32);
-        self.config_write_word(
+        self.write_register(device_function, bar_offset(bar_index), address as u32);
+        self.write_register(
             device_function,
-            BAR0_OFFSET + 4 * (bar_index + 1),
+            bar_offset(bar_index + 1),
             (address >> 32) as u32,
         );
     }
 
+    /// Gets information about the MSI-X capability of the given device function, if it has one.
+    pub fn msix_info(&self, device_function: DeviceFunction) -> Option<MsixInfo> {
+        let capability = self
+            .capabilities(device_function)
+            .find(|capability| capability.id == PCI_CAP_ID_MSIX)?;
+        let table = self.read_register(device_function, u16::from(capability.offset) + 4);
+        let pba = self.read_register(device_function, u16::from(capability.offset) + 8);
+        Some(MsixInfo {
+            offset: capability.offset,
+            table_size: (capability.private_header & MSIX_TABLE_SIZE_MASK) + 1,
+            table_bar: (table & MSIX_BIR_MASK) as u8,
+            table_offset: table & !MSIX_BIR_MASK,
+            pba_bar: (pba & MSIX_BIR_MASK) as u8,
+            pba_offset: pba & !MSIX_BIR_MASK,
+        })
+    }
+
+    /// Enables or disables MSI-X for the given device function, and sets whether all of its
+    /// vectors are masked.
+    ///
+    /// `msix_info` must have come from [`PciRoot::msix_info`] for the same device function.
+    pub fn set_msix_enabled(
+        &mut self,
+        device_function: DeviceFunction,
+        msix_info: &MsixInfo,
+        enabled: bool,
+        function_mask: bool,
+    ) {
+        let mut header = self.read_register(device_function, msix_info.offset.into());
+        header &= !(MSIX_ENABLE | MSIX_FUNCTION_MASK);
+        if enabled {
+            header |= MSIX_ENABLE;
+        }
+        if function_mask {
+            header |= MSIX_FUNCTION_MASK;
+        }
+        self.write_register(device_function, msix_info.offset.into(), header);
+    }
+
+    /// Returns whether MSI-X is enabled for the given device function.
+    pub fn msix_enabled(&self, device_function: DeviceFunction, msix_info: &MsixInfo) -> bool {
+        self.read_register(device_function, msix_info.offset.into()) & MSIX_ENABLE != 0
+    }
+
     /// Gets the capabilities 'pointer' for the device function, if any.
     fn capabilities_offset(&self, device_function: DeviceFunction) -> Option<u8> {
         let (status, _) = self.get_status_command(device_function);
         if status.contains(Status::CAPABILITIES_LIST) {
-            Some((self.config_read_word(device_function, 0x34) & 0xFC) as u8)
+            Some((self.read_register(device_function, 0x34) & 0xFC) as u8)
         } else {
             None
         }
     }
 }
 
+/// Returns the offset in configuration space of the BAR with the given index.
+fn bar_offset(bar_index: u8) -> u16 {
+    BAR0_OFFSET + 4 * u16::from(bar_index)
+}
+
+/// The bits of the MSI-X message control register which hold the table size minus one.
+const MSIX_TABLE_SIZE_MASK: u16 = 0x07ff;
+/// The bit of the first word of the MSI-X capability which masks all vectors of the function.
+const MSIX_FUNCTION_MASK: u32 = 1 << 30;
+/// The bit of the first word of the MSI-X capability which enables MSI-X.
+const MSIX_ENABLE: u32 = 1 << 31;
+/// The bits of the MSI-X table and PBA offset registers which hold the BAR index.
+const MSIX_BIR_MASK: u32 = 0x7;
+/// The bit of an MSI-X table entry's vector control which masks the vector.
+const MSIX_VECTOR_MASKED: u32 = 1 << 0;
+
+/// Information about the MSI-X capability of a device function.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MsixInfo {
+    /// The offset of the capability in the PCI configuration space of the device function.
+    pub offset: u8,
+    /// The number of entries in the MSI-X table.
+    pub table_size: u16,
+    /// The index of the BAR which contains the MSI-X table.
+    pub table_bar: u8,
+    /// The offset of the MSI-X table within its BAR.
+    pub table_offset: u32,
+    /// The index of the BAR which contains the pending bit array.
+    pub pba_bar: u8,
+    /// The offset of the pending bit array within its BAR.
+    pub pba_offset: u32,
+}
+
+/// An entry in an MSI-X table.
+///
+/// Ref: PCI Local Bus Specification 3.0, 6.8.2.6 MSI-X Table
+#[repr(C)]
+struct MsixTableEntry {
+    address_low: Volatile<u32>,
+    address_high: Volatile<u32>,
+    data: Volatile<u32>,
+    vector_control: Volatile<u32>,
+}
+
+/// The MSI-X table of a device function, mapped into memory.
+///
+/// The address and data of each entry come from the platform's interrupt controller code, which
+/// this crate knows nothing about.
+#[derive(Debug)]
+pub struct MsixTable {
+    entries: NonNull<u8>,
+    len: u16,
+}
+
+impl MsixTable {
+    /// Wraps the MSI-X table with `len` entries mapped at the given address.
+    ///
+    /// Panics if the address is not aligned to a 4-byte boundary.
+    ///
+    /// # Safety
+    ///
+    /// `entries` must be a valid pointer to the MSI-X table of a device function, mapped as device
+    /// memory, and at least `len` entries long. It must stay valid for the lifetime of the
+    /// `MsixTable`, and nothing else may access the table in the meantime.
+    pub unsafe fn new(entries: NonNull<u8>, len: u16) -> Self {
+        assert!(entries.as_ptr() as usize & 0x3 == 0);
+        Self { entries, len }
+    }
+
+    /// Returns the number of entries in the table.
+    pub fn len(&self) -> u16 {
+        self.len
+    }
+
+    /// Returns whether the table has no entries.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Sets the message address and data of the given entry, and unmasks it.
+    ///
+    /// Panics if the index is out of range.
+    pub fn set_entry(&mut self, index: u16, address: u64, data: u32) {
+        let entry = self.entry(index);
+        // Safe because `entry` points to a valid table entry, according to the contract of `new`.
+        // The entry is masked while it is changed so the device can't send a torn message.
+        unsafe {
+            volwrite!(entry, vector_control, MSIX_VECTOR_MASKED);
+            volwrite!(entry, address_low, address as u32);
+            volwrite!(entry, address_high, (address >> 32) as u32);
+            volwrite!(entry, data, data);
+            volwrite!(entry, vector_control, 0);
+        }
+    }
+
+    /// Masks or unmasks the given entry.
+    ///
+    /// Panics if the index is out of range.
+    pub fn set_masked(&mut self, index: u16, masked: bool) {
+        let entry = self.entry(index);
+        // Safe because `entry` points to a valid table entry, according to the contract of `new`.
+        unsafe {
+            let vector_control = volread!(entry, vector_control);
+            volwrite!(
+                entry,
+                vector_control,
+                if masked {
+                    vector_control | MSIX_VECTOR_MASKED
+                } else {
+                    vector_control & !MSIX_VECTOR_MASKED
+                }
+            );
+        }
+    }
+
+    /// Returns whether the given entry is masked.
+    ///
+    /// Panics if the index is out of range.
+    pub fn masked(&self, index: u16) -> bool {
+        let entry = self.entry(index);
+        // Safe because `entry` points to a valid table entry, according to the contract of `new`.
+        unsafe { volread!(entry, vector_control) & MSIX_VECTOR_MASKED != 0 }
+    }
+
+    fn entry(&self, index: u16) -> NonNull<MsixTableEntry> {
+        assert!(index < self.len, "MSI-X table index {} out of range", index);
+        // Safe because the index is within the table, which `new` requires to be valid.
+        unsafe {
+            NonNull::new_unchecked(
+                self.entries
+                    .cast::<MsixTableEntry>()
+                    .as_ptr()
+                    .add(usize::from(index)),
+            )
+        }
+    }
+}
+
 /// Information about a PCI Base Address Register.
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum BarInfo {
@@ -420,20 +909,20 @@ impl TryFrom<u8> for MemoryBarType {
 
 /// Iterator over capabilities for a device.
 #[derive(Debug)]
-pub struct CapabilityIterator<'a> {
-    root: &'a PciRoot,
+pub struct CapabilityIterator<'a, C: ConfigurationAccess> {
+    root: &'a PciRoot<C>,
     device_function: DeviceFunction,
     next_capability_offset: Option<u8>,
 }
 
-impl<'a> Iterator for CapabilityIterator<'a> {
+impl<'a, C: ConfigurationAccess> Iterator for CapabilityIterator<'a, C> {
     type Item = CapabilityInfo;
 
     fn next(&mut self) -> Option<Self::Item> {
         let offset = self.next_capability_offset?;
 
         // Read the first 4 bytes of the capability.
-        let capability_header = self.root.config_read_word(self.device_function, offset);
+        let capability_header = self.root.read_register(self.device_function, offset.into());
         let id = capability_header as u8;
         let next_offset = (capability_header >> 8) as u8;
         let private_header = (capability_header >> 16) as u16;
@@ -468,21 +957,21 @@ pub struct CapabilityInfo {
 
 /// An iterator which enumerates PCI devices and functions on a given bus.
 #[derive(Debug)]
-pub struct BusDeviceIterator {
+pub struct BusDeviceIterator<C: ConfigurationAccess> {
     /// This must only be used to read read-only fields, and must not be exposed outside this
-    /// module, because it uses the same CAM as the main `PciRoot` instance.
-    root: PciRoot,
+    /// module, because it uses the same configuration space as the main `PciRoot` instance.
+    root: PciRoot<C>,
     next: DeviceFunction,
 }
 
-impl Iterator for BusDeviceIterator {
+impl<C: ConfigurationAccess> Iterator for BusDeviceIterator<C> {
     type Item = (DeviceFunction, DeviceFunctionInfo);
 
     fn next(&mut self) -> Option<Self::Item> {
         while self.next.device < MAX_DEVICES {
             // Read the header for the current device and function.
             let current = self.next;
-            let device_vendor = self.root.config_read_word(current, 0);
+            let device_vendor = self.root.read_register(current, 0);
 
             // Advance to the next device or function.
             self.next.function += 1;
@@ -492,14 +981,14 @@ impl Iterator for BusDeviceIterator {
             }
 
             if device_vendor != INVALID_READ {
-                let class_revision = self.root.config_read_word(current, 8);
+                let class_revision = self.root.read_register(current, 8);
                 let device_id = (device_vendor >> 16) as u16;
                 let vendor_id = device_vendor as u16;
                 let class = (class_revision >> 24) as u8;
                 let subclass = (class_revision >> 16) as u8;
                 let prog_if = (class_revision >> 8) as u8;
                 let revision = class_revision as u8;
-                let bist_type_latency_cache = self.root.config_read_word(current, 12);
+                let bist_type_latency_cache = self.root.read_register(current, 12);
                 let header_type = HeaderType::from((bist_type_latency_cache >> 16) as u8 & 0x7f);
                 return Some((
                     current,
@@ -519,6 +1008,118 @@ impl Iterator for BusDeviceIterator {
     }
 }
 
+/// An iterator which enumerates PCI devices and functions on a bus and the buses behind its
+/// bridges, depth-first.
+#[derive(Debug)]
+pub struct TreeDeviceIterator<C: ConfigurationAccess> {
+    /// An iterator for the bus at each level of the path currently being explored. Only the
+    /// first `path.len() + 1` are `Some`.
+    stack: [Option<BusDeviceIterator<C>>; MAX_BRIDGE_DEPTH + 1],
+    /// The bridges leading to the bus currently being enumerated.
+    path: PciPath,
+}
+
+impl<C: ConfigurationAccess> Iterator for TreeDeviceIterator<C> {
+    type Item = (DeviceFunction, DeviceFunctionInfo, PciPath);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let depth = self.path.len;
+            let Some((device_function, info)) = self.stack[depth].as_mut()?.next() else {
+                // This bus is finished, so go back up to the bus of the bridge which led to it.
+                self.stack[depth] = None;
+                self.path.pop()?;
+                continue;
+            };
+            let path = self.path;
+            if info.header_type == HeaderType::PciPciBridge {
+                let root = &self.stack[depth].as_ref().unwrap().root;
+                let (_, secondary, _) = root.bridge_bus_numbers(device_function);
+                // A secondary bus number no higher than the bridge's own bus must not have been
+                // assigned, and following it could loop forever.
+                if secondary <= device_function.bus {
+                    warn!(
+                        "Bridge {} has invalid secondary bus number {}",
+                        device_function, secondary
+                    );
+                } else if depth == MAX_BRIDGE_DEPTH {
+                    warn!(
+                        "Not following bridge {}, as it is too deep",
+                        device_function
+                    );
+                } else {
+                    self.stack[depth + 1] = Some(root.enumerate_bus(secondary));
+                    self.path.push(device_function);
+                }
+            }
+            return Some((device_function, info, path));
+        }
+    }
+}
+
+/// The PCI-to-PCI bridges through which a device function is reached from the root bus which was
+/// enumerated, in order from the root.
+#[derive(Copy, Clone, Debug)]
+pub struct PciPath {
+    bridges: [DeviceFunction; MAX_BRIDGE_DEPTH],
+    len: usize,
+}
+
+impl PciPath {
+    /// Returns the bridges in the path, starting with the one on the root bus.
+    pub fn bridges(&self) -> &[DeviceFunction] {
+        &self.bridges[..self.len]
+    }
+
+    /// Returns whether the device function is on the root bus itself.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn push(&mut self, bridge: DeviceFunction) {
+        self.bridges[self.len] = bridge;
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<DeviceFunction> {
+        self.len = self.len.checked_sub(1)?;
+        Some(self.bridges[self.len])
+    }
+}
+
+impl PartialEq for PciPath {
+    fn eq(&self, other: &Self) -> bool {
+        self.bridges() == other.bridges()
+    }
+}
+
+impl Eq for PciPath {}
+
+impl Default for PciPath {
+    fn default() -> Self {
+        Self {
+            bridges: [DeviceFunction {
+                bus: 0,
+                device: 0,
+                function: 0,
+            }; MAX_BRIDGE_DEPTH],
+            len: 0,
+        }
+    }
+}
+
+impl Display for PciPath {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        for (i, bridge) in self.bridges().iter().enumerate() {
+            if i != 0 {
+                write!(f, "/")?;
+            }
+            write!(f, "{}", bridge)?;
+        }
+        Ok(())
+    }
+}
+
 /// An identifier for a PCI bus, device and function.
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub struct DeviceFunction {
@@ -601,3 +1202,276 @@ impl From<u8> for HeaderType {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::fake::{FakeConfigSpace, FakeFunction};
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Records the I/O port accesses made through it, and returns the given value for reads.
+    #[derive(Clone, Debug)]
+    struct RecordingPorts<'a> {
+        accesses: &'a Mutex<Vec<(u32, Option<u32>)>>,
+        read_value: u32,
+    }
+
+    impl PortIo for RecordingPorts<'_> {
+        fn read8(&self, _port: u32) -> u8 {
+            unreachable!("PortCam only makes 32-bit port accesses")
+        }
+
+        fn read16(&self, _port: u32) -> u16 {
+            unreachable!("PortCam only makes 32-bit port accesses")
+        }
+
+        fn read32(&self, port: u32) -> u32 {
+            self.accesses.lock().unwrap().push((port, None));
+            self.read_value
+        }
+
+        fn write8(&self, _port: u32, _value: u8) {
+            unreachable!("PortCam only makes 32-bit port accesses")
+        }
+
+        fn write16(&self, _port: u32, _value: u16) {
+            unreachable!("PortCam only makes 32-bit port accesses")
+        }
+
+        fn write32(&self, port: u32, value: u32) {
+            self.accesses.lock().unwrap().push((port, Some(value)));
+        }
+    }
+
+    fn info(vendor_id: u16, device_id: u16) -> DeviceFunctionInfo {
+        DeviceFunctionInfo {
+            vendor_id,
+            device_id,
+            class: 0x02,
+            subclass: 0x00,
+            prog_if: 0x00,
+            revision: 0x01,
+            header_type: HeaderType::Standard,
+        }
+    }
+
+    fn bridge_info() -> DeviceFunctionInfo {
+        DeviceFunctionInfo {
+            vendor_id: 0x1b36,
+            device_id: 0x000c,
+            class: 0x06,
+            subclass: 0x04,
+            prog_if: 0x00,
+            revision: 0x00,
+            header_type: HeaderType::PciPciBridge,
+        }
+    }
+
+    fn device_function(bus: u8, device: u8, function: u8) -> DeviceFunction {
+        DeviceFunction {
+            bus,
+            device,
+            function,
+        }
+    }
+
+    #[test]
+    fn assign_bus_numbers_and_enumerate_tree() {
+        let config_space = FakeConfigSpace::default();
+        config_space.add(FakeFunction::new(
+            device_function(0, 0, 0),
+            &info(0x1af4, 0x1041),
+        ));
+        let bridge1 = config_space.add(FakeFunction::new(device_function(0, 1, 0), &bridge_info()));
+        let bridge2 = config_space.add(FakeFunction::new(device_function(0, 2, 0), &bridge_info()));
+        let bridge3 = config_space.add_behind(
+            bridge1,
+            FakeFunction::new(device_function(0, 0, 0), &bridge_info()),
+        );
+        config_space.add_behind(
+            bridge3,
+            FakeFunction::new(device_function(0, 0, 0), &info(0x1af4, 0x1042)),
+        );
+        config_space.add_behind(
+            bridge2,
+            FakeFunction::new(device_function(0, 3, 0), &info(0x1af4, 0x1043)),
+        );
+        let mut root = PciRoot::new(config_space);
+
+        // Nothing behind the bridges can be found until they have bus numbers.
+        let found: Vec<_> = root
+            .enumerate_tree(0)
+            .map(|(device_function, _, path)| (device_function, path))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                (device_function(0, 0, 0), PciPath::default()),
+                (device_function(0, 1, 0), PciPath::default()),
+                (device_function(0, 2, 0), PciPath::default()),
+            ]
+        );
+
+        assert_eq!(root.assign_bus_numbers(0), Ok(3));
+        assert_eq!(root.bridge_bus_numbers(device_function(0, 1, 0)), (0, 1, 2));
+        assert_eq!(root.bridge_bus_numbers(device_function(1, 0, 0)), (1, 2, 2));
+        assert_eq!(root.bridge_bus_numbers(device_function(0, 2, 0)), (0, 3, 3));
+
+        let found: Vec<_> = root
+            .enumerate_tree(0)
+            .map(|(device_function, info, path)| {
+                (device_function, info.device_id, path.to_string())
+            })
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                (device_function(0, 0, 0), 0x1041, "".to_string()),
+                (device_function(0, 1, 0), 0x000c, "".to_string()),
+                (device_function(1, 0, 0), 0x000c, "00:01.0".to_string()),
+                (
+                    device_function(2, 0, 0),
+                    0x1042,
+                    "00:01.0/01:00.0".to_string()
+                ),
+                (device_function(0, 2, 0), 0x000c, "".to_string()),
+                (device_function(3, 3, 0), 0x1043, "00:02.0".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn assign_bus_numbers_replaces_stale_numbers() {
+        let config_space = FakeConfigSpace::default();
+        let bridge1 = config_space.add(FakeFunction::new(device_function(0, 1, 0), &bridge_info()));
+        let bridge2 = config_space.add(FakeFunction::new(device_function(0, 2, 0), &bridge_info()));
+        config_space.add_behind(
+            bridge1,
+            FakeFunction::new(device_function(0, 0, 0), &bridge_info()),
+        );
+        config_space.add_behind(
+            bridge2,
+            FakeFunction::new(device_function(0, 1, 0), &bridge_info()),
+        );
+        let mut root = PciRoot::new(config_space);
+        // Firmware put the second bridge on the bus which the first will be given.
+        root.set_bridge_bus_numbers(device_function(0, 2, 0), 0, 1, 2);
+
+        assert_eq!(root.assign_bus_numbers(0), Ok(4));
+        assert_eq!(root.bridge_bus_numbers(device_function(0, 1, 0)), (0, 1, 2));
+        assert_eq!(root.bridge_bus_numbers(device_function(1, 0, 0)), (1, 2, 2));
+        assert_eq!(root.bridge_bus_numbers(device_function(0, 2, 0)), (0, 3, 4));
+        assert_eq!(root.bridge_bus_numbers(device_function(3, 1, 0)), (3, 4, 4));
+
+        let found: Vec<_> = root
+            .enumerate_tree(0)
+            .map(|(device_function, _, path)| (device_function, path.to_string()))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                (device_function(0, 1, 0), "".to_string()),
+                (device_function(1, 0, 0), "00:01.0".to_string()),
+                (device_function(0, 2, 0), "".to_string()),
+                (device_function(3, 1, 0), "00:02.0".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn port_cam() {
+        let accesses = Mutex::new(Vec::new());
+        let mut root = PciRoot::new(PortCam::new(RecordingPorts {
+            accesses: &accesses,
+            read_value: 0x1234_5678,
+        }));
+        let device_function = DeviceFunction {
+            bus: 1,
+            device: 2,
+            function: 3,
+        };
+
+        assert_eq!(
+            root.config_read_word(device_function, 0x10),
+            Ok(0x1234_5678)
+        );
+        assert_eq!(root.config_write_word(device_function, 0x3c, 42), Ok(()));
+        assert_eq!(
+            root.config_read_word(device_function, 0x100),
+            Err(PciError::InvalidRegisterOffset(0x100))
+        );
+        assert_eq!(
+            root.config_write_word(device_function, 0x3e, 42),
+            Err(PciError::InvalidRegisterOffset(0x3e))
+        );
+        assert_eq!(
+            *accesses.lock().unwrap(),
+            vec![
+                (0xcf8, Some(0x8001_1310)),
+                (0xcfc, None),
+                (0xcf8, Some(0x8001_133c)),
+                (0xcfc, Some(42)),
+            ]
+        );
+    }
+
+    #[test]
+    fn enumerate_and_size_bars() {
+        let config_space = FakeConfigSpace::default();
+        let first = DeviceFunction {
+            bus: 0,
+            device: 0,
+            function: 0,
+        };
+        let second = DeviceFunction {
+            bus: 0,
+            device: 3,
+            function: 1,
+        };
+        config_space.add(
+            FakeFunction::new(first, &info(0x1af4, 0x1041))
+                .memory_bar_32(0, 0x1000, false)
+                .memory_bar_64(2, 0x4000, true),
+        );
+        config_space.add(FakeFunction::new(second, &info(0x8086, 0x1234)).io_bar(0, 0x40));
+        let mut root = PciRoot::new(config_space);
+
+        let functions: Vec<_> = root.enumerate_bus(0).collect();
+        assert_eq!(
+            functions,
+            vec![
+                (first, info(0x1af4, 0x1041)),
+                (second, info(0x8086, 0x1234))
+            ]
+        );
+        assert_eq!(root.enumerate_bus(1).count(), 0);
+
+        assert_eq!(
+            root.bar_info(first, 0).unwrap(),
+            BarInfo::Memory {
+                address_type: MemoryBarType::Width32,
+                prefetchable: false,
+                address: 0,
+                size: 0x1000,
+            }
+        );
+        root.set_bar_64(first, 2, 0x1_0000_4000);
+        assert_eq!(
+            root.bar_info(first, 2).unwrap(),
+            BarInfo::Memory {
+                address_type: MemoryBarType::Width64,
+                prefetchable: true,
+                address: 0x1_0000_4000,
+                size: 0x4000,
+            }
+        );
+        root.set_bar_32(second, 0, 0xc040);
+        assert_eq!(
+            root.bar_info(second, 0).unwrap(),
+            BarInfo::IO {
+                address: 0xc040,
+                size: 0x40,
+            }
+        );
+    }
+}
@@ -0,0 +1,513 @@
+//! Assignment of addresses to the BARs of PCI devices and the windows of the bridges in front of
+//! them, for platforms where firmware hasn't already done it.
+
+use super::{
+    BarInfo, Command, ConfigurationAccess, DeviceFunction, HeaderType, MemoryBarType, PciError,
+    PciRoot,
+};
+use log::debug;
+
+/// The number of BARs of a normal device function.
+const STANDARD_BAR_COUNT: u8 = 6;
+/// The number of BARs of a PCI-to-PCI bridge.
+const BRIDGE_BAR_COUNT: u8 = 2;
+
+/// The offset in bytes of the memory base and limit of a PCI-to-PCI bridge.
+const BRIDGE_MEMORY_OFFSET: u16 = 0x20;
+/// The offset in bytes of the prefetchable memory base and limit of a PCI-to-PCI bridge.
+const BRIDGE_PREFETCHABLE_OFFSET: u16 = 0x24;
+/// The offset in bytes of the upper 32 bits of the prefetchable memory base of a PCI-to-PCI
+/// bridge.
+const BRIDGE_PREFETCHABLE_BASE_UPPER_OFFSET: u16 = 0x28;
+/// The offset in bytes of the upper 32 bits of the prefetchable memory limit of a PCI-to-PCI
+/// bridge.
+const BRIDGE_PREFETCHABLE_LIMIT_UPPER_OFFSET: u16 = 0x2c;
+/// The value of the low bits of a bridge's prefetchable memory base if it supports 64-bit
+/// addresses.
+const BRIDGE_PREFETCHABLE_64: u32 = 0x1;
+
+/// The granularity of the memory windows of a PCI-to-PCI bridge.
+const BRIDGE_WINDOW_ALIGNMENT: u64 = 0x100000;
+
+/// A range of PCI bus addresses.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct PciWindow {
+    /// The first address in the window.
+    pub start: u64,
+    /// The size of the window in bytes.
+    pub size: u64,
+}
+
+/// The memory windows which a host bridge forwards to PCI, from which [`BarAllocator`] assigns
+/// addresses.
+///
+/// These usually come from the `ranges` property of the host bridge's device tree node, or from
+/// ACPI. The addresses are as seen on the PCI bus, which are often but not always the same as
+/// the CPU physical addresses.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct PciWindows {
+    /// Non-prefetchable memory below 4 GiB.
+    pub memory_32: Option<PciWindow>,
+    /// Non-prefetchable memory which may be above 4 GiB.
+    ///
+    /// Bridges can't forward non-prefetchable memory above 4 GiB, so this is only used for 64-bit
+    /// BARs of devices on the root bus.
+    pub memory_64: Option<PciWindow>,
+    /// Prefetchable memory below 4 GiB.
+    pub prefetchable_32: Option<PciWindow>,
+    /// Prefetchable memory which may be above 4 GiB, for 64-bit BARs.
+    pub prefetchable_64: Option<PciWindow>,
+}
+
+/// The next free address and the end of a window.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+struct Cursor {
+    next: u64,
+    end: u64,
+}
+
+impl Cursor {
+    fn new(window: Option<PciWindow>) -> Self {
+        window.map_or(Self::default(), |window| Self {
+            next: window.start,
+            end: window.start + window.size,
+        })
+    }
+
+    /// Allocates a region of the given size, which must be a power of two, aligned to its size.
+    fn allocate(&mut self, size: u64) -> Option<u64> {
+        let address = align_up(self.next, size)?;
+        let next = address.checked_add(size)?;
+        if next > self.end {
+            return None;
+        }
+        self.next = next;
+        Some(address)
+    }
+
+    /// Skips ahead to the given alignment, without going past the end.
+    fn align(&mut self, alignment: u64) {
+        self.next = align_up(self.next, alignment).map_or(self.end, |next| next.min(self.end));
+    }
+}
+
+/// Which prefetchable window the prefetchable BARs of devices behind a bridge can use.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Prefetchable {
+    /// Devices on the root bus can use any window.
+    Root,
+    /// The bridges above forward the 64-bit prefetchable window.
+    Window64,
+    /// The bridges above forward the 32-bit prefetchable window.
+    Window32,
+    /// The bridges above can't forward a prefetchable window, so prefetchable BARs must go in
+    /// non-prefetchable memory.
+    None,
+}
+
+/// Assigns addresses to the memory BARs of the devices on a PCI bus and behind its bridges, and
+/// programs the bridges' memory windows to match.
+///
+/// I/O BARs are left unassigned. Bus numbers must already have been assigned to any bridges,
+/// such as with [`PciRoot::assign_bus_numbers`].
+#[derive(Debug)]
+pub struct BarAllocator {
+    memory_32: Cursor,
+    memory_64: Cursor,
+    prefetchable_32: Cursor,
+    prefetchable_64: Cursor,
+}
+
+impl BarAllocator {
+    /// Creates an allocator which assigns addresses from the given windows.
+    pub fn new(windows: PciWindows) -> Self {
+        Self {
+            memory_32: Cursor::new(windows.memory_32),
+            memory_64: Cursor::new(windows.memory_64),
+            prefetchable_32: Cursor::new(windows.prefetchable_32),
+            prefetchable_64: Cursor::new(windows.prefetchable_64),
+        }
+    }
+
+    /// Assigns addresses to the memory BARs of every device function on the given root bus and
+    /// behind its bridges, programs the bridges' windows, and enables memory decoding for them
+    /// all. Bridges also have bus mastering enabled so that devices behind them can use DMA.
+    ///
+    /// Returns [`PciError::NoSpaceForBar`] if the windows run out of space.
+    pub fn allocate_bus(
+        &mut self,
+        root: &mut PciRoot<impl ConfigurationAccess>,
+        bus: u8,
+    ) -> Result<(), PciError> {
+        self.allocate_bus_behind(root, bus, Prefetchable::Root)
+    }
+
+    fn allocate_bus_behind(
+        &mut self,
+        root: &mut PciRoot<impl ConfigurationAccess>,
+        bus: u8,
+        prefetchable: Prefetchable,
+    ) -> Result<(), PciError> {
+        for (device_function, info) in root.enumerate_bus(bus) {
+            let (_, command) = root.get_status_command(device_function);
+            // Stop the function decoding addresses while its BARs are being sized and changed.
+            root.set_command(
+                device_function,
+                command - (Command::MEMORY_SPACE | Command::IO_SPACE),
+            );
+            match info.header_type {
+                HeaderType::Standard => {
+                    self.allocate_bars(root, device_function, STANDARD_BAR_COUNT, prefetchable)?;
+                    root.set_command(
+                        device_function,
+                        (command - Command::IO_SPACE) | Command::MEMORY_SPACE,
+                    );
+                }
+                HeaderType::PciPciBridge => {
+                    self.allocate_bars(root, device_function, BRIDGE_BAR_COUNT, prefetchable)?;
+                    self.allocate_bridge(root, device_function, prefetchable)?;
+                    root.set_command(
+                        device_function,
+                        (command - Command::IO_SPACE) | Command::MEMORY_SPACE | Command::BUS_MASTER,
+                    );
+                }
+                header_type => {
+                    debug!(
+                        "Not assigning BARs of {} with header type {:?}",
+                        device_function, header_type
+                    );
+                    root.set_command(device_function, command);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Assigns addresses to the memory BARs of the given device function.
+    fn allocate_bars(
+        &mut self,
+        root: &mut PciRoot<impl ConfigurationAccess>,
+        device_function: DeviceFunction,
+        bar_count: u8,
+        prefetchable: Prefetchable,
+    ) -> Result<(), PciError> {
+        let mut bar_index = 0;
+        while bar_index < bar_count {
+            let info = root.bar_info(device_function, bar_index)?;
+            if let BarInfo::Memory {
+                address_type,
+                prefetchable: bar_prefetchable,
+                size,
+                ..
+            } = info
+            {
+                if size > 0 {
+                    let width_64 = address_type == MemoryBarType::Width64;
+                    let address = self
+                        .allocate(u64::from(size), width_64, bar_prefetchable, prefetchable)
+                        .ok_or(PciError::NoSpaceForBar(device_function, bar_index))?;
+                    debug!(
+                        "Assigned BAR {} of {} to {:#x} ({:#x} bytes)",
+                        bar_index, device_function, address, size
+                    );
+                    if width_64 {
+                        root.set_bar_64(device_function, bar_index, address);
+                    } else {
+                        root.set_bar_32(device_function, bar_index, address as u32);
+                    }
+                }
+            }
+            bar_index += if info.takes_two_entries() { 2 } else { 1 };
+        }
+        Ok(())
+    }
+
+    /// Allocates a region for a BAR with the given size and properties, from the most appropriate
+    /// window which has space and which the bridges above can forward.
+    fn allocate(
+        &mut self,
+        size: u64,
+        width_64: bool,
+        bar_prefetchable: bool,
+        prefetchable: Prefetchable,
+    ) -> Option<u64> {
+        let mut address = None;
+        if bar_prefetchable
+            && width_64
+            && matches!(prefetchable, Prefetchable::Root | Prefetchable::Window64)
+        {
+            address = address.or_else(|| self.prefetchable_64.allocate(size));
+        }
+        if bar_prefetchable && matches!(prefetchable, Prefetchable::Root | Prefetchable::Window32) {
+            address = address.or_else(|| self.prefetchable_32.allocate(size));
+        }
+        if width_64 && prefetchable == Prefetchable::Root {
+            address = address.or_else(|| self.memory_64.allocate(size));
+        }
+        address.or_else(|| self.memory_32.allocate(size))
+    }
+
+    /// Assigns addresses to the devices behind the given bridge, and sets its windows to cover
+    /// them.
+    fn allocate_bridge(
+        &mut self,
+        root: &mut PciRoot<impl ConfigurationAccess>,
+        bridge: DeviceFunction,
+        prefetchable: Prefetchable,
+    ) -> Result<(), PciError> {
+        let (_, secondary, _) = root.bridge_bus_numbers(bridge);
+        let prefetchable_base = root.read_register(bridge, BRIDGE_PREFETCHABLE_OFFSET);
+        let bridge_64 = prefetchable_base & 0xf == BRIDGE_PREFETCHABLE_64;
+        let prefetchable = match prefetchable {
+            Prefetchable::Root if bridge_64 && self.prefetchable_64 != Cursor::default() => {
+                Prefetchable::Window64
+            }
+            Prefetchable::Root if self.prefetchable_32 != Cursor::default() => {
+                Prefetchable::Window32
+            }
+            Prefetchable::Window64 if !bridge_64 => Prefetchable::None,
+            Prefetchable::Root => Prefetchable::None,
+            prefetchable => prefetchable,
+        };
+
+        self.memory_32.align(BRIDGE_WINDOW_ALIGNMENT);
+        let memory_start = self.memory_32.next;
+        let prefetchable_cursor = match prefetchable {
+            Prefetchable::Window64 => Some(&mut self.prefetchable_64),
+            Prefetchable::Window32 => Some(&mut self.prefetchable_32),
+            _ => None,
+        };
+        let prefetchable_start = prefetchable_cursor.map(|cursor| {
+            cursor.align(BRIDGE_WINDOW_ALIGNMENT);
+            cursor.next
+        });
+
+        if secondary > bridge.bus {
+            self.allocate_bus_behind(root, secondary, prefetchable)?;
+        } else {
+            debug!("Bridge {} has no secondary bus number", bridge);
+        }
+
+        self.memory_32.align(BRIDGE_WINDOW_ALIGNMENT);
+        let (base, limit) = window_registers(memory_start, self.memory_32.next);
+        root.write_register(
+            bridge,
+            BRIDGE_MEMORY_OFFSET,
+            (limit as u32) << 16 | base as u32,
+        );
+
+        let prefetchable_end = match prefetchable {
+            Prefetchable::Window64 => Some(&mut self.prefetchable_64),
+            Prefetchable::Window32 => Some(&mut self.prefetchable_32),
+            _ => None,
+        }
+        .map(|cursor| {
+            cursor.align(BRIDGE_WINDOW_ALIGNMENT);
+            cursor.next
+        });
+        let (base, limit) = match (prefetchable_start, prefetchable_end) {
+            (Some(start), Some(end)) => window_registers(start, end),
+            _ => window_registers(0, 0),
+        };
+        root.write_register(
+            bridge,
+            BRIDGE_PREFETCHABLE_OFFSET,
+            (limit as u32) << 16 | base as u32,
+        );
+        if bridge_64 {
+            root.write_register(
+                bridge,
+                BRIDGE_PREFETCHABLE_BASE_UPPER_OFFSET,
+                (base >> 32) as u32,
+            );
+            root.write_register(
+                bridge,
+                BRIDGE_PREFETCHABLE_LIMIT_UPPER_OFFSET,
+                (limit >> 32) as u32,
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Returns the values of a bridge's base and limit registers for a memory window from `start` to
+/// `end`, both aligned to 1 MiB. The low 16 bits are as for the 16-bit base or limit registers,
+/// and the upper 32 bits for the prefetchable upper base or limit registers.
+///
+/// An empty window is represented by a base above the limit, which disables it.
+fn window_registers(start: u64, end: u64) -> (u64, u64) {
+    if start == end {
+        return (0xfff0, 0x0000);
+    }
+    let limit = end - 1;
+    (
+        (start >> 16) & 0xfff0 | (start >> 32) << 32,
+        (limit >> 16) & 0xfff0 | (limit >> 32) << 32,
+    )
+}
+
+/// Aligns the given address up to the given alignment, which must be a power of two, or returns
+/// `None` if it would overflow.
+fn align_up(address: u64, alignment: u64) -> Option<u64> {
+    Some(address.checked_add(alignment - 1)? & !(alignment - 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::pci::bus::{
+        fake::{FakeConfigSpace, FakeFunction},
+        DeviceFunctionInfo,
+    };
+
+    fn device_function(bus: u8, device: u8, function: u8) -> DeviceFunction {
+        DeviceFunction {
+            bus,
+            device,
+            function,
+        }
+    }
+
+    fn info(header_type: HeaderType) -> DeviceFunctionInfo {
+        DeviceFunctionInfo {
+            vendor_id: 0x1af4,
+            device_id: 0x1041,
+            class: 0,
+            subclass: 0,
+            prog_if: 0,
+            revision: 0,
+            header_type,
+        }
+    }
+
+    fn bar_address(
+        root: &mut PciRoot<FakeConfigSpace>,
+        device_function: DeviceFunction,
+        bar_index: u8,
+    ) -> u64 {
+        match root.bar_info(device_function, bar_index).unwrap() {
+            BarInfo::Memory { address, .. } => address,
+            BarInfo::IO { address, .. } => address.into(),
+        }
+    }
+
+    #[test]
+    fn allocate_behind_bridge() {
+        let config_space = FakeConfigSpace::default();
+        let device = device_function(0, 0, 0);
+        let bridge = device_function(0, 1, 0);
+        config_space.add(
+            FakeFunction::new(device, &info(HeaderType::Standard))
+                .memory_bar_32(0, 0x1000, false)
+                .memory_bar_64(2, 0x4000, true)
+                .io_bar(4, 0x40),
+        );
+        let bridge_index = config_space
+            .add(FakeFunction::new(bridge, &info(HeaderType::PciPciBridge)).prefetchable_64());
+        config_space.add_behind(
+            bridge_index,
+            FakeFunction::new(device_function(0, 0, 0), &info(HeaderType::Standard))
+                .memory_bar_32(0, 0x2000, false)
+                .memory_bar_64(1, 0x10_0000, true),
+        );
+        let mut root = PciRoot::new(config_space);
+        assert_eq!(root.assign_bus_numbers(0), Ok(1));
+        let behind = device_function(1, 0, 0);
+
+        let mut allocator = BarAllocator::new(PciWindows {
+            memory_32: Some(PciWindow {
+                start: 0x1000_0000,
+                size: 0x1000_0000,
+            }),
+            prefetchable_64: Some(PciWindow {
+                start: 0x80_0000_0000,
+                size: 0x1_0000_0000,
+            }),
+            ..Default::default()
+        });
+        allocator.allocate_bus(&mut root, 0).unwrap();
+
+        assert_eq!(bar_address(&mut root, device, 0), 0x1000_0000);
+        assert_eq!(bar_address(&mut root, device, 2), 0x80_0000_0000);
+        // I/O BARs are left alone.
+        assert_eq!(bar_address(&mut root, device, 4), 0);
+        // Windows behind the bridge start on a 1 MiB boundary.
+        assert_eq!(bar_address(&mut root, behind, 0), 0x1010_0000);
+        assert_eq!(bar_address(&mut root, behind, 1), 0x80_0010_0000);
+
+        assert_eq!(
+            root.read_register(bridge, BRIDGE_MEMORY_OFFSET),
+            0x1010_1010
+        );
+        assert_eq!(
+            root.read_register(bridge, BRIDGE_PREFETCHABLE_OFFSET),
+            0x0011_0011
+        );
+        assert_eq!(
+            root.read_register(bridge, BRIDGE_PREFETCHABLE_BASE_UPPER_OFFSET),
+            0x80
+        );
+        assert_eq!(
+            root.read_register(bridge, BRIDGE_PREFETCHABLE_LIMIT_UPPER_OFFSET),
+            0x80
+        );
+
+        assert_eq!(root.get_status_command(device).1, Command::MEMORY_SPACE);
+        assert_eq!(
+            root.get_status_command(bridge).1,
+            Command::MEMORY_SPACE | Command::BUS_MASTER
+        );
+        assert_eq!(root.get_status_command(behind).1, Command::MEMORY_SPACE);
+    }
+
+    #[test]
+    fn empty_bridge_window_disabled() {
+        let config_space = FakeConfigSpace::default();
+        let bridge = device_function(0, 1, 0);
+        config_space.add(FakeFunction::new(bridge, &info(HeaderType::PciPciBridge)));
+        let mut root = PciRoot::new(config_space);
+        root.assign_bus_numbers(0).unwrap();
+
+        let mut allocator = BarAllocator::new(PciWindows {
+            memory_32: Some(PciWindow {
+                start: 0x1000_0000,
+                size: 0x1000_0000,
+            }),
+            ..Default::default()
+        });
+        allocator.allocate_bus(&mut root, 0).unwrap();
+
+        assert_eq!(
+            root.read_register(bridge, BRIDGE_MEMORY_OFFSET),
+            0x0000_fff0
+        );
+        assert_eq!(
+            root.read_register(bridge, BRIDGE_PREFETCHABLE_OFFSET),
+            0x0000_fff0
+        );
+    }
+
+    #[test]
+    fn no_space() {
+        let config_space = FakeConfigSpace::default();
+        let device = device_function(0, 0, 0);
+        config_space.add(
+            FakeFunction::new(device, &info(HeaderType::Standard))
+                .memory_bar_32(0, 0x1000, false)
+                .memory_bar_32(1, 0x1000, false),
+        );
+        let mut root = PciRoot::new(config_space);
+
+        let mut allocator = BarAllocator::new(PciWindows {
+            memory_32: Some(PciWindow {
+                start: 0x1000_0000,
+                size: 0x1000,
+            }),
+            ..Default::default()
+        });
+        assert_eq!(
+            allocator.allocate_bus(&mut root, 0),
+            Err(PciError::NoSpaceForBar(device, 1))
+        );
+    }
+}
@@ -0,0 +1,705 @@
+//! PCIe extended capabilities, in the configuration space from offset 0x100 onwards which is only
+//! reachable through ECAM.
+
+use super::{ConfigurationAccess, DeviceFunction, PciRoot, INVALID_READ};
+use bitflags::bitflags;
+use log::warn;
+
+/// The offset in bytes of the first extended capability within PCIe configuration space.
+const EXTENDED_CAPABILITIES_OFFSET: u16 = 0x100;
+/// The size in bytes of PCIe configuration space, including the extended capabilities.
+const EXTENDED_CONFIG_SPACE_SIZE: u16 = 0x1000;
+/// The maximum number of extended capabilities which fit in configuration space, to stop a
+/// malformed list with a loop from being followed forever.
+const MAX_EXTENDED_CAPABILITIES: u16 =
+    (EXTENDED_CONFIG_SPACE_SIZE - EXTENDED_CAPABILITIES_OFFSET) / 4;
+
+/// ID for Advanced Error Reporting extended capabilities.
+pub const PCI_EXT_CAP_ID_AER: u16 = 0x0001;
+/// ID for Access Control Services extended capabilities.
+pub const PCI_EXT_CAP_ID_ACS: u16 = 0x000d;
+/// ID for Single Root I/O Virtualization extended capabilities.
+pub const PCI_EXT_CAP_ID_SRIOV: u16 = 0x0010;
+
+/// The size in bytes of the Advanced Error Reporting capability, up to the end of the header log.
+const AER_CAPABILITY_SIZE: u16 = 0x2c;
+/// The size in bytes of the Access Control Services capability, without the egress control
+/// vector.
+const ACS_CAPABILITY_SIZE: u16 = 0x08;
+/// The size in bytes of the Single Root I/O Virtualization capability.
+const SRIOV_CAPABILITY_SIZE: u16 = 0x40;
+
+impl<C: ConfigurationAccess> PciRoot<C> {
+    /// Returns an iterator over the PCIe extended capabilities of the given device function.
+    ///
+    /// This is always empty if the configuration access mechanism can't reach the extended
+    /// configuration space, or if the device function isn't a PCIe function.
+    pub fn extended_capabilities(
+        &self,
+        device_function: DeviceFunction,
+    ) -> ExtendedCapabilityIterator<'_, C> {
+        let next_capability_offset =
+            if self.config_access.config_space_size() >= EXTENDED_CONFIG_SPACE_SIZE {
+                Some(EXTENDED_CAPABILITIES_OFFSET)
+            } else {
+                None
+            };
+        ExtendedCapabilityIterator {
+            root: self,
+            device_function,
+            next_capability_offset,
+            remaining: MAX_EXTENDED_CAPABILITIES,
+        }
+    }
+
+    /// Gets information about the Advanced Error Reporting capability of the given device
+    /// function, if it has one.
+    pub fn aer_info(&self, device_function: DeviceFunction) -> Option<AerInfo> {
+        let offset = self.find_extended_capability(
+            device_function,
+            PCI_EXT_CAP_ID_AER,
+            AER_CAPABILITY_SIZE,
+        )?;
+        let mut header_log = [0; 4];
+        for (i, word) in header_log.iter_mut().enumerate() {
+            *word = self.read_register(device_function, offset + 0x1c + 4 * i as u16);
+        }
+        Some(AerInfo {
+            offset,
+            uncorrectable_status: AerUncorrectable::from_bits_retain(
+                self.read_register(device_function, offset + 0x04),
+            ),
+            uncorrectable_mask: AerUncorrectable::from_bits_retain(
+                self.read_register(device_function, offset + 0x08),
+            ),
+            uncorrectable_severity: AerUncorrectable::from_bits_retain(
+                self.read_register(device_function, offset + 0x0c),
+            ),
+            correctable_status: AerCorrectable::from_bits_retain(
+                self.read_register(device_function, offset + 0x10),
+            ),
+            correctable_mask: AerCorrectable::from_bits_retain(
+                self.read_register(device_function, offset + 0x14),
+            ),
+            capabilities_control: self.read_register(device_function, offset + 0x18),
+            header_log,
+        })
+    }
+
+    /// Clears the given bits of the uncorrectable and correctable error status registers of the
+    /// given device function's Advanced Error Reporting capability.
+    ///
+    /// `aer_info` must have come from [`PciRoot::aer_info`] for the same device function.
+    pub fn clear_aer_status(
+        &mut self,
+        device_function: DeviceFunction,
+        aer_info: &AerInfo,
+        uncorrectable: AerUncorrectable,
+        correctable: AerCorrectable,
+    ) {
+        // The status bits are cleared by writing 1 to them.
+        self.write_register(
+            device_function,
+            aer_info.offset + 0x04,
+            uncorrectable.bits(),
+        );
+        self.write_register(device_function, aer_info.offset + 0x10, correctable.bits());
+    }
+
+    /// Gets information about the Access Control Services capability of the given device function,
+    /// if it has one.
+    pub fn acs_info(&self, device_function: DeviceFunction) -> Option<AcsInfo> {
+        let offset = self.find_extended_capability(
+            device_function,
+            PCI_EXT_CAP_ID_ACS,
+            ACS_CAPABILITY_SIZE,
+        )?;
+        let capability_control = self.read_register(device_function, offset + 0x04);
+        Some(AcsInfo {
+            offset,
+            capabilities: Acs::from_bits_retain(capability_control as u16 & 0xff),
+            egress_control_vector_size: (capability_control >> 8) as u8,
+            control: Acs::from_bits_retain((capability_control >> 16) as u16),
+        })
+    }
+
+    /// Sets the Access Control Services features which are enabled for the given device function.
+    ///
+    /// `acs_info` must have come from [`PciRoot::acs_info`] for the same device function.
+    /// Features which the function doesn't support are ignored.
+    pub fn set_acs_control(
+        &mut self,
+        device_function: DeviceFunction,
+        acs_info: &AcsInfo,
+        control: Acs,
+    ) {
+        let control = control & acs_info.capabilities;
+        let capability_control = self.read_register(device_function, acs_info.offset + 0x04);
+        self.write_register(
+            device_function,
+            acs_info.offset + 0x04,
+            u32::from(control.bits()) << 16 | capability_control & 0xffff,
+        );
+    }
+
+    /// Gets information about the Single Root I/O Virtualization capability of the given device
+    /// function, if it has one.
+    pub fn sriov_info(&self, device_function: DeviceFunction) -> Option<SrIovInfo> {
+        let offset = self.find_extended_capability(
+            device_function,
+            PCI_EXT_CAP_ID_SRIOV,
+            SRIOV_CAPABILITY_SIZE,
+        )?;
+        let control_status = self.read_register(device_function, offset + 0x08);
+        let vfs = self.read_register(device_function, offset + 0x0c);
+        let num_vfs = self.read_register(device_function, offset + 0x10);
+        let vf_offset_stride = self.read_register(device_function, offset + 0x14);
+        let vf_device_id = self.read_register(device_function, offset + 0x18);
+        Some(SrIovInfo {
+            offset,
+            capabilities: self.read_register(device_function, offset + 0x04),
+            control: SrIovControl::from_bits_retain(control_status as u16),
+            status: (control_status >> 16) as u16,
+            initial_vfs: vfs as u16,
+            total_vfs: (vfs >> 16) as u16,
+            num_vfs: num_vfs as u16,
+            function_dependency_link: (num_vfs >> 16) as u8,
+            first_vf_offset: vf_offset_stride as u16,
+            vf_stride: (vf_offset_stride >> 16) as u16,
+            vf_device_id: (vf_device_id >> 16) as u16,
+            supported_page_sizes: self.read_register(device_function, offset + 0x1c),
+            system_page_size: self.read_register(device_function, offset + 0x20),
+        })
+    }
+
+    /// Returns the offset of the first extended capability of the given device function with the
+    /// given ID, if there is one and the `size` bytes of it fit in configuration space.
+    fn find_extended_capability(
+        &self,
+        device_function: DeviceFunction,
+        id: u16,
+        size: u16,
+    ) -> Option<u16> {
+        let offset = self
+            .extended_capabilities(device_function)
+            .find(|capability| capability.id == id)?
+            .offset;
+        if u32::from(offset) + u32::from(size) > u32::from(self.config_access.config_space_size()) {
+            warn!(
+                "Extended capability {:#06x} at {:#05x} doesn't fit in configuration space",
+                id, offset
+            );
+            return None;
+        }
+        Some(offset)
+    }
+}
+
+/// Iterator over the PCIe extended capabilities of a device function.
+#[derive(Debug)]
+pub struct ExtendedCapabilityIterator<'a, C: ConfigurationAccess> {
+    root: &'a PciRoot<C>,
+    device_function: DeviceFunction,
+    next_capability_offset: Option<u16>,
+    /// The number of capabilities which may still be returned.
+    remaining: u16,
+}
+
+impl<'a, C: ConfigurationAccess> Iterator for ExtendedCapabilityIterator<'a, C> {
+    type Item = ExtendedCapabilityInfo;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let offset = self.next_capability_offset.take()?;
+        if self.remaining == 0 {
+            warn!(
+                "Too many extended capabilities, stopping at {:#05x}",
+                offset
+            );
+            return None;
+        }
+        self.remaining -= 1;
+
+        let capability_header = self.root.read_register(self.device_function, offset);
+        // Functions without any extended capabilities have a header of 0 at offset 0x100, and
+        // conventional PCI functions behind a PCIe bridge may read all ones.
+        if capability_header == 0 || capability_header == INVALID_READ {
+            return None;
+        }
+        let id = capability_header as u16;
+        let version = (capability_header >> 16 & 0xf) as u8;
+        let next_offset = (capability_header >> 20) as u16 & !0x3;
+
+        self.next_capability_offset = if next_offset == 0 {
+            None
+        } else if next_offset < EXTENDED_CAPABILITIES_OFFSET {
+            warn!(
+                "Invalid next extended capability offset {:#05x}",
+                next_offset
+            );
+            None
+        } else {
+            Some(next_offset)
+        };
+
+        Some(ExtendedCapabilityInfo {
+            offset,
+            id,
+            version,
+        })
+    }
+}
+
+/// Information about a PCIe extended capability.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct ExtendedCapabilityInfo {
+    /// The offset of the capability in the PCIe configuration space of the device function.
+    pub offset: u16,
+    /// The ID of the capability.
+    pub id: u16,
+    /// The version of the capability structure.
+    pub version: u8,
+}
+
+bitflags! {
+    /// Uncorrectable errors, as reported by the Advanced Error Reporting capability.
+    #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+    pub struct AerUncorrectable: u32 {
+        /// A data link protocol error.
+        const DATA_LINK_PROTOCOL = 1 << 4;
+        /// The link went down unexpectedly.
+        const SURPRISE_DOWN = 1 << 5;
+        /// A poisoned TLP was received.
+        const POISONED_TLP = 1 << 12;
+        /// A flow control protocol error.
+        const FLOW_CONTROL_PROTOCOL = 1 << 13;
+        /// A request timed out waiting for its completion.
+        const COMPLETION_TIMEOUT = 1 << 14;
+        /// The function aborted a request which it received.
+        const COMPLETER_ABORT = 1 << 15;
+        /// A completion was received which didn't match any outstanding request.
+        const UNEXPECTED_COMPLETION = 1 << 16;
+        /// The receive buffer overflowed.
+        const RECEIVER_OVERFLOW = 1 << 17;
+        /// A malformed TLP was received.
+        const MALFORMED_TLP = 1 << 18;
+        /// A TLP with a bad end-to-end CRC was received.
+        const ECRC = 1 << 19;
+        /// An unsupported request was received.
+        const UNSUPPORTED_REQUEST = 1 << 20;
+        /// A request violated the function's Access Control Services settings.
+        const ACS_VIOLATION = 1 << 21;
+        /// An internal error which the function couldn't correct.
+        const UNCORRECTABLE_INTERNAL = 1 << 22;
+        /// A multicast TLP was blocked.
+        const MC_BLOCKED_TLP = 1 << 23;
+        /// An AtomicOp request was blocked on egress.
+        const ATOMIC_OP_EGRESS_BLOCKED = 1 << 24;
+        /// A TLP with a prefix was blocked.
+        const TLP_PREFIX_BLOCKED = 1 << 25;
+        /// A poisoned TLP was blocked on egress.
+        const POISONED_TLP_EGRESS_BLOCKED = 1 << 26;
+    }
+}
+
+bitflags! {
+    /// Correctable errors, as reported by the Advanced Error Reporting capability.
+    #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+    pub struct AerCorrectable: u32 {
+        /// A receiver error.
+        const RECEIVER = 1 << 0;
+        /// A TLP with a bad CRC or sequence number was received.
+        const BAD_TLP = 1 << 6;
+        /// A DLLP with a bad CRC was received.
+        const BAD_DLLP = 1 << 7;
+        /// The replay number rolled over.
+        const REPLAY_NUM_ROLLOVER = 1 << 8;
+        /// The replay timer timed out.
+        const REPLAY_TIMER_TIMEOUT = 1 << 12;
+        /// An uncorrectable error was handled as an advisory non-fatal error.
+        const ADVISORY_NON_FATAL = 1 << 13;
+        /// An internal error which the function corrected.
+        const CORRECTED_INTERNAL = 1 << 14;
+        /// The header log overflowed.
+        const HEADER_LOG_OVERFLOW = 1 << 15;
+    }
+}
+
+/// The contents of the Advanced Error Reporting capability of a device function.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AerInfo {
+    /// The offset of the capability in the PCIe configuration space of the device function.
+    pub offset: u16,
+    /// The uncorrectable errors which have been detected.
+    pub uncorrectable_status: AerUncorrectable,
+    /// The uncorrectable errors which are masked from being reported.
+    pub uncorrectable_mask: AerUncorrectable,
+    /// The uncorrectable errors which are reported as fatal rather than non-fatal.
+    pub uncorrectable_severity: AerUncorrectable,
+    /// The correctable errors which have been detected.
+    pub correctable_status: AerCorrectable,
+    /// The correctable errors which are masked from being reported.
+    pub correctable_mask: AerCorrectable,
+    /// The advanced error capabilities and control register, including the first error pointer.
+    pub capabilities_control: u32,
+    /// The header of the TLP which caused the first reported uncorrectable error.
+    pub header_log: [u32; 4],
+}
+
+impl AerInfo {
+    /// Returns the bit position in the uncorrectable error status register of the first error
+    /// which was reported.
+    pub fn first_error_pointer(&self) -> u8 {
+        (self.capabilities_control & 0x1f) as u8
+    }
+}
+
+bitflags! {
+    /// Access Control Services features, used for both the capability and control registers.
+    #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+    pub struct Acs: u16 {
+        /// Validation of the bus number of requests from downstream.
+        const SOURCE_VALIDATION = 1 << 0;
+        /// Blocking of upstream requests with translated addresses.
+        const TRANSLATION_BLOCKING = 1 << 1;
+        /// Redirection of peer-to-peer requests upstream.
+        const P2P_REQUEST_REDIRECT = 1 << 2;
+        /// Redirection of peer-to-peer completions upstream.
+        const P2P_COMPLETION_REDIRECT = 1 << 3;
+        /// Forwarding of upstream requests which target the same port back downstream.
+        const UPSTREAM_FORWARDING = 1 << 4;
+        /// Control of which ports peer-to-peer requests may be sent to.
+        const P2P_EGRESS_CONTROL = 1 << 5;
+        /// Direct peer-to-peer forwarding of requests with translated addresses.
+        const DIRECT_TRANSLATED_P2P = 1 << 6;
+    }
+}
+
+/// The contents of the Access Control Services capability of a device function.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AcsInfo {
+    /// The offset of the capability in the PCIe configuration space of the device function.
+    pub offset: u16,
+    /// The features which the function supports.
+    pub capabilities: Acs,
+    /// The number of bits in the egress control vector, where 0 means 256, if
+    /// [`Acs::P2P_EGRESS_CONTROL`] is supported.
+    pub egress_control_vector_size: u8,
+    /// The features which are enabled.
+    pub control: Acs,
+}
+
+bitflags! {
+    /// The control register of the SR-IOV capability.
+    #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+    pub struct SrIovControl: u16 {
+        /// The virtual functions are enabled.
+        const VF_ENABLE = 1 << 0;
+        /// Migration of virtual functions is enabled.
+        const VF_MIGRATION_ENABLE = 1 << 1;
+        /// The migration interrupt is enabled.
+        const VF_MIGRATION_INTERRUPT_ENABLE = 1 << 2;
+        /// The virtual functions respond to memory space accesses to their BARs.
+        const VF_MEMORY_SPACE_ENABLE = 1 << 3;
+        /// The function is in a hierarchy which supports Alternative Routing-ID Interpretation.
+        const ARI_CAPABLE_HIERARCHY = 1 << 4;
+    }
+}
+
+/// The contents of the Single Root I/O Virtualization capability of a physical function.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SrIovInfo {
+    /// The offset of the capability in the PCIe configuration space of the device function.
+    pub offset: u16,
+    /// The SR-IOV capabilities register.
+    pub capabilities: u32,
+    /// The SR-IOV control register.
+    pub control: SrIovControl,
+    /// The SR-IOV status register.
+    pub status: u16,
+    /// The number of virtual functions initially associated with the physical function.
+    pub initial_vfs: u16,
+    /// The maximum number of virtual functions which may be associated with the physical
+    /// function.
+    pub total_vfs: u16,
+    /// The number of virtual functions which are currently visible.
+    pub num_vfs: u16,
+    /// The function number of the physical function which this one depends on.
+    pub function_dependency_link: u8,
+    /// The offset of the routing ID of the first virtual function from that of the physical
+    /// function.
+    pub first_vf_offset: u16,
+    /// The difference between the routing IDs of consecutive virtual functions.
+    pub vf_stride: u16,
+    /// The device ID of the virtual functions.
+    pub vf_device_id: u16,
+    /// The page sizes which the physical function supports, as a bitmap where bit n means
+    /// 2^(n+12) bytes.
+    pub supported_page_sizes: u32,
+    /// The page size which the virtual functions' BARs are aligned to, in the same format.
+    pub system_page_size: u32,
+}
+
+impl SrIovInfo {
+    /// Returns the address of the virtual function with the given index (starting from 0) of the
+    /// physical function at `physical_function`, or `None` if it is beyond the last routing ID.
+    ///
+    /// The offset and stride may change when [`num_vfs`](Self::num_vfs) or
+    /// [`SrIovControl::ARI_CAPABLE_HIERARCHY`] is changed, so this should be called with
+    /// information read afterwards.
+    pub fn vf_device_function(
+        &self,
+        physical_function: DeviceFunction,
+        index: u16,
+    ) -> Option<DeviceFunction> {
+        let routing_id = u32::from(physical_function.bus) << 8
+            | u32::from(physical_function.device) << 3
+            | u32::from(physical_function.function);
+        let vf_routing_id = routing_id
+            + u32::from(self.first_vf_offset)
+            + u32::from(index) * u32::from(self.vf_stride);
+        if vf_routing_id > 0xffff {
+            return None;
+        }
+        Some(DeviceFunction {
+            bus: (vf_routing_id >> 8) as u8,
+            device: (vf_routing_id >> 3 & 0x1f) as u8,
+            function: (vf_routing_id & 0x7) as u8,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::pci::bus::{
+        fake::{FakeConfigSpace, FakeFunction},
+        DeviceFunctionInfo, HeaderType,
+    };
+
+    fn device_function(bus: u8, device: u8, function: u8) -> DeviceFunction {
+        DeviceFunction {
+            bus,
+            device,
+            function,
+        }
+    }
+
+    fn info() -> DeviceFunctionInfo {
+        DeviceFunctionInfo {
+            vendor_id: 0x8086,
+            device_id: 0x10fb,
+            class: 0x02,
+            subclass: 0x00,
+            prog_if: 0,
+            revision: 1,
+            header_type: HeaderType::Standard,
+        }
+    }
+
+    #[test]
+    fn no_extended_capabilities() {
+        let config_space = FakeConfigSpace::default();
+        config_space.add(FakeFunction::new(device_function(0, 1, 0), &info()));
+        let root = PciRoot::new(config_space);
+
+        assert_eq!(
+            root.extended_capabilities(device_function(0, 1, 0)).count(),
+            0
+        );
+        // Absent functions read as all ones.
+        assert_eq!(
+            root.extended_capabilities(device_function(0, 2, 0)).count(),
+            0
+        );
+        assert_eq!(root.aer_info(device_function(0, 1, 0)), None);
+    }
+
+    #[test]
+    fn typed_capabilities() {
+        let physical_function = device_function(0, 2, 0);
+        let config_space = FakeConfigSpace::default();
+        config_space.add(
+            FakeFunction::new(physical_function, &info())
+                .extended_capability(
+                    0x100,
+                    PCI_EXT_CAP_ID_AER,
+                    2,
+                    &[
+                        0x0010_4000,
+                        0x0040_0000,
+                        0x0006_2030,
+                        0x0000_2041,
+                        0x0000_e000,
+                        0x0e,
+                        1,
+                        2,
+                        3,
+                        4,
+                    ],
+                )
+                .extended_capability(0x148, 0x0003, 1, &[0x1234, 0x5678])
+                .extended_capability(0x160, PCI_EXT_CAP_ID_ACS, 1, &[0x0005_001f])
+                .extended_capability(
+                    0x180,
+                    PCI_EXT_CAP_ID_SRIOV,
+                    1,
+                    &[
+                        0x0000_0002,
+                        0x0000_0009,
+                        0x0040_0040,
+                        0x0000_0004,
+                        0x0002_0100,
+                        0x10ed_0000,
+                        0x0000_0553,
+                        0x0000_0001,
+                    ],
+                ),
+        );
+        let mut root = PciRoot::new(config_space.clone());
+
+        assert_eq!(
+            root.extended_capabilities(physical_function)
+                .collect::<Vec<_>>(),
+            vec![
+                ExtendedCapabilityInfo {
+                    offset: 0x100,
+                    id: PCI_EXT_CAP_ID_AER,
+                    version: 2,
+                },
+                ExtendedCapabilityInfo {
+                    offset: 0x148,
+                    id: 0x0003,
+                    version: 1,
+                },
+                ExtendedCapabilityInfo {
+                    offset: 0x160,
+                    id: PCI_EXT_CAP_ID_ACS,
+                    version: 1,
+                },
+                ExtendedCapabilityInfo {
+                    offset: 0x180,
+                    id: PCI_EXT_CAP_ID_SRIOV,
+                    version: 1,
+                },
+            ]
+        );
+
+        let aer_info = root.aer_info(physical_function).unwrap();
+        assert_eq!(
+            aer_info,
+            AerInfo {
+                offset: 0x100,
+                uncorrectable_status: AerUncorrectable::COMPLETION_TIMEOUT
+                    | AerUncorrectable::UNSUPPORTED_REQUEST,
+                uncorrectable_mask: AerUncorrectable::UNCORRECTABLE_INTERNAL,
+                uncorrectable_severity: AerUncorrectable::DATA_LINK_PROTOCOL
+                    | AerUncorrectable::SURPRISE_DOWN
+                    | AerUncorrectable::FLOW_CONTROL_PROTOCOL
+                    | AerUncorrectable::RECEIVER_OVERFLOW
+                    | AerUncorrectable::MALFORMED_TLP,
+                correctable_status: AerCorrectable::RECEIVER
+                    | AerCorrectable::BAD_TLP
+                    | AerCorrectable::ADVISORY_NON_FATAL,
+                correctable_mask: AerCorrectable::ADVISORY_NON_FATAL
+                    | AerCorrectable::CORRECTED_INTERNAL
+                    | AerCorrectable::HEADER_LOG_OVERFLOW,
+                capabilities_control: 0x0e,
+                header_log: [1, 2, 3, 4],
+            }
+        );
+        assert_eq!(aer_info.first_error_pointer(), 14);
+
+        let acs_info = root.acs_info(physical_function).unwrap();
+        assert_eq!(
+            acs_info,
+            AcsInfo {
+                offset: 0x160,
+                capabilities: Acs::SOURCE_VALIDATION
+                    | Acs::TRANSLATION_BLOCKING
+                    | Acs::P2P_REQUEST_REDIRECT
+                    | Acs::P2P_COMPLETION_REDIRECT
+                    | Acs::UPSTREAM_FORWARDING,
+                egress_control_vector_size: 0,
+                control: Acs::SOURCE_VALIDATION | Acs::P2P_REQUEST_REDIRECT,
+            }
+        );
+        // Unsupported features aren't enabled.
+        root.set_acs_control(
+            physical_function,
+            &acs_info,
+            Acs::P2P_COMPLETION_REDIRECT | Acs::DIRECT_TRANSLATED_P2P,
+        );
+        assert_eq!(
+            root.acs_info(physical_function).unwrap().control,
+            Acs::P2P_COMPLETION_REDIRECT
+        );
+
+        let sriov_info = root.sriov_info(physical_function).unwrap();
+        assert_eq!(
+            sriov_info,
+            SrIovInfo {
+                offset: 0x180,
+                capabilities: 0x2,
+                control: SrIovControl::VF_ENABLE | SrIovControl::VF_MEMORY_SPACE_ENABLE,
+                status: 0,
+                initial_vfs: 64,
+                total_vfs: 64,
+                num_vfs: 4,
+                function_dependency_link: 0,
+                first_vf_offset: 0x100,
+                vf_stride: 2,
+                vf_device_id: 0x10ed,
+                supported_page_sizes: 0x553,
+                system_page_size: 1,
+            }
+        );
+        assert_eq!(
+            sriov_info.vf_device_function(physical_function, 0),
+            Some(device_function(1, 2, 0))
+        );
+        assert_eq!(
+            sriov_info.vf_device_function(physical_function, 3),
+            Some(device_function(1, 2, 6))
+        );
+        assert_eq!(
+            sriov_info.vf_device_function(device_function(0xff, 0, 0), 0),
+            None
+        );
+    }
+
+    #[test]
+    fn capability_past_end_of_config_space() {
+        let config_space = FakeConfigSpace::default();
+        config_space.add(
+            FakeFunction::new(device_function(0, 1, 0), &info())
+                .extended_capability(0x100, PCI_EXT_CAP_ID_ACS, 1, &[])
+                .extended_capability(0xff0, PCI_EXT_CAP_ID_AER, 1, &[])
+                .extended_capability(0xff4, PCI_EXT_CAP_ID_SRIOV, 1, &[]),
+        );
+        let root = PciRoot::new(config_space);
+
+        assert_eq!(
+            root.extended_capabilities(device_function(0, 1, 0)).count(),
+            3
+        );
+        assert!(root.acs_info(device_function(0, 1, 0)).is_some());
+        assert_eq!(root.aer_info(device_function(0, 1, 0)), None);
+        assert_eq!(root.sriov_info(device_function(0, 1, 0)), None);
+    }
+
+    #[test]
+    fn extended_capability_loop() {
+        let config_space = FakeConfigSpace::default();
+        let mut function = FakeFunction::new(device_function(0, 1, 0), &info())
+            .extended_capability(0x100, PCI_EXT_CAP_ID_AER, 1, &[]);
+        // Make the capability point back to itself.
+        function.config[0x100 / 4] |= 0x100 << 20;
+        config_space.add(function);
+        let root = PciRoot::new(config_space);
+
+        assert_eq!(
+            root.extended_capabilities(device_function(0, 1, 0)).count(),
+            usize::from(MAX_EXTENDED_CAPABILITIES)
+        );
+    }
+}
@@ -0,0 +1,217 @@
+//! A fake PCI configuration space for unit tests.
+
+use super::{ConfigurationAccess, DeviceFunction, DeviceFunctionInfo, HeaderType};
+use std::sync::{Arc, Mutex};
+
+/// The offset in words of BAR0 within configuration space.
+const BAR0_WORD: usize = 4;
+/// The offset in words of a bridge's bus numbers within configuration space.
+const BRIDGE_BUS_NUMBERS_WORD: usize = 0x18 / 4;
+/// The offset in words of a bridge's prefetchable memory base and limit within configuration space.
+const BRIDGE_PREFETCHABLE_WORD: usize = 0x24 / 4;
+/// The offset in words of the capabilities pointer within configuration space.
+const CAPABILITIES_POINTER_WORD: usize = 0x34 / 4;
+/// The capabilities list bit of the status register, in the word which contains it.
+const STATUS_CAPABILITIES_LIST: u32 = 1 << 20;
+/// The offset in words of the first PCIe extended capability within configuration space.
+const EXTENDED_CAPABILITIES_WORD: usize = 0x100 / 4;
+
+/// A fake device function in a [`FakeConfigSpace`].
+#[derive(Clone, Debug)]
+pub struct FakeFunction {
+    /// The address of the function. The bus number is ignored for functions behind a bridge, as
+    /// they are on whichever bus the bridge's secondary bus number says.
+    pub device_function: DeviceFunction,
+    /// The index of the bridge which the function is behind, or `None` if it is on a root bus.
+    pub parent: Option<usize>,
+    /// The contents of the function's configuration space.
+    pub config: [u32; 1024],
+    /// The bits of each BAR register which can be written, as determined by the BAR's size. The
+    /// other bits are read-only.
+    pub bar_masks: [u32; 6],
+}
+
+impl FakeFunction {
+    /// Creates a function with the given identification and nothing else in its configuration
+    /// space.
+    pub fn new(device_function: DeviceFunction, info: &DeviceFunctionInfo) -> Self {
+        let mut config = [0; 1024];
+        config[0] = u32::from(info.device_id) << 16 | u32::from(info.vendor_id);
+        config[2] = u32::from(info.class) << 24
+            | u32::from(info.subclass) << 16
+            | u32::from(info.prog_if) << 8
+            | u32::from(info.revision);
+        config[3] = u32::from(match info.header_type {
+            HeaderType::Standard => 0x00,
+            HeaderType::PciPciBridge => 0x01,
+            HeaderType::PciCardbusBridge => 0x02,
+            HeaderType::Unrecognised(header_type) => header_type,
+        }) << 16;
+        Self {
+            device_function,
+            parent: None,
+            config,
+            bar_masks: [0; 6],
+        }
+    }
+
+    /// Adds a capability with the given ID, private header and following words at the given offset
+    /// in configuration space, at the end of the capability list.
+    pub fn capability(mut self, offset: u8, id: u8, private_header: u16, body: &[u32]) -> Self {
+        let word = usize::from(offset / 4);
+        self.config[word] = u32::from(private_header) << 16 | u32::from(id);
+        self.config[word + 1..word + 1 + body.len()].copy_from_slice(body);
+        if self.config[1] & STATUS_CAPABILITIES_LIST == 0 {
+            self.config[1] |= STATUS_CAPABILITIES_LIST;
+            self.config[CAPABILITIES_POINTER_WORD] = offset.into();
+        } else {
+            let mut last = self.config[CAPABILITIES_POINTER_WORD] as usize / 4;
+            while self.config[last] >> 8 & 0xff != 0 {
+                last = (self.config[last] >> 8 & 0xff) as usize / 4;
+            }
+            self.config[last] |= u32::from(offset) << 8;
+        }
+        self
+    }
+
+    /// Adds a PCIe extended capability with the given ID, version and following words at the given
+    /// offset in configuration space, at the end of the extended capability list.
+    ///
+    /// The first extended capability must be at offset 0x100.
+    pub fn extended_capability(mut self, offset: u16, id: u16, version: u8, body: &[u32]) -> Self {
+        let word = usize::from(offset / 4);
+        self.config[word] = u32::from(version) << 16 | u32::from(id);
+        self.config[word + 1..word + 1 + body.len()].copy_from_slice(body);
+        if word != EXTENDED_CAPABILITIES_WORD {
+            assert_ne!(self.config[EXTENDED_CAPABILITIES_WORD], 0);
+            let mut last = EXTENDED_CAPABILITIES_WORD;
+            while self.config[last] >> 20 != 0 {
+                last = (self.config[last] >> 20) as usize / 4;
+            }
+            self.config[last] |= u32::from(offset) << 20;
+        }
+        self
+    }
+
+    /// Makes the bridge's prefetchable memory window support 64-bit addresses.
+    pub fn prefetchable_64(mut self) -> Self {
+        self.config[BRIDGE_PREFETCHABLE_WORD] = 0x00010001;
+        self
+    }
+
+    /// Makes the given BAR an I/O BAR of the given size.
+    pub fn io_bar(mut self, bar_index: usize, size: u32) -> Self {
+        self.config[BAR0_WORD + bar_index] = 0x1;
+        self.bar_masks[bar_index] = !(size - 1) & !0x3;
+        self
+    }
+
+    /// Makes the given BAR a 32-bit memory BAR of the given size.
+    pub fn memory_bar_32(mut self, bar_index: usize, size: u32, prefetchable: bool) -> Self {
+        self.config[BAR0_WORD + bar_index] = if prefetchable { 0x8 } else { 0x0 };
+        self.bar_masks[bar_index] = !(size - 1) & !0xf;
+        self
+    }
+
+    /// Makes the given BAR and the one after it a 64-bit memory BAR of the given size.
+    pub fn memory_bar_64(mut self, bar_index: usize, size: u64, prefetchable: bool) -> Self {
+        self.config[BAR0_WORD + bar_index] = if prefetchable { 0xc } else { 0x4 };
+        let mask = !(size - 1);
+        self.bar_masks[bar_index] = mask as u32 & !0xf;
+        self.bar_masks[bar_index + 1] = (mask >> 32) as u32;
+        self
+    }
+}
+
+/// A fake configuration space containing some device functions, which behaves like real hardware
+/// for BAR sizing. Clones share the same state.
+#[derive(Clone, Debug, Default)]
+pub struct FakeConfigSpace {
+    /// The device functions which are present.
+    pub functions: Arc<Mutex<Vec<FakeFunction>>>,
+}
+
+impl FakeConfigSpace {
+    /// Adds the given function, and returns its index.
+    pub fn add(&self, function: FakeFunction) -> usize {
+        let mut functions = self.functions.lock().unwrap();
+        functions.push(function);
+        functions.len() - 1
+    }
+
+    /// Adds the given function behind the bridge with the given index, and returns its index.
+    pub fn add_behind(&self, bridge: usize, mut function: FakeFunction) -> usize {
+        function.parent = Some(bridge);
+        self.add(function)
+    }
+}
+
+/// Returns the bus which the function with the given index is currently on, or `None` if it can't
+/// be reached because a bridge above it has no bus number assigned.
+fn bus_of(functions: &[FakeFunction], index: usize) -> Option<u8> {
+    let function = &functions[index];
+    match function.parent {
+        None => Some(function.device_function.bus),
+        Some(parent) => {
+            let parent_bus = bus_of(functions, parent)?;
+            let secondary = (functions[parent].config[BRIDGE_BUS_NUMBERS_WORD] >> 8) as u8;
+            if secondary > parent_bus {
+                Some(secondary)
+            } else {
+                None
+            }
+        }
+    }
+}
+
+/// Returns the index of the function at the given address, if there is one.
+fn find(functions: &[FakeFunction], device_function: DeviceFunction) -> Option<usize> {
+    (0..functions.len()).find(|&index| {
+        let function = &functions[index].device_function;
+        function.device == device_function.device
+            && function.function == device_function.function
+            && bus_of(functions, index) == Some(device_function.bus)
+    })
+}
+
+impl ConfigurationAccess for FakeConfigSpace {
+    fn read_word(&self, device_function: DeviceFunction, register_offset: u16) -> u32 {
+        assert_eq!(register_offset % 4, 0);
+        let functions = self.functions.lock().unwrap();
+        find(&functions, device_function).map_or(0xffffffff, |index| {
+            functions[index].config[usize::from(register_offset / 4)]
+        })
+    }
+
+    fn write_word(&mut self, device_function: DeviceFunction, register_offset: u16, data: u32) {
+        assert_eq!(register_offset % 4, 0);
+        let mut functions = self.functions.lock().unwrap();
+        let Some(index) = find(&functions, device_function) else {
+            return;
+        };
+        let function = &mut functions[index];
+        let word = usize::from(register_offset / 4);
+        let bar_count = match function.config[3] >> 16 & 0x7f {
+            0x00 => 6,
+            0x01 => 2,
+            _ => 0,
+        };
+        let mask = if (BAR0_WORD..BAR0_WORD + bar_count).contains(&word) {
+            function.bar_masks[word - BAR0_WORD]
+        } else if bar_count == 2 && word == BRIDGE_PREFETCHABLE_WORD {
+            // The low bits of the prefetchable base and limit say whether they are 64-bit.
+            0xfff0fff0
+        } else {
+            0xffffffff
+        };
+        function.config[word] = data & mask | function.config[word] & !mask;
+    }
+
+    fn config_space_size(&self) -> u16 {
+        0x1000
+    }
+
+    unsafe fn unsafe_clone(&self) -> Self {
+        self.clone()
+    }
+}
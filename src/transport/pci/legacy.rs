@@ -0,0 +1,518 @@
+//! Legacy PCI transport for VirtIO, for devices which only provide the register layout in an I/O
+//! port BAR0 rather than the modern vendor capabilities.
+//!
+//! Ref: 4.1.4.10 Legacy Interfaces: A Note on PCI Device Layout
+
+use super::bus::{BarInfo, ConfigurationAccess, DeviceFunction, PciRoot, PortIo};
+use super::{device_type, VirtioPciError, VIRTIO_VENDOR_ID};
+use crate::{
+    barrier::{arch_barrier, Barrier},
+    hal::PhysAddr,
+    timeout::spin_until,
+    transport::{check_config_space_access, DeviceStatus, DeviceType, Transport},
+    Error, PAGE_SIZE,
+};
+use core::{
+    convert::{TryFrom, TryInto},
+    mem::size_of,
+    ptr::NonNull,
+};
+use log::warn;
+use zerocopy::{AsBytes, FromBytes};
+
+/// The range of PCI device IDs used by transitional devices, which support the legacy interface.
+const TRANSITIONAL_DEVICE_IDS: core::ops::RangeInclusive<u16> = 0x1000..=0x103f;
+
+/// The offset of the subsystem vendor ID and subsystem ID within PCI configuration space.
+const SUBSYSTEM_OFFSET: u16 = 0x2c;
+
+// Offsets of the legacy registers within BAR0.
+const DEVICE_FEATURES: u32 = 0;
+const GUEST_FEATURES: u32 = 4;
+const QUEUE_ADDRESS: u32 = 8;
+const QUEUE_SIZE: u32 = 12;
+const QUEUE_SELECT: u32 = 14;
+const QUEUE_NOTIFY: u32 = 16;
+const DEVICE_STATUS: u32 = 18;
+const ISR_STATUS: u32 = 19;
+const CONFIG_MSIX_VECTOR: u32 = 20;
+const QUEUE_MSIX_VECTOR: u32 = 22;
+
+/// The offset of the device-specific configuration when MSI-X is disabled.
+const CONFIG_OFFSET: u32 = 20;
+/// The offset of the device-specific configuration when MSI-X is enabled, after the vector
+/// registers.
+const CONFIG_OFFSET_MSIX: u32 = 24;
+
+/// Legacy PCI transport for VirtIO, accessing the device's registers through I/O ports.
+///
+/// Only the low 32 feature bits are available, and queues must be the size which the device
+/// chooses, so drivers return [`Error::Unsupported`] if they need a smaller queue than that.
+///
+/// Ref: 4.1.5.1.1.1 Legacy Interface: Device Initialization
+#[derive(Debug)]
+pub struct LegacyPciTransport<P: PortIo> {
+    device_type: DeviceType,
+    /// The bus, device and function identifier for the VirtIO device.
+    device_function: DeviceFunction,
+    /// The accessor for I/O port space.
+    ports: P,
+    /// The I/O port address of BAR0.
+    base: u32,
+    /// The size in bytes of BAR0.
+    bar_size: u32,
+    /// The offset of the device-specific configuration within BAR0, which depends on whether
+    /// MSI-X is enabled.
+    config_offset: u32,
+}
+
+impl<P: PortIo> LegacyPciTransport<P> {
+    /// Constructs a new legacy PCI VirtIO transport for the given device function on the given
+    /// PCI root controller, using `ports` to access its registers.
+    ///
+    /// The device must be a transitional or legacy device, and its I/O BAR0 must already have been
+    /// allocated.
+    pub fn new(
+        root: &mut PciRoot<impl ConfigurationAccess>,
+        device_function: DeviceFunction,
+        ports: P,
+    ) -> Result<Self, VirtioPciError> {
+        let device_vendor = root.read_register(device_function, 0);
+        let device_id = (device_vendor >> 16) as u16;
+        let vendor_id = device_vendor as u16;
+        if vendor_id != VIRTIO_VENDOR_ID {
+            return Err(VirtioPciError::InvalidVendorId(vendor_id));
+        }
+        if !TRANSITIONAL_DEVICE_IDS.contains(&device_id) {
+            return Err(VirtioPciError::NotTransitional(device_id));
+        }
+        // Legacy devices identify their type by the PCI subsystem ID.
+        let device_type = match device_type(device_id) {
+            DeviceType::Invalid => {
+                let subsystem_id =
+                    (root.read_register(device_function, SUBSYSTEM_OFFSET) >> 16) as u8;
+                DeviceType::from(subsystem_id)
+            }
+            device_type => device_type,
+        };
+
+        let (base, bar_size) = match root.bar_info(device_function, 0)? {
+            BarInfo::IO { address, size } => (address, size),
+            BarInfo::Memory { .. } => return Err(VirtioPciError::UnexpectedMemoryBar),
+        };
+        if base == 0 {
+            return Err(VirtioPciError::BarNotAllocated(0));
+        }
+
+        let msix_enabled = match root.msix_info(device_function) {
+            Some(msix_info) => root.msix_enabled(device_function, &msix_info),
+            None => false,
+        };
+
+        let mut transport = Self {
+            device_type,
+            device_function,
+            ports,
+            base,
+            bar_size,
+            config_offset: CONFIG_OFFSET,
+        };
+        transport.set_msix_enabled(msix_enabled);
+        Ok(transport)
+    }
+
+    /// Tells the transport whether MSI-X is enabled for the device, which moves the
+    /// device-specific configuration to make room for the MSI-X vector registers.
+    ///
+    /// This must be called if MSI-X is enabled or disabled after the transport was constructed.
+    pub fn set_msix_enabled(&mut self, enabled: bool) {
+        self.config_offset = if enabled {
+            CONFIG_OFFSET_MSIX
+        } else {
+            CONFIG_OFFSET
+        };
+    }
+
+    /// Returns the bus, device and function identifier of the device.
+    pub fn device_function(&self) -> DeviceFunction {
+        self.device_function
+    }
+
+    /// Sets the MSI-X vector used for configuration change notifications, and returns the vector
+    /// which the device accepted, or `None` if MSI-X is not enabled.
+    pub fn set_config_msix_vector(&mut self, vector: u16) -> Option<u16> {
+        if self.config_offset != CONFIG_OFFSET_MSIX {
+            return None;
+        }
+        self.ports.write16(self.base + CONFIG_MSIX_VECTOR, vector);
+        Some(self.ports.read16(self.base + CONFIG_MSIX_VECTOR))
+    }
+
+    /// Sets the MSI-X vector used for used buffer notifications from the given queue, and returns
+    /// the vector which the device accepted, or `None` if MSI-X is not enabled.
+    pub fn set_queue_msix_vector(&mut self, queue: u16, vector: u16) -> Option<u16> {
+        if self.config_offset != CONFIG_OFFSET_MSIX {
+            return None;
+        }
+        self.ports.write16(self.base + QUEUE_SELECT, queue);
+        self.ports.write16(self.base + QUEUE_MSIX_VECTOR, vector);
+        Some(self.ports.read16(self.base + QUEUE_MSIX_VECTOR))
+    }
+
+    /// Returns the I/O port of a value of type `T` at the given offset within the device-specific
+    /// configuration, after checking that it fits within BAR0 and is aligned.
+    fn config_port<T>(&self, offset: usize) -> Result<u32, Error> {
+        let config_size = self.bar_size.saturating_sub(self.config_offset) as usize;
+        check_config_space_access::<T>(offset, config_size)?;
+        Ok(self.base + self.config_offset + offset as u32)
+    }
+}
+
+impl<P: PortIo> Transport for LegacyPciTransport<P> {
+    fn device_type(&self) -> DeviceType {
+        self.device_type
+    }
+
+    fn read_device_features(&mut self) -> u64 {
+        self.ports.read32(self.base + DEVICE_FEATURES).into()
+    }
+
+    fn write_driver_features(&mut self, driver_features: u64) {
+        // Legacy devices only have 32 feature bits.
+        self.ports
+            .write32(self.base + GUEST_FEATURES, driver_features as u32);
+    }
+
+    fn max_queue_size(&mut self, queue: u16) -> u32 {
+        self.ports.write16(self.base + QUEUE_SELECT, queue);
+        self.ports.read16(self.base + QUEUE_SIZE).into()
+    }
+
+    fn notify(&mut self, queue: u16) {
+        // Make sure the device sees the queue as it was before the notification.
+        arch_barrier(Barrier::Write, true);
+        self.ports.write16(self.base + QUEUE_NOTIFY, queue);
+    }
+
+    fn get_status(&self) -> DeviceStatus {
+        let status = self.ports.read8(self.base + DEVICE_STATUS);
+        DeviceStatus::from_bits_truncate(status.into())
+    }
+
+    fn set_status(&mut self, status: DeviceStatus) {
+        self.ports
+            .write8(self.base + DEVICE_STATUS, status.bits() as u8);
+    }
+
+    fn set_guest_page_size(&mut self, _guest_page_size: u32) {
+        // No-op, the legacy PCI interface always uses 4 KiB pages.
+    }
+
+    fn requires_legacy_layout(&self) -> bool {
+        true
+    }
+
+    fn requires_fixed_queue_size(&self) -> bool {
+        true
+    }
+
+    fn queue_set(
+        &mut self,
+        queue: u16,
+        size: u32,
+        descriptors: PhysAddr,
+        _driver_area: PhysAddr,
+        _device_area: PhysAddr,
+    ) {
+        // The legacy interface can't change the queue size, so the queue must have been created
+        // with the size the device wants.
+        assert_eq!(size, self.max_queue_size(queue));
+        assert_eq!(descriptors % PAGE_SIZE, 0);
+        let pfn = u32::try_from(descriptors / PAGE_SIZE)
+            .expect("Legacy PCI queues must be below 16 TiB of physical memory");
+        self.ports.write16(self.base + QUEUE_SELECT, queue);
+        self.ports.write32(self.base + QUEUE_ADDRESS, pfn);
+    }
+
+    fn queue_unset(&mut self, queue: u16) {
+        self.ports.write16(self.base + QUEUE_SELECT, queue);
+        self.ports.write32(self.base + QUEUE_ADDRESS, 0);
+    }
+
+    fn queue_used(&mut self, queue: u16) -> bool {
+        self.ports.write16(self.base + QUEUE_SELECT, queue);
+        self.ports.read32(self.base + QUEUE_ADDRESS) != 0
+    }
+
+    fn ack_interrupt(&mut self) -> bool {
+        // Reading the ISR status resets it to 0 and causes the device to de-assert the interrupt.
+        let isr_status = self.ports.read8(self.base + ISR_STATUS);
+        isr_status & 0x3 != 0
+    }
+
+    fn read_config_space<T: AsBytes + FromBytes>(&self, offset: usize) -> Result<T, Error> {
+        let port = self.config_port::<T>(offset)?;
+        let mut value = T::new_zeroed();
+        let bytes = value.as_bytes_mut();
+        match bytes.len() {
+            1 => bytes.copy_from_slice(&self.ports.read8(port).to_ne_bytes()),
+            2 => bytes.copy_from_slice(&self.ports.read16(port).to_ne_bytes()),
+            4 => bytes.copy_from_slice(&self.ports.read32(port).to_ne_bytes()),
+            _ => {
+                for (i, byte) in bytes.iter_mut().enumerate() {
+                    *byte = self.ports.read8(port + i as u32);
+                }
+            }
+        }
+        Ok(value)
+    }
+
+    fn write_config_space<T: AsBytes>(&mut self, offset: usize, value: T) -> Result<(), Error> {
+        let port = self.config_port::<T>(offset)?;
+        let bytes = value.as_bytes();
+        match size_of::<T>() {
+            1 => self.ports.write8(port, bytes[0]),
+            2 => self
+                .ports
+                .write16(port, u16::from_ne_bytes(bytes.try_into().unwrap())),
+            4 => self
+                .ports
+                .write32(port, u32::from_ne_bytes(bytes.try_into().unwrap())),
+            _ => {
+                for (i, byte) in bytes.iter().enumerate() {
+                    self.ports.write8(port + i as u32, *byte);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn config_space<T: 'static>(&self) -> Result<NonNull<T>, Error> {
+        // The configuration space is only accessible through I/O ports.
+        Err(Error::Unsupported)
+    }
+}
+
+impl<P: PortIo> Drop for LegacyPciTransport<P> {
+    fn drop(&mut self) {
+        // Reset the device when the transport is dropped.
+        self.set_status(DeviceStatus::empty());
+        // Don't hang forever if the device never finishes resetting.
+        if spin_until(|| self.get_status() == DeviceStatus::empty()).is_err() {
+            warn!("Timed out waiting for legacy virtio PCI device to reset");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        device::common::Feature,
+        hal::fake::FakeHal,
+        queue::VirtQueue,
+        transport::pci::bus::{
+            fake::{FakeConfigSpace, FakeFunction},
+            DeviceFunctionInfo, HeaderType,
+        },
+    };
+    use std::sync::{Arc, Mutex};
+
+    const BASE: u32 = 0xc000;
+
+    /// Fake I/O port space containing the registers of BAR0, backed by memory.
+    #[derive(Clone, Debug)]
+    struct FakePorts {
+        registers: Arc<Mutex<[u8; 64]>>,
+    }
+
+    impl FakePorts {
+        fn new() -> Self {
+            Self {
+                registers: Arc::new(Mutex::new([0; 64])),
+            }
+        }
+
+        fn read<const N: usize>(&self, port: u32) -> [u8; N] {
+            let offset = (port - BASE) as usize;
+            assert_eq!(offset % N, 0);
+            self.registers.lock().unwrap()[offset..offset + N]
+                .try_into()
+                .unwrap()
+        }
+
+        fn write<const N: usize>(&self, port: u32, value: [u8; N]) {
+            let offset = (port - BASE) as usize;
+            assert_eq!(offset % N, 0);
+            self.registers.lock().unwrap()[offset..offset + N].copy_from_slice(&value);
+        }
+    }
+
+    impl PortIo for FakePorts {
+        fn read8(&self, port: u32) -> u8 {
+            u8::from_ne_bytes(self.read(port))
+        }
+
+        fn read16(&self, port: u32) -> u16 {
+            u16::from_ne_bytes(self.read(port))
+        }
+
+        fn read32(&self, port: u32) -> u32 {
+            u32::from_ne_bytes(self.read(port))
+        }
+
+        fn write8(&self, port: u32, value: u8) {
+            self.write(port, value.to_ne_bytes());
+        }
+
+        fn write16(&self, port: u32, value: u16) {
+            self.write(port, value.to_ne_bytes());
+        }
+
+        fn write32(&self, port: u32, value: u32) {
+            self.write(port, value.to_ne_bytes());
+        }
+    }
+
+    fn legacy_transport(ports: &FakePorts, msix_enabled: bool) -> LegacyPciTransport<FakePorts> {
+        let mut transport = LegacyPciTransport {
+            device_type: DeviceType::Block,
+            device_function: DeviceFunction {
+                bus: 0,
+                device: 1,
+                function: 0,
+            },
+            ports: ports.clone(),
+            base: BASE,
+            bar_size: 64,
+            config_offset: CONFIG_OFFSET,
+        };
+        transport.set_msix_enabled(msix_enabled);
+        transport
+    }
+
+    #[test]
+    fn new_from_config_space() {
+        let device_function = DeviceFunction {
+            bus: 0,
+            device: 2,
+            function: 0,
+        };
+        let config_space = FakeConfigSpace::default();
+        let mut function = FakeFunction::new(
+            device_function,
+            &DeviceFunctionInfo {
+                vendor_id: VIRTIO_VENDOR_ID,
+                device_id: 0x1001,
+                class: 0x01,
+                subclass: 0x00,
+                prog_if: 0x00,
+                revision: 0x00,
+                header_type: HeaderType::Standard,
+            },
+        )
+        .io_bar(0, 64);
+        function.config[4] |= BASE;
+        config_space.add(function);
+        let mut root = PciRoot::new(config_space);
+
+        let transport =
+            LegacyPciTransport::new(&mut root, device_function, FakePorts::new()).unwrap();
+        assert_eq!(transport.device_type(), DeviceType::Block);
+        assert_eq!(transport.base, BASE);
+        assert_eq!(transport.bar_size, 64);
+        assert_eq!(transport.config_offset, CONFIG_OFFSET);
+    }
+
+    #[test]
+    fn features_and_status() {
+        let ports = FakePorts::new();
+        let mut transport = legacy_transport(&ports, false);
+        ports.write(BASE + DEVICE_FEATURES, 0x1234_5678u32.to_ne_bytes());
+
+        assert_eq!(transport.read_device_features(), 0x1234_5678);
+        transport.write_driver_features(0xffff_0000_0000_0042);
+        assert_eq!(u32::from_ne_bytes(ports.read(BASE + GUEST_FEATURES)), 0x42);
+
+        transport.set_status(DeviceStatus::ACKNOWLEDGE | DeviceStatus::DRIVER);
+        assert_eq!(
+            ports.read::<1>(BASE + DEVICE_STATUS),
+            [(DeviceStatus::ACKNOWLEDGE | DeviceStatus::DRIVER).bits() as u8]
+        );
+        assert_eq!(
+            transport.get_status(),
+            DeviceStatus::ACKNOWLEDGE | DeviceStatus::DRIVER
+        );
+
+        drop(transport);
+        assert_eq!(ports.read::<1>(BASE + DEVICE_STATUS), [0]);
+    }
+
+    #[test]
+    fn queue_pfn() {
+        let ports = FakePorts::new();
+        let mut transport = legacy_transport(&ports, false);
+        ports.write(BASE + QUEUE_SIZE, 8u16.to_ne_bytes());
+
+        assert_eq!(transport.max_queue_size(1), 8);
+        assert!(!transport.queue_used(1));
+        transport.queue_set(1, 8, 0x4_2000, 0x4_2080, 0x4_3000);
+        assert_eq!(u16::from_ne_bytes(ports.read(BASE + QUEUE_SELECT)), 1);
+        assert_eq!(u32::from_ne_bytes(ports.read(BASE + QUEUE_ADDRESS)), 0x42);
+        assert!(transport.queue_used(1));
+        transport.queue_unset(1);
+        assert!(!transport.queue_used(1));
+    }
+
+    #[test]
+    fn queue_size_fixed() {
+        let ports = FakePorts::new();
+        let mut transport = legacy_transport(&ports, false);
+        ports.write(BASE + QUEUE_SIZE, 8u16.to_ne_bytes());
+
+        assert_eq!(
+            VirtQueue::<FakeHal, 4>::new_with_features(&mut transport, 0, Feature::empty())
+                .unwrap_err(),
+            Error::Unsupported
+        );
+    }
+
+    #[test]
+    fn config_without_msix() {
+        let ports = FakePorts::new();
+        let mut transport = legacy_transport(&ports, false);
+
+        transport.write_config_space(0, 0x1122_3344u32).unwrap();
+        transport.write_config_space(4, 0x55u8).unwrap();
+        assert_eq!(
+            u32::from_ne_bytes(ports.read(BASE + CONFIG_OFFSET)),
+            0x1122_3344
+        );
+        assert_eq!(transport.read_config_space::<u32>(0), Ok(0x1122_3344));
+        assert_eq!(transport.read_config_space::<[u8; 6]>(0).unwrap()[4], 0x55);
+        assert_eq!(
+            transport.read_config_space::<u32>(44),
+            Err(Error::ConfigSpaceTooSmall)
+        );
+        assert_eq!(transport.set_config_msix_vector(1), None);
+    }
+
+    #[test]
+    fn config_with_msix() {
+        let ports = FakePorts::new();
+        let mut transport = legacy_transport(&ports, true);
+
+        transport.write_config_space(0, 0xabcdu16).unwrap();
+        assert_eq!(
+            u16::from_ne_bytes(ports.read(BASE + CONFIG_OFFSET_MSIX)),
+            0xabcd
+        );
+        assert_eq!(transport.read_config_space::<u16>(0), Ok(0xabcd));
+
+        assert_eq!(transport.set_config_msix_vector(3), Some(3));
+        assert_eq!(u16::from_ne_bytes(ports.read(BASE + CONFIG_MSIX_VECTOR)), 3);
+        assert_eq!(transport.set_queue_msix_vector(2, 5), Some(5));
+        assert_eq!(u16::from_ne_bytes(ports.read(BASE + QUEUE_SELECT)), 2);
+        assert_eq!(u16::from_ne_bytes(ports.read(BASE + QUEUE_MSIX_VECTOR)), 5);
+    }
+}
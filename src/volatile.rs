@@ -104,5 +104,88 @@ macro_rules! volwrite {
     };
 }
 
+/// Returns the offset in bytes of the given field within the given struct.
+///
+/// This does the same as `core::mem::offset_of!`, which needs Rust 1.77.
+macro_rules! field_offset {
+    ($struct:ty, $field:ident) => {{
+        let base = core::mem::MaybeUninit::<$struct>::uninit();
+        let base_ptr = base.as_ptr();
+        // Safe because `addr_of!` only computes the address of the field, within `base`, without
+        // reading it or creating a reference to it.
+        let field_ptr = unsafe { core::ptr::addr_of!((*base_ptr).$field) };
+        field_ptr as usize - base_ptr as usize
+    }};
+}
+
+/// A field of a device configuration space struct which the driver may read.
+pub(crate) trait ReadableField {
+    /// The type of value which the field holds.
+    type Value;
+}
+
+impl<T: Copy> ReadableField for ReadOnly<T> {
+    type Value = T;
+}
+
+impl<T: Copy> ReadableField for Volatile<T> {
+    type Value = T;
+}
+
+/// A field of a device configuration space struct which the driver may write.
+pub(crate) trait WritableField {
+    /// The type of value which the field holds.
+    type Value;
+}
+
+impl<T: Copy> WritableField for WriteOnly<T> {
+    type Value = T;
+}
+
+impl<T: Copy> WritableField for Volatile<T> {
+    type Value = T;
+}
+
+/// Checks at compile time that the field selected by `_field` may be read, and that `_value` is
+/// the result of reading it.
+pub(crate) fn check_readable<S, F: ReadableField>(
+    _field: fn(&S) -> &F,
+    _value: &crate::Result<F::Value>,
+) {
+}
+
+/// Checks at compile time that the field selected by `_field` may be written, and that `_value`
+/// has the right type to be written to it.
+pub(crate) fn check_writable<S, F: WritableField>(_field: fn(&S) -> &F, _value: &F::Value) {}
+
+/// Reads the given field of a device configuration space struct through a transport.
+///
+/// The field must be `ReadOnly` or `Volatile`, and the result is a `Result` of the type which it
+/// wraps.
+macro_rules! read_config {
+    ($transport:expr, $struct:ty, $field:ident) => {{
+        let value = $transport.read_config_space($crate::volatile::field_offset!($struct, $field));
+        $crate::volatile::check_readable(|config: &$struct| &config.$field, &value);
+        value
+    }};
+}
+
+/// Writes the given field of a device configuration space struct through a transport.
+///
+/// The field must be `WriteOnly` or `Volatile`, and the value must be of the type which it wraps.
+// Only drivers which need `alloc` write to their config space.
+#[cfg_attr(not(feature = "alloc"), allow(unused_macros))]
+macro_rules! write_config {
+    ($transport:expr, $struct:ty, $field:ident, $value:expr) => {{
+        let value = $value;
+        $crate::volatile::check_writable(|config: &$struct| &config.$field, &value);
+        $transport.write_config_space($crate::volatile::field_offset!($struct, $field), value)
+    }};
+}
+
+pub(crate) use field_offset;
+pub(crate) use read_config;
 pub(crate) use volread;
 pub(crate) use volwrite;
+#[cfg_attr(not(feature = "alloc"), allow(unused_imports))]
+pub(crate) use write_config;